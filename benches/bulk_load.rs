@@ -0,0 +1,50 @@
+#![feature(test)]
+
+extern crate test;
+
+use std::path::PathBuf;
+
+use pr65::{Db, DefaultComparator, Options};
+use test::Bencher;
+
+const KEY_COUNT: u32 = 20000;
+
+fn bench_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("pr65_bench_{}_{}", name, std::process::id()))
+}
+
+fn options() -> Options {
+    Options::new("bench_bulk_load", 64, 4, 10, 64, 64 * 1024, 64, 1024)
+}
+
+fn pairs() -> impl Iterator<Item=(Vec<u8>, Vec<u8>)> {
+    (0..KEY_COUNT).map(|i| (format!("key{:08}", i).into_bytes(), vec![b'v'; 100]))
+}
+
+/// Imports the same sorted pairs in every iteration into a fresh database, which is closed so the
+/// flushes and compactions the import leads to are part of it.
+fn import(b: &mut Bencher, name: &str, load: impl Fn(&Db<DefaultComparator>)) {
+    let dir = bench_dir(name);
+    b.bytes = pairs().map(|(key, value)| (key.len() + value.len()) as u64).sum();
+    b.iter(|| {
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Db::open(&dir, options(), DefaultComparator()).unwrap();
+        load(&db);
+        db.close().unwrap();
+    });
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[bench]
+fn bench_bulk_load(b: &mut Bencher) {
+    import(b, "bulk_load", |db| db.bulk_load(pairs()).unwrap())
+}
+
+#[bench]
+fn bench_put_loop(b: &mut Bencher) {
+    import(b, "put_loop", |db| {
+        for (key, value) in pairs() {
+            db.put(&key, &value).unwrap();
+        }
+    })
+}
@@ -0,0 +1,27 @@
+#![feature(test)]
+
+extern crate test;
+
+use crc::crc32;
+use test::Bencher;
+
+/// About the size of the data region of a large table.
+const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+fn data_block() -> Vec<u8> {
+    (0..BLOCK_SIZE).map(|i| (i * 7919 % 251) as u8).collect()
+}
+
+#[bench]
+fn bench_checksum_ieee(b: &mut Bencher) {
+    let block = data_block();
+    b.bytes = block.len() as u64;
+    b.iter(|| crc32::checksum_ieee(&block))
+}
+
+#[bench]
+fn bench_checksum_castagnoli(b: &mut Bencher) {
+    let block = data_block();
+    b.bytes = block.len() as u64;
+    b.iter(|| crc32::checksum_castagnoli(&block))
+}
@@ -0,0 +1,59 @@
+#![feature(test)]
+
+extern crate test;
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use pr65::{Db, DefaultComparator, Options};
+use test::Bencher;
+
+const KEY_COUNT: u32 = 5000;
+const THREAD_COUNTS: [usize; 4] = [1, 2, 4, 8];
+
+fn bench_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("pr65_bench_{}_{}", name, std::process::id()))
+}
+
+/// Writes enough keys for level 0 to be compacted over and over, each compaction producing many
+/// small tables, then closes the database so every compaction is done before it returns.
+fn write_and_compact(background_threads: usize) {
+    let dir = bench_dir(&format!("compaction_{}", background_threads));
+    let value = vec![b'v'; 100];
+    let _ = std::fs::remove_dir_all(&dir);
+    let options = Options::new("bench_compaction", 64, 2, 10, 64, 8192, 64, 1024)
+        .with_level1_bytes(64 * 1024)
+        .with_background_threads(background_threads);
+    let db = Db::open(&dir, options, DefaultComparator()).unwrap();
+    for i in (0..KEY_COUNT).map(|i| i * 7919 % KEY_COUNT) {
+        db.put(format!("key{:08}", i).as_bytes(), &value).unwrap();
+    }
+    db.close().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Every iteration runs with the next of `THREAD_COUNTS` background threads, so the time per
+/// iteration is their mean. How long a run took with each of them is printed at the end, along
+/// with the speedup over a single thread, which `--nocapture` shows.
+#[bench]
+fn bench_compaction_scaling(b: &mut Bencher) {
+    let mut elapsed = [Duration::ZERO; THREAD_COUNTS.len()];
+    let mut runs = [0u32; THREAD_COUNTS.len()];
+    let mut iteration = 0;
+    b.iter(|| {
+        let i = iteration % THREAD_COUNTS.len();
+        iteration += 1;
+        let start = Instant::now();
+        write_and_compact(THREAD_COUNTS[i]);
+        elapsed[i] += start.elapsed();
+        runs[i] += 1;
+    });
+    let per_run: Vec<_> = elapsed.iter().zip(runs.iter())
+        .filter(|&(_, &runs)| runs != 0)
+        .map(|(&elapsed, &runs)| elapsed / runs)
+        .collect();
+    for (background_threads, run) in THREAD_COUNTS.iter().zip(per_run.iter()) {
+        eprintln!("{} background threads: {:?} per run, {:.2}x the speed of 1",
+                  background_threads, run, per_run[0].as_secs_f64() / run.as_secs_f64());
+    }
+}
@@ -0,0 +1,57 @@
+#![feature(test)]
+
+extern crate test;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use pr65::{Db, DefaultComparator, Options};
+use test::Bencher;
+
+/// Counts the allocations made and the ones still alive, to see what the memtable holds on to.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static FREES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        FREES.fetch_add(1, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Small puts into a memtable large enough never to be flushed meanwhile.
+fn small_puts(db: &Db<DefaultComparator>, count: u32) {
+    for i in (0..count).map(|i| i.wrapping_mul(7919) % count) {
+        db.put(format!("key{:08}", i).as_bytes(), b"value").unwrap();
+    }
+}
+
+fn open_db() -> Db<DefaultComparator> {
+    Db::open_in_memory(Options::new("bench_memtable", 64, 4, 10, 64, 1 << 26, 64, 64), DefaultComparator()).unwrap()
+}
+
+/// Reports the allocations of a million small puts, in all and still alive once they are done,
+/// then times a smaller batch. The live ones are what the memtable holds: an arena block per many
+/// entries plus the nodes of its map, rather than a key and a value for every entry.
+#[bench]
+fn bench_small_puts(b: &mut Bencher) {
+    let db = open_db();
+    let (allocations, frees) = (ALLOCATIONS.load(Ordering::Relaxed), FREES.load(Ordering::Relaxed));
+    small_puts(&db, 1_000_000);
+    let allocated = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let live = allocated - (FREES.load(Ordering::Relaxed) - frees);
+    eprintln!("1000000 small puts: {} allocations, {} of them alive", allocated, live);
+    drop(db);
+
+    b.iter(|| small_puts(&open_db(), 10_000));
+}
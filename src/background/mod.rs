@@ -0,0 +1,302 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::background::rate_limiter::RateLimiter;
+
+mod rate_limiter;
+
+pub(crate) type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct TaskQueue {
+    jobs: VecDeque<Job>,
+    running: usize,
+    shutdown: bool
+}
+
+struct TaskQueueShared {
+    queue: Mutex<TaskQueue>,
+    condvar: Condvar
+}
+
+/// A fixed pool of worker threads running flush and compaction jobs in submission order.
+pub(crate) struct BackgroundTaskManager {
+    shared: Arc<TaskQueueShared>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    /// Set once the timer is to stop, it sleeps on the condvar between ticks.
+    timer_stop: Arc<(Mutex<bool>, Condvar)>,
+    timer: Mutex<Option<JoinHandle<()>>>,
+    rate_limiter: RateLimiter
+}
+
+impl BackgroundTaskManager {
+    pub(crate) fn new(thread_count: usize) -> Self {
+        let shared = Arc::new(TaskQueueShared {
+            queue: Mutex::new(TaskQueue { jobs: VecDeque::new(), running: 0, shutdown: false }),
+            condvar: Condvar::new()
+        });
+        let workers = (0..thread_count).map(|i| {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("pr65-background-{}", i))
+                .spawn(move || Self::worker_loop(&shared))
+                .unwrap()
+        }).collect();
+        Self {
+            shared,
+            workers: Mutex::new(workers),
+            timer_stop: Arc::new((Mutex::new(false), Condvar::new())),
+            timer: Mutex::new(None),
+            rate_limiter: RateLimiter::new(0)
+        }
+    }
+
+    /// Holds the writes reported to `throttle` back to `bytes_per_sec` on average, 0 for no limit.
+    pub(crate) fn with_rate_limit(mut self, bytes_per_sec: usize) -> Self {
+        self.rate_limiter = RateLimiter::new(bytes_per_sec);
+        self
+    }
+
+    /// Waits until a background job may write `bytes` under the rate limit.
+    pub(crate) fn throttle(&self, bytes: usize) {
+        self.rate_limiter.request(bytes);
+    }
+
+    /// Bytes per second passed through `throttle` over the last second or two.
+    pub(crate) fn write_rate(&self) -> u64 {
+        self.rate_limiter.rate()
+    }
+
+    pub(crate) fn submit(&self, job: impl FnOnce() + Send + 'static) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.jobs.push_back(Box::new(job));
+        self.shared.condvar.notify_all();
+    }
+
+    /// Runs `jobs` side by side and returns their results in order. Idle workers help out, but the
+    /// calling thread runs whatever no worker has picked up yet itself, so a job may call this
+    /// without waiting on a pool whose workers are all busy.
+    pub(crate) fn run_all<T: Send + 'static>(&self, jobs: Vec<Box<dyn FnOnce() -> T + Send>>) -> Vec<T> {
+        let job_count = jobs.len();
+        let group = Arc::new(JobGroup {
+            state: Mutex::new(JobGroupState {
+                jobs: jobs.into_iter().enumerate().collect(),
+                results: (0..job_count).map(|_| None).collect(),
+                remaining: job_count
+            }),
+            condvar: Condvar::new()
+        });
+        for _ in 1..job_count {
+            let group = group.clone();
+            self.submit(move || group.run_jobs());
+        }
+        group.run_jobs();
+        let mut state = group.state.lock().unwrap();
+        while state.remaining != 0 {
+            state = group.condvar.wait(state).unwrap();
+        }
+        state.results.drain(..).map(Option::unwrap).collect()
+    }
+
+    /// Calls `tick` every `period` on a thread of its own until shutdown, which waits for a tick
+    /// under way. Started at most once, `tick` should submit work rather than do it.
+    pub(crate) fn start_timer(&self, period: Duration, tick: impl Fn() + Send + 'static) {
+        let mut timer = self.timer.lock().unwrap();
+        assert!(timer.is_none(), "the timer is started once");
+        let timer_stop = self.timer_stop.clone();
+        let handle = thread::Builder::new()
+            .name("pr65-timer".to_string())
+            .spawn(move || {
+                let (stop, stopped) = &*timer_stop;
+                let mut stop = stop.lock().unwrap();
+                loop {
+                    stop = stopped.wait_timeout_while(stop, period, |stop| !*stop).unwrap().0;
+                    if *stop {
+                        return;
+                    }
+                    drop(stop);
+                    tick();
+                    stop = timer_stop.0.lock().unwrap();
+                }
+            })
+            .unwrap();
+        timer.replace(handle);
+    }
+
+    /// Blocks until no job is queued or running.
+    #[cfg(test)]
+    pub(crate) fn wait_idle(&self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        while !queue.jobs.is_empty() || queue.running != 0 {
+            queue = self.shared.condvar.wait(queue).unwrap();
+        }
+    }
+
+    /// Stops the workers after every queued job, including jobs submitted by running jobs, has
+    /// finished. Calling it more than once is harmless.
+    pub(crate) fn shutdown(&self) {
+        let current = thread::current().id();
+        // the timer goes first, so it submits nothing the workers would never run
+        *self.timer_stop.0.lock().unwrap() = true;
+        self.timer_stop.1.notify_all();
+        if let Some(timer) = self.timer.lock().unwrap().take() {
+            if timer.thread().id() != current {
+                let _ = timer.join();
+            }
+        }
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.condvar.notify_all();
+        for worker in self.workers.lock().unwrap().drain(..) {
+            // the last reference to the manager may be dropped by one of its own jobs
+            if worker.thread().id() != current {
+                let _ = worker.join();
+            }
+        }
+    }
+
+    fn worker_loop(shared: &TaskQueueShared) {
+        let mut queue = shared.queue.lock().unwrap();
+        loop {
+            if let Some(job) = queue.jobs.pop_front() {
+                queue.running += 1;
+                drop(queue);
+                job();
+                queue = shared.queue.lock().unwrap();
+                queue.running -= 1;
+                shared.condvar.notify_all();
+            } else if queue.shutdown {
+                return;
+            } else {
+                queue = shared.condvar.wait(queue).unwrap();
+            }
+        }
+    }
+}
+
+struct JobGroupState<T> {
+    jobs: VecDeque<(usize, Box<dyn FnOnce() -> T + Send>)>,
+    results: Vec<Option<T>>,
+    remaining: usize
+}
+
+/// The jobs of one `run_all`, taken by whichever thread gets to them first.
+struct JobGroup<T> {
+    state: Mutex<JobGroupState<T>>,
+    condvar: Condvar
+}
+
+impl<T> JobGroup<T> {
+    fn run_jobs(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some((idx, job)) = state.jobs.pop_front() {
+            drop(state);
+            let result = job();
+            state = self.state.lock().unwrap();
+            state.results[idx] = Some(result);
+            state.remaining -= 1;
+        }
+        if state.remaining == 0 {
+            self.condvar.notify_all();
+        }
+    }
+}
+
+impl Drop for BackgroundTaskManager {
+    fn drop(&mut self) {
+        self.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::background::BackgroundTaskManager;
+
+    #[test]
+    fn test_shutdown_drains_jobs() {
+        let manager = Arc::new(BackgroundTaskManager::new(2));
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..16 {
+            let counter = counter.clone();
+            let inner_manager = manager.clone();
+            manager.submit(move || {
+                thread::sleep(Duration::from_millis(1));
+                counter.fetch_add(1, Ordering::SeqCst);
+                let counter = counter.clone();
+                inner_manager.submit(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            });
+        }
+        manager.shutdown();
+        assert_eq!(counter.load(Ordering::SeqCst), 32);
+    }
+
+    #[test]
+    fn test_timer_stops_at_shutdown() {
+        let manager = BackgroundTaskManager::new(1);
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let counter = ticks.clone();
+        manager.start_timer(Duration::from_millis(5), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        while ticks.load(Ordering::SeqCst) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        manager.shutdown();
+        let stopped_at = ticks.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(ticks.load(Ordering::SeqCst), stopped_at);
+    }
+
+    #[test]
+    fn test_run_all() {
+        let manager = BackgroundTaskManager::new(4);
+        let jobs: Vec<Box<dyn FnOnce() -> usize + Send>> = (0..16usize).map(|i| {
+            Box::new(move || {
+                thread::sleep(Duration::from_millis(1));
+                i * 2
+            }) as Box<dyn FnOnce() -> usize + Send>
+        }).collect();
+        assert_eq!(manager.run_all(jobs), (0..16).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(manager.run_all(Vec::<Box<dyn FnOnce() + Send>>::new()).is_empty());
+    }
+
+    #[test]
+    fn test_run_all_within_job() {
+        // the only worker is busy with the job calling run_all, which runs the group itself
+        let manager = Arc::new(BackgroundTaskManager::new(1));
+        let counter = Arc::new(AtomicUsize::new(0));
+        let (inner_manager, inner_counter) = (manager.clone(), counter.clone());
+        manager.submit(move || {
+            let jobs: Vec<Box<dyn FnOnce() + Send>> = (0..8).map(|_| {
+                let counter = inner_counter.clone();
+                Box::new(move || { counter.fetch_add(1, Ordering::SeqCst); }) as Box<dyn FnOnce() + Send>
+            }).collect();
+            inner_manager.run_all(jobs);
+            inner_counter.fetch_add(100, Ordering::SeqCst);
+        });
+        manager.wait_idle();
+        assert_eq!(counter.load(Ordering::SeqCst), 108);
+        manager.shutdown();
+    }
+
+    #[test]
+    fn test_wait_idle() {
+        let manager = BackgroundTaskManager::new(4);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..64 {
+            let counter = counter.clone();
+            manager.submit(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        manager.wait_idle();
+        assert_eq!(counter.load(Ordering::SeqCst), 64);
+    }
+}
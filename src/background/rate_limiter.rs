@@ -0,0 +1,119 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Holds writes back to `bytes_per_sec` on average. Every write books the time it takes at that
+/// rate right after the writes booked before it, then sleeps until that time is up, so the rate is
+/// shared by however many threads write. A limit of 0 lets every write through at once.
+pub(crate) struct RateLimiter {
+    bytes_per_sec: usize,
+    /// When the writes booked so far are done at the limited rate.
+    booked_until: Mutex<Instant>,
+    window: Mutex<RateWindow>
+}
+
+/// Bytes let through in the current second and the one before it, what `RateLimiter::rate`
+/// averages over.
+struct RateWindow {
+    start: Instant,
+    bytes: u64,
+    last_bytes: u64
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            bytes_per_sec,
+            booked_until: Mutex::new(now),
+            window: Mutex::new(RateWindow { start: now, bytes: 0, last_bytes: 0 })
+        }
+    }
+
+    /// Waits until `bytes` may be written.
+    pub(crate) fn request(&self, bytes: usize) {
+        if self.bytes_per_sec != 0 {
+            let until = {
+                let mut booked_until = self.booked_until.lock().unwrap();
+                let start = (*booked_until).max(Instant::now());
+                *booked_until = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+                *booked_until
+            };
+            let now = Instant::now();
+            if until > now {
+                thread::sleep(until - now);
+            }
+        }
+        let mut window = self.window.lock().unwrap();
+        window.roll(Instant::now());
+        window.bytes += bytes as u64;
+    }
+
+    /// Bytes per second let through lately, averaged over the last one to two seconds.
+    pub(crate) fn rate(&self) -> u64 {
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap();
+        window.roll(now);
+        let elapsed = 1.0 + (now - window.start).as_secs_f64();
+        ((window.last_bytes + window.bytes) as f64 / elapsed) as u64
+    }
+}
+
+impl RateWindow {
+    /// Starts a new second once the current one is over. After a second without writes there is
+    /// nothing left to average.
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now - self.start;
+        if elapsed < Duration::from_secs(1) {
+            return
+        }
+        self.last_bytes = if elapsed < Duration::from_secs(2) { self.bytes } else { 0 };
+        self.bytes = 0;
+        self.start = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::background::rate_limiter::RateLimiter;
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = Arc::new(RateLimiter::new(40 * 1024));
+        let start = Instant::now();
+        // four writers share the rate, 48 KiB take at least 1.2 seconds
+        let writers: Vec<_> = (0..4).map(|_| {
+            let limiter = limiter.clone();
+            thread::spawn(move || {
+                for _ in 0..3 {
+                    limiter.request(4 * 1024);
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(1200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+        let rate = limiter.rate();
+        assert!(rate > 10 * 1024 && rate <= 48 * 1024, "{}", rate);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new(0);
+        assert_eq!(limiter.rate(), 0);
+        let start = Instant::now();
+        for _ in 0..100 {
+            limiter.request(1024 * 1024);
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // the bytes are counted all the same
+        assert!(limiter.rate() >= 50 * 1024 * 1024);
+    }
+}
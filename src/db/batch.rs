@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use crate::{Comparator, Options};
+use crate::error::Error;
+use crate::partition::{Entry, InternalKey, UserKey};
+
+/// How `Db::write_with_options` applies a write.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Applies the write to the memtables without logging it, which is much faster. Such writes
+    /// are lost on a crash before they are flushed, so this only suits writes which can be done
+    /// again, like a bulk import which is simply rerun.
+    pub disable_wal: bool,
+    /// Syncs the log before returning, otherwise `Options::sync_policy` decides. Nothing is synced
+    /// if `disable_wal` is set.
+    pub sync: bool
+}
+
+impl WriteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_disable_wal(mut self, disable_wal: bool) -> Self {
+        self.disable_wal = disable_wal;
+        self
+    }
+
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+}
+
+#[derive(Clone)]
+enum BatchOp {
+    Put(Vec<u8>),
+    Delete,
+    Merge(Vec<u8>)
+}
+
+/// Puts, deletes and merges applied together by `Db::write`. Later operations on the same key win
+/// over earlier ones, a merge is applied on top of them.
+#[derive(Default, Clone)]
+pub struct WriteBatch {
+    ops: Vec<(Vec<u8>, BatchOp)>
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push((key.to_vec(), BatchOp::Put(value.to_vec())));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push((key.to_vec(), BatchOp::Delete));
+        self
+    }
+
+    /// Records `operand` for `Options::merge_operator` to fold into the value of `key`, see
+    /// `Db::merge`.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> &mut Self {
+        self.ops.push((key.to_vec(), BatchOp::Merge(operand.to_vec())));
+        self
+    }
+
+    pub fn clear(&mut self) -> &mut Self {
+        self.ops.clear();
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Fails on the first key or value larger than `options` allow, or on a merge without
+    /// `Options::merge_operator`.
+    pub(crate) fn check_sizes(&self, options: &Options) -> Result<(), Error> {
+        for (key, op) in self.ops.iter() {
+            let value_size = match op {
+                BatchOp::Put(value) => value.len(),
+                BatchOp::Delete => 0,
+                BatchOp::Merge(_) if options.merge_operator.is_none() => {
+                    return Err(Error::invalid_argument("merge without Options::merge_operator".into()))
+                },
+                BatchOp::Merge(operand) => operand.len()
+            };
+            check_pair_sizes(key, value_size, options)?;
+        }
+        Ok(())
+    }
+
+    /// The entries of the batch in the order they were added. Their sequence numbers are 0 until
+    /// the partition writing them numbers them, see `ArcPartition::write_next`.
+    pub(crate) fn into_entries<Comp>(self, comparator: &Arc<Comp>) -> Vec<Entry<Comp>>
+        where Comp: Comparator {
+        self.ops.into_iter().map(|(key, op)| {
+            let user_key = UserKey::new_owned(key, comparator);
+            match op {
+                BatchOp::Put(value) => (InternalKey::new(0, user_key), value),
+                BatchOp::Delete => (InternalKey::new_deletion(0, user_key), Vec::new()),
+                BatchOp::Merge(operand) => (InternalKey::new_merge(0, user_key), operand)
+            }
+        }).collect()
+    }
+}
+
+/// Fails if `key` or a value of `value_size` bytes is larger than `options` allow.
+pub(crate) fn check_pair_sizes(key: &[u8], value_size: usize, options: &Options) -> Result<(), Error> {
+    if key.len() > options.key_size_max {
+        return Err(Error::invalid_argument(
+            format!("key of {} bytes exceeds key_size_max ({})", key.len(), options.key_size_max).into()
+        ))
+    }
+    if value_size > options.value_size_max {
+        return Err(Error::invalid_argument(
+            format!("value of {} bytes exceeds value_size_max ({})", value_size, options.value_size_max).into()
+        ))
+    }
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::Comparator;
+use crate::db::{DbIterator, Snapshot};
+use crate::error::Error;
+use crate::partition::{ArcPartition, UserKey};
+
+type Pair = (Vec<u8>, Vec<u8>);
+
+/// A position among the key/value pairs of a database live as of a snapshot, which can be moved
+/// either way. Moving forward merges on from where the cursor is with a `DbIterator`, which is
+/// sought to every new position rather than built anew. Moving back
+/// reads the partition before the position up to it first, the pairs read are then stepped
+/// through one by one, so walking back costs a read of every partition walked through. Should a
+/// table fail to load, the cursor becomes invalid and the error is reported by `status`.
+pub struct Cursor<Comp: 'static + Comparator> {
+    partitions: Vec<ArcPartition<Comp>>,
+    comparator: Arc<Comp>,
+    snapshot: Snapshot,
+    current: Option<Pair>,
+    /// The pairs after `current`, sought back to it when moving forward after moving back.
+    forward: DbIterator<Comp>,
+    /// Whether `forward` is at the pair after `current`.
+    forward_in_step: bool,
+    /// The pairs before `current` down to the start of its partition, nearest last, once moving
+    /// back has read them.
+    backward: Vec<Pair>,
+    error: Option<Error>
+}
+
+impl<Comp: 'static + Comparator> Cursor<Comp> {
+    /// A cursor over `partitions`, in key order, positioned nowhere yet.
+    pub(crate) fn new(partitions: Vec<ArcPartition<Comp>>, comparator: Arc<Comp>, snapshot: Snapshot) -> Self {
+        let forward = DbIterator::new(partitions.iter().cloned().collect(), comparator.clone(),
+                                      Bound::Unbounded, Bound::Unbounded, snapshot.clone(), false);
+        Self {
+            partitions,
+            comparator,
+            snapshot,
+            current: None,
+            forward,
+            forward_in_step: false,
+            backward: Vec::new(),
+            error: None
+        }
+    }
+
+    /// Whether the cursor is at a pair. It is not before the first seek, after moving past either
+    /// end, or after an error.
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// The key of the pair the cursor is at. Panics unless the cursor is `valid`.
+    pub fn key(&self) -> &[u8] {
+        &self.current.as_ref().expect("cursor is not at a pair").0
+    }
+
+    /// The value of the pair the cursor is at. Panics unless the cursor is `valid`.
+    pub fn value(&self) -> &[u8] {
+        &self.current.as_ref().expect("cursor is not at a pair").1
+    }
+
+    pub fn status(&self) -> Result<(), Error> {
+        match &self.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(())
+        }
+    }
+
+    /// Moves to the first pair whose key is not less than `key`.
+    pub fn seek(&mut self, key: &[u8]) {
+        self.seek_forward(Bound::Included(key));
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.seek_forward(Bound::Unbounded);
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.current = None;
+        self.forward_in_step = false;
+        self.backward.clear();
+        self.step_back(None);
+    }
+
+    /// Moves to the next pair. Does nothing unless the cursor is `valid`.
+    pub fn next(&mut self) {
+        let key = match &self.current {
+            Some((key, _)) => key.clone(),
+            None => return
+        };
+        self.backward.clear();
+        if !self.forward_in_step {
+            self.forward.seek(Bound::Excluded(&key));
+        }
+        self.step_forward();
+    }
+
+    /// Moves to the previous pair. Does nothing unless the cursor is `valid`.
+    pub fn prev(&mut self) {
+        let key = match self.current.take() {
+            Some((key, _)) => key,
+            None => return
+        };
+        self.forward_in_step = false;
+        self.step_back(Some(&key));
+    }
+
+    fn seek_forward(&mut self, start: Bound<&[u8]>) {
+        self.backward.clear();
+        self.forward.seek(start);
+        self.step_forward();
+    }
+
+    fn step_forward(&mut self) {
+        self.current = self.forward.next();
+        self.forward_in_step = true;
+        if let Err(e) = self.forward.status() {
+            self.fail(e);
+        }
+    }
+
+    /// Moves to the last pair before `before`, or the last one of all without it. Once the pairs
+    /// read before run out, the partitions before are read until one has a pair left.
+    fn step_back(&mut self, before: Option<&[u8]>) {
+        if self.backward.is_empty() {
+            match self.read_backward(before) {
+                Ok(pairs) => self.backward = pairs,
+                Err(e) => return self.fail(e)
+            }
+        }
+        self.current = self.backward.pop();
+    }
+
+    fn read_backward(&self, before: Option<&[u8]>) -> Result<Vec<Pair>, Error> {
+        let end = before.map_or(Bound::Unbounded, Bound::Excluded);
+        for partition in self.partitions.iter().rev() {
+            // a partition starting after the position has nothing before it
+            let above = before.map_or(false, |before| {
+                partition.cmp_key(&UserKey::new_borrow(before, &self.comparator)).is_lt()
+            });
+            if above {
+                continue;
+            }
+            let mut iter = DbIterator::new(VecDeque::from(vec![partition.clone()]), self.comparator.clone(),
+                                           Bound::Unbounded, end, self.snapshot.clone(), false);
+            let pairs: Vec<Pair> = iter.by_ref().collect();
+            iter.status()?;
+            if !pairs.is_empty() {
+                return Ok(pairs)
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    fn fail(&mut self, error: Error) {
+        self.current = None;
+        self.forward_in_step = false;
+        self.backward.clear();
+        self.error.get_or_insert(error);
+    }
+}
@@ -0,0 +1,227 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::Comparator;
+use crate::db::Snapshot;
+use crate::error::Error;
+use crate::partition::{ArcPartition, UserKey};
+use crate::partition::iterator::MergingIterator;
+
+/// Iterates the key/value pairs of a database live as of a snapshot, between two bounds, in
+/// comparator order, which under `Partitioning::Hash` holds within each partition only. Partitions
+/// are only opened once the iteration reaches them, the snapshot held by the iterator keeps what
+/// it sees from being compacted away meanwhile. Should a table fail to
+/// load, the iteration ends early and the error is reported by `status`.
+pub struct DbIterator<Comp: 'static + Comparator> {
+    /// Every partition iterated, for `seek`.
+    all_partitions: Vec<ArcPartition<Comp>>,
+    /// The partitions not opened yet.
+    partitions: VecDeque<ArcPartition<Comp>>,
+    comparator: Arc<Comp>,
+    current: Option<MergingIterator<'static, Comp>>,
+    /// The partition `current` reads.
+    current_partition: Option<ArcPartition<Comp>>,
+    /// The lower bound `current` was opened with, it cannot be sought below it.
+    current_start: Option<Vec<u8>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    snapshot: Snapshot,
+    /// Leaves the values out, see `DbKeyIterator`.
+    keys_only: bool,
+    /// Whether the partitions hold disjoint ranges in order, so the first key past the end is
+    /// past the end of the following partitions as well.
+    ordered_partitions: bool,
+    error: Option<Error>
+}
+
+impl<Comp: 'static + Comparator> DbIterator<Comp> {
+    pub(crate) fn new(partitions: VecDeque<ArcPartition<Comp>>,
+                      comparator: Arc<Comp>,
+                      start: Bound<&[u8]>,
+                      end: Bound<&[u8]>,
+                      snapshot: Snapshot,
+                      keys_only: bool) -> Self {
+        Self {
+            all_partitions: partitions.iter().cloned().collect(),
+            partitions,
+            comparator,
+            current: None,
+            current_partition: None,
+            current_start: None,
+            start: bound_to_owned(start),
+            end: bound_to_owned(end),
+            snapshot,
+            keys_only,
+            ordered_partitions: true,
+            error: None
+        }
+    }
+
+    pub(crate) fn with_ordered_partitions(mut self, ordered_partitions: bool) -> Self {
+        self.ordered_partitions = ordered_partitions;
+        self
+    }
+
+    pub fn status(&self) -> Result<(), Error> {
+        match &self.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(())
+        }
+    }
+
+    /// Moves to `start`, backwards or forwards, so the iteration goes on as a new iterator from
+    /// `start` with the same end and snapshot would. The partition read so far is sought rather
+    /// than opened anew if `start` falls into it, at or after where it was opened. An error seen
+    /// before is kept.
+    pub(crate) fn seek(&mut self, start: Bound<&[u8]>) {
+        if self.error.is_some() {
+            return
+        }
+        self.start = bound_to_owned(start);
+        let key = match start {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None
+        };
+        let mut partitions: VecDeque<_> = self.all_partitions.iter().cloned().collect();
+        if let (Some(key), true) = (key, self.ordered_partitions) {
+            let user_key = UserKey::new_borrow(key, &self.comparator);
+            while partitions.front().map_or(false, |partition| partition.cmp_key(&user_key) == Ordering::Greater) {
+                partitions.pop_front();
+            }
+        }
+        let reusable = match (key, &self.current_partition) {
+            (Some(key), Some(current)) => partitions.front().map_or(false, |front| front.ptr_eq(current))
+                && self.current_start.as_ref().map_or(true, |current_start| {
+                    self.comparator.compare(current_start, key) != Ordering::Greater
+                }),
+            _ => false
+        };
+        if reusable {
+            partitions.pop_front();
+            self.current.as_mut().unwrap().seek(key.unwrap());
+        } else {
+            self.release_current();
+        }
+        self.partitions = partitions;
+    }
+
+    fn after_start(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => self.comparator.compare(key, start) != Ordering::Less,
+            Bound::Excluded(start) => self.comparator.compare(key, start) == Ordering::Greater,
+            Bound::Unbounded => true
+        }
+    }
+
+    fn before_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => self.comparator.compare(key, end) != Ordering::Greater,
+            Bound::Excluded(end) => self.comparator.compare(key, end) == Ordering::Less,
+            Bound::Unbounded => true
+        }
+    }
+
+    fn finish(&mut self) {
+        self.release_current();
+        self.partitions.clear();
+    }
+
+    /// Lets go of the tables of the current partition, removing the files of those compactions
+    /// replaced meanwhile unless another iterator still reads them.
+    fn release_current(&mut self) {
+        self.current = None;
+        if let Some(partition) = self.current_partition.take() {
+            partition.remove_obsolete_files();
+        }
+    }
+}
+
+impl<Comp: 'static + Comparator> Drop for DbIterator<Comp> {
+    fn drop(&mut self) {
+        self.release_current();
+    }
+}
+
+impl<Comp: 'static + Comparator> Iterator for DbIterator<Comp> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let partition = self.partitions.pop_front()?;
+                let lower_bound = match &self.start {
+                    Bound::Included(start) | Bound::Excluded(start) => Some(start.as_slice()),
+                    Bound::Unbounded => None
+                };
+                let current = if self.keys_only {
+                    partition.iter_keys(lower_bound, self.snapshot.seq())
+                } else {
+                    partition.iter(lower_bound, self.snapshot.seq())
+                };
+                self.current.replace(current);
+                self.current_partition.replace(partition);
+                self.current_start = lower_bound.map(|lower_bound| lower_bound.to_vec());
+            }
+
+            let current = self.current.as_mut().unwrap();
+            match current.next() {
+                Some((key, value)) => {
+                    if !self.after_start(&key) {
+                        continue;
+                    }
+                    if !self.before_end(&key) {
+                        if self.ordered_partitions {
+                            self.finish();
+                            return None
+                        }
+                        self.release_current();
+                        continue;
+                    }
+                    return Some((key, value))
+                },
+                None => {
+                    if let Err(e) = current.status() {
+                        self.error.replace(e);
+                        self.finish();
+                        return None
+                    }
+                    self.release_current();
+                }
+            }
+        }
+    }
+}
+
+/// Iterates the keys a `DbIterator` with the same bounds and snapshot would, without copying any
+/// value out of the memtables or tables. The tables are still loaded whole, keys and values share
+/// their data region.
+pub struct DbKeyIterator<Comp: 'static + Comparator>(DbIterator<Comp>);
+
+impl<Comp: 'static + Comparator> DbKeyIterator<Comp> {
+    pub(crate) fn new(iter: DbIterator<Comp>) -> Self {
+        debug_assert!(iter.keys_only);
+        Self(iter)
+    }
+
+    pub fn status(&self) -> Result<(), Error> {
+        self.0.status()
+    }
+}
+
+impl<Comp: 'static + Comparator> Iterator for DbKeyIterator<Comp> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, _)| key)
+    }
+}
+
+fn bound_to_owned(bound: Bound<&[u8]>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+        Bound::Unbounded => Bound::Unbounded
+    }
+}
@@ -0,0 +1,2905 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{self, AtomicU64};
+
+use crate::{Comparator, DefaultComparator, Options, Partitioning, SyncPolicy, TableLoading};
+use crate::error::Error;
+use crate::io::{IOManager, TEMP_FILE_SUFFIX};
+use crate::manifest::{Manifest, PartitionRecord};
+use crate::table::cache::{ScTableCache, TableCacheManager, KeyCursor};
+use crate::table::builder::ScTableBuilder;
+use crate::table::sctable::ScTableFile;
+use crate::background::BackgroundTaskManager;
+use crate::partition::{ArcPartition, Entry, InternalKey, StagedTable, UserKey};
+use crate::partition::set::PartitionSet;
+use crate::wal::{parse_log_file_name, unfinished_batches};
+
+mod batch;
+mod cursor;
+mod iterator;
+mod pinned;
+mod snapshot;
+mod stats;
+
+pub use batch::{WriteBatch, WriteOptions};
+pub use cursor::Cursor;
+pub use iterator::{DbIterator, DbKeyIterator};
+pub use pinned::PinnedValue;
+pub use snapshot::Snapshot;
+pub use stats::{PartitionInfo, StatsSnapshot};
+use batch::check_pair_sizes;
+use snapshot::SnapshotList;
+use stats::Stats;
+
+/// Everything shared by all partitions of one database.
+pub(crate) struct DbContext {
+    pub(crate) options: Options,
+    pub(crate) seq: AtomicU64,
+    /// The number the next table file gets.
+    pub(crate) file_number: AtomicU64,
+    pub(crate) snapshots: SnapshotList,
+    pub(crate) cache_manager: TableCacheManager,
+    pub(crate) io_manager: IOManager,
+    pub(crate) background: BackgroundTaskManager,
+    pub(crate) stats: Stats,
+    pub(crate) manifest: Manifest,
+    /// Opened by `Db::open_readonly`: nothing is written, flushed or compacted.
+    pub(crate) read_only: bool
+}
+
+impl DbContext {
+    pub(crate) fn new(db_path: impl AsRef<Path>, options: Options) -> Self {
+        let io_manager = IOManager::new(db_path, options.max_open_files);
+        Self::with_io_manager(io_manager, options, false)
+    }
+
+    pub(crate) fn in_memory(options: Options) -> Self {
+        let io_manager = IOManager::in_memory(options.max_open_files);
+        Self::with_io_manager(io_manager, options, false)
+    }
+
+    /// No background thread is started, there is nothing for one to do.
+    pub(crate) fn read_only(db_path: impl AsRef<Path>, options: Options) -> Self {
+        let io_manager = IOManager::new(db_path, options.max_open_files);
+        Self::with_io_manager(io_manager, options, true)
+    }
+
+    fn with_io_manager(io_manager: IOManager, options: Options, read_only: bool) -> Self {
+        let cache_manager = TableCacheManager::new(options.cache_count)
+            .with_index_only(options.table_loading == TableLoading::IndexOnly)
+            .with_block_cache_bytes(options.block_cache_bytes);
+        let background_threads = if read_only { 0 } else { options.background_threads };
+        let background = BackgroundTaskManager::new(background_threads)
+            .with_rate_limit(options.compaction_rate_limit);
+        let manifest = Manifest::new(options.partitioning);
+        Self {
+            options,
+            seq: AtomicU64::new(0),
+            file_number: AtomicU64::new(1),
+            snapshots: SnapshotList::new(),
+            cache_manager,
+            io_manager,
+            background,
+            stats: Stats::new(),
+            manifest,
+            read_only
+        }
+    }
+
+    /// A table file no other table of this database ever had.
+    pub(crate) fn new_table_file(&self) -> ScTableFile {
+        ScTableFile::new(self.file_number.fetch_add(1, atomic::Ordering::SeqCst))
+    }
+
+    /// Makes sure `table_file` is never handed out again, it names a table from a previous run.
+    fn reserve_table_file(&self, table_file: ScTableFile) {
+        self.file_number.fetch_max(table_file.file_number() + 1, atomic::Ordering::SeqCst);
+    }
+}
+
+pub struct Db<Comp: 'static + Comparator> {
+    context: Arc<DbContext>,
+    comparator: Arc<Comp>,
+    /// Shared with the timer flushing idle memtables, which only holds on to it weakly.
+    partitions: Arc<RwLock<PartitionSet<Comp>>>,
+    /// Set by `close`, which already shut down, so dropping does not do it again.
+    closed: bool
+}
+
+/// A database of keys in bytewise order.
+pub type DefaultDb = Db<DefaultComparator>;
+
+impl<Comp: 'static + Comparator> Db<Comp> {
+    /// Opens the database at `db_path`, keys are ordered by `comparator`. A database must always be
+    /// opened with a comparator ordering keys the same way. The partitions and their tables are
+    /// taken from the manifest, then the logs are replayed on top of them.
+    pub fn open(db_path: impl AsRef<Path>, options: Options, comparator: Comp) -> Result<Self, Error> {
+        options.validate()?;
+        let db_path = db_path.as_ref();
+        fs::create_dir_all(db_path).or_else(
+            |e| Err(Error::io_error(e, db_path.display().to_string()))
+        )?;
+        Self::open_context(DbContext::new(db_path, options), comparator)
+    }
+
+    /// Opens a database which never touches the disk: tables are buffers in memory, and there is
+    /// neither a log nor a manifest. Everything is gone once the database is dropped.
+    pub fn open_in_memory(options: Options, comparator: Comp) -> Result<Self, Error> {
+        options.validate()?;
+        Self::open_context(DbContext::in_memory(options), comparator)
+    }
+
+    /// Opens the database at `db_path` for reading only. Nothing in the directory is touched: the
+    /// tables come from the manifest and the logs are replayed into memtables which are never
+    /// flushed, so reads see what a writable handle opened at the same moment would. Writes fail
+    /// with `Error::ReadOnly`. Any number of read-only handles may share a directory, but a writer
+    /// going on alongside them may remove tables they still refer to.
+    pub fn open_readonly(db_path: impl AsRef<Path>, options: Options, comparator: Comp) -> Result<Self, Error> {
+        options.validate()?;
+        Self::open_context(DbContext::read_only(db_path, options), comparator)
+    }
+
+    fn open_context(context: DbContext, comparator: Comp) -> Result<Self, Error> {
+        let context = Arc::new(context);
+        let comparator = Arc::new(comparator);
+        // above every table on disk, even those about to be removed as orphans
+        for file_name in context.io_manager.list_files()? {
+            if let Some(table_file) = ScTableFile::parse_file_name(&file_name) {
+                context.reserve_table_file(table_file);
+            }
+        }
+        let unfinished = unfinished_batches(&context.io_manager, &comparator, !context.read_only)?;
+        let restored = match Manifest::load(&context.io_manager)? {
+            Some((last_seq, partitioning, mut records)) => {
+                if partitioning != context.options.partitioning {
+                    return Err(Error::invalid_argument(
+                        format!("database was created with {:?}, not {:?}", partitioning,
+                                context.options.partitioning).into()
+                    ))
+                }
+                context.seq.store(last_seq, atomic::Ordering::SeqCst);
+                if !context.read_only {
+                    remove_orphan_files(&context.io_manager, &records)?;
+                }
+                check_tables(&context, &mut records)?;
+                let mut tables = HashMap::new();
+                records.iter()
+                    .map(|record| ArcPartition::restore(context.clone(), comparator.clone(), record, &mut tables))
+                    .collect()
+            },
+            None => Vec::new()
+        };
+        let partitions = PartitionSet::new(&context, &comparator, restored);
+        if !context.read_only {
+            let records = partitions.partitions().iter().map(|partition| partition.manifest_record()).collect();
+            context.manifest.reset(records, context.seq.load(atomic::Ordering::SeqCst), &context.io_manager)?;
+        }
+        for partition in partitions.partitions() {
+            let max_seq = partition.recover(&unfinished)?;
+            context.seq.fetch_max(max_seq, atomic::Ordering::SeqCst);
+        }
+        let partitions = Arc::new(RwLock::new(partitions));
+        if let (Some(idle_timeout), false) = (context.options.memtable_idle_timeout, context.read_only) {
+            let timer_partitions = Arc::downgrade(&partitions);
+            context.background.start_timer(idle_timeout / 2, move || {
+                if let Some(partitions) = timer_partitions.upgrade() {
+                    for partition in partitions.read().unwrap().partitions() {
+                        partition.flush_if_idle(idle_timeout);
+                    }
+                }
+            });
+        }
+        Ok(Self { context, comparator, partitions, closed: false })
+    }
+
+    /// The empty key and the empty value are a key and a value like any other, neither stands for
+    /// a deletion. Under `DefaultComparator` the empty key comes before every other key.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.put_with_sync(key, value, self.sync_policy())
+    }
+
+    /// Like `put`, but syncs the log as `sync` says instead of `Options::sync_policy`.
+    pub fn put_with_sync(&self, key: &[u8], value: &[u8], sync: SyncPolicy) -> Result<(), Error> {
+        self.put_impl(key, value, Some(sync))
+    }
+
+    /// Like `put`, but logs as `options` say, see `write_with_options`.
+    pub fn put_with_options(&self, key: &[u8], value: &[u8], options: &WriteOptions) -> Result<(), Error> {
+        let sync = if options.sync { SyncPolicy::Sync } else { self.sync_policy() };
+        self.put_impl(key, value, Some(sync).filter(|_| !options.disable_wal))
+    }
+
+    /// Writes `value` only if `key` has no live value, a deleted key counts as absent. The check and
+    /// the write happen under one lock of the partition, so of concurrent calls for the same key
+    /// only one writes. Returns whether it wrote.
+    pub fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, Error> {
+        if self.context.read_only {
+            return Err(Error::read_only())
+        }
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        batch.check_sizes(&self.context.options)?;
+        let entry = batch.into_entries(&self.comparator).pop().unwrap();
+        loop {
+            let partition = self.route(key);
+            match partition.put_if_absent((entry.0.make_owned(), entry.1.clone()), Some(self.sync_policy())) {
+                // the halves of a partition grown too large take the entry instead
+                Err(Error::RequiresExplode) => self.split(&partition)?,
+                result => return result
+            }
+        }
+    }
+
+    /// Reads `key` as of `snapshot`, or the latest value if no snapshot is given.
+    pub fn get(&self, key: &[u8], snapshot: Option<&Snapshot>) -> Result<Option<Vec<u8>>, Error> {
+        let seq = match snapshot {
+            Some(snapshot) => snapshot.seq(),
+            // the newest version when the partition is locked: the memtable drops versions as soon
+            // as no snapshot needs them, so a sequence number read earlier may miss its version
+            None => u64::max_value()
+        };
+        self.route(key).get(&InternalKey::new(seq, UserKey::new_borrow(key, &self.comparator)))
+    }
+
+    /// Reads the latest value of `key` like `get`, but a value found in a table is not copied, see
+    /// `PinnedValue`.
+    pub fn get_pinned(&self, key: &[u8]) -> Result<Option<PinnedValue>, Error> {
+        self.route(key).get_pinned(&InternalKey::new(u64::max_value(), UserKey::new_borrow(key, &self.comparator)))
+    }
+
+    /// Reads the latest value of `key` together with the sequence number of the write it came from.
+    pub fn get_with_seq(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        self.route(key).get_with_seq(&InternalKey::new(u64::max_value(), UserKey::new_borrow(key, &self.comparator)))
+    }
+
+    /// Reads the latest values of `keys`, the results are in the same order. The keys of one
+    /// partition are read together under a single lock of it, so they see the partition as of the
+    /// same moment, keys of different partitions may not.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Vec<Result<Option<Vec<u8>>, Error>> {
+        let mut groups: HashMap<u32, (ArcPartition<Comp>, Vec<usize>)> = HashMap::new();
+        {
+            let partitions = self.partitions.read().unwrap();
+            for (idx, key) in keys.iter().enumerate() {
+                let partition = partitions.route(key);
+                groups.entry(partition.partition_id())
+                    .or_insert_with(|| (partition.clone(), Vec::new()))
+                    .1.push(idx);
+            }
+        }
+        let mut results: Vec<_> = keys.iter().map(|_| Ok(None)).collect();
+        for (partition, indices) in groups.into_values() {
+            let group_keys: Vec<_> = indices.iter()
+                .map(|&idx| InternalKey::new(u64::max_value(), UserKey::new_borrow(keys[idx], &self.comparator)))
+                .collect();
+            for (idx, result) in indices.into_iter().zip(partition.multi_get(&group_keys)) {
+                results[idx] = result;
+            }
+        }
+        results
+    }
+
+    /// Tells whether `key` has a live value, without copying the value. Tables whose filter rules
+    /// the key out are not searched, and a deleted key is not there.
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool, Error> {
+        self.route(key).contains(&InternalKey::new(u64::max_value(), UserKey::new_borrow(key, &self.comparator)))
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        self.delete_with_sync(key, self.sync_policy())
+    }
+
+    /// Like `delete`, but syncs the log as `sync` says instead of `Options::sync_policy`.
+    pub fn delete_with_sync(&self, key: &[u8], sync: SyncPolicy) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write_with_sync(batch, sync)
+    }
+
+    /// Like `delete`, but logs as `options` say, see `write_with_options`.
+    pub fn delete_with_options(&self, key: &[u8], options: &WriteOptions) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write_with_options(batch, options)
+    }
+
+    /// Records `operand` for `Options::merge_operator` to fold into the value of `key` when it is
+    /// read or compacted, without reading the value first. Fails without a merge operator.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        batch.merge(key, operand);
+        self.write(batch)
+    }
+
+    /// Deletes every key of `[start, end)` with a single range tombstone per partition holding
+    /// some of them, however many keys there are. Nothing is deleted if `start` is not below
+    /// `end`.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<(), Error> {
+        self.delete_range_with_sync(start, end, self.sync_policy())
+    }
+
+    /// Like `delete_range`, but syncs the logs as `sync` says instead of `Options::sync_policy`.
+    pub fn delete_range_with_sync(&self, start: &[u8], end: &[u8], sync: SyncPolicy) -> Result<(), Error> {
+        if self.context.read_only {
+            return Err(Error::read_only())
+        }
+        let key_size_max = self.context.options.key_size_max;
+        if let Some(key) = [start, end].iter().find(|key| key.len() > key_size_max) {
+            return Err(Error::invalid_argument(
+                format!("key of {} bytes exceeds key_size_max ({})", key.len(), key_size_max).into()
+            ))
+        }
+        if self.comparator.compare(start, end) != Ordering::Less {
+            return Ok(())
+        }
+        let mut written: Vec<ArcPartition<Comp>> = Vec::new();
+        loop {
+            let next = self.partitions.read().unwrap().route_range(start, end).iter()
+                .find(|partition| !written.iter().any(|done| done.ptr_eq(partition)))
+                .cloned();
+            let partition = match next {
+                Some(partition) => partition,
+                None => return Ok(())
+            };
+            let start = UserKey::new_owned(start.to_vec(), &self.comparator);
+            let mut entries = vec![(InternalKey::new_range_deletion(0, start), end.to_vec())];
+            match partition.write_next(&mut entries, Some(sync)) {
+                Ok(()) => written.push(partition),
+                // the halves of a partition grown too large take the tombstone instead
+                Err(Error::RequiresExplode) => self.split(&partition)?,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /// Applies every operation of `batch` with consecutive sequence numbers, all together or not at
+    /// all. The operations going to one partition are logged as a single record and applied under
+    /// one lock. A batch going to several partitions holds the locks of all of them while it logs a
+    /// record in each, and is only recovered if every one of those records made it to its log. A
+    /// batch with a key or value larger than
+    /// `Options::key_size_max` or `Options::value_size_max` is refused as a whole before anything
+    /// is logged.
+    pub fn write(&self, batch: WriteBatch) -> Result<(), Error> {
+        self.write_with_sync(batch, self.sync_policy())
+    }
+
+    /// Like `write`, but syncs the log as `sync` says instead of `Options::sync_policy`.
+    pub fn write_with_sync(&self, batch: WriteBatch, sync: SyncPolicy) -> Result<(), Error> {
+        self.write_impl(batch, Some(sync))
+    }
+
+    /// Like `write`, but logs as `options` say. A write with `WriteOptions::disable_wal` set goes
+    /// to the memtables alone: it is lost if the process crashes before the memtables holding it
+    /// are flushed, see `flush`.
+    pub fn write_with_options(&self, batch: WriteBatch, options: &WriteOptions) -> Result<(), Error> {
+        let sync = if options.sync { SyncPolicy::Sync } else { self.sync_policy() };
+        self.write_impl(batch, Some(sync).filter(|_| !options.disable_wal))
+    }
+
+    /// Writes `batch` without logging it if there is no `sync` policy.
+    fn write_impl(&self, batch: WriteBatch, sync: Option<SyncPolicy>) -> Result<(), Error> {
+        if self.context.read_only {
+            return Err(Error::read_only())
+        }
+        if batch.is_empty() {
+            return Ok(())
+        }
+        batch.check_sizes(&self.context.options)?;
+        self.write_entries(batch.into_entries(&self.comparator), sync)
+    }
+
+    /// `write_impl` for a single put, without building a batch first.
+    fn put_impl(&self, key: &[u8], value: &[u8], sync: Option<SyncPolicy>) -> Result<(), Error> {
+        if self.context.read_only {
+            return Err(Error::read_only())
+        }
+        check_pair_sizes(key, value.len(), &self.context.options)?;
+        let key = InternalKey::new(0, UserKey::new_owned(key.to_vec(), &self.comparator));
+        self.write_entries(vec![(key, value.to_vec())], sync)
+    }
+
+    fn write_entries(&self, mut entries: Vec<Entry<Comp>>, sync: Option<SyncPolicy>) -> Result<(), Error> {
+        loop {
+            let mut parts = self.group_by_partition(entries);
+            // a partition grown too large is split first, the entries are routed again
+            let split = if parts.len() == 1 {
+                let (partition, mut group) = parts.pop().unwrap();
+                match partition.write_next(&mut group, sync) {
+                    Ok(()) => return Ok(()),
+                    Err(Error::RequiresExplode) => {
+                        entries = group;
+                        partition
+                    },
+                    Err(e) => return Err(e)
+                }
+            } else {
+                match ArcPartition::write_parts(&mut parts, sync)? {
+                    None => return Ok(()),
+                    Some(partition) => {
+                        entries = parts.into_iter().flat_map(|(_, group)| group).collect();
+                        partition
+                    }
+                }
+            };
+            self.split(&split)?;
+        }
+    }
+
+    /// Links the table at `path`, built offline in the format `tablefmt` describes, into the
+    /// database without rewriting its data. All of its entries get one new sequence number, so they
+    /// are newer than anything written before and no snapshot taken so far sees them. Its keys have
+    /// to be unique, sorted by the comparator of the database and all go to the same partition.
+    pub fn ingest_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        if self.context.read_only {
+            return Err(Error::read_only())
+        }
+        if self.context.options.partitioning != Partitioning::Range {
+            return Err(Error::invalid_argument("ingest_file needs Partitioning::Range".into()))
+        }
+        let path = path.as_ref();
+        let raw = std::fs::read(path).or_else(|e| Err(Error::io_error(e, path.display().to_string())))?;
+        let (lower, upper) = {
+            let table = ScTableCache::from_raw(&raw, self.context.cache_manager.acquire_quota())?;
+            let mut keys = KeyCursor::default();
+            let mut last = keys.nth_key(&table, 0, &self.comparator).user_key;
+            for n in 1..table.catalog_size() {
+                let user_key = keys.nth_key(&table, n, &self.comparator).user_key;
+                if last >= user_key {
+                    return Err(Error::invalid_argument(
+                        format!("keys of {} are not unique and sorted", path.display()).into()
+                    ))
+                }
+                last = user_key;
+            }
+            (UserKey::new_owned(table.smallest_key().into_owned(), &self.comparator),
+             UserKey::new_owned(table.largest_key().into_owned(), &self.comparator))
+        };
+        loop {
+            let partition = self.route(lower.key());
+            if !partition.ptr_eq(&self.route(upper.key())) {
+                return Err(Error::invalid_argument(
+                    format!("keys of {} span several partitions", path.display()).into()
+                ))
+            }
+            match partition.ingest(raw.clone(), lower.clone(), upper.clone()) {
+                // the halves of a partition grown too large take the table instead
+                Err(Error::RequiresExplode) => self.split(&partition)?,
+                result => return result
+            }
+        }
+    }
+
+    /// Loads `sorted`, whose keys have to ascend strictly under the comparator, straight into tables
+    /// linked in like `ingest_file` links its table, without going through the memtables or the
+    /// log. All of it gets one sequence number, newer than anything written before. Much faster
+    /// than putting the pairs one by one, which makes it the way to do an initial import. The
+    /// tables are written as the pairs come and linked only once the last one is checked, so a key
+    /// not above the one before it, a key or value larger than `Options` allow, or an I/O error
+    /// fails the load with nothing loaded. No partition is split until the load is done.
+    pub fn bulk_load<I: Iterator<Item=(Vec<u8>, Vec<u8>)>>(&self, sorted: I) -> Result<(), Error> {
+        if self.context.read_only {
+            return Err(Error::read_only())
+        }
+        if self.context.options.partitioning != Partitioning::Range {
+            return Err(Error::invalid_argument("bulk_load needs Partitioning::Range".into()))
+        }
+        // holding the partitions keeps them from being split under us
+        let partitions = self.partitions.read().unwrap();
+        let mut staged = Vec::new();
+        let result = self.write_sorted(&partitions, sorted, &mut staged)
+            .and_then(|()| ArcPartition::link(&mut staged));
+        if result.is_err() {
+            // left behind by a crash, they are removed at the next open
+            for table in staged.iter().flat_map(|(_, tables)| tables.iter()) {
+                let _ = self.context.io_manager.remove_file(table.file_name());
+            }
+        }
+        result
+    }
+
+    /// Checks `sorted` as `bulk_load` does and writes it into tables of at most
+    /// `Options::table_size` each, none of them holding keys of two partitions. They are added to
+    /// `staged` as they are written, with the partition they go to.
+    fn write_sorted<I>(&self,
+                       partitions: &PartitionSet<Comp>,
+                       sorted: I,
+                       staged: &mut Vec<(ArcPartition<Comp>, Vec<StagedTable<Comp>>)>) -> Result<(), Error>
+        where I: Iterator<Item=(Vec<u8>, Vec<u8>)> {
+        let options = &self.context.options;
+        let mut builder = ScTableBuilder::from_options(options);
+        let mut first: Option<(ArcPartition<Comp>, Vec<u8>)> = None;
+        let mut last: Option<Vec<u8>> = None;
+        for (key, value) in sorted {
+            check_pair_sizes(&key, value.len(), options)?;
+            if let Some(last) = &last {
+                if self.comparator.compare(&key, last) != Ordering::Greater {
+                    return Err(Error::invalid_argument("keys given to bulk_load are not unique and sorted".into()))
+                }
+            }
+            let partition = partitions.route(&key);
+            if let Some((table_partition, _)) = &first {
+                if !table_partition.ptr_eq(partition) || builder.size_with(&key, &value) > options.table_size {
+                    let (table_partition, lower) = first.take().unwrap();
+                    self.write_table(&builder, table_partition, lower, last.clone().unwrap(), staged)?;
+                    builder = ScTableBuilder::from_options(options);
+                }
+            }
+            if first.is_none() {
+                first = Some((partition.clone(), key.clone()));
+            }
+            builder.add_kv(0, &key, &value);
+            last = Some(key);
+        }
+        if let Some((partition, lower)) = first {
+            self.write_table(&builder, partition, lower, last.unwrap(), staged)?;
+        }
+        Ok(())
+    }
+
+    fn write_table(&self,
+                   builder: &ScTableBuilder,
+                   partition: ArcPartition<Comp>,
+                   lower: Vec<u8>,
+                   upper: Vec<u8>,
+                   staged: &mut Vec<(ArcPartition<Comp>, Vec<StagedTable<Comp>>)>) -> Result<(), Error> {
+        let lower = UserKey::new_owned(lower, &self.comparator);
+        let upper = UserKey::new_owned(upper, &self.comparator);
+        let table = StagedTable::write(&self.context, &builder.finish()?, lower, upper)?;
+        match staged.last_mut() {
+            // the keys ascend, so the tables of a partition come one after another
+            Some((last, tables)) if last.ptr_eq(&partition) => tables.push(table),
+            _ => staged.push((partition, vec![table]))
+        }
+        Ok(())
+    }
+
+    /// Writes the memtables of all partitions into tables and waits until they are on disk. Once it
+    /// returns, the writes acknowledged before it survive a crash even if they were not synced.
+    /// There is nothing to flush in a read-only database.
+    pub fn flush(&self) -> Result<(), Error> {
+        if self.context.read_only {
+            return Ok(())
+        }
+        // holding the partitions keeps them from being split under us
+        let partitions = self.partitions.read().unwrap();
+        for partition in partitions.partitions() {
+            partition.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Compacts every table holding keys of `[start, end)` down into the deepest level and waits
+    /// until it is done, so the space of deleted and overwritten keys is reclaimed right away
+    /// rather than whenever compaction gets to them. `None` leaves that side unbounded. Versions a
+    /// snapshot still reads are kept.
+    pub fn compact_range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> Result<(), Error> {
+        if self.context.read_only {
+            return Err(Error::read_only())
+        }
+        // holding the partitions keeps them from being split under us
+        let partitions = self.partitions.read().unwrap();
+        for partition in partitions.partitions() {
+            partition.compact_range(start, end)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes, then shuts down like dropping the database does, but reports what went wrong.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.closed = true;
+        self.shutdown()
+    }
+
+    /// The policy of writes which do not pick one of their own.
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.context.options.sync_policy
+    }
+
+    /// The counters of this database right now.
+    pub fn stats(&self) -> StatsSnapshot {
+        let mut level_sizes: Vec<usize> = Vec::new();
+        let mut level0_tables = 0;
+        for partition in self.partitions.read().unwrap().partitions() {
+            level0_tables += partition.level0_table_count();
+            for (level, size) in partition.level_sizes().into_iter().enumerate() {
+                if level_sizes.len() == level {
+                    level_sizes.push(0);
+                }
+                level_sizes[level] += size;
+            }
+        }
+        let (context, cache_manager) = (&self.context, &self.context.cache_manager);
+        StatsSnapshot {
+            cache_hits: cache_manager.cache_hits(),
+            cache_misses: cache_manager.cache_misses(),
+            filter_negatives: cache_manager.filter_negatives(),
+            filter_false_positives: cache_manager.filter_false_positives(),
+            cached_tables: cache_manager.current_cache_count(),
+            block_cache_bytes: cache_manager.block_cache().bytes(),
+            flushes: context.stats.flushes(),
+            compactions: context.stats.compactions(),
+            bytes_written: context.stats.bytes_written(),
+            bytes_reclaimed: context.stats.bytes_reclaimed(),
+            corrupt_tables: context.stats.corrupt_tables(),
+            compaction_write_rate: context.background.write_rate(),
+            level0_tables,
+            level_sizes
+        }
+    }
+
+    /// The layout of every partition, in the order of the keys they hold. Each partition is looked
+    /// at on its own, so the result may be slightly inconsistent while the database is busy.
+    pub fn partition_info(&self) -> Vec<PartitionInfo> {
+        self.partitions.read().unwrap().partitions().iter().map(ArcPartition::info).collect()
+    }
+
+    /// Internal state by name, as text, for tools to look at without a method of their own:
+    ///
+    /// * `scottdb.num-partitions`: the number of partitions.
+    /// * `scottdb.level-sizes`: bytes on disk of every level summed over all partitions, comma
+    ///   separated from level 0 on.
+    /// * `scottdb.cache-usage`: tables alive in memory and how many may be, as `<alive>/<limit>`.
+    /// * `scottdb.block-cache-usage`: bytes of the block cache in use and how many may be, as
+    ///   `<used>/<limit>`.
+    /// * `scottdb.memtable-size`: bytes of keys and values in the memtables being written to.
+    ///
+    /// Returns `None` for any other name.
+    pub fn property(&self, name: &str) -> Option<String> {
+        match name {
+            "scottdb.num-partitions" => Some(self.partitions.read().unwrap().partitions().len().to_string()),
+            "scottdb.level-sizes" => {
+                let level_sizes = self.stats().level_sizes;
+                Some(level_sizes.iter().map(usize::to_string).collect::<Vec<_>>().join(","))
+            },
+            "scottdb.cache-usage" => {
+                let cache_manager = &self.context.cache_manager;
+                Some(format!("{}/{}", cache_manager.current_cache_count(), cache_manager.cache_count()))
+            },
+            "scottdb.block-cache-usage" => {
+                let block_cache = self.context.cache_manager.block_cache();
+                Some(format!("{}/{}", block_cache.bytes(), block_cache.capacity()))
+            },
+            "scottdb.memtable-size" => {
+                let partitions = self.partitions.read().unwrap();
+                Some(partitions.partitions().iter().map(ArcPartition::memtable_data_size).sum::<usize>().to_string())
+            },
+            _ => None
+        }
+    }
+
+    /// Roughly how many bytes the keys in `[start, end)` take, summed over the tables which may
+    /// hold them and the memtables, as LevelDB's `GetApproximateSizes` does. Tables partly in the
+    /// range count by the share of their data in it, and versions of a key in several levels count
+    /// once each. Meant for planning scans and splits, nothing is read beyond table catalogs.
+    pub fn approximate_size(&self, start: &[u8], end: &[u8]) -> u64 {
+        if self.comparator.compare(start, end) != Ordering::Less {
+            return 0
+        }
+        let partitions = self.partitions.read().unwrap().partitions().to_vec();
+        partitions.iter().map(|partition| partition.approximate_size(start, end)).sum()
+    }
+
+    /// Drops every cached table and block, to give the memory back. Tables are read from disk again as they
+    /// are needed, those in use by an iterator stay in memory until it is done with them.
+    pub fn clear_cache(&self) {
+        self.context.cache_manager.clear()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.context.clone())
+    }
+
+    /// Scans the keys in `[start, end)`, `None` leaves that side unbounded.
+    pub fn scan(&self,
+                start: Option<&[u8]>,
+                end: Option<&[u8]>,
+                snapshot: Option<&Snapshot>) -> DbIterator<Comp> {
+        let start = start.map_or(Bound::Unbounded, Bound::Included);
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        self.scan_bounds(start, end, snapshot)
+    }
+
+    /// Scans the keys starting with `prefix`, up to `Comparator::successor` of the prefix unless that
+    /// starts with the prefix itself, like the successor of a bytewise prefix of 0xFF bytes only. The
+    /// scan also stops at the first key without the prefix, so other orders cannot make it return
+    /// keys outside of the prefix.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> impl Iterator<Item=(Vec<u8>, Vec<u8>)> {
+        let end = self.comparator.successor(prefix).filter(|end| !end.starts_with(prefix));
+        let prefix = prefix.to_vec();
+        self.scan(Some(&prefix), end.as_ref().map(|end| end.as_slice()), None)
+            .take_while(move |(key, _)| key.starts_with(&prefix))
+    }
+
+    /// Without a snapshot the scan takes one of its own, so it reads a consistent view either way.
+    pub fn scan_bounds(&self,
+                       start: Bound<&[u8]>,
+                       end: Bound<&[u8]>,
+                       snapshot: Option<&Snapshot>) -> DbIterator<Comp> {
+        let snapshot = snapshot.cloned().unwrap_or_else(|| self.snapshot());
+        let partitions = self.partitions.read().unwrap().partitions().iter().cloned().collect();
+        DbIterator::new(partitions, self.comparator.clone(), start, end, snapshot, false)
+            .with_ordered_partitions(self.ordered_partitions())
+    }
+
+    /// A cursor over the database as of now, to be positioned by one of its seeks first. Moving a
+    /// cursor back relies on partitions holding ranges of keys, so this needs `Partitioning::Range`.
+    pub fn cursor(&self) -> Result<Cursor<Comp>, Error> {
+        if !self.ordered_partitions() {
+            return Err(Error::invalid_argument("cursor needs Partitioning::Range".into()))
+        }
+        let partitions = self.partitions.read().unwrap().partitions().to_vec();
+        Ok(Cursor::new(partitions, self.comparator.clone(), self.snapshot()))
+    }
+
+    /// Like `scan`, but yields the keys only. Tables not cached are read for their keys alone, so
+    /// values are neither copied nor decompressed when enumerating a key space.
+    pub fn keys(&self,
+                start: Option<&[u8]>,
+                end: Option<&[u8]>,
+                snapshot: Option<&Snapshot>) -> DbKeyIterator<Comp> {
+        let start = start.map_or(Bound::Unbounded, Bound::Included);
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        let snapshot = snapshot.cloned().unwrap_or_else(|| self.snapshot());
+        let partitions = self.partitions.read().unwrap().partitions().iter().cloned().collect();
+        let iter = DbIterator::new(partitions, self.comparator.clone(), start, end, snapshot, true)
+            .with_ordered_partitions(self.ordered_partitions());
+        DbKeyIterator::new(iter)
+    }
+
+    /// Groups `entries`, which are not empty, by the partition each goes to, keeping their order
+    /// within each group.
+    fn group_by_partition(&self, entries: Vec<Entry<Comp>>) -> Vec<(ArcPartition<Comp>, Vec<Entry<Comp>>)> {
+        let partitions = self.partitions.read().unwrap();
+        let partition = partitions.route(entries[0].0.user_key.key()).clone();
+        if entries[1..].iter().all(|(key, _)| partitions.route(key.user_key.key()).ptr_eq(&partition)) {
+            return vec![(partition, entries)]
+        }
+        let mut groups: Vec<(ArcPartition<Comp>, Vec<Entry<Comp>>)> = Vec::new();
+        for entry in entries {
+            let partition = partitions.route(entry.0.user_key.key());
+            match groups.iter_mut().find(|(group_partition, _)| group_partition.ptr_eq(partition)) {
+                Some((_, group)) => group.push(entry),
+                None => groups.push((partition.clone(), vec![entry]))
+            }
+        }
+        groups
+    }
+
+    /// Replaces `partition` by the two halves it splits into. Nothing is done if another writer
+    /// has split it already.
+    fn split(&self, partition: &ArcPartition<Comp>) -> Result<(), Error> {
+        let mut partitions = self.partitions.write().unwrap();
+        if !partitions.contains(partition) {
+            return Ok(())
+        }
+        let (left_id, right_id) = (partitions.next_partition_id(), partitions.next_partition_id());
+        if let Some((left, right)) = partition.explode(left_id, right_id)? {
+            partitions.remove(partition);
+            partitions.insert(left);
+            partitions.insert(right);
+        }
+        Ok(())
+    }
+
+    fn route(&self, key: &[u8]) -> ArcPartition<Comp> {
+        self.partitions.read().unwrap().route(key).clone()
+    }
+
+    /// Whether the partitions hold disjoint key ranges, in the order they are listed.
+    fn ordered_partitions(&self) -> bool {
+        self.context.options.partitioning == Partitioning::Range
+    }
+
+    /// Waits for the flushes and compactions in flight, then writes the manifest once more so it
+    /// matches the tables they left behind.
+    fn shutdown(&self) -> Result<(), Error> {
+        self.context.background.shutdown();
+        if self.context.read_only {
+            return Ok(())
+        }
+        let records = self.partitions.read().unwrap().partitions().iter()
+            .map(|partition| partition.manifest_record())
+            .collect();
+        let last_seq = self.context.seq.load(atomic::Ordering::SeqCst);
+        self.context.manifest.reset(records, last_seq, &self.context.io_manager)
+    }
+}
+
+/// Removes the files a crash left behind: tables no partition in the manifest refers to, like the
+/// outputs of an unfinished compaction, the logs of partitions which never made it into the
+/// manifest, like those of an unfinished split, and temporary files never renamed into place.
+fn remove_orphan_files(io_manager: &IOManager, records: &[PartitionRecord]) -> Result<(), Error> {
+    let live_tables: HashSet<ScTableFile> = records.iter()
+        .flat_map(|record| record.tables.iter().map(|table| table.table_file))
+        .collect();
+    let live_partitions: HashSet<u32> = records.iter().map(|record| record.partition_id).collect();
+    for file_name in io_manager.list_files()? {
+        let orphan = match ScTableFile::parse_file_name(&file_name) {
+            Some(table_file) => !live_tables.contains(&table_file),
+            None if file_name.ends_with(TEMP_FILE_SUFFIX) => true,
+            None => parse_log_file_name(&file_name)
+                .map_or(false, |(partition_id, _)| !live_partitions.contains(&partition_id))
+        };
+        if orphan {
+            io_manager.remove_file(file_name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Loads every table in `records` once, so corruption is found at open rather than by some read
+/// later. A table whose size differs from the one the manifest records is corrupt as well. A
+/// corrupt table fails the open if `Options::paranoid_checks` is set or the database is read-only,
+/// otherwise it is renamed to `<table>.corrupt`, counted in `StatsSnapshot::corrupt_tables` and
+/// left out of `records`, its keys are lost. A missing table fails the open with
+/// `Error::MissingTable`, other errors fail it as they are.
+fn check_tables(context: &DbContext, records: &mut [PartitionRecord]) -> Result<(), Error> {
+    let table_files: BTreeMap<ScTableFile, usize> = records.iter()
+        .flat_map(|record| record.tables.iter().map(|table| (table.table_file, table.file_size)))
+        .collect();
+    let mut corrupt_tables = HashSet::new();
+    for (table_file, file_size) in table_files {
+        let file_name = table_file.file_name();
+        let checked = match context.io_manager.file_size(file_name.clone())? {
+            None => return Err(Error::missing_table(file_name)),
+            Some(size) if size != file_size as u64 => Err(Error::sc_table_corrupt(
+                format!("{} bytes on disk, the manifest records {}", size, file_size).into()
+            )),
+            Some(_) => context.io_manager.acquire_quota().read_file(file_name.clone())
+                .and_then(|raw| ScTableCache::from_raw(&raw, context.cache_manager.acquire_quota()).map(drop))
+        };
+        match checked {
+            Ok(()) => {},
+            Err(Error::ScTableCorrupt { reason }) => {
+                if context.options.paranoid_checks || context.read_only {
+                    return Err(Error::sc_table_corrupt(format!("{}: {}", file_name, reason).into()))
+                }
+                context.io_manager.rename_file(file_name.clone(), format!("{}.corrupt", file_name))?;
+                context.stats.record_corrupt_table();
+                corrupt_tables.insert(table_file);
+            },
+            Err(e) => return Err(e)
+        }
+    }
+    for record in records.iter_mut() {
+        record.tables.retain(|table| !corrupt_tables.contains(&table.table_file));
+    }
+    Ok(())
+}
+
+impl<Comp: 'static + Comparator> Drop for Db<Comp> {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+    use std::ops::Bound;
+
+    use std::sync::{atomic, Arc, Condvar, Mutex};
+    use std::sync::atomic::AtomicBool;
+    use std::time::{Duration, Instant};
+
+    use crate::{Comparator, Cursor, Db, DbIterator, DefaultComparator, DefaultDb, ReverseComparator, BigEndianU64Comparator, Options,
+                SyncPolicy, WriteBatch, Compression, CompactionFilter, FilterDecision, Error, MemTableKind, TableLoading, WriteOptions, U64AddMergeOperator, Partitioning, wal};
+    use crate::table::builder::ScTableBuilder;
+    use crate::table::lz4;
+    use crate::table::sctable::ScTableFile;
+    use crate::partition::{InternalKey, UserKey};
+    use crate::testutil::{TestDir, default_comparator};
+    use crate::wal::BatchParts;
+
+    #[test]
+    fn test_put_get_delete() {
+        let dir = TestDir::new("db_put_get_delete");
+        let options = Options::new("test_put_get_delete", 4, 4, 10, 4, 4096, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+
+        db.put(b"hello", b"world").unwrap();
+        db.put(b"foo", b"bar").unwrap();
+        assert_eq!(db.get(b"hello", None).unwrap(), Some(b"world".to_vec()));
+        assert_eq!(db.get(b"foo", None).unwrap(), Some(b"bar".to_vec()));
+        assert_eq!(db.get(b"missing", None).unwrap(), None);
+
+        db.put(b"hello", b"again").unwrap();
+        assert_eq!(db.get(b"hello", None).unwrap(), Some(b"again".to_vec()));
+
+        db.delete(b"hello").unwrap();
+        assert_eq!(db.get(b"hello", None).unwrap(), None);
+        assert_eq!(db.get(b"foo", None).unwrap(), Some(b"bar".to_vec()));
+    }
+
+    #[test]
+    fn test_empty_key_and_value() {
+        let dir = TestDir::new("db_empty_key_and_value");
+        let options = || Options::new("test_empty_key_and_value", 4, 4, 10, 4, 4096, 64, 64);
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            db.put(b"", b"empty key").unwrap();
+            db.put(b"empty value", b"").unwrap();
+            db.put(b"a", b"a").unwrap();
+            assert_eq!(db.get(b"", None).unwrap(), Some(b"empty key".to_vec()));
+            assert_eq!(db.get(b"empty value", None).unwrap(), Some(Vec::new()));
+            assert!(db.contains_key(b"empty value").unwrap());
+            db.flush().unwrap();
+            assert_eq!(db.get(b"", None).unwrap(), Some(b"empty key".to_vec()));
+            assert_eq!(db.get(b"empty value", None).unwrap(), Some(Vec::new()));
+            assert_eq!(db.scan(None, None, None).next(), Some((Vec::new(), b"empty key".to_vec())));
+            assert_eq!(db.scan_prefix(b"").count(), 3);
+        }
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        let scanned: Vec<(Vec<u8>, Vec<u8>)> = db.scan(Some(b""), None, None).collect();
+        assert_eq!(scanned, vec![
+            (Vec::new(), b"empty key".to_vec()),
+            (b"a".to_vec(), b"a".to_vec()),
+            (b"empty value".to_vec(), Vec::new())
+        ]);
+        db.delete(b"").unwrap();
+        assert_eq!(db.get(b"", None).unwrap(), None);
+        db.put(b"", b"").unwrap();
+        assert_eq!(db.get(b"", None).unwrap(), Some(Vec::new()));
+        db.flush().unwrap();
+        assert_eq!(db.get(b"", None).unwrap(), Some(Vec::new()));
+        assert_eq!(db.keys(None, Some(b"a"), None).collect::<Vec<_>>(), vec![Vec::new()]);
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = TestDir::new("db_scan");
+        let options = Options::new("test_scan", 4, 1, 4, 4, 512, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        for i in 0..200u32 {
+            db.put(format!("key{:03}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        for i in (0..200u32).step_by(7) {
+            db.delete(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        let live = |range: std::ops::Range<u32>| -> Vec<Vec<u8>> {
+            range.filter(|i| i % 7 != 0).map(|i| format!("key{:03}", i).into_bytes()).collect()
+        };
+        let keys = |iter: DbIterator<DefaultComparator>| -> Vec<Vec<u8>> {
+            assert!(iter.status().is_ok());
+            iter.map(|(k, _)| k).collect()
+        };
+
+        assert_eq!(keys(db.scan(None, None, None)), live(0..200));
+        assert_eq!(keys(db.scan(Some(b"key050"), Some(b"key060"), None)), live(50..60));
+        assert_eq!(keys(db.scan(None, Some(b"key010"), None)), live(0..10));
+        assert_eq!(keys(db.scan(Some(b"key190"), None, None)), live(190..200));
+        assert_eq!(keys(db.scan_bounds(Bound::Excluded(b"key050"), Bound::Included(b"key060"), None)), live(51..61));
+        assert_eq!(keys(db.scan(Some(b"key060"), Some(b"key050"), None)), live(0..0));
+
+        let (key, value) = db.scan(Some(b"key001"), None, None).next().unwrap();
+        assert_eq!((key, value), (b"key001".to_vec(), b"value1".to_vec()));
+    }
+
+    #[test]
+    fn test_iterator_seek() {
+        let dir = TestDir::new("db_iterator_seek");
+        let options = Options::new("test_iterator_seek", 16, 2, 4, 16, 512, 64, 64).with_partition_size(14 * 1024);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        write_keys(&db, 500);
+        assert!(db.partitions.read().unwrap().partitions().len() > 1);
+        let key = |i: u32| format!("key{:04}", i).into_bytes();
+        let take = |iter: &mut DbIterator<DefaultComparator>, count: usize| -> Vec<Vec<u8>> {
+            iter.by_ref().take(count).map(|(k, _)| k).collect()
+        };
+
+        let mut iter = db.scan(None, Some(b"key0400"), None);
+        assert_eq!(take(&mut iter, 3), vec![key(0), key(1), key(2)]);
+        // forward within the partition read, back before it, and on into the others
+        iter.seek(Bound::Included(&key(10)));
+        assert_eq!(take(&mut iter, 2), vec![key(10), key(11)]);
+        iter.seek(Bound::Excluded(&key(5)));
+        assert_eq!(take(&mut iter, 2), vec![key(6), key(7)]);
+        iter.seek(Bound::Included(&key(390)));
+        assert_eq!(take(&mut iter, 20), (390..400).map(key).collect::<Vec<_>>());
+        iter.seek(Bound::Excluded(&key(150)));
+        assert_eq!(take(&mut iter, 1), vec![key(151)]);
+        iter.seek(Bound::Unbounded);
+        assert_eq!(take(&mut iter, 1), vec![key(0)]);
+        iter.seek(Bound::Included(b"key9"));
+        assert_eq!(take(&mut iter, 1), Vec::<Vec<u8>>::new());
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn test_keys() {
+        let dir = TestDir::new("db_keys");
+        let options = Options::new("test_keys", 4, 1, 4, 4, 4096, 64, 1024).with_compression(Compression::Lz4);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        let value = vec![b'v'; 300];
+        for i in (0..200u32).map(|i| i * 37 % 200) {
+            db.put(format!("key{:03}", i).as_bytes(), &value).unwrap();
+        }
+        let snapshot = db.snapshot();
+        for i in (0..200u32).step_by(7) {
+            db.delete(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        db.put(b"key200", b"in the memtable").unwrap();
+        db.context.background.wait_idle();
+        assert!(db.stats().level_sizes.iter().sum::<usize>() > 0);
+
+        // the tables are read from disk by their keys only, no value is decompressed
+        db.context.cache_manager.clear();
+        let decompressions = lz4::decompressions();
+        let scan_keys = |iter: DbIterator<DefaultComparator>| iter.map(|(k, _)| k).collect::<Vec<_>>();
+        let keys = db.keys(None, None, None);
+        assert!(keys.status().is_ok());
+        let keys: Vec<_> = keys.collect();
+        assert_eq!(keys.len(), 201 - 29);
+        assert_eq!(lz4::decompressions(), decompressions);
+        assert_eq!(keys, scan_keys(db.scan(None, None, None)));
+        assert!(lz4::decompressions() > decompressions);
+        assert_eq!(db.keys(Some(b"key050"), Some(b"key060"), None).collect::<Vec<_>>(),
+                   scan_keys(db.scan(Some(b"key050"), Some(b"key060"), None)));
+        assert_eq!(db.keys(None, None, Some(&snapshot)).collect::<Vec<_>>(),
+                   (0..200).map(|i| format!("key{:03}", i).into_bytes()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_get_pinned() {
+        let dir = TestDir::new("db_get_pinned");
+        let options = Options::new("test_get_pinned", 16, 4, 4, 16, 4096, 64, 1024);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        let large = vec![7u8; 1000];
+        db.put(b"large", &large).unwrap();
+        db.put(b"deleted", b"value").unwrap();
+        db.delete(b"deleted").unwrap();
+        db.flush().unwrap();
+        db.put(b"fresh", b"in the memtable").unwrap();
+
+        let value = db.get_pinned(b"fresh").unwrap().unwrap();
+        assert!(!value.is_pinned());
+        assert_eq!(&*value, b"in the memtable");
+        assert!(db.get_pinned(b"deleted").unwrap().is_none());
+        assert!(db.get_pinned(b"missing").unwrap().is_none());
+
+        let value = db.get_pinned(b"large").unwrap().unwrap();
+        assert!(value.is_pinned());
+        assert_eq!(&*value, large.as_slice());
+        // the table stays in memory while the value is held, however the cache is cleared
+        db.clear_cache();
+        assert_eq!(db.stats().cached_tables, 1);
+        assert_eq!(&*value, large.as_slice());
+        drop(value);
+        assert_eq!(db.stats().cached_tables, 0);
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = TestDir::new("db_stats");
+        let options = Options::new("test_stats", 4, 1, 4, 4, 512, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        assert_eq!(db.stats(), Default::default());
+        for i in 0..100u32 {
+            db.put(format!("key{:03}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        db.context.background.wait_idle();
+
+        let stats = db.stats();
+        assert!(stats.flushes > 0);
+        assert!(stats.compactions > 0);
+        assert!(stats.bytes_written > 0);
+        assert!(stats.level_sizes.iter().sum::<usize>() > 0);
+
+        // more tables than the cache holds, so reading all of them must miss
+        for i in 0..100u32 {
+            assert!(db.get(format!("key{:03}", i).as_bytes(), None).unwrap().is_some());
+        }
+        let after_scan = db.stats();
+        assert!(after_scan.cache_misses > stats.cache_misses);
+        assert!(after_scan.cached_tables > 0);
+
+        db.get(b"key000", None).unwrap();
+        let before = db.stats();
+        db.get(b"key000", None).unwrap();
+        let after = db.stats();
+        assert!(after.cache_hits > before.cache_hits);
+        assert_eq!(after.cache_misses, before.cache_misses);
+
+        db.clear_cache();
+        assert_eq!(db.stats().cached_tables, 0);
+        db.get(b"key000", None).unwrap();
+        assert_eq!(db.stats().cache_misses, after.cache_misses + 1);
+    }
+
+    #[test]
+    fn test_filter_stats() {
+        let dir = TestDir::new("db_filter_stats");
+        let options = |rate| Options::new("test_filter_stats", 16, 4, 4, 16, 1 << 16, 64, 64)
+            .with_bloom_false_positive_rate(rate);
+        for &rate in [0.01, 0.0].iter() {
+            let _ = std::fs::remove_dir_all(dir.path());
+            let db = Db::open(dir.path(), options(rate), DefaultComparator()).unwrap();
+            for i in (0..1000).step_by(2) {
+                db.put(format!("key{:04}", i).as_bytes(), b"value").unwrap();
+            }
+            db.flush().unwrap();
+
+            // the odd keys are not in the table, all but key0999 within its bounds
+            for i in (1..1000).step_by(2) {
+                assert_eq!(db.get(format!("key{:04}", i).as_bytes(), None).unwrap(), None);
+            }
+            let stats = db.stats();
+            if rate == 0.0 {
+                assert_eq!((stats.filter_negatives, stats.filter_false_positives), (0, 0));
+            } else {
+                assert_eq!(stats.filter_negatives + stats.filter_false_positives, 499);
+                assert!(stats.filter_false_positives < 50);
+            }
+
+            // a key found is neither
+            assert!(db.get(b"key0000", None).unwrap().is_some());
+            let after = db.stats();
+            assert_eq!((after.filter_negatives, after.filter_false_positives),
+                       (stats.filter_negatives, stats.filter_false_positives));
+        }
+    }
+
+    #[test]
+    fn test_property() {
+        let dir = TestDir::new("db_property");
+        let options = Options::new("test_property", 16, 1, 4, 16, 512, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        assert_eq!(db.property("scottdb.num-partitions").unwrap(), "1");
+        assert_eq!(db.property("scottdb.memtable-size").unwrap(), "0");
+        assert_eq!(db.property("scottdb.cache-usage").unwrap(), "0/16");
+        assert_eq!(db.property("scottdb.level-sizes").unwrap(), "");
+        assert_eq!(db.property("scottdb.no-such-property"), None);
+        assert_eq!(db.property("num-partitions"), None);
+
+        db.put(b"key", b"value").unwrap();
+        assert_eq!(db.property("scottdb.memtable-size").unwrap(), "8");
+
+        write_keys(&db, 100);
+        let level_sizes = db.property("scottdb.level-sizes").unwrap();
+        let level_sizes: Vec<usize> = level_sizes.split(',').map(|size| size.parse().unwrap()).collect();
+        assert_eq!(level_sizes, db.stats().level_sizes);
+        assert!(level_sizes.iter().sum::<usize>() > 0);
+
+        db.clear_cache();
+        assert_eq!(db.property("scottdb.cache-usage").unwrap(), "0/16");
+        db.get(b"key0000", None).unwrap();
+        assert_eq!(db.property("scottdb.cache-usage").unwrap(), "1/16");
+    }
+
+    #[test]
+    fn test_compression() {
+        let dir = TestDir::new("db_compression");
+        let options = Options::new("test_compression", 4, 1, 4, 4, 1024, 64, 256)
+            .with_compression(Compression::Lz4);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        let value = |i: u32| format!("value{}", i % 10).repeat(16).into_bytes();
+        for i in 0..200u32 {
+            db.put(format!("key{:03}", i).as_bytes(), &value(i)).unwrap();
+        }
+        db.context.background.wait_idle();
+        assert!(db.stats().flushes > 0);
+
+        for i in 0..200u32 {
+            assert_eq!(db.get(format!("key{:03}", i).as_bytes(), None).unwrap(), Some(value(i)));
+        }
+        let values: Vec<_> = db.scan(None, None, None).map(|(_, v)| v).collect();
+        assert_eq!(values, (0..200u32).map(value).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_index_only_tables() {
+        let dir = TestDir::new("db_index_only_tables");
+        let options = Options::new("test_index_only_tables", 16, 1, 4, 16, 1024, 64, 256)
+            .with_table_loading(TableLoading::IndexOnly)
+            .with_block_cache_bytes(2048);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        let value = |i: u32| format!("value{}", i).repeat(8).into_bytes();
+        for i in 0..200u32 {
+            db.put(format!("key{:03}", i).as_bytes(), &value(i)).unwrap();
+        }
+        db.delete(b"key100").unwrap();
+        db.flush().unwrap();
+        db.context.background.wait_idle();
+        assert!(db.stats().compactions > 0);
+
+        for i in 0..200u32 {
+            let expected = if i == 100 { None } else { Some(value(i)) };
+            assert_eq!(db.get(format!("key{:03}", i).as_bytes(), None).unwrap(), expected);
+        }
+        // the values read last are cached, up to the budget
+        let block_cache_bytes = db.stats().block_cache_bytes;
+        assert!(block_cache_bytes > 0 && block_cache_bytes <= 2048);
+        assert_eq!(db.property("scottdb.block-cache-usage").unwrap(), format!("{}/2048", block_cache_bytes));
+        assert_eq!(db.get(b"key199", None).unwrap(), Some(value(199)));
+        db.clear_cache();
+        assert_eq!(db.stats().block_cache_bytes, 0);
+
+        // read once, then shared with the block cache rather than copied out of it
+        let value_7 = db.get_pinned(b"key007").unwrap().unwrap();
+        assert!(value_7.is_pinned());
+        assert_eq!(&*value_7, value(7).as_slice());
+        let block_cache_bytes = db.stats().block_cache_bytes;
+        assert_eq!(&*db.get_pinned(b"key007").unwrap().unwrap(), value(7).as_slice());
+        assert_eq!(db.stats().block_cache_bytes, block_cache_bytes);
+        let values: Vec<_> = db.scan(None, None, None).map(|(_, v)| v).collect();
+        assert_eq!(values, (0..200u32).filter(|&i| i != 100).map(value).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let dir = TestDir::new("db_scan_prefix");
+        let options = Options::new("test_scan_prefix", 4, 1, 4, 4, 512, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        for i in 0..50u32 {
+            db.put(format!("user:{}:", i).as_bytes(), b"name").unwrap();
+            db.put(format!("user:{}:email", i).as_bytes(), b"email").unwrap();
+        }
+        for key in [b"user:12".as_ref(), b"user:12;", b"user:120", b"user:1", b"user;"].iter() {
+            db.put(key, b"other").unwrap();
+        }
+        db.put(&[0xFF, 0xFF], b"ff").unwrap();
+        db.put(&[0xFF, 0xFF, 0x00], b"ff00").unwrap();
+        db.put(&[0xFF, 0xFF, 0x01], b"ff01").unwrap();
+        db.put(&[0xFF, 0xFE, 0xFF], b"fefe").unwrap();
+        db.delete(b"user:12:email").unwrap();
+        db.context.background.wait_idle();
+
+        let keys = |prefix: &[u8]| -> Vec<Vec<u8>> { db.scan_prefix(prefix).map(|(k, _)| k).collect() };
+        assert_eq!(keys(b"user:12:"), vec![b"user:12:".to_vec()]);
+        assert_eq!(keys(b"user:3:"), vec![b"user:3:".to_vec(), b"user:3:email".to_vec()]);
+        assert_eq!(keys(b"user:").len(), 50 * 2 - 1 + 4);
+        assert!(keys(b"user:").iter().all(|key| key.starts_with(b"user:")));
+        // the successor of 0xFF 0xFF is 0xFF 0xFF 0x00, which does not bound the prefix
+        assert_eq!(keys(&[0xFF, 0xFF]), vec![vec![0xFF, 0xFF], vec![0xFF, 0xFF, 0x00], vec![0xFF, 0xFF, 0x01]]);
+        assert_eq!(keys(&[0xFF]).len(), 4);
+        assert_eq!(keys(b"nobody"), Vec::<Vec<u8>>::new());
+        assert_eq!(keys(b"").len(), 50 * 2 - 1 + 5 + 4);
+    }
+
+    /// Orders keys by their bytes from `skip` on, a comparator whose order depends on its state.
+    struct SkipPrefixComparator {
+        skip: usize
+    }
+
+    impl Comparator for SkipPrefixComparator {
+        fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+            lhs[self.skip.min(lhs.len())..].cmp(&rhs[self.skip.min(rhs.len())..]).then(lhs.cmp(rhs))
+        }
+    }
+
+    #[test]
+    fn test_stateful_comparator() {
+        let dir = TestDir::new("db_stateful_comparator");
+        let options = Options::new("test_stateful_comparator", 4, 1, 4, 4, 512, 64, 64);
+        let db = Db::open(dir.path(), options, SkipPrefixComparator { skip: 2 }).unwrap();
+        for i in 0..100u32 {
+            db.put(format!("{:02}{:03}", 99 - i, i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        db.context.background.wait_idle();
+
+        assert_eq!(db.get(b"99000", None).unwrap(), Some(b"value0".to_vec()));
+        assert_eq!(db.get(b"00099", None).unwrap(), Some(b"value99".to_vec()));
+        assert_eq!(db.get(b"00000", None).unwrap(), None);
+        let keys: Vec<_> = db.scan(None, None, None).map(|(k, _)| k).collect();
+        let expected: Vec<_> = (0..100u32).map(|i| format!("{:02}{:03}", 99 - i, i).into_bytes()).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_reverse_comparator() {
+        let dir = TestDir::new("db_reverse_comparator");
+        let options = Options::new("test_reverse_comparator", 4, 1, 4, 4, 512, 64, 64);
+        let db = Db::open(dir.path(), options, ReverseComparator()).unwrap();
+        for i in 0..100u32 {
+            let i = i * 37 % 100;
+            db.put(format!("key{:02}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        db.context.background.wait_idle();
+
+        let keys = |iter: DbIterator<ReverseComparator>| -> Vec<Vec<u8>> { iter.map(|(k, _)| k).collect() };
+        let expected = |range: std::ops::Range<u32>| -> Vec<Vec<u8>> {
+            range.rev().map(|i| format!("key{:02}", i).into_bytes()).collect()
+        };
+        assert_eq!(keys(db.scan(None, None, None)), expected(0..100));
+        // under this order a scan starts from the larger key
+        assert_eq!(keys(db.scan(Some(b"key50"), Some(b"key40"), None)), expected(41..51));
+    }
+
+    #[test]
+    fn test_big_endian_u64_comparator() {
+        let dir = TestDir::new("db_big_endian_u64_comparator");
+        let options = Options::new("test_big_endian_u64_comparator", 4, 1, 4, 4, 512, 64, 64);
+        let db: Db<BigEndianU64Comparator> = Db::open(dir.path(), options, BigEndianU64Comparator()).unwrap();
+        let numbers = [10u64, 2, 1 << 40, 255, 256, 0, u64::max_value(), 7, 1000];
+        for round in 0..10u64 {
+            for &number in numbers.iter() {
+                let mut key = number.to_be_bytes().to_vec();
+                key.extend_from_slice(format!("-{}", round).as_bytes());
+                db.put(&key, b"value").unwrap();
+            }
+        }
+        db.put(b"short", b"value").unwrap();
+        db.context.background.wait_idle();
+
+        let keys: Vec<_> = db.scan(None, None, None).map(|(k, _)| k).collect();
+        assert_eq!(keys.len(), numbers.len() * 10 + 1);
+        assert_eq!(keys[0], b"short".to_vec());
+        let mut sorted_numbers = numbers.to_vec();
+        sorted_numbers.sort();
+        let scanned_numbers: Vec<_> = keys[1..].iter().step_by(10).map(|k| {
+            let mut number = [0u8; 8];
+            number.copy_from_slice(&k[..8]);
+            u64::from_be_bytes(number)
+        }).collect();
+        assert_eq!(scanned_numbers, sorted_numbers);
+        let two = keys.iter().position(|k| k.starts_with(&2u64.to_be_bytes())).unwrap();
+        let ten = keys.iter().position(|k| k.starts_with(&10u64.to_be_bytes())).unwrap();
+        assert!(two < ten);
+    }
+
+    #[test]
+    fn test_split_partition() {
+        let dir = TestDir::new("db_split_partition");
+        let mut options = Options::new("test_split_partition", 16, 2, 4, 16, 512, 64, 64);
+        options.partition_size = 14 * 1024;
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        let count = 500u32;
+        // spread over the key space, so both halves keep growing after the split
+        for i in (0..count).map(|i| i * 37 % count) {
+            db.put(format!("key{:04}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        db.context.background.wait_idle();
+
+        {
+            let partitions = db.partitions.read().unwrap();
+            assert_eq!(partitions.partitions().len(), 2);
+            let (left_lower, left_upper) = partitions.partitions()[0].bounds().unwrap();
+            let (right_lower, right_upper) = partitions.partitions()[1].bounds().unwrap();
+            assert_eq!(left_lower.key(), b"key0000");
+            assert!(left_upper < right_lower);
+            assert_eq!(right_upper.key(), format!("key{:04}", count - 1).as_bytes());
+            assert!(partitions.partitions()[0] < partitions.partitions()[1]);
+        }
+        let info = db.partition_info();
+        assert_eq!(info.len(), 2);
+        let (left_lower, left_upper) = info[0].bounds.clone().unwrap();
+        let (right_lower, right_upper) = info[1].bounds.clone().unwrap();
+        assert!(left_lower <= left_upper && left_upper < right_lower && right_lower <= right_upper);
+        assert_ne!(info[0].partition_id, info[1].partition_id);
+        assert!(info.iter().all(|info| info.table_count > 0 && info.level_sizes.iter().sum::<usize>() > 0));
+        assert_eq!(info.iter().map(|info| info.memtable_size).sum::<usize>().to_string(),
+                   db.property("scottdb.memtable-size").unwrap());
+        for i in 0..count {
+            assert_eq!(db.get(format!("key{:04}", i).as_bytes(), None).unwrap(),
+                       Some(format!("value{}", i).into_bytes()));
+        }
+        let keys: Vec<_> = db.scan(None, None, None).map(|(k, _)| k).collect();
+        assert_eq!(keys, (0..count).map(|i| format!("key{:04}", i).into_bytes()).collect::<Vec<_>>());
+
+        // a batch spanning both partitions
+        let mut batch = WriteBatch::new();
+        batch.delete(b"key0000").put(format!("key{:04}", count - 1).as_bytes(), b"last");
+        db.write(batch).unwrap();
+        assert_eq!(db.get(b"key0000", None).unwrap(), None);
+        assert_eq!(db.get(format!("key{:04}", count - 1).as_bytes(), None).unwrap(), Some(b"last".to_vec()));
+    }
+
+    #[test]
+    fn test_cursor() {
+        let dir = TestDir::new("db_cursor");
+        let mut options = Options::new("test_cursor", 16, 2, 4, 16, 512, 64, 64);
+        options.partition_size = 14 * 1024;
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        write_keys(&db, 500);
+        for i in (0..500u32).step_by(3) {
+            db.delete(format!("key{:04}", i).as_bytes()).unwrap();
+        }
+        assert!(db.partitions.read().unwrap().partitions().len() > 1);
+        let expected: Vec<_> = db.scan(None, None, None).collect();
+        let at = |cursor: &Cursor<DefaultComparator>| (cursor.key().to_vec(), cursor.value().to_vec());
+
+        let mut cursor = db.cursor().unwrap();
+        assert!(!cursor.valid());
+        // written after the cursor was taken, so it does not see it
+        db.put(b"key0000", b"later").unwrap();
+
+        cursor.seek_to_first();
+        let mut forward = Vec::new();
+        while cursor.valid() {
+            forward.push(at(&cursor));
+            cursor.next();
+        }
+        assert_eq!(forward, expected);
+
+        // backwards across the partitions
+        cursor.seek_to_last();
+        let mut backward = Vec::new();
+        while cursor.valid() {
+            backward.push(at(&cursor));
+            cursor.prev();
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+
+        // a deleted key seeks to the one after it, back and forth from there
+        cursor.seek(b"key0300");
+        assert_eq!(cursor.key(), b"key0301");
+        cursor.prev();
+        assert_eq!(cursor.key(), b"key0299");
+        cursor.prev();
+        assert_eq!(cursor.key(), b"key0298");
+        cursor.next();
+        cursor.next();
+        assert_eq!(cursor.key(), b"key0301");
+        cursor.prev();
+        assert_eq!(cursor.key(), b"key0299");
+
+        cursor.seek(b"key0001");
+        cursor.prev();
+        assert!(!cursor.valid());
+        cursor.seek(b"key9999");
+        assert!(!cursor.valid());
+        assert!(cursor.status().is_ok());
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let syncs_for = |name: &str, policy: SyncPolicy| {
+            let dir = TestDir::new(&format!("db_sync_policy_{}", name));
+            let mut options = Options::new(name, 4, 4, 10, 4, 4096, 64, 64);
+            options.sync_policy = policy;
+            let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+            assert_eq!(db.sync_policy(), policy);
+            for i in 0..10u32 {
+                db.put(format!("key{}", i).as_bytes(), b"value").unwrap();
+            }
+            let syncs = db.context.io_manager.counts.syncs();
+            db.put_with_sync(b"override", b"value", SyncPolicy::Sync).unwrap();
+            db.delete_with_sync(b"key0", SyncPolicy::Sync).unwrap();
+            assert_eq!(db.context.io_manager.counts.syncs(), syncs + 2);
+            db.delete_with_sync(b"key1", SyncPolicy::NoSync).unwrap();
+            assert_eq!(db.context.io_manager.counts.syncs(), syncs + 2);
+            syncs
+        };
+
+        assert_eq!(syncs_for("no_sync", SyncPolicy::NoSync), 0);
+        assert_eq!(syncs_for("sync", SyncPolicy::Sync), 10);
+        assert_eq!(syncs_for("every_3", SyncPolicy::EveryN(3)), 3);
+    }
+
+    #[test]
+    fn test_write_without_wal() {
+        let dir = TestDir::new("db_write_without_wal");
+        let options = || Options::new("test_write_without_wal", 4, 4, 10, 4, 4096, 64, 64);
+        let unlogged = WriteOptions::new().with_disable_wal(true);
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            db.put(b"logged", b"value").unwrap();
+            let (appends, syncs) = (db.context.io_manager.counts.appends(), db.context.io_manager.counts.syncs());
+            db.put_with_options(b"unlogged", b"value", &unlogged).unwrap();
+            db.delete_with_options(b"logged", &unlogged.with_sync(true)).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"batch", b"value");
+            db.write_with_options(batch, &unlogged).unwrap();
+            assert_eq!(db.context.io_manager.counts.appends(), appends);
+            assert_eq!(db.context.io_manager.counts.syncs(), syncs);
+            assert_eq!(db.get(b"unlogged", None).unwrap(), Some(b"value".to_vec()));
+            assert_eq!(db.get(b"logged", None).unwrap(), None);
+
+            db.put_with_options(b"synced", b"value", &WriteOptions::new().with_sync(true)).unwrap();
+            assert_eq!(db.context.io_manager.counts.appends(), appends + 1);
+            assert_eq!(db.context.io_manager.counts.syncs(), syncs + 1);
+        }
+
+        // a crash before the flush loses the unlogged writes, the logged ones come back
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            assert_eq!(db.get(b"logged", None).unwrap(), Some(b"value".to_vec()));
+            assert_eq!(db.get(b"synced", None).unwrap(), Some(b"value".to_vec()));
+            assert_eq!(db.get(b"unlogged", None).unwrap(), None);
+            assert_eq!(db.get(b"batch", None).unwrap(), None);
+            db.put_with_options(b"unlogged", b"flushed", &unlogged).unwrap();
+            db.flush().unwrap();
+        }
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.get(b"unlogged", None).unwrap(), Some(b"flushed".to_vec()));
+    }
+
+    #[test]
+    fn test_snapshot_reads_old_value() {
+        let dir = TestDir::new("db_snapshot");
+        let options = Options::new("test_snapshot", 4, 1, 4, 4, 512, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        db.put(b"key", b"old").unwrap();
+        db.put(b"gone", b"still here").unwrap();
+        let snapshot = db.snapshot();
+
+        db.put(b"key", b"new").unwrap();
+        db.delete(b"gone").unwrap();
+        db.put(b"added", b"later").unwrap();
+        // push everything through flushes and compactions
+        for round in 0..4 {
+            for i in 0..100u32 {
+                db.put(format!("key{:03}", i).as_bytes(), format!("value{}_{}", round, i).as_bytes()).unwrap();
+            }
+        }
+        db.context.background.wait_idle();
+
+        assert_eq!(db.get(b"key", Some(&snapshot)).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(db.get(b"gone", Some(&snapshot)).unwrap(), Some(b"still here".to_vec()));
+        assert_eq!(db.get(b"added", Some(&snapshot)).unwrap(), None);
+        assert_eq!(db.get(b"key", None).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"gone", None).unwrap(), None);
+
+        let scanned: Vec<_> = db.scan(None, None, Some(&snapshot)).collect();
+        assert_eq!(scanned, vec![(b"gone".to_vec(), b"still here".to_vec()), (b"key".to_vec(), b"old".to_vec())]);
+        drop(snapshot);
+        assert!(db.context.snapshots.seqs().is_empty());
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let dir = TestDir::new("db_write_batch");
+        let options = Options::new("test_write_batch", 4, 4, 10, 4, 4096, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        db.put(b"a", b"old").unwrap();
+        db.put(b"b", b"old").unwrap();
+        let snapshot = db.snapshot();
+        let seq = db.context.seq.load(atomic::Ordering::SeqCst);
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"c", b"first").delete(b"a").put(b"b", b"new").put(b"c", b"second");
+        assert_eq!(batch.len(), 4);
+        db.write(batch).unwrap();
+        assert_eq!(db.context.seq.load(atomic::Ordering::SeqCst), seq + 4);
+
+        assert_eq!(db.get(b"a", None).unwrap(), None);
+        assert_eq!(db.get(b"b", None).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"c", None).unwrap(), Some(b"second".to_vec()));
+        assert_eq!(db.get(b"a", Some(&snapshot)).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(db.get(b"c", Some(&snapshot)).unwrap(), None);
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"d", b"value").clear();
+        assert!(batch.is_empty());
+        db.write(batch).unwrap();
+        assert_eq!(db.context.seq.load(atomic::Ordering::SeqCst), seq + 4);
+    }
+
+    #[test]
+    fn test_recover_batch_all_or_nothing() {
+        let dir = TestDir::new("db_recover_batch_all_or_nothing");
+        let options = || Options::new("test_recover_batch_all_or_nothing", 4, 4, 10, 4, 4096, 64, 64);
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"hello", b"world").put(b"foo", b"bar");
+            db.write(batch).unwrap();
+        }
+
+        // the second batch is torn in its last entry, its first entry must not come back either
+        let log_path = dir.path().join("0_0.log");
+        let mut raw = std::fs::read(&log_path).unwrap();
+        let comparator = default_comparator();
+        let torn = wal::encode_record(&[
+            (InternalKey::new(100, UserKey::new_owned(b"hello".to_vec(), &comparator)), b"torn".to_vec()),
+            (InternalKey::new(101, UserKey::new_owned(b"torn".to_vec(), &comparator)), b"x".to_vec())
+        ], &BatchParts::default());
+        raw.extend_from_slice(&torn[..torn.len() - 1]);
+        std::fs::write(&log_path, raw).unwrap();
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.get(b"hello", None).unwrap(), Some(b"world".to_vec()));
+        assert_eq!(db.get(b"foo", None).unwrap(), Some(b"bar".to_vec()));
+        assert_eq!(db.get(b"torn", None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_recover_batch_across_partitions() {
+        let dir = TestDir::new("db_recover_batch_across_partitions");
+        let options = || Options::new("test_recover_batch_across_partitions", 4, 4, 10, 4, 4096, 64, 64)
+            .with_partitioning(Partitioning::Hash { partitions: 2 });
+        let batch = |value: &[u8]| {
+            let mut batch = WriteBatch::new();
+            for i in 0..10u32 {
+                batch.put(format!("key{}", i).as_bytes(), value);
+            }
+            batch
+        };
+        let logs = || {
+            let mut logs: Vec<_> = std::fs::read_dir(dir.path()).unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().map_or(false, |extension| extension == "log"))
+                .map(|path| (std::fs::metadata(&path).unwrap().len(), path))
+                .collect();
+            logs.sort_by(|(_, a), (_, b)| a.cmp(b));
+            logs
+        };
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            db.write(batch(b"first")).unwrap();
+            db.write(batch(b"second")).unwrap();
+        }
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert!((0..10u32).all(|i| db.get(format!("key{}", i).as_bytes(), None).unwrap() == Some(b"second".to_vec())));
+        let before = logs();
+        assert_eq!(before.len(), 2);
+        db.write(batch(b"third")).unwrap();
+        drop(db);
+
+        // the crash came before the part of the second partition was logged
+        let (size, path) = &before[1];
+        std::fs::OpenOptions::new().write(true).open(path).unwrap().set_len(*size).unwrap();
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert!((0..10u32).all(|i| db.get(format!("key{}", i).as_bytes(), None).unwrap() == Some(b"second".to_vec())));
+        db.close().unwrap();
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert!((0..10u32).all(|i| db.get(format!("key{}", i).as_bytes(), None).unwrap() == Some(b"second".to_vec())));
+    }
+
+    #[test]
+    fn test_corrupt_table_at_open() {
+        let dir = TestDir::new("db_corrupt_table_at_open");
+        let options = || Options::new("test_corrupt_table_at_open", 4, 4, 10, 4, 4096, 64, 64);
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            db.put(b"apple", b"1").unwrap();
+            db.flush().unwrap();
+            db.put(b"banana", b"2").unwrap();
+            db.flush().unwrap();
+        }
+        let table_file = ScTableFile::new(*table_file_numbers(&dir).last().unwrap());
+        let table_path = table_file.path(dir.path());
+        let mut raw = std::fs::read(&table_path).unwrap();
+        let middle = raw.len() / 2;
+        raw[middle] ^= 0xFF;
+        std::fs::write(&table_path, raw).unwrap();
+
+        match Db::open(dir.path(), options(), DefaultComparator()) {
+            Err(Error::ScTableCorrupt { reason }) => assert!(reason.to_string().starts_with(&table_file.file_name())),
+            _ => panic!("a corrupt table should fail the open")
+        }
+        assert!(table_path.exists());
+
+        {
+            let db = Db::open(dir.path(), options().with_paranoid_checks(false), DefaultComparator()).unwrap();
+            assert_eq!(db.stats().corrupt_tables, 1);
+            assert_eq!(db.get(b"apple", None).unwrap(), Some(b"1".to_vec()));
+            assert_eq!(db.get(b"banana", None).unwrap(), None);
+        }
+        assert!(!table_path.exists());
+        assert!(dir.path().join(format!("{}.corrupt", table_file.file_name())).exists());
+
+        // the manifest no longer lists the table
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.stats().corrupt_tables, 0);
+        assert_eq!(db.get(b"apple", None).unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_missing_or_resized_table_at_open() {
+        let dir = TestDir::new("db_missing_or_resized_table_at_open");
+        let options = || Options::new("test_missing_or_resized_table_at_open", 4, 4, 10, 4, 4096, 64, 64);
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            db.put(b"apple", b"1").unwrap();
+            db.flush().unwrap();
+            db.put(b"banana", b"2").unwrap();
+            db.flush().unwrap();
+        }
+        let numbers = table_file_numbers(&dir);
+        let (first, last) = (ScTableFile::new(numbers[0]), ScTableFile::new(*numbers.last().unwrap()));
+
+        // a table grown by a byte is caught by its size before its crcs are looked at
+        let mut raw = std::fs::read(last.path(dir.path())).unwrap();
+        raw.push(0);
+        std::fs::write(last.path(dir.path()), &raw).unwrap();
+        match Db::open(dir.path(), options(), DefaultComparator()) {
+            Err(Error::ScTableCorrupt { reason }) => {
+                assert_eq!(reason.to_string(),
+                           format!("{}: {} bytes on disk, the manifest records {}", last.file_name(), raw.len(), raw.len() - 1));
+            },
+            _ => panic!("a table of the wrong size should fail the open")
+        }
+        raw.pop();
+        std::fs::write(last.path(dir.path()), &raw).unwrap();
+
+        std::fs::remove_file(first.path(dir.path())).unwrap();
+        for options in [options(), options().with_paranoid_checks(false)].iter() {
+            match Db::open(dir.path(), options.clone(), DefaultComparator()) {
+                Err(Error::MissingTable { file }) => assert_eq!(file, first.file_name()),
+                _ => panic!("a missing table should fail the open")
+            }
+        }
+        match Db::open_readonly(dir.path(), options(), DefaultComparator()) {
+            Err(e) => assert_eq!(e.to_string(), format!("table {} listed by the manifest is missing", first.file_name())),
+            Ok(_) => panic!("a missing table should fail the open")
+        }
+    }
+
+    #[test]
+    fn test_ingest_file() {
+        let dir = TestDir::new("db_ingest_file");
+        let external = TestDir::new("db_ingest_file_external");
+        let options = || Options::new("test_ingest_file", 16, 4, 10, 16, 4096, 64, 64);
+        let build = |name: &str, entries: &[(&[u8], &[u8])]| {
+            let mut builder = ScTableBuilder::new();
+            for (key, value) in entries {
+                builder.add_kv(0, key, value);
+            }
+            let path = external.path().join(name);
+            std::fs::write(&path, builder.build()).unwrap();
+            path
+        };
+        let bulk = build("bulk.sct", &[(b"apple", b"bulk"), (b"banana", b"bulk"), (b"cherry", b"bulk")]);
+        let disjoint = build("disjoint.sct", &[(b"zoo1", b"1"), (b"zoo2", b"2")]);
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            db.put(b"banana", b"old").unwrap();
+            db.put(b"zebra", b"kept").unwrap();
+            let snapshot = db.snapshot();
+            db.ingest_file(&bulk).unwrap();
+            // the memtable holding an older banana was flushed first
+            assert!(db.stats().flushes > 0);
+            for key in [&b"apple"[..], b"banana", b"cherry"].iter() {
+                assert_eq!(db.get(key, None).unwrap(), Some(b"bulk".to_vec()));
+            }
+            assert_eq!(db.get(b"banana", Some(&snapshot)).unwrap(), Some(b"old".to_vec()));
+            assert_eq!(db.get(b"apple", Some(&snapshot)).unwrap(), None);
+            db.put(b"cherry", b"new").unwrap();
+            assert_eq!(db.get(b"cherry", None).unwrap(), Some(b"new".to_vec()));
+
+            // overlapping nothing, it goes to the last level
+            db.ingest_file(&disjoint).unwrap();
+            assert_eq!(db.stats().level_sizes.len(), 7);
+            assert!(db.stats().level_sizes[6] > 0);
+            let keys: Vec<_> = db.scan(None, None, None).map(|(k, _)| k).collect();
+            assert_eq!(keys, vec![b"apple".to_vec(), b"banana".to_vec(), b"cherry".to_vec(),
+                                  b"zebra".to_vec(), b"zoo1".to_vec(), b"zoo2".to_vec()]);
+            assert!(std::fs::metadata(&bulk).is_ok());
+        }
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.get(b"banana", None).unwrap(), Some(b"bulk".to_vec()));
+        assert_eq!(db.get(b"cherry", None).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"zoo2", None).unwrap(), Some(b"2".to_vec()));
+        db.put(b"zoo2", b"3").unwrap();
+        assert_eq!(db.get(b"zoo2", None).unwrap(), Some(b"3".to_vec()));
+
+        // versions of one key cannot all take the same sequence number
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(2, b"key", b"newer");
+        builder.add_kv(1, b"key", b"older");
+        let versions = external.path().join("versions.sct");
+        std::fs::write(&versions, builder.build()).unwrap();
+        assert!(matches!(db.ingest_file(&versions), Err(Error::InvalidArgument { .. })));
+        let corrupt = external.path().join("corrupt.sct");
+        std::fs::write(&corrupt, b"not a table at all, not at all").unwrap();
+        assert!(matches!(db.ingest_file(&corrupt), Err(Error::ScTableCorrupt { .. })));
+        assert!(db.ingest_file(external.path().join("missing.sct")).is_err());
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let dir = TestDir::new("db_bulk_load");
+        let options = || Options::new("test_bulk_load", 16, 4, 10, 16, 1024, 64, 64).with_partition_size(8 * 1024);
+        let pairs = |range: std::ops::Range<u32>| range.map(|i| (format!("key{:04}", i).into_bytes(),
+                                                                 format!("value{}", i).into_bytes()));
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            db.bulk_load(pairs(0..1000)).unwrap();
+            // nothing went through the memtables, the tables went straight to the last level
+            assert_eq!(db.stats().flushes, 0);
+            assert!(db.partition_info().iter().all(|info| info.memtable_size == 0));
+            let level_sizes = db.stats().level_sizes;
+            assert!(level_sizes.iter().take(level_sizes.len() - 1).all(|&size| size == 0));
+            assert!(db.partition_info().iter().map(|info| info.table_count).sum::<usize>() > 10);
+            assert_keys(&db, 1000);
+            assert_eq!(db.scan(None, None, None).count(), 1000);
+            db.put(b"key0500", b"overwritten").unwrap();
+            db.close().unwrap();
+        }
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.get(b"key0500", None).unwrap(), Some(b"overwritten".to_vec()));
+        assert_eq!(db.get(b"key0999", None).unwrap(), Some(b"value999".to_vec()));
+
+        // nothing is loaded if a key is out of order, not even what came before it
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        let unsorted = pairs(2000..2100).chain(pairs(1500..1501)).chain(pairs(2100..2200));
+        assert!(matches!(db.bulk_load(unsorted), Err(Error::InvalidArgument { .. })));
+        assert_eq!(db.get(b"key2000", None).unwrap(), None);
+        assert_eq!(db.get(b"key2099", None).unwrap(), None);
+        assert_eq!(db.get(b"key1500", None).unwrap(), None);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), files);
+        let duplicate = pairs(3000..3001).chain(pairs(3000..3001));
+        assert!(matches!(db.bulk_load(duplicate), Err(Error::InvalidArgument { .. })));
+        assert_eq!(db.get(b"key3000", None).unwrap(), None);
+        db.bulk_load(std::iter::empty()).unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), files);
+
+        // a load is newer than everything before it, memtables included, and shows up all at once
+        db.put(b"key0450", b"overwritten").unwrap();
+        assert!(db.partition_info().len() > 1);
+        let snapshot = db.snapshot();
+        db.bulk_load(pairs(400..600)).unwrap();
+        assert_eq!(db.get(b"key0450", None).unwrap(), Some(b"value450".to_vec()));
+        assert_eq!(db.get(b"key0500", None).unwrap(), Some(b"value500".to_vec()));
+        assert_eq!(db.get(b"key0450", Some(&snapshot)).unwrap(), Some(b"overwritten".to_vec()));
+        assert_eq!(db.get(b"key0500", Some(&snapshot)).unwrap(), Some(b"overwritten".to_vec()));
+        drop(snapshot);
+        assert_keys(&db, 1000);
+    }
+
+    #[test]
+    fn test_recover_from_log() {
+        let dir = TestDir::new("db_recover_from_log");
+        let options = || Options::new("test_recover_from_log", 4, 4, 10, 4, 4096, 64, 64);
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            db.put(b"hello", b"world").unwrap();
+            db.put(b"foo", b"bar").unwrap();
+            db.put(b"gone", b"soon").unwrap();
+            db.delete(b"gone").unwrap();
+        }
+
+        // a crash in the middle of the last write leaves a torn record behind
+        let log_path = dir.path().join("0_0.log");
+        let mut raw = std::fs::read(&log_path).unwrap();
+        let torn_key = InternalKey::new(100, UserKey::new_owned(b"torn".to_vec(), &default_comparator()));
+        let torn = wal::encode_record(&[(torn_key, b"x".to_vec())], &BatchParts::default());
+        raw.extend_from_slice(&torn[..torn.len() - 1]);
+        std::fs::write(&log_path, raw).unwrap();
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.get(b"hello", None).unwrap(), Some(b"world".to_vec()));
+        assert_eq!(db.get(b"foo", None).unwrap(), Some(b"bar".to_vec()));
+        assert_eq!(db.get(b"gone", None).unwrap(), None);
+        assert_eq!(db.get(b"torn", None).unwrap(), None);
+        assert!(!log_path.exists());
+
+        // newer writes keep winning over the recovered ones
+        db.put(b"hello", b"again").unwrap();
+        assert_eq!(db.get(b"hello", None).unwrap(), Some(b"again".to_vec()));
+    }
+
+    fn write_keys(db: &Db<DefaultComparator>, count: u32) {
+        for i in (0..count).map(|i| i * 37 % count) {
+            db.put(format!("key{:04}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        db.context.background.wait_idle();
+    }
+
+    fn assert_keys(db: &Db<DefaultComparator>, count: u32) {
+        for i in 0..count {
+            assert_eq!(db.get(format!("key{:04}", i).as_bytes(), None).unwrap(),
+                       Some(format!("value{}", i).into_bytes()));
+        }
+        let keys: Vec<_> = db.scan(None, None, None).map(|(k, _)| k).collect();
+        assert_eq!(keys, (0..count).map(|i| format!("key{:04}", i).into_bytes()).collect::<Vec<_>>());
+    }
+
+    fn log_files(dir: &TestDir) -> Vec<String> {
+        std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|file_name| wal::parse_log_file_name(file_name).is_some())
+            .collect()
+    }
+
+    #[test]
+    fn test_recover_log_segments() {
+        let options = |log_segment_size| Options::new("test_recover_log_segments", 16, 4, 10, 16, 64 * 1024, 64, 64)
+            .with_log_segment_size(log_segment_size);
+        let write = |db: &Db<DefaultComparator>| {
+            write_keys(db, 200);
+            for i in (0..200u32).step_by(3) {
+                db.delete(format!("key{:04}", i).as_bytes()).unwrap();
+            }
+            for i in (0..200u32).step_by(7) {
+                db.put(format!("key{:04}", i).as_bytes(), b"again").unwrap();
+            }
+        };
+        let contents = |db: &Db<DefaultComparator>| db.scan(None, None, None).collect::<Vec<_>>();
+
+        let single_dir = TestDir::new("db_recover_log_segments_single");
+        let segmented_dir = TestDir::new("db_recover_log_segments");
+        {
+            write(&Db::open(single_dir.path(), options(1024 * 1024), DefaultComparator()).unwrap());
+            write(&Db::open(segmented_dir.path(), options(512), DefaultComparator()).unwrap());
+        }
+        assert_eq!(log_files(&single_dir).len(), 1);
+        assert!(log_files(&segmented_dir).len() > 10);
+
+        let single = Db::open(single_dir.path(), options(1024 * 1024), DefaultComparator()).unwrap();
+        let segmented = Db::open(segmented_dir.path(), options(512), DefaultComparator()).unwrap();
+        assert_eq!(contents(&segmented), contents(&single));
+        assert_eq!(segmented.get(b"key0001", None).unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(segmented.get(b"key0003", None).unwrap(), None);
+        assert_eq!(segmented.get(b"key0021", None).unwrap(), Some(b"again".to_vec()));
+
+        // the flushed memtable takes all of its segments with it
+        segmented.flush().unwrap();
+        assert!(log_files(&segmented_dir).is_empty());
+        drop(segmented);
+        let segmented = Db::open(segmented_dir.path(), options(512), DefaultComparator()).unwrap();
+        assert_eq!(contents(&segmented), contents(&single));
+    }
+
+    #[test]
+    fn test_oversized_entries_rejected() {
+        let dir = TestDir::new("db_oversized_entries_rejected");
+        let options = || Options::new("test_oversized_entries_rejected", 4, 4, 10, 4, 4096, 8, 16);
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            db.put(b"key", b"value").unwrap();
+            let seq = db.context.seq.load(atomic::Ordering::SeqCst);
+            match db.put(b"key", &[b'v'; 17]) {
+                Err(Error::InvalidArgument { reason }) => assert!(reason.to_string().contains("value_size_max")),
+                _ => panic!("an oversized value should be refused")
+            }
+            assert!(db.put(b"too long key", b"value").is_err());
+            assert!(db.delete(b"too long key").is_err());
+
+            // the valid put of a refused batch is not applied either
+            let mut batch = WriteBatch::new();
+            batch.put(b"other", b"value").put(b"key", &[b'v'; 17]);
+            assert!(db.write(batch).is_err());
+
+            assert_eq!(db.context.seq.load(atomic::Ordering::SeqCst), seq);
+            assert_eq!(db.get(b"key", None).unwrap(), Some(b"value".to_vec()));
+            assert_eq!(db.get(b"other", None).unwrap(), None);
+            db.put(&[b'k'; 8], &[b'v'; 16]).unwrap();
+        }
+        // nor did any of them make it into the log
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        let scanned: Vec<_> = db.scan(None, None, None).collect();
+        assert_eq!(scanned, vec![(b"key".to_vec(), b"value".to_vec()), (vec![b'k'; 8], vec![b'v'; 16])]);
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let dir = TestDir::new("db_multi_get");
+        let mut options = Options::new("test_multi_get", 16, 2, 4, 16, 512, 64, 64);
+        options.partition_size = 14 * 1024;
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        write_keys(&db, 500);
+        assert_eq!(db.partitions.read().unwrap().partitions().len(), 2);
+        db.delete(b"key0002").unwrap();
+        db.put(b"key0499", b"in memtable").unwrap();
+
+        let keys: Vec<&[u8]> = vec![b"key0499", b"absent", b"key0000", b"key0002", b"key0250", b"key0500",
+                                    b"key0000", b"key0001"];
+        let expected = vec![Some(b"in memtable".to_vec()), None, Some(b"value0".to_vec()), None,
+                            Some(b"value250".to_vec()), None, Some(b"value0".to_vec()), Some(b"value1".to_vec())];
+        let results: Vec<_> = db.multi_get(&keys).into_iter().map(|result| result.unwrap()).collect();
+        assert_eq!(results, expected);
+        for (key, result) in keys.iter().zip(results) {
+            assert_eq!(db.get(key, None).unwrap(), result);
+        }
+        assert!(db.multi_get(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let dir = TestDir::new("db_contains_key");
+        let db = Db::open(dir.path(), Options::new("test_contains_key", 4, 4, 10, 4, 1 << 20, 64, 64),
+                          DefaultComparator()).unwrap();
+        write_keys(&db, 100);
+        db.delete(b"key0001").unwrap();
+        assert!(db.contains_key(b"key0000").unwrap());
+        assert!(!db.contains_key(b"key0001").unwrap());
+        assert!(!db.contains_key(b"key0100").unwrap());
+        assert!(!db.contains_key(b"absent").unwrap());
+
+        db.flush().unwrap();
+        db.delete(b"key0002").unwrap();
+        assert!(db.contains_key(b"key0000").unwrap());
+        assert!(!db.contains_key(b"key0001").unwrap());
+        assert!(!db.contains_key(b"key0002").unwrap());
+        assert!(!db.contains_key(b"key0100").unwrap());
+        db.put(b"key0001", b"again").unwrap();
+        assert!(db.contains_key(b"key0001").unwrap());
+    }
+
+    #[test]
+    fn test_get_with_seq() {
+        let dir = TestDir::new("db_get_with_seq");
+        let db = Db::open(dir.path(), Options::new("test_get_with_seq", 4, 4, 10, 4, 1 << 20, 64, 64),
+                          DefaultComparator()).unwrap();
+        assert_eq!(db.get_with_seq(b"key").unwrap(), None);
+        db.put(b"key", b"first").unwrap();
+        let (_, first_seq) = db.get_with_seq(b"key").unwrap().unwrap();
+        db.put(b"other", b"value").unwrap();
+        db.put(b"key", b"second").unwrap();
+        let (value, second_seq) = db.get_with_seq(b"key").unwrap().unwrap();
+        assert_eq!(value, b"second".to_vec());
+        assert!(second_seq > first_seq);
+
+        // tables keep the sequence numbers of their entries
+        db.flush().unwrap();
+        assert_eq!(db.get_with_seq(b"key").unwrap(), Some((b"second".to_vec(), second_seq)));
+        db.delete(b"key").unwrap();
+        assert_eq!(db.get_with_seq(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_open_in_memory() {
+        let options = Options::new("test_open_in_memory", 16, 1, 4, 16, 512, 64, 64).with_level1_bytes(2048);
+        let db: DefaultDb = Db::open_in_memory(options, DefaultComparator()).unwrap();
+        write_keys(&db, 1000);
+        db.delete(b"key1000").unwrap();
+        let stats = db.stats();
+        assert!(stats.flushes > 0);
+        assert!(stats.compactions > 0);
+        assert!(db.context.io_manager.list_files().unwrap().iter().all(|file_name| file_name.ends_with(".sct")));
+        assert_keys(&db, 1000);
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_flush() {
+        let dir = TestDir::new("db_flush");
+        let options = || Options::new("test_flush", 4, 4, 10, 4, 1 << 20, 64, 64);
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            db.flush().unwrap();
+            assert_eq!(db.stats().flushes, 0);
+            write_keys(&db, 100);
+            db.delete(b"key0000").unwrap();
+            assert_eq!(db.stats().flushes, 0);
+            db.flush().unwrap();
+            assert_eq!(db.stats().flushes, 1);
+            // everything is in a table, no log is needed to get it back
+            assert!(log_files(&dir).is_empty());
+            db.flush().unwrap();
+            assert_eq!(db.stats().flushes, 1);
+        }
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.get(b"key0000", None).unwrap(), None);
+        for i in 1..100 {
+            assert_eq!(db.get(format!("key{:04}", i).as_bytes(), None).unwrap(),
+                       Some(format!("value{}", i).into_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_hash_partitioning() {
+        let dir = TestDir::new("db_hash_partitioning");
+        let options = || Options::new("test_hash_partitioning", 16, 4, 4, 16, 1024, 64, 64)
+            .with_partition_size(1024)
+            .with_partitioning(Partitioning::Hash { partitions: 4 });
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            write_keys(&db, 1000);
+            let infos = db.partition_info();
+            let ids: Vec<_> = infos.iter().map(|info| info.partition_id).collect();
+            assert_eq!(ids, vec![0, 1, 2, 3]);
+            // far beyond partition_size, yet nothing is split
+            db.flush().unwrap();
+            assert!(infos.iter().all(|info| info.bounds.is_some()));
+            assert!(db.partition_info().iter().all(|info| info.level_sizes.iter().sum::<usize>() > 2048));
+
+            // each partition is scanned in order, one after the other
+            let mut keys: Vec<_> = db.scan(Some(b"key0100"), Some(b"key0200"), None).map(|(key, _)| key).collect();
+            assert_eq!(keys.len(), 100);
+            assert_ne!(keys, { let mut sorted = keys.clone(); sorted.sort(); sorted });
+            keys.sort();
+            assert_eq!(keys, (100..200).map(|i| format!("key{:04}", i).into_bytes()).collect::<Vec<_>>());
+            assert_eq!(db.keys(None, None, None).count(), 1000);
+            assert!(matches!(db.cursor(), Err(Error::InvalidArgument { .. })));
+
+            db.delete_range(b"key0000", b"key0500").unwrap();
+            db.close().unwrap();
+        }
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.partition_info().len(), 4);
+        assert_eq!(db.get(b"key0400", None).unwrap(), None);
+        assert_eq!(db.get(b"key0600", None).unwrap(), Some(b"value600".to_vec()));
+        assert_eq!(db.scan(None, None, None).count(), 500);
+        db.close().unwrap();
+        for partitioning in [Partitioning::Hash { partitions: 2 }, Partitioning::Hash { partitions: 8 }, Partitioning::Range] {
+            let other = options().with_partitioning(partitioning);
+            assert!(matches!(Db::open(dir.path(), other, DefaultComparator()), Err(Error::InvalidArgument { .. })));
+        }
+
+        let range_dir = TestDir::new("db_hash_partitioning_range");
+        let range = options().with_partitioning(Partitioning::Range);
+        Db::open(range_dir.path(), range, DefaultComparator()).unwrap().close().unwrap();
+        assert!(matches!(Db::open(range_dir.path(), options(), DefaultComparator()), Err(Error::InvalidArgument { .. })));
+    }
+
+    #[test]
+    fn test_flush_idle_memtable() {
+        let dir = TestDir::new("db_flush_idle_memtable");
+        let options = Options::new("test_flush_idle_memtable", 4, 4, 10, 4, 1 << 20, 64, 64)
+            .with_memtable_idle_timeout(Some(Duration::from_millis(20)));
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        db.put(b"key", b"value").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while db.stats().flushes == 0 {
+            assert!(Instant::now() < deadline, "the idle memtable was never flushed");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        db.context.background.wait_idle();
+        assert!(log_files(&dir).is_empty());
+        assert_eq!(db.get(b"key", None).unwrap(), Some(b"value".to_vec()));
+        // an empty memtable is never flushed
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(db.stats().flushes, 1);
+    }
+
+    #[test]
+    fn test_close() {
+        let dir = TestDir::new("db_close");
+        let options = || Options::new("test_close", 4, 1, 4, 4, 512, 64, 64);
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        write_keys(&db, 200);
+        let level_count = db.stats().level_sizes.len();
+        db.close().unwrap();
+        assert!(log_files(&dir).is_empty());
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert!(db.stats().level_sizes.len() >= level_count);
+        assert_keys(&db, 200);
+    }
+
+    #[test]
+    fn test_concurrent_writers_wait_for_flush() {
+        let dir = TestDir::new("db_concurrent_writers_wait_for_flush");
+        let options = Options::new("test_concurrent_writers_wait_for_flush", 16, 4, 10, 16, 512, 64, 64)
+            .with_background_threads(2);
+        let db = Arc::new(Db::open(dir.path(), options, DefaultComparator()).unwrap());
+
+        // the memtable fills after a handful of writes, writers keep waiting for the flush before
+        let (done, finished) = std::sync::mpsc::channel();
+        for t in 0..8 {
+            let (db, done) = (db.clone(), done.clone());
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    db.put(format!("key{}_{:03}", t, i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+                }
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..8 {
+            // a writer never woken again would hang here
+            finished.recv_timeout(std::time::Duration::from_secs(60)).unwrap();
+        }
+        db.context.background.wait_idle();
+        assert!(db.stats().flushes > 8);
+        for t in 0..8 {
+            for i in 0..200 {
+                assert_eq!(db.get(format!("key{}_{:03}", t, i).as_bytes(), None).unwrap(),
+                           Some(format!("value{}", i).into_bytes()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_put_if_absent() {
+        let dir = TestDir::new("db_put_if_absent");
+        let options = Options::new("test_put_if_absent", 16, 4, 4, 16, 1024, 64, 64);
+        let db = Arc::new(Db::open(dir.path(), options, DefaultComparator()).unwrap());
+        for round in 0..20 {
+            let key = format!("key{:02}", round).into_bytes();
+            let barrier = Arc::new(std::sync::Barrier::new(2));
+            let writers: Vec<_> = (0..2).map(|t| {
+                let (db, barrier, key) = (db.clone(), barrier.clone(), key.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    db.put_if_absent(&key, format!("writer{}", t).as_bytes()).unwrap()
+                })
+            }).collect();
+            let wrote: Vec<bool> = writers.into_iter().map(|writer| writer.join().unwrap()).collect();
+            assert_eq!(wrote.iter().filter(|&&wrote| wrote).count(), 1);
+            let winner = wrote.iter().position(|&wrote| wrote).unwrap();
+            assert_eq!(db.get(&key, None).unwrap(), Some(format!("writer{}", winner).into_bytes()));
+        }
+
+        // a value in a table is found as well, and a deleted key is absent
+        db.flush().unwrap();
+        assert!(!db.put_if_absent(b"key00", b"again").unwrap());
+        db.delete(b"key00").unwrap();
+        assert!(db.put_if_absent(b"key00", b"again").unwrap());
+        assert_eq!(db.get(b"key00", None).unwrap(), Some(b"again".to_vec()));
+    }
+
+    #[test]
+    fn test_level_targets() {
+        let dir = TestDir::new("db_level_targets");
+        let options = Options::new("test_level_targets", 16, 1, 4, 16, 512, 64, 64)
+            .with_level1_bytes(2048)
+            .with_max_levels(3);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        write_keys(&db, 1000);
+
+        // level 1 went past its target and was compacted into level 2, the last level
+        let stats = db.stats();
+        assert_eq!(stats.level_sizes.len(), 3);
+        assert!(stats.level_sizes[1] <= 2048);
+        assert!(stats.level_sizes[2] > 2048);
+        assert!(stats.compactions > 0);
+        assert_keys(&db, 1000);
+    }
+
+    #[test]
+    fn test_read_compaction() {
+        let dir = TestDir::new("db_read_compaction");
+        let options = |threshold| Options::new("test_read_compaction", 16, 8, 4, 16, 4096, 64, 64)
+            .with_read_compaction_threshold(threshold);
+        for &threshold in [10, 0].iter() {
+            let _ = std::fs::remove_dir_all(dir.path());
+            let db = Db::open(dir.path(), options(threshold), DefaultComparator()).unwrap();
+            // two overlapping tables in level 0, far fewer than it may hold
+            for i in (0..100).step_by(2).chain((1..100).step_by(2)) {
+                db.put(format!("key{:03}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+                if i == 98 {
+                    db.flush().unwrap();
+                }
+            }
+            db.flush().unwrap();
+            db.context.background.wait_idle();
+            assert_eq!(db.stats().compactions, 0);
+
+            // every even key is looked for in the newer table first, in vain
+            let read_even_keys = |count: u32| {
+                for i in 0..count {
+                    let key = format!("key{:03}", i * 2 % 100);
+                    assert_eq!(db.get(key.as_bytes(), None).unwrap(), Some(format!("value{}", i * 2 % 100).into_bytes()));
+                }
+                db.context.background.wait_idle();
+            };
+            read_even_keys(9);
+            assert_eq!(db.stats().compactions, 0);
+            read_even_keys(20);
+            if threshold == 0 {
+                assert_eq!(db.stats().compactions, 0);
+                assert!(db.stats().level_sizes[0] > 0);
+            } else {
+                assert_eq!(db.stats().compactions, 1);
+                assert_eq!(db.stats().level_sizes[0], 0);
+                assert!(db.stats().level_sizes[1] > 0);
+            }
+            for i in 0..100 {
+                assert_eq!(db.get(format!("key{:03}", i).as_bytes(), None).unwrap(),
+                           Some(format!("value{}", i).into_bytes()));
+            }
+        }
+    }
+
+    struct PrefixFilter(&'static [u8]);
+
+    impl CompactionFilter for PrefixFilter {
+        fn filter(&self, key: &[u8], _value: &[u8]) -> FilterDecision {
+            if key.starts_with(self.0) { FilterDecision::Remove } else { FilterDecision::Keep }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let dir = TestDir::new("db_compaction_filter");
+        let options = Options::new("test_compaction_filter", 4, 1, 4, 4, 512, 64, 64)
+            .with_compaction_filter(PrefixFilter(b"tmp"));
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        for i in 0..50 {
+            db.put(format!("tmp{:04}", i).as_bytes(), b"expired").unwrap();
+        }
+        db.flush().unwrap();
+
+        // the next flush puts a second table into level 0, which is compacted as a whole
+        write_keys(&db, 100);
+        db.flush().unwrap();
+        db.context.background.wait_idle();
+        assert!(db.stats().compactions > 0);
+        for i in 0..50 {
+            assert_eq!(db.get(format!("tmp{:04}", i).as_bytes(), None).unwrap(), None);
+        }
+        assert_keys(&db, 100);
+    }
+
+    /// Holds every compaction in the filter until the gate is opened.
+    struct GateFilter(Arc<(Mutex<bool>, Condvar)>);
+
+    impl CompactionFilter for GateFilter {
+        fn filter(&self, _key: &[u8], _value: &[u8]) -> FilterDecision {
+            let (open, opened) = &*self.0;
+            let _open = opened.wait_while(open.lock().unwrap(), |open| !*open).unwrap();
+            FilterDecision::Keep
+        }
+    }
+
+    #[test]
+    fn test_level0_stops_writes() {
+        let dir = TestDir::new("db_level0_stops_writes");
+        let gate = Arc::new((Mutex::new(false), Condvar::new()));
+        let options = Options::new("test_level0_stops_writes", 16, 1, 4, 16, 4096, 64, 64)
+            .with_level0_slowdown_writes_trigger(2)
+            .with_level0_stop_writes_trigger(3)
+            .with_background_threads(2)
+            .with_compaction_filter(GateFilter(gate.clone()));
+        let db = Arc::new(Db::open(dir.path(), options, DefaultComparator()).unwrap());
+
+        // the second table starts a compaction of level 0 which the gate holds, the third one
+        // reaches the stop count
+        for t in 0..3 {
+            db.put(format!("key{}", t).as_bytes(), b"value").unwrap();
+            db.flush().unwrap();
+        }
+        assert_eq!(db.stats().level0_tables, 3);
+
+        let (done, finished) = std::sync::mpsc::channel();
+        let writer = db.clone();
+        std::thread::spawn(move || {
+            writer.put(b"stopped", b"value").unwrap();
+            done.send(()).unwrap();
+        });
+        assert!(finished.recv_timeout(Duration::from_millis(200)).is_err());
+
+        *gate.0.lock().unwrap() = true;
+        gate.1.notify_all();
+        finished.recv_timeout(Duration::from_secs(60)).unwrap();
+        db.context.background.wait_idle();
+        assert!(db.stats().level0_tables < 3);
+        assert!(db.stats().compactions > 0);
+        assert_eq!(db.get(b"stopped", None).unwrap(), Some(b"value".to_vec()));
+        for t in 0..3 {
+            assert_eq!(db.get(format!("key{}", t).as_bytes(), None).unwrap(), Some(b"value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_compact_range() {
+        let dir = TestDir::new("db_compact_range");
+        let options = Options::new("test_compact_range", 16, 4, 4, 16, 1024, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        let disk_size = || std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| ScTableFile::parse_file_name(entry.file_name().to_str().unwrap()).is_some())
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum::<u64>();
+        write_keys(&db, 1000);
+        for i in 100..1000 {
+            db.delete(format!("key{:04}", i).as_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+        db.context.background.wait_idle();
+        let before = disk_size();
+
+        // the keys outside of the range keep their tables
+        db.compact_range(Some(b"key0000"), Some(b"key0100")).unwrap();
+        assert_keys(&db, 100);
+
+        db.compact_range(None, None).unwrap();
+        let after = disk_size();
+        assert!(after * 2 < before, "{} {}", after, before);
+        assert_keys(&db, 100);
+        assert_eq!(db.stats().level_sizes.iter().filter(|&&size| size > 0).count(), 1);
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let dir = TestDir::new("db_delete_range");
+        let options = || Options::new("test_delete_range", 16, 4, 4, 16, 1024, 64, 64);
+        let range_tombstones = |db: &Db<DefaultComparator>| db.partitions.read().unwrap().partitions().iter()
+            .map(|partition| partition.manifest_record().range_tombstones.len())
+            .sum::<usize>();
+        let live_keys = |db: &Db<DefaultComparator>| db.scan(None, None, None).map(|(k, _)| k).collect::<Vec<_>>();
+        let expected: Vec<_> = (0..100).map(|i| format!("key{:04}", i).into_bytes())
+            .chain(std::iter::once(b"key0600".to_vec()))
+            .collect();
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            write_keys(&db, 1000);
+            db.flush().unwrap();
+            db.put(b"key0500", b"in the memtable").unwrap();
+            let snapshot = db.snapshot();
+            db.delete_range(b"key0100", b"key1000").unwrap();
+            db.put(b"key0600", b"written after").unwrap();
+
+            assert_eq!(db.get(b"key0099", None).unwrap(), Some(b"value99".to_vec()));
+            assert_eq!(db.get(b"key0100", None).unwrap(), None);
+            assert_eq!(db.get(b"key0500", None).unwrap(), None);
+            assert_eq!(db.get(b"key0999", None).unwrap(), None);
+            assert_eq!(db.get(b"key0600", None).unwrap(), Some(b"written after".to_vec()));
+            assert!(!db.contains_key(b"key0200").unwrap());
+            assert!(db.get_pinned(b"key0200").unwrap().is_none());
+            let found: Vec<_> = db.multi_get(&[b"key0050", b"key0300"]).into_iter().map(|r| r.unwrap()).collect();
+            assert_eq!(found, vec![Some(b"value50".to_vec()), None]);
+            assert_eq!(live_keys(&db), expected);
+            // the snapshot still reads what was there before
+            assert_eq!(db.get(b"key0500", Some(&snapshot)).unwrap(), Some(b"in the memtable".to_vec()));
+            assert_eq!(db.scan(None, None, Some(&snapshot)).count(), 1000);
+
+            // versions the snapshot reads survive compaction, and so does the tombstone
+            db.compact_range(None, None).unwrap();
+            assert_eq!(db.get(b"key0300", Some(&snapshot)).unwrap(), Some(b"value300".to_vec()));
+            assert_eq!(range_tombstones(&db), 1);
+            assert_eq!(live_keys(&db), expected);
+            drop(snapshot);
+            db.close().unwrap();
+        }
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.get(b"key0300", None).unwrap(), None);
+        assert_eq!(live_keys(&db), expected);
+        // once nothing is left it hides, the tombstone goes as well
+        db.compact_range(None, None).unwrap();
+        assert_eq!(range_tombstones(&db), 0);
+        assert_eq!(live_keys(&db), expected);
+        assert_eq!(db.get(b"key0600", None).unwrap(), Some(b"written after".to_vec()));
+
+        // an empty range deletes nothing
+        db.delete_range(b"key0050", b"key0050").unwrap();
+        db.delete_range(b"key0060", b"key0050").unwrap();
+        assert_eq!(live_keys(&db), expected);
+
+        // a memtable holding nothing else hands the tombstone over to the manifest
+        db.delete_range(b"key0000", b"key0010").unwrap();
+        db.close().unwrap();
+        assert!(log_files(&dir).is_empty());
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(range_tombstones(&db), 1);
+        assert_eq!(live_keys(&db), expected[10..].to_vec());
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = TestDir::new("db_merge");
+        let options = || Options::new("test_merge", 16, 4, 4, 16, 1024, 64, 64)
+            .with_merge_operator(U64AddMergeOperator());
+        let number = |value: u64| value.to_be_bytes().to_vec();
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            for _ in 0..10 {
+                db.merge(b"counter", &number(1)).unwrap();
+            }
+            assert_eq!(db.get(b"counter", None).unwrap(), Some(number(10)));
+            let snapshot = db.snapshot();
+
+            // operands go on top of values in the tables, and tombstones hide what is below them
+            db.flush().unwrap();
+            db.merge(b"counter", &number(5)).unwrap();
+            db.put(b"deleted", &number(100)).unwrap();
+            db.delete(b"deleted").unwrap();
+            db.merge(b"deleted", &number(2)).unwrap();
+            assert_eq!(db.get(b"counter", None).unwrap(), Some(number(15)));
+            assert_eq!(db.get(b"deleted", None).unwrap(), Some(number(2)));
+            assert_eq!(db.get(b"counter", Some(&snapshot)).unwrap(), Some(number(10)));
+
+            db.compact_range(None, None).unwrap();
+            assert_eq!(db.get(b"counter", None).unwrap(), Some(number(15)));
+            assert_eq!(db.get(b"counter", Some(&snapshot)).unwrap(), Some(number(10)));
+            let found: Vec<_> = db.scan(None, None, None).collect();
+            assert_eq!(found, vec![(b"counter".to_vec(), number(15)), (b"deleted".to_vec(), number(2))]);
+            drop(snapshot);
+            db.merge(b"counter", &number(1)).unwrap();
+            db.close().unwrap();
+        }
+
+        // operands still in the log are recovered
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.get(b"counter", None).unwrap(), Some(number(16)));
+        db.compact_range(None, None).unwrap();
+        assert_eq!(db.get(b"counter", None).unwrap(), Some(number(16)));
+        db.close().unwrap();
+
+        let dir = TestDir::new("db_merge_without_operator");
+        let db = Db::open(dir.path(), Options::new("test_merge_without_operator", 16, 4, 4, 16, 1024, 64, 64),
+                          DefaultComparator()).unwrap();
+        assert!(matches!(db.merge(b"counter", &number(1)), Err(Error::InvalidArgument { .. })));
+    }
+
+    #[test]
+    fn test_compaction_rate_limit() {
+        let dir = TestDir::new("db_compaction_rate_limit");
+        let limit = 8 * 1024;
+        let options = Options::new("test_compaction_rate_limit", 16, 1, 4, 16, 512, 64, 64)
+            .with_compaction_rate_limit(limit);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        write_keys(&db, 200);
+        db.flush().unwrap();
+        db.context.background.wait_idle();
+
+        // the second table in level 0 overlaps the first, both are compacted into level 1
+        let start = Instant::now();
+        write_keys(&db, 200);
+        db.flush().unwrap();
+        db.context.background.wait_idle();
+        let elapsed = start.elapsed();
+        let stats = db.stats();
+        assert!(stats.compactions > 0);
+        assert!(stats.compaction_write_rate > 0);
+        let compacted: usize = stats.level_sizes[1..].iter().sum();
+        assert!(compacted > limit);
+        assert!(elapsed >= Duration::from_secs_f64(compacted as f64 / limit as f64), "{:?}", elapsed);
+        assert_keys(&db, 200);
+    }
+
+    #[test]
+    fn test_approximate_size() {
+        let dir = TestDir::new("db_approximate_size");
+        let options = Options::new("test_approximate_size", 16, 2, 4, 16, 1024, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        assert_eq!(db.approximate_size(b"", b"\xff"), 0);
+        write_keys(&db, 1000);
+        db.flush().unwrap();
+
+        // every table is entirely in the full range
+        let total = db.approximate_size(b"", b"\xff");
+        assert_eq!(total, db.stats().level_sizes.iter().sum::<usize>() as u64);
+        assert_eq!(db.approximate_size(b"key0500", b"key0500"), 0);
+        assert_eq!(db.approximate_size(b"key0600", b"key0500"), 0);
+        assert_eq!(db.approximate_size(b"zzz", b"\xff"), 0);
+
+        let ranges: [(&[u8], &[u8]); 5] = [(b"key0400", b"key0410"), (b"key0400", b"key0500"), (b"key0300", b"key0500"),
+                                           (b"key0300", b"key0800"), (b"key", b"key1")];
+        let sizes: Vec<u64> = ranges.iter().map(|(start, end)| db.approximate_size(start, end)).collect();
+        assert!(sizes.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", sizes);
+        assert!(sizes[1] < sizes[3] && sizes[4] <= total);
+        // half of the keys take about half of the bytes
+        let half = db.approximate_size(b"key0000", b"key0500");
+        assert!(half > total / 4 && half < total * 3 / 4, "{} of {}", half, total);
+
+        // what is still in the memtable counts as well
+        let before = db.approximate_size(b"new", b"new\xff");
+        for i in 0..10 {
+            db.put(format!("new{}", i).as_bytes(), b"value").unwrap();
+        }
+        assert!(db.approximate_size(b"new", b"new\xff") > before);
+    }
+
+    #[test]
+    fn test_skiplist_memtable() {
+        let dir = TestDir::new("db_skiplist_memtable");
+        let options = || Options::new("test_skiplist_memtable", 16, 1, 4, 16, 512, 64, 64)
+            .with_memtable_kind(MemTableKind::SkipList);
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            write_keys(&db, 300);
+            assert!(db.stats().flushes > 0);
+            assert_keys(&db, 300);
+
+            let snapshot = db.snapshot();
+            db.put(b"key0001", b"changed").unwrap();
+            db.delete(b"key0002").unwrap();
+            assert_eq!(db.get(b"key0001", Some(&snapshot)).unwrap(), Some(b"value1".to_vec()));
+            assert_eq!(db.get(b"key0001", None).unwrap(), Some(b"changed".to_vec()));
+            assert_eq!(db.get(b"key0002", Some(&snapshot)).unwrap(), Some(b"value2".to_vec()));
+            assert_eq!(db.get(b"key0002", None).unwrap(), None);
+            assert_eq!(db.scan(None, None, None).count(), 299);
+        }
+
+        // what was left in the memtable is recovered from the log into a skiplist again
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.get(b"key0001", None).unwrap(), Some(b"changed".to_vec()));
+        assert_eq!(db.get(b"key0002", None).unwrap(), None);
+        assert_eq!(db.scan(None, None, None).count(), 299);
+    }
+
+    #[test]
+    fn test_skiplist_concurrent_writers() {
+        let dir = TestDir::new("db_skiplist_concurrent_writers");
+        let options = Options::new("test_skiplist_concurrent_writers", 16, 4, 10, 16, 1 << 20, 64, 64)
+            .with_memtable_kind(MemTableKind::SkipList);
+        let db = Arc::new(Db::open(dir.path(), options, DefaultComparator()).unwrap());
+        const THREADS: u32 = 8;
+        const WRITES: u32 = 2000;
+        const KEYS: u32 = 100;
+        // every thread writes every key, so the threads insert versions of the same keys at once
+        let key = |t: u32, i: u32| format!("key{:03}", (i * 7 + t) % KEYS).into_bytes();
+
+        let writing = Arc::new(AtomicBool::new(true));
+        let reader = {
+            let (db, writing) = (db.clone(), writing.clone());
+            std::thread::spawn(move || {
+                // what a snapshot sees does not change while inserts of older versions go on
+                while writing.load(atomic::Ordering::SeqCst) {
+                    let snapshot = db.snapshot();
+                    let first: Vec<_> = db.scan(None, None, Some(&snapshot)).collect();
+                    let second: Vec<_> = db.scan(None, None, Some(&snapshot)).collect();
+                    assert_eq!(first, second);
+                }
+            })
+        };
+        let writers: Vec<_> = (0..THREADS).map(|t| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in 0..WRITES {
+                    db.put(&key(t, i), format!("{}-{}", t, i).as_bytes()).unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        writing.store(false, atomic::Ordering::SeqCst);
+        reader.join().unwrap();
+
+        let entries: Vec<_> = db.scan(None, None, None).collect();
+        assert_eq!(entries.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(),
+                   (0..KEYS).map(|k| format!("key{:03}", k).into_bytes()).collect::<Vec<_>>());
+        // the value of a key is the last one some thread wrote to it
+        for (k, value) in entries {
+            let last_writes: Vec<_> = (0..THREADS)
+                .map(|t| (0..WRITES).rev().find(|&i| key(t, i) == k).unwrap())
+                .enumerate()
+                .map(|(t, i)| format!("{}-{}", t, i).into_bytes())
+                .collect();
+            assert!(last_writes.contains(&value), "{:?}", String::from_utf8_lossy(&value));
+        }
+    }
+
+    #[test]
+    fn test_reopen_keeps_tables() {
+        let dir = TestDir::new("db_reopen_keeps_tables");
+        let options = || Options::new("test_reopen_keeps_tables", 4, 1, 4, 4, 512, 64, 64);
+        let level_sizes = {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            write_keys(&db, 200);
+            db.stats().level_sizes
+        };
+        assert!(level_sizes.iter().sum::<usize>() > 0);
+
+        // the tables come back from the manifest, nothing is flushed again
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.stats().flushes, 0);
+        assert_eq!(db.stats().level_sizes, level_sizes);
+        assert_keys(&db, 200);
+
+        // a table file the manifest does not know of is left over by a crash and removed
+        drop(db);
+        std::fs::write(dir.path().join("00999999.sct"), b"garbage").unwrap();
+        // as is a table a crash caught before it was renamed into place
+        std::fs::write(dir.path().join("00999998.sct.tmp"), b"half a table").unwrap();
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert!(!dir.path().join("00999999.sct").exists());
+        assert!(!dir.path().join("00999998.sct.tmp").exists());
+        assert_keys(&db, 200);
+        // without logs the sequence number comes from the manifest, or the tables would be invisible
+        drop(db);
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let file_name = entry.unwrap().file_name().into_string().unwrap();
+            if wal::parse_log_file_name(&file_name).is_some() {
+                std::fs::remove_file(dir.path().join(file_name)).unwrap();
+            }
+        }
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert!(db.context.seq.load(atomic::Ordering::SeqCst) > 0);
+        let pairs: Vec<_> = db.scan(None, None, None).collect();
+        assert!(!pairs.is_empty());
+        for (key, value) in pairs {
+            assert_eq!(db.get(&key, None).unwrap(), Some(value.clone()));
+            assert_eq!(value, format!("value{}", std::str::from_utf8(&key[3..]).unwrap().parse::<u32>().unwrap()).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_open_readonly() {
+        let dir = TestDir::new("db_open_readonly");
+        let options = || Options::new("test_open_readonly", 4, 1, 4, 4, 512, 64, 64);
+        assert!(Db::open_readonly(dir.path().join("missing"), options(), DefaultComparator()).is_err());
+        assert!(!dir.path().join("missing").exists());
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            write_keys(&db, 200);
+            // left in the log only
+            db.delete(b"key0007").unwrap();
+            db.put(b"key0200", b"value200").unwrap();
+        }
+        let files = || {
+            let mut ret: Vec<_> = std::fs::read_dir(dir.path()).unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            ret.sort();
+            ret
+        };
+        let files_before = files();
+        assert!(!log_files(&dir).is_empty());
+
+        let first = Db::open_readonly(dir.path(), options(), DefaultComparator()).unwrap();
+        let second = Db::open_readonly(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(files(), files_before);
+        assert_eq!(first.get(b"key0007", None).unwrap(), None);
+        assert_eq!(first.get(b"key0200", None).unwrap(), Some(b"value200".to_vec()));
+        match first.put(b"key0300", b"value300") {
+            Err(Error::ReadOnly) => (),
+            _ => panic!("a read-only database takes no writes")
+        }
+        assert!(matches!(first.delete(b"key0001"), Err(Error::ReadOnly)));
+        let mut batch = WriteBatch::new();
+        batch.put(b"key0300", b"value300");
+        assert!(matches!(second.write(batch), Err(Error::ReadOnly)));
+        first.flush().unwrap();
+        drop(first);
+        assert_eq!(files(), files_before);
+
+        // reads match those of a writable handle, which replays the same logs
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        let expected: Vec<_> = db.scan(None, None, None).collect();
+        assert_eq!(expected.len(), 200);
+        assert_eq!(second.scan(None, None, None).collect::<Vec<_>>(), expected);
+        assert_eq!(second.scan(Some(b"key0100"), Some(b"key0150"), None).collect::<Vec<_>>(),
+                   db.scan(Some(b"key0100"), Some(b"key0150"), None).collect::<Vec<_>>());
+        for i in 0..=200 {
+            let key = format!("key{:04}", i);
+            assert_eq!(second.get(key.as_bytes(), None).unwrap(), db.get(key.as_bytes(), None).unwrap());
+        }
+        assert_eq!(second.stats().flushes, 0);
+        assert_eq!(second.stats().compactions, 0);
+    }
+
+    #[test]
+    fn test_remove_obsolete_files() {
+        let dir = TestDir::new("db_remove_obsolete_files");
+        let options = Options::new("test_remove_obsolete_files", 16, 4, 4, 16, 1024, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        write_keys(&db, 500);
+        db.flush().unwrap();
+        // compactions the writes started would otherwise reclaim bytes of their own below
+        db.context.background.wait_idle();
+        let before = table_file_sizes(&dir);
+
+        let mut iter = db.scan(None, None, None);
+        assert_eq!(iter.next(), Some((b"key0000".to_vec(), b"value0".to_vec())));
+        db.compact_range(None, None).unwrap();
+        // the iterator still reads the tables the compaction replaced, while tables compact_range
+        // both wrote and replaced itself may already be gone
+        let during = table_file_numbers(&dir);
+        assert!(before.iter().all(|(file_number, _)| during.contains(file_number)));
+        let reclaimed = db.stats().bytes_reclaimed;
+        let rest: Vec<_> = iter.by_ref().map(|(key, _)| key).collect();
+        assert_eq!(rest, (1..500).map(|i| format!("key{:04}", i).into_bytes()).collect::<Vec<_>>());
+        assert!(iter.status().is_ok());
+
+        // once it is through or dropped, their files go and exactly their bytes count as reclaimed
+        drop(iter);
+        let after = table_file_numbers(&dir);
+        let removed: u64 = before.iter()
+            .filter(|(file_number, _)| !after.contains(file_number))
+            .map(|(_, file_size)| file_size)
+            .sum();
+        assert!(removed > 0);
+        assert_eq!(db.stats().bytes_reclaimed - reclaimed, removed);
+        assert_keys(&db, 500);
+    }
+
+    #[test]
+    fn test_split_with_live_iterator() {
+        let dir = TestDir::new("db_split_with_live_iterator");
+        let options = Options::new("test_split_with_live_iterator", 16, 4, 4, 16, 1024, 64, 64);
+        let db = Db::open(dir.path(), options, DefaultComparator()).unwrap();
+        write_keys(&db, 500);
+        db.flush().unwrap();
+        db.context.background.wait_idle();
+        let before = table_file_numbers(&dir);
+
+        let mut iter = db.scan(None, None, None);
+        assert_eq!(iter.next(), Some((b"key0000".to_vec(), b"value0".to_vec())));
+        let partition = db.route(b"key0000");
+        db.split(&partition).unwrap();
+        drop(partition);
+        assert_eq!(db.partition_info().len(), 2);
+        // the iterator still reads the tables rewritten for the split
+        let during = table_file_numbers(&dir);
+        assert!(before.iter().all(|file_number| during.contains(file_number)));
+        let rest: Vec<_> = iter.by_ref().map(|(key, _)| key).collect();
+        assert_eq!(rest, (1..500).map(|i| format!("key{:04}", i).into_bytes()).collect::<Vec<_>>());
+
+        // once it is dropped, only the tables of the new partitions are left
+        drop(iter);
+        let mut live: Vec<_> = db.partitions.read().unwrap().partitions().iter()
+            .flat_map(|partition| partition.manifest_record().tables)
+            .map(|table| table.table_file.file_number())
+            .collect();
+        live.sort();
+        assert!(before.iter().any(|file_number| !live.contains(file_number)));
+        assert_eq!(table_file_numbers(&dir), live);
+        assert_keys(&db, 500);
+    }
+
+    fn table_file_sizes(dir: &TestDir) -> Vec<(u64, u64)> {
+        std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap())
+            .filter_map(|entry| ScTableFile::parse_file_name(entry.file_name().to_str().unwrap())
+                .map(|table_file| (table_file.file_number(), entry.metadata().unwrap().len())))
+            .collect()
+    }
+
+    fn table_file_numbers(dir: &TestDir) -> Vec<u64> {
+        let mut ret: Vec<_> = std::fs::read_dir(dir.path()).unwrap()
+            .filter_map(|entry| ScTableFile::parse_file_name(entry.unwrap().file_name().to_str().unwrap()))
+            .map(|table_file| table_file.file_number())
+            .collect();
+        ret.sort();
+        ret
+    }
+
+    #[test]
+    fn test_file_numbers_across_reopen() {
+        let dir = TestDir::new("db_file_numbers_across_reopen");
+        let options = || Options::new("test_file_numbers_across_reopen", 4, 1, 4, 4, 512, 64, 64);
+        let first_run = {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            write_keys(&db, 200);
+            table_file_numbers(&dir)
+        };
+        assert!(!first_run.is_empty());
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        let new_table_file = db.context.new_table_file();
+        assert!(new_table_file.file_number() > *first_run.last().unwrap());
+        assert_eq!(new_table_file.path(dir.path()), dir.path().join(new_table_file.file_name()));
+        for i in 0..200u32 {
+            db.put(format!("key{:04}", i).as_bytes(), format!("again{}", i).as_bytes()).unwrap();
+        }
+        db.context.background.wait_idle();
+        // tables of the second run are numbered above every table of the first one
+        let second_run: Vec<_> = table_file_numbers(&dir).into_iter()
+            .filter(|file_number| !first_run.contains(file_number))
+            .collect();
+        assert!(!second_run.is_empty());
+        assert!(second_run[0] > new_table_file.file_number());
+    }
+
+    #[test]
+    fn test_reopen_after_split() {
+        let dir = TestDir::new("db_reopen_after_split");
+        let options = || {
+            let mut options = Options::new("test_reopen_after_split", 16, 2, 4, 16, 512, 64, 64);
+            options.partition_size = 14 * 1024;
+            options
+        };
+        {
+            let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+            write_keys(&db, 500);
+            assert_eq!(db.partitions.read().unwrap().partitions().len(), 2);
+        }
+
+        let db = Db::open(dir.path(), options(), DefaultComparator()).unwrap();
+        assert_eq!(db.partitions.read().unwrap().partitions().len(), 2);
+        assert_keys(&db, 500);
+    }
+
+    const CRASH_DIR_VAR: &'static str = "PR65_CRASH_DIR";
+
+    /// Run by `test_crash_after_flush` in a process of its own, which it kills after the flush.
+    #[test]
+    #[ignore]
+    fn crash_after_flush_child() {
+        let dir = match std::env::var(CRASH_DIR_VAR) {
+            Ok(dir) => dir,
+            Err(_) => return
+        };
+        let db = Db::open(std::path::Path::new(&dir), Options::new("test_crash_after_flush", 4, 1, 4, 4, 512, 64, 64),
+                          DefaultComparator()).unwrap();
+        write_keys(&db, 200);
+        std::process::abort();
+    }
+
+    #[test]
+    fn test_crash_after_flush() {
+        let dir = TestDir::new("db_crash_after_flush");
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(&["--exact", "db::test::crash_after_flush_child", "--ignored", "--nocapture"])
+            .env(CRASH_DIR_VAR, dir.path())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(!status.success());
+
+        let db = Db::open(dir.path(), Options::new("test_crash_after_flush", 4, 1, 4, 4, 512, 64, 64),
+                          DefaultComparator()).unwrap();
+        assert!(db.stats().level_sizes.iter().sum::<usize>() > 0);
+        assert_keys(&db, 200);
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+use crate::table::cache::ScTableCache;
+
+/// A value read by `Db::get_pinned`. A value found in a table is not copied out of it, the table
+/// is kept in memory instead for as long as this is alive, evicted or not, and counts against
+/// `Options::cache_count` meanwhile. A value of a table cached without its values is shared with
+/// the block cache if it fits there. A value found in a memtable is a copy.
+pub struct PinnedValue(Pinned);
+
+enum Pinned {
+    Copied(Vec<u8>),
+    InTable(Arc<ScTableCache>, Range<usize>),
+    Shared(Arc<[u8]>)
+}
+
+impl PinnedValue {
+    pub(crate) fn copied(value: Vec<u8>) -> Self {
+        Self(Pinned::Copied(value))
+    }
+
+    /// The value at `range` of the data region of `table`.
+    pub(crate) fn in_table(table: Arc<ScTableCache>, range: Range<usize>) -> Self {
+        Self(Pinned::InTable(table, range))
+    }
+
+    /// A block of the block cache.
+    pub(crate) fn shared(block: Arc<[u8]>) -> Self {
+        Self(Pinned::Shared(block))
+    }
+
+    /// Whether the value borrows from a table or the block cache rather than being a copy.
+    pub fn is_pinned(&self) -> bool {
+        !matches!(self.0, Pinned::Copied(_))
+    }
+
+    /// The value as a vector of its own, which is only copied if it is pinned.
+    pub(crate) fn into_vec(self) -> Vec<u8> {
+        match self.0 {
+            Pinned::Copied(value) => value,
+            _ => self.to_vec()
+        }
+    }
+}
+
+impl Deref for PinnedValue {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Pinned::Copied(value) => value,
+            Pinned::InTable(table, range) => table.data_range(range.clone()),
+            Pinned::Shared(block) => block
+        }
+    }
+}
+
+impl AsRef<[u8]> for PinnedValue {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for PinnedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PinnedValue").field(&self.deref()).finish()
+    }
+}
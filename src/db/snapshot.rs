@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{self, AtomicU64};
+
+use crate::db::DbContext;
+
+/// Sequence numbers pinned by live snapshots, with the number of snapshots pinning each.
+pub(crate) struct SnapshotList {
+    seqs: Mutex<BTreeMap<u64, usize>>
+}
+
+impl SnapshotList {
+    pub(crate) fn new() -> Self {
+        Self { seqs: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Pins the current value of `seq`. Done under the list lock, so `seqs` never misses a
+    /// snapshot that is being taken concurrently.
+    fn acquire(&self, seq: &AtomicU64) -> u64 {
+        let mut seqs = self.seqs.lock().unwrap();
+        let snapshot_seq = seq.load(atomic::Ordering::SeqCst);
+        *seqs.entry(snapshot_seq).or_insert(0) += 1;
+        snapshot_seq
+    }
+
+    fn pin(&self, snapshot_seq: u64) {
+        *self.seqs.lock().unwrap().entry(snapshot_seq).or_insert(0) += 1;
+    }
+
+    fn release(&self, snapshot_seq: u64) {
+        let mut seqs = self.seqs.lock().unwrap();
+        let count = seqs.get_mut(&snapshot_seq).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            seqs.remove(&snapshot_seq);
+        }
+    }
+
+    /// Whether a snapshot pins a sequence number in `[lower, upper)`.
+    pub(crate) fn pinned_between(&self, lower: u64, upper: u64) -> bool {
+        lower < upper && self.seqs.lock().unwrap().range(lower..upper).next().is_some()
+    }
+
+    /// The sequence numbers of the live snapshots, ascending. Readers without a snapshot read the
+    /// newest versions, whatever their sequence number.
+    pub(crate) fn seqs(&self) -> Vec<u64> {
+        self.seqs.lock().unwrap().keys().copied().collect()
+    }
+}
+
+/// A consistent read view of the database. Reads through a snapshot only see writes that
+/// happened before it was taken, and compaction keeps those versions around until it is dropped.
+pub struct Snapshot {
+    context: Arc<DbContext>,
+    seq: u64
+}
+
+impl Snapshot {
+    pub(crate) fn new(context: Arc<DbContext>) -> Self {
+        let seq = context.snapshots.acquire(&context.seq);
+        Self { context, seq }
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Clone for Snapshot {
+    fn clone(&self) -> Self {
+        self.context.snapshots.pin(self.seq);
+        Self { context: self.context.clone(), seq: self.seq }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.context.snapshots.release(self.seq)
+    }
+}
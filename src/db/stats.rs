@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of one database. They are only ever added to, with relaxed atomics, so keeping them
+/// costs the write and compaction paths next to nothing.
+#[derive(Default)]
+pub(crate) struct Stats {
+    flushes: AtomicU64,
+    compactions: AtomicU64,
+    bytes_written: AtomicU64,
+    bytes_reclaimed: AtomicU64,
+    corrupt_tables: AtomicU64
+}
+
+impl Stats {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// A memtable was written into a table of `bytes`.
+    pub(crate) fn record_flush(&self, bytes: usize) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.record_bytes_written(bytes);
+    }
+
+    /// A compaction wrote tables of `bytes` in total.
+    pub(crate) fn record_compaction(&self, bytes: usize) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.record_bytes_written(bytes);
+    }
+
+    /// Tables of `bytes` were written, by a split for instance.
+    pub(crate) fn record_bytes_written(&self, bytes: usize) {
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Files of replaced tables taking `bytes` were removed.
+    pub(crate) fn record_reclaimed(&self, bytes: u64) {
+        self.bytes_reclaimed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A corrupt table was set aside when opening the database.
+    pub(crate) fn record_corrupt_table(&self) {
+        self.corrupt_tables.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn compactions(&self) -> u64 {
+        self.compactions.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes_reclaimed(&self) -> u64 {
+        self.bytes_reclaimed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn corrupt_tables(&self) -> u64 {
+        self.corrupt_tables.load(Ordering::Relaxed)
+    }
+}
+
+/// The counters of a database as `Db::stats` read them. Counters are read one after the other, so
+/// a snapshot taken while the database is busy may be slightly inconsistent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Table lookups served by the table cache.
+    pub cache_hits: u64,
+    /// Table lookups which had to read the table from disk.
+    pub cache_misses: u64,
+    /// Table lookups the table's bloom filter answered, the table was not searched.
+    pub filter_negatives: u64,
+    /// Table lookups the table's bloom filter let through for a key the table did not hold. Few
+    /// of them next to `filter_negatives` tell `Options::bloom_false_positive_rate` is low enough.
+    pub filter_false_positives: u64,
+    /// Tables alive in memory, cached or still used after being evicted.
+    pub cached_tables: usize,
+    /// Bytes of values the block cache holds, see `Options::block_cache_bytes`.
+    pub block_cache_bytes: usize,
+    pub flushes: u64,
+    pub compactions: u64,
+    /// Bytes of tables written by flushes, compactions and splits.
+    pub bytes_written: u64,
+    /// Bytes of table files removed after compactions and splits replaced them, once no iterator
+    /// read them anymore.
+    pub bytes_reclaimed: u64,
+    /// Corrupt tables renamed aside by `Db::open` since `Options::paranoid_checks` was off.
+    pub corrupt_tables: u64,
+    /// Bytes per second compactions wrote over the last second or two.
+    pub compaction_write_rate: u64,
+    /// Tables in level 0, summed over all partitions. Writes slow down and stop as a partition's
+    /// count reaches `Options::level0_slowdown_writes_trigger` and
+    /// `Options::level0_stop_writes_trigger`.
+    pub level0_tables: usize,
+    /// Bytes on disk of every level, summed over all partitions.
+    pub level_sizes: Vec<usize>
+}
+
+/// The layout of one partition as `Db::partition_info` reports it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    pub partition_id: u32,
+    /// The lowest and the highest key the partition has held, both included. `None` for a
+    /// partition nothing has been written to yet.
+    pub bounds: Option<(Vec<u8>, Vec<u8>)>,
+    /// Bytes on disk of every level from level 0 on.
+    pub level_sizes: Vec<usize>,
+    /// Tables in all levels.
+    pub table_count: usize,
+    /// Bytes of the keys and values in the memtable being written to.
+    pub memtable_size: usize
+}
@@ -1,5 +1,7 @@
 use std::u32;
 
+use crate::error::Error;
+
 pub fn decode_fixed32(s: &[u8]) -> u32 {
     debug_assert_eq!(s.len(), 4);
     unsafe {
@@ -38,6 +40,7 @@ pub fn decode_fixed64(s: &[u8]) -> u64 {
     }
 }
 
+#[cfg(test)]
 pub fn encode_fixed64(s: &mut [u8], num: u64) {
     debug_assert_eq!(s.len(), 8);
     unsafe {
@@ -56,11 +59,76 @@ pub fn encode_fixed64_ret(num: u64) -> [u8; 8] {
     num.to_be_bytes()
 }
 
+// Nothing in the crate writes varints yet, they are kept for length prefixes in the table format,
+// so they are allowed to be unused until then.
+
+/// Appends `num` as a LEB128 varint: 7 bits per byte, least significant group first, the high
+/// bit set on every byte but the last.
+#[allow(dead_code)]
+pub fn encode_varint32(dest: &mut Vec<u8>, num: u32) {
+    encode_varint64(dest, num as u64)
+}
+
+/// Decodes a varint written by `encode_varint32`, returning it with the number of bytes consumed.
+#[allow(dead_code)]
+pub fn decode_varint32(s: &[u8]) -> Result<(u32, usize), Error> {
+    let (num, len) = decode_varint(s, 5)?;
+    if num > u32::MAX as u64 {
+        return Err(Error::decode_error("varint32 overflows".into()))
+    }
+    Ok((num as u32, len))
+}
+
+#[allow(dead_code)]
+pub fn encode_varint64(dest: &mut Vec<u8>, mut num: u64) {
+    while num >= 0x80 {
+        dest.push((num & 0x7F) as u8 | 0x80);
+        num >>= 7;
+    }
+    dest.push(num as u8)
+}
+
+#[allow(dead_code)]
+pub fn decode_varint64(s: &[u8]) -> Result<(u64, usize), Error> {
+    decode_varint(s, 10)
+}
+
+#[allow(dead_code)]
+pub fn varint_length(mut num: u64) -> usize {
+    let mut len = 1;
+    while num >= 0x80 {
+        num >>= 7;
+        len += 1;
+    }
+    len
+}
+
+#[allow(dead_code)]
+fn decode_varint(s: &[u8], max_len: usize) -> Result<(u64, usize), Error> {
+    let mut num = 0u64;
+    for (i, &byte) in s.iter().take(max_len).enumerate() {
+        let bits = (byte & 0x7F) as u64;
+        if i == 9 && bits > 1 {
+            return Err(Error::decode_error("varint64 overflows".into()))
+        }
+        num |= bits << (7 * i as u64);
+        if byte & 0x80 == 0 {
+            return Ok((num, i + 1))
+        }
+    }
+    if s.len() < max_len {
+        Err(Error::decode_error("truncated varint".into()))
+    } else {
+        Err(Error::decode_error("varint too long".into()))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use rand::{thread_rng, Rng};
     use crate::encode::{encode_fixed32, decode_fixed32, encode_fixed32_ret,
-                        encode_fixed64, decode_fixed64, encode_fixed64_ret};
+                        encode_fixed64, decode_fixed64, encode_fixed64_ret,
+                        encode_varint32, decode_varint32, encode_varint64, decode_varint64, varint_length};
 
     #[test]
     fn test_encode_decode_32() {
@@ -99,4 +167,86 @@ mod test {
             assert_eq!(decode_fixed64(&buffer), number)
         }
     }
+
+    #[test]
+    fn test_encode_decode_64_edges() {
+        for &number in [0u64, 1, 0xFF, 0x40490fd0fffffffe, u64::max_value() - 1, u64::max_value()].iter() {
+            let mut buffer = [0u8; 8];
+            encode_fixed64(&mut buffer, number);
+            assert_eq!(buffer, encode_fixed64_ret(number));
+            assert_eq!(decode_fixed64(&buffer), number)
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_32_edges() {
+        for &number in [0u32, 1, 0xFF, 0x80000000, u32::max_value() - 1, u32::max_value()].iter() {
+            let mut buffer = [0u8; 4];
+            encode_fixed32(&mut buffer, number);
+            assert_eq!(buffer, encode_fixed32_ret(number));
+            assert_eq!(decode_fixed32(&buffer), number)
+        }
+    }
+
+    #[test]
+    fn test_varint_boundaries() {
+        let mut numbers = vec![0u64, u64::max_value()];
+        for width in 1..10 {
+            let boundary = 1u64 << (7 * width);
+            numbers.extend_from_slice(&[boundary - 1, boundary]);
+        }
+        for number in numbers {
+            let mut buffer = Vec::new();
+            encode_varint64(&mut buffer, number);
+            assert_eq!(buffer.len(), varint_length(number));
+            assert_eq!(decode_varint64(&buffer).unwrap(), (number, buffer.len()));
+
+            if number <= u32::max_value() as u64 {
+                let mut buffer32 = Vec::new();
+                encode_varint32(&mut buffer32, number as u32);
+                assert_eq!(buffer32, buffer);
+                assert_eq!(decode_varint32(&buffer32).unwrap(), (number as u32, buffer.len()));
+            } else {
+                assert!(decode_varint32(&buffer).is_err());
+            }
+        }
+        assert_eq!(varint_length(127), 1);
+        assert_eq!(varint_length(128), 2);
+        assert_eq!(varint_length(16383), 2);
+        assert_eq!(varint_length(16384), 3);
+    }
+
+    #[test]
+    fn test_varint_consumes_only_itself() {
+        let mut buffer = Vec::new();
+        encode_varint32(&mut buffer, 300);
+        encode_varint64(&mut buffer, 0x40490fd0fffffffe);
+        let (first, len) = decode_varint32(&buffer).unwrap();
+        assert_eq!((first, len), (300, 2));
+        assert_eq!(decode_varint64(&buffer[len..]).unwrap(), (0x40490fd0fffffffe, buffer.len() - len));
+    }
+
+    #[test]
+    fn test_varint_random() {
+        for _ in 1..1024 {
+            let number: u64 = thread_rng().gen();
+            let mut buffer = Vec::new();
+            encode_varint64(&mut buffer, number);
+            assert_eq!(decode_varint64(&buffer).unwrap(), (number, buffer.len()));
+        }
+    }
+
+    #[test]
+    fn test_varint_truncated() {
+        for &number in [128u64, 16384, u32::max_value() as u64, u64::max_value()].iter() {
+            let mut buffer = Vec::new();
+            encode_varint64(&mut buffer, number);
+            for len in 0..buffer.len() {
+                assert!(decode_varint64(&buffer[..len]).is_err());
+            }
+        }
+        assert!(decode_varint32(&[]).is_err());
+        assert!(decode_varint32(&[0xFF; 5]).is_err());
+        assert!(decode_varint64(&[0xFF; 11]).is_err());
+    }
 }
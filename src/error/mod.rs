@@ -1,10 +1,20 @@
 use std::fmt::{Debug, Display, Formatter};
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub enum Error {
     ScTableCorrupt { reason: ErrorStr },
     ScSplitCorrupt { reason: ErrorStr },
-    IOError { reason: ErrorStr, file: String },
+    DecodeError { reason: ErrorStr },
+    /// `file` is the path the failed operation was about, if there is one.
+    IOError { source: Arc<std::io::Error>, file: Option<String> },
+    InvalidOptions { reason: ErrorStr },
+    /// A call was given something it cannot take, like a key above `Options::key_size_max`.
+    InvalidArgument { reason: ErrorStr },
+    /// A write to a database opened with `Db::open_readonly`.
+    ReadOnly,
+    /// `Db::open` found a table the manifest lists gone, `file` is its name.
+    MissingTable { file: String },
     RequiresExplode
 }
 
@@ -26,17 +36,46 @@ impl From<&'static str> for ErrorStr {
     }
 }
 
+impl Display for ErrorStr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ErrorStr::Owned(s) => f.write_str(s),
+            ErrorStr::StaticBorrow(s) => f.write_str(s)
+        }
+    }
+}
+
 impl Error {
     pub(crate) fn sc_table_corrupt(reason: ErrorStr) -> Self {
         Error::ScTableCorrupt { reason }
     }
 
-    pub(crate) fn sc_split_corrupt(reason: ErrorStr) -> Self {
-        Error::ScSplitCorrupt { reason }
+    pub(crate) fn decode_error(reason: ErrorStr) -> Self {
+        Error::DecodeError { reason }
+    }
+
+    pub(crate) fn io(source: std::io::Error) -> Self {
+        Error::IOError { source: Arc::new(source), file: None }
+    }
+
+    pub(crate) fn io_error(source: std::io::Error, file: String) -> Self {
+        Error::IOError { source: Arc::new(source), file: Some(file) }
+    }
+
+    pub(crate) fn invalid_options(reason: ErrorStr) -> Self {
+        Error::InvalidOptions { reason }
+    }
+
+    pub(crate) fn invalid_argument(reason: ErrorStr) -> Self {
+        Error::InvalidArgument { reason }
+    }
+
+    pub(crate) fn read_only() -> Self {
+        Error::ReadOnly
     }
 
-    pub(crate) fn io_error(reason: ErrorStr, file: String) -> Self {
-        Error::IOError { reason, file }
+    pub(crate) fn missing_table(file: String) -> Self {
+        Error::MissingTable { file }
     }
 
     pub(crate) fn requires_explode() -> Self {
@@ -44,10 +83,69 @@ impl Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Self {
+        Error::io(source)
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-        unimplemented!()
+        match self {
+            Error::ScTableCorrupt { reason } => write!(f, "corrupt table: {}", reason),
+            Error::ScSplitCorrupt { reason } => write!(f, "corrupt split: {}", reason),
+            Error::DecodeError { reason } => write!(f, "decode error: {}", reason),
+            Error::IOError { source, file: Some(file) } => write!(f, "I/O error on {}: {}", file, source),
+            Error::IOError { source, file: None } => write!(f, "I/O error: {}", source),
+            Error::InvalidOptions { reason } => write!(f, "invalid options: {}", reason),
+            Error::InvalidArgument { reason } => write!(f, "invalid argument: {}", reason),
+            Error::ReadOnly => f.write_str("database is opened read-only"),
+            Error::MissingTable { file } => write!(f, "table {} listed by the manifest is missing", file),
+            Error::RequiresExplode => f.write_str("partition has to be split first")
+        }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError { source, .. } => Some(source.as_ref()),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::error::Error;
+
+    fn failing_read() -> Result<(), Error> {
+        Err(io::Error::new(io::ErrorKind::NotFound, "no such file"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Error::sc_table_corrupt("incorrect block crc".into()).to_string(),
+                   "corrupt table: incorrect block crc");
+        assert_eq!(Error::invalid_options(format!("max_levels must be at least 2, got {}", 1).into()).to_string(),
+                   "invalid options: max_levels must be at least 2, got 1");
+        assert_eq!(Error::invalid_argument("key of 9 bytes exceeds key_size_max (8)".into()).to_string(),
+                   "invalid argument: key of 9 bytes exceeds key_size_max (8)");
+        assert_eq!(Error::read_only().to_string(), "database is opened read-only");
+        assert_eq!(Error::missing_table("00000007.sct".to_string()).to_string(),
+                   "table 00000007.sct listed by the manifest is missing");
+        let e = Error::io_error(io::Error::new(io::ErrorKind::NotFound, "no such file"), "db/00000001.sct".to_string());
+        assert_eq!(e.to_string(), "I/O error on db/00000001.sct: no such file");
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let e = failing_read().unwrap_err();
+        assert_eq!(e.to_string(), "I/O error: no such file");
+        let source = std::error::Error::source(&e).unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -1,37 +1,128 @@
-use std::sync::atomic::AtomicUsize;
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std_semaphore::Semaphore;
+use crc::crc32;
 
 use crate::error;
+#[cfg(test)]
+use crate::testutil::IOCounts;
+
+/// Suffix of the files `write_atomic` has not yet renamed into place. Such files are left behind
+/// only by a crash and are removed at the next open.
+pub(crate) const TEMP_FILE_SUFFIX: &str = ".tmp";
+
+/// Where the files of a database live.
+enum Storage {
+    Disk(PathBuf),
+    /// Files are buffers in a map, for databases which never touch the disk. Logs are not kept.
+    Memory(Mutex<HashMap<String, Vec<u8>>>)
+}
 
 pub(crate) struct IOManager {
-    open_files: AtomicUsize,
-    sem: Semaphore
+    storage: Storage,
+    sem: Semaphore,
+    #[cfg(test)]
+    pub(crate) counts: IOCounts
 }
 
 pub(crate) struct FileQuota<'a>(&'a IOManager);
 
 impl<'a> FileQuota<'a> {
     pub(crate) fn read_file(self, file_name: String) -> Result<Vec<u8>, error::Error> {
-        self.read_file_impl(&file_name).or_else(
+        let path = match &self.0.storage {
+            Storage::Disk(db_path) => db_path.join(&file_name),
+            Storage::Memory(files) => return files.lock().unwrap().get(&file_name).cloned().ok_or_else(
+                || error::Error::io_error(not_found(), file_name)
+            )
+        };
+        self.read_file_impl(&path).or_else(
             |e| {
-                Err(error::Error::io_error(e.to_string().into(),
-                                           file_name))
+                Err(error::Error::io_error(e, path.display().to_string()))
             }
         )
     }
 
+    /// Reads `len` bytes starting at `offset`, a file ending before that is an error.
+    pub(crate) fn read_range(self, file_name: String, offset: u64, len: usize) -> Result<Vec<u8>, error::Error> {
+        let path = match &self.0.storage {
+            Storage::Disk(db_path) => db_path.join(&file_name),
+            Storage::Memory(files) => {
+                let files = files.lock().unwrap();
+                let range = files.get(&file_name).and_then(|data| {
+                    let offset = offset as usize;
+                    data.get(offset..offset.checked_add(len)?)
+                });
+                return match range {
+                    Some(range) => Ok(range.to_vec()),
+                    None => Err(error::Error::io_error(std::io::ErrorKind::UnexpectedEof.into(), file_name))
+                }
+            }
+        };
+        self.read_range_impl(&path, offset, len).or_else(
+            |e| {
+                Err(error::Error::io_error(e, path.display().to_string()))
+            }
+        )
+    }
+
+    /// Writes `data` and syncs it. A crash may leave the file partially written, see `write_atomic`.
     pub(crate) fn write_file(self, file_name: String, data: &[u8]) -> Result<(), error::Error> {
-        self.write_file_impl(&file_name, data).or_else(
+        let path = match &self.0.storage {
+            Storage::Disk(db_path) => db_path.join(&file_name),
+            Storage::Memory(files) => {
+                files.lock().unwrap().insert(file_name, data.to_vec());
+                return Ok(())
+            }
+        };
+        self.write_file_impl(&path, data).or_else(
             |e| {
-                Err(error::Error::io_error(e.to_string().into(),
-                                           file_name))
+                Err(error::Error::io_error(e, path.display().to_string()))
             }
         )
     }
 
-    fn read_file_impl(self, file_name: &String) -> Result<Vec<u8>, std::io::Error> {
+    /// Overwrites the bytes of `file_name` from `offset` on with `data` and syncs it, leaving the
+    /// rest of the file as it is. The file has to be at least `offset + data.len()` bytes long.
+    pub(crate) fn write_range(self, file_name: String, offset: u64, data: &[u8]) -> Result<(), error::Error> {
+        let path = match &self.0.storage {
+            Storage::Disk(db_path) => db_path.join(&file_name),
+            Storage::Memory(files) => {
+                let mut files = files.lock().unwrap();
+                let range = files.get_mut(&file_name).and_then(|file| {
+                    let offset = offset as usize;
+                    file.get_mut(offset..offset.checked_add(data.len())?)
+                });
+                return match range {
+                    Some(range) => {
+                        range.copy_from_slice(data);
+                        Ok(())
+                    },
+                    None => Err(error::Error::io_error(std::io::ErrorKind::UnexpectedEof.into(), file_name))
+                }
+            }
+        };
+        self.write_range_impl(&path, offset, data).map_err(|e| error::Error::io_error(e, path.display().to_string()))
+    }
+
+    /// Writes `data` to a temporary file next to `file_name` and renames it into place once it is
+    /// synced, then syncs the directory so the rename itself survives a crash. A crash leaves either
+    /// the old contents or the new ones under `file_name`, never a partially written file.
+    pub(crate) fn write_atomic(self, file_name: String, data: &[u8]) -> Result<(), error::Error> {
+        let path = match &self.0.storage {
+            Storage::Disk(db_path) => db_path.join(&file_name),
+            Storage::Memory(_) => return self.write_file(file_name, data)
+        };
+        self.write_atomic_impl(&path, data).or_else(
+            |e| {
+                Err(error::Error::io_error(e, path.display().to_string()))
+            }
+        )
+    }
+
+    fn read_file_impl(self, file_name: &Path) -> Result<Vec<u8>, std::io::Error> {
         let mut v = Vec::new();
         File::with_options()
             .read(true)
@@ -41,14 +132,53 @@ impl<'a> FileQuota<'a> {
         Ok(v)
     }
 
-    fn write_file_impl(self, file_name: &String, data: &[u8]) -> Result<(), std::io::Error> {
-        File::with_options()
+    fn read_range_impl(self, file_name: &Path, offset: u64, len: usize) -> Result<Vec<u8>, std::io::Error> {
+        let mut v = vec![0; len];
+        let mut file = File::with_options()
+            .read(true)
+            .write(false)
+            .open(file_name)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut v)?;
+        Ok(v)
+    }
+
+    fn write_file_impl(self, file_name: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut file = File::with_options()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(file_name)?
-            .write_all(data)?;
-        Ok(())
+            .open(file_name)?;
+        file.write_all(data)?;
+        file.sync_data()
+    }
+
+    fn write_range_impl(self, file_name: &Path, offset: u64, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut file = File::with_options()
+            .write(true)
+            .open(file_name)?;
+        if file.metadata()?.len() < offset + data.len() as u64 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into())
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        file.sync_data()
+    }
+
+    fn write_atomic_impl(self, file_name: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+        let temp_file_name = temp_file_path(file_name);
+        let mut file = File::with_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_file_name)?;
+        file.write_all(data)?;
+        file.sync_data()?;
+        std::fs::rename(&temp_file_name, file_name)?;
+        match file_name.parent() {
+            Some(dir) => File::open(dir)?.sync_all(),
+            None => Ok(())
+        }
     }
 }
 
@@ -60,11 +190,136 @@ impl<'a> Drop for FileQuota<'a> {
 }
 
 impl IOManager {
-    pub fn new(max_open_files: usize) -> Self {
-        Self { open_files: AtomicUsize::new(0), sem: Semaphore::new(max_open_files as isize) }
+    pub fn new(db_path: impl AsRef<Path>, max_open_files: usize) -> Self {
+        Self::with_storage(Storage::Disk(db_path.as_ref().to_path_buf()), max_open_files)
+    }
+
+    /// Keeps every file in memory, nothing is read from or written to the disk.
+    pub fn in_memory(max_open_files: usize) -> Self {
+        Self::with_storage(Storage::Memory(Mutex::new(HashMap::new())), max_open_files)
+    }
+
+    fn with_storage(storage: Storage, max_open_files: usize) -> Self {
+        Self {
+            storage,
+            sem: Semaphore::new(max_open_files as isize),
+            #[cfg(test)]
+            counts: IOCounts::default()
+        }
+    }
+
+    /// Whether files outlive the process. Databases kept in memory need neither logs nor a manifest.
+    pub(crate) fn is_persistent(&self) -> bool {
+        match self.storage {
+            Storage::Disk(_) => true,
+            Storage::Memory(_) => false
+        }
     }
 
-    pub fn acquire_quota(&self) -> FileQuota {
+    /// Creates a log file for appending. Log files stay open as long as their memtable lives, so
+    /// they do not count against `max_open_files`.
+    pub(crate) fn create_log_file(&self, file_name: String) -> Result<File, error::Error> {
+        let path = match &self.storage {
+            Storage::Disk(db_path) => db_path.join(&file_name),
+            Storage::Memory(_) => return Err(error::Error::io_error(std::io::ErrorKind::Unsupported.into(), file_name))
+        };
+        File::with_options()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .or_else(|e| Err(error::Error::io_error(e, path.display().to_string())))
+    }
+
+    /// Appends `data` to `file`, a log file from `create_log_file`.
+    pub(crate) fn append(&self, file: &mut File, file_name: &str, data: &[u8]) -> Result<(), error::Error> {
+        #[cfg(test)]
+        self.counts.record_append();
+        file.write_all(data).or_else(|e| Err(error::Error::io_error(e, file_name.to_string())))
+    }
+
+    /// Flushes the data written to `file` down to the disk.
+    pub(crate) fn sync(&self, file: &File, file_name: &str) -> Result<(), error::Error> {
+        #[cfg(test)]
+        self.counts.record_sync();
+        file.sync_data().or_else(|e| Err(error::Error::io_error(e, file_name.to_string())))
+    }
+
+    pub(crate) fn list_files(&self) -> Result<Vec<String>, error::Error> {
+        let db_path = match &self.storage {
+            Storage::Disk(db_path) => db_path,
+            Storage::Memory(files) => return Ok(files.lock().unwrap().keys().cloned().collect())
+        };
+        let io_error = |e: std::io::Error| error::Error::io_error(e, db_path.display().to_string());
+        let mut ret = Vec::new();
+        for entry in std::fs::read_dir(db_path).map_err(io_error)? {
+            if let Some(file_name) = entry.map_err(io_error)?.file_name().to_str() {
+                ret.push(file_name.to_string());
+            }
+        }
+        Ok(ret)
+    }
+
+    pub(crate) fn remove_file(&self, file_name: String) -> Result<(), error::Error> {
+        let path = match &self.storage {
+            Storage::Disk(db_path) => db_path.join(&file_name),
+            Storage::Memory(files) => return match files.lock().unwrap().remove(&file_name) {
+                Some(_) => Ok(()),
+                None => Err(error::Error::io_error(not_found(), file_name))
+            }
+        };
+        std::fs::remove_file(&path).or_else(
+            |e| Err(error::Error::io_error(e, path.display().to_string()))
+        )
+    }
+
+    /// Bytes of `file_name`, `None` if there is no such file.
+    pub(crate) fn file_size(&self, file_name: String) -> Result<Option<u64>, error::Error> {
+        let path = match &self.storage {
+            Storage::Disk(db_path) => db_path.join(&file_name),
+            Storage::Memory(files) => return Ok(files.lock().unwrap().get(&file_name).map(|data| data.len() as u64))
+        };
+        match std::fs::metadata(&path) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(error::Error::io_error(e, path.display().to_string()))
+        }
+    }
+
+    /// Renames `file_name` to `new_name`, replacing any file already named so.
+    pub(crate) fn rename_file(&self, file_name: String, new_name: String) -> Result<(), error::Error> {
+        let path = match &self.storage {
+            Storage::Disk(db_path) => db_path.join(&file_name),
+            Storage::Memory(files) => {
+                let mut files = files.lock().unwrap();
+                return match files.remove(&file_name) {
+                    Some(data) => {
+                        files.insert(new_name, data);
+                        Ok(())
+                    },
+                    None => Err(error::Error::io_error(not_found(), file_name))
+                }
+            }
+        };
+        std::fs::rename(&path, path.with_file_name(new_name)).or_else(
+            |e| Err(error::Error::io_error(e, path.display().to_string()))
+        )
+    }
+
+    /// Reads `len` bytes at `offset` of a table file again and checks them against the crc they
+    /// had when the table was parsed, rather than trusting the disk to return the same bytes.
+    pub(crate) fn read_verified(&self,
+                                file_name: String,
+                                offset: u64,
+                                len: usize,
+                                expected_crc: u32) -> Result<Vec<u8>, error::Error> {
+        let data = self.acquire_quota().read_range(file_name, offset, len)?;
+        if crc32::checksum_ieee(&data) != expected_crc {
+            return Err(error::Error::sc_table_corrupt("incorrect block crc".into()))
+        }
+        Ok(data)
+    }
+
+    pub fn acquire_quota(&self) -> FileQuota<'_> {
         self.sem.acquire();
         FileQuota(self)
     }
@@ -73,3 +328,100 @@ impl IOManager {
         self.sem.release()
     }
 }
+
+/// Where `write_atomic` writes a file before renaming it into place.
+fn temp_file_path(file_name: &Path) -> PathBuf {
+    let mut temp_file_name = file_name.as_os_str().to_os_string();
+    temp_file_name.push(TEMP_FILE_SUFFIX);
+    PathBuf::from(temp_file_name)
+}
+
+fn not_found() -> std::io::Error {
+    std::io::ErrorKind::NotFound.into()
+}
+
+#[cfg(test)]
+mod test {
+    use crc::crc32;
+
+    use crate::error::Error;
+    use crate::io::IOManager;
+    use crate::testutil::TestDir;
+
+    #[test]
+    fn test_in_memory() {
+        let io_manager = IOManager::in_memory(4);
+        assert!(!io_manager.is_persistent());
+        let data: Vec<u8> = (0..=255u8).collect();
+        io_manager.acquire_quota().write_file("block".to_string(), &data).unwrap();
+        assert_eq!(io_manager.acquire_quota().read_file("block".to_string()).unwrap(), data);
+        assert_eq!(io_manager.acquire_quota().read_range("block".to_string(), 16, 64).unwrap(), data[16..80].to_vec());
+        assert!(io_manager.acquire_quota().read_range("block".to_string(), 200, 64).is_err());
+        io_manager.acquire_quota().write_atomic("block".to_string(), b"replaced").unwrap();
+        assert_eq!(io_manager.acquire_quota().read_file("block".to_string()).unwrap(), b"replaced".to_vec());
+        assert_eq!(io_manager.list_files().unwrap(), vec!["block".to_string()]);
+        assert!(io_manager.create_log_file("0001.log".to_string()).is_err());
+
+        io_manager.remove_file("block".to_string()).unwrap();
+        assert!(io_manager.list_files().unwrap().is_empty());
+        match io_manager.acquire_quota().read_file("block".to_string()) {
+            Err(Error::IOError { file, .. }) => assert_eq!(file, Some("block".to_string())),
+            _ => panic!("a removed file should be gone")
+        }
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = TestDir::new("io_write_atomic");
+        let io_manager = IOManager::new(dir.path(), 4);
+        let data: Vec<u8> = (0..=255u8).cycle().take(10000).collect();
+        io_manager.acquire_quota().write_atomic("00000001.sct".to_string(), &data).unwrap();
+        assert_eq!(io_manager.list_files().unwrap(), vec!["00000001.sct".to_string()]);
+        assert_eq!(std::fs::read(dir.path().join("00000001.sct")).unwrap(), data);
+
+        io_manager.acquire_quota().write_atomic("00000001.sct".to_string(), b"replaced").unwrap();
+        assert_eq!(io_manager.list_files().unwrap(), vec!["00000001.sct".to_string()]);
+        assert_eq!(std::fs::read(dir.path().join("00000001.sct")).unwrap(), b"replaced".to_vec());
+    }
+
+    #[test]
+    fn test_write_range() {
+        let dir = TestDir::new("io_write_range");
+        for io_manager in [IOManager::new(dir.path(), 4), IOManager::in_memory(4)] {
+            let mut data: Vec<u8> = (0..=255u8).collect();
+            io_manager.acquire_quota().write_file("block".to_string(), &data).unwrap();
+            io_manager.acquire_quota().write_range("block".to_string(), 16, b"replaced").unwrap();
+            data[16..24].copy_from_slice(b"replaced");
+            assert_eq!(io_manager.acquire_quota().read_file("block".to_string()).unwrap(), data);
+            // it never grows the file
+            assert!(io_manager.acquire_quota().write_range("block".to_string(), 250, b"replaced").is_err());
+            assert_eq!(io_manager.acquire_quota().read_file("block".to_string()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_read_verified() {
+        let dir = TestDir::new("io_read_verified");
+        let io_manager = IOManager::new(dir.path(), 4);
+        let data: Vec<u8> = (0..=255u8).collect();
+        io_manager.acquire_quota().write_file("block".to_string(), &data).unwrap();
+        let crc = crc32::checksum_ieee(&data[16..80]);
+
+        assert_eq!(io_manager.read_verified("block".to_string(), 16, 64, crc).unwrap(), data[16..80].to_vec());
+        match io_manager.read_verified("block".to_string(), 200, 64, crc) {
+            Err(Error::IOError { file, .. }) => assert_eq!(file, Some(dir.path().join("block").display().to_string())),
+            _ => panic!("a range beyond the end of the file should fail")
+        }
+
+        let mut flipped = data.clone();
+        flipped[40] ^= 0xff;
+        io_manager.acquire_quota().write_file("block".to_string(), &flipped).unwrap();
+        match io_manager.read_verified("block".to_string(), 16, 64, crc) {
+            Err(Error::ScTableCorrupt { .. }) => (),
+            _ => panic!("flipped bytes should fail the crc")
+        }
+        // bytes outside the range do not matter
+        let crc = crc32::checksum_ieee(&data[64..128]);
+        assert_eq!(io_manager.read_verified("block".to_string(), 64, 64, crc).unwrap(), data[64..128].to_vec());
+    }
+}
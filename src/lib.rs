@@ -3,39 +3,389 @@
 #![feature(map_first_last)]
 
 use std::cmp::Ordering;
-use std::marker::PhantomData;
-use std::collections::VecDeque;
-use std::sync::atomic::AtomicU64;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
 
 mod encode;
 mod error;
 mod table;
 mod partition;
 mod io;
+mod db;
+mod background;
+mod wal;
+mod manifest;
+#[cfg(test)]
+mod testutil;
 
 pub use table::tablefmt;
+pub use table::inspect::{inspect_table, TableInfo};
+pub use error::{Error, ErrorStr};
+pub use db::{Cursor, Db, DbIterator, DefaultDb, DbKeyIterator, PartitionInfo, PinnedValue, Snapshot, StatsSnapshot, WriteBatch, WriteOptions};
+
+/// Orders the user keys of a database. A comparator may carry state of its own, the instance given
+/// to `Db::open` is shared by every key of that database. Only keys with the same bytes may compare
+/// equal, since table filters are built over the raw bytes.
+pub trait Comparator: Send + Sync {
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering;
+
+    /// A key as short as possible ordered strictly after `key`, for bounds and separators which need
+    /// not be keys of the database. `None` if no key is ordered after `key`. The default is right
+    /// for bytewise orders only, comparators ordering keys otherwise have to override it.
+    fn successor(&self, key: &[u8]) -> Option<Vec<u8>> {
+        Some(short_successor(key))
+    }
+
+    /// A key as short as possible in `[lhs, rhs)`, to bound a table ending with `lhs` from the next
+    /// one starting with `rhs`. `lhs` itself if `rhs` is not after it. The default is right for
+    /// bytewise orders only, like the one of `successor`.
+    fn separator(&self, lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
+        short_separator(lhs, rhs)
+    }
+}
+
+/// The shortest key in `[lhs, rhs)` in bytewise order, `lhs` if there is none. Keys shorter than
+/// the first byte they differ at cannot be in between, so the separator is `lhs` cut right after
+/// it, with that byte raised if `lhs` goes on. Otherwise the shortest one within the rest of `lhs`.
+fn short_separator(lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
+    let diff = match lhs.iter().zip(rhs.iter()).position(|(lhs, rhs)| lhs != rhs) {
+        Some(diff) if lhs[diff] < rhs[diff] => diff,
+        // `lhs` is a prefix of `rhs`, so nothing shorter is in between, or `rhs` is not after `lhs`
+        _ => return lhs.to_vec()
+    };
+    if lhs.len() == diff + 1 {
+        lhs.to_vec()
+    } else if lhs[diff] + 1 < rhs[diff] {
+        let mut ret = lhs[..=diff].to_vec();
+        ret[diff] += 1;
+        ret
+    } else if rhs.len() > diff + 1 {
+        rhs[..=diff].to_vec()
+    } else {
+        // only keys starting like `lhs` up to `diff` are left, they are all below `rhs`
+        let mut ret = lhs[..=diff].to_vec();
+        ret.extend_from_slice(&shortest_from(&lhs[diff + 1..]));
+        ret
+    }
+}
+
+/// The shortest key not below `key` in bytewise order.
+fn shortest_from(key: &[u8]) -> Vec<u8> {
+    let successor = short_successor(key);
+    if successor.len() < key.len() { successor } else { key.to_vec() }
+}
 
-pub trait Comparator {
-    fn compare(lhs: &[u8], rhs: &[u8]) -> Ordering;
+/// The shortest key above `key` in bytewise order: `key` up to its first byte which is not 0xFF,
+/// with that byte incremented. A key of 0xFF bytes only has a 0 appended instead.
+fn short_successor(key: &[u8]) -> Vec<u8> {
+    match key.iter().position(|&byte| byte != 0xFF) {
+        Some(first) => {
+            let mut ret = key[..=first].to_vec();
+            ret[first] += 1;
+            ret
+        },
+        None => {
+            let mut ret = key.to_vec();
+            ret.push(0);
+            ret
+        }
+    }
 }
 
+/// Bytewise order.
+#[derive(Default, Clone, Copy)]
 pub struct DefaultComparator();
 
 impl Comparator for DefaultComparator {
-    fn compare(lhs: &[u8], rhs: &[u8]) -> Ordering {
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
         lhs.cmp(rhs)
     }
 }
 
+/// Reversed bytewise order.
+#[derive(Default, Clone, Copy)]
+pub struct ReverseComparator();
+
+impl Comparator for ReverseComparator {
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        rhs.cmp(lhs)
+    }
+
+    /// The empty key comes after every other key, and nothing after it.
+    fn successor(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if key.is_empty() { None } else { Some(Vec::new()) }
+    }
+
+    /// `lhs` cut right after the first byte it differs from `rhs` at, which is above `rhs` in
+    /// bytewise order and not above `lhs`.
+    fn separator(&self, lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
+        match lhs.iter().zip(rhs.iter()).position(|(lhs, rhs)| lhs != rhs) {
+            Some(diff) if lhs[diff] > rhs[diff] => lhs[..=diff].to_vec(),
+            None if lhs.len() > rhs.len() => lhs[..=rhs.len()].to_vec(),
+            _ => lhs.to_vec()
+        }
+    }
+}
+
+/// Orders keys by their first 8 bytes read as a big-endian `u64`, then by the remaining bytes.
+/// Keys shorter than 8 bytes come first, in bytewise order.
+#[derive(Default, Clone, Copy)]
+pub struct BigEndianU64Comparator();
+
+impl Comparator for BigEndianU64Comparator {
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        match (lhs.len() >= 8, rhs.len() >= 8) {
+            (true, true) => encode::decode_fixed64(&lhs[..8]).cmp(&encode::decode_fixed64(&rhs[..8]))
+                .then_with(|| lhs[8..].cmp(&rhs[8..])),
+            (false, true) => Ordering::Less,
+            (true, false) => Ordering::Greater,
+            (false, false) => lhs.cmp(rhs)
+        }
+    }
+
+    /// The next number on its own if there is one, otherwise the bytewise successor of the rest.
+    /// Short keys stay short as long as they can.
+    fn successor(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if key.len() < 8 {
+            let ret = short_successor(key);
+            return Some(if ret.len() < 8 { ret } else { vec![0; 8] })
+        }
+        match encode::decode_fixed64(&key[..8]).checked_add(1) {
+            Some(next) => Some(encode::encode_fixed64_ret(next).to_vec()),
+            None => {
+                let mut ret = key[..8].to_vec();
+                ret.extend_from_slice(&short_successor(&key[8..]));
+                Some(ret)
+            }
+        }
+    }
+
+    /// Between two numbers the next number after `lhs` on its own if it is below `rhs`, otherwise
+    /// the number of `lhs` with the shortest rest not below its own. A short `lhs` before a long
+    /// `rhs` is followed by short keys only.
+    fn separator(&self, lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
+        if self.compare(lhs, rhs) != Ordering::Less {
+            return lhs.to_vec()
+        }
+        match (lhs.len() >= 8, rhs.len() >= 8) {
+            (false, false) => short_separator(lhs, rhs),
+            (false, true) => shortest_from(lhs),
+            (true, true) if lhs[..8] == rhs[..8] => [&lhs[..8], &short_separator(&lhs[8..], &rhs[8..])[..]].concat(),
+            (true, true) => {
+                let next = encode::decode_fixed64(&lhs[..8]) + 1;
+                let next_key = encode::encode_fixed64_ret(next);
+                if lhs.len() == 8 {
+                    lhs.to_vec()
+                } else if self.compare(&next_key, rhs) == Ordering::Less {
+                    next_key.to_vec()
+                } else {
+                    [&lhs[..8], &shortest_from(&lhs[8..])[..]].concat()
+                }
+            },
+            // a long key is never before a short one
+            (true, false) => unreachable!()
+        }
+    }
+}
+
+/// When appends to the write-ahead log are synced to disk. Writes not synced yet may be lost if
+/// the machine crashes, a crash of the process alone loses nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every write.
+    Sync,
+    /// Leave it to the operating system.
+    NoSync,
+    /// Sync after every `n` writes.
+    EveryN(usize)
+}
+
+/// How the data region of tables is compressed. Tables already written stay as they are, so this
+/// may change between runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4
+}
+
+/// How the memtables of partitions are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemTableKind {
+    BTree,
+    /// A skiplist writers insert into after unlocking their partition, so they exclude each other
+    /// only while they are numbered and logged, not while they insert. Reads and flushes wait for
+    /// the inserts going on. Versions hidden by newer ones stay until the memtable is flushed.
+    SkipList
+}
+
+/// How much of a table the cache keeps in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableLoading {
+    /// Catalog, keys and values, lookups never touch the disk once a table is cached.
+    Whole,
+    /// Catalog and keys only, a lookup reads just the value it finds from disk. Scans and
+    /// compactions read the tables they need whole, without caching them. Compressed tables are
+    /// always kept whole.
+    IndexOnly
+}
+
+/// How keys are spread over partitions. A database has to be opened with the partitioning it was
+/// created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Partitioning {
+    /// Every partition holds a range of keys and is split in two once it grows beyond
+    /// `Options::partition_size`.
+    Range,
+    /// A fixed number of partitions, a key goes to the one its hash modulo `partitions` picks,
+    /// which spreads writes evenly however the keys are distributed. Partitions are never split.
+    /// Their keys overlap, so scans and key iterators see the keys of one partition after the
+    /// other, each in comparator order, rather than in one global order. `Db::cursor` and
+    /// `Db::ingest_file` are not supported.
+    Hash { partitions: usize }
+}
+
+/// How the crcs of tables are computed. Every table records its own, so this may change between
+/// runs as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// CRC-32 as in zlib.
+    Ieee,
+    /// CRC-32C, which recent CPUs compute in hardware.
+    Castagnoli
+}
+
+impl Checksum {
+    pub(crate) fn checksum(self, data: &[u8]) -> u32 {
+        match self {
+            Checksum::Ieee => crc::crc32::checksum_ieee(data),
+            Checksum::Castagnoli => crc::crc32::checksum_castagnoli(data)
+        }
+    }
+}
+
+/// What a `CompactionFilter` wants done with an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    /// Drop the entry without leaving a tombstone, an older version of the key in a level below the
+    /// compaction output becomes visible again.
+    Remove,
+    ChangeValue(Vec<u8>)
+}
+
+/// Decides about the values compaction carries into the next level, e.g. to expire them. Only
+/// versions every snapshot already sees are given to the filter, tombstones never are.
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &[u8], value: &[u8]) -> FilterDecision;
+}
+
+impl fmt::Debug for dyn CompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactionFilter")
+    }
+}
+
+/// Folds the operands `Db::merge` recorded for a key over the value below them, so updates like
+/// increments need no read before the write. A database holding operands must always be opened
+/// with an operator folding them the same way, like it must with the same comparator.
+pub trait MergeOperator: Send + Sync {
+    /// The value of `key` once `operands`, oldest first, are applied to `existing`. `existing` is
+    /// `None` if the key had no value below the operands, or a tombstone.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8>;
+}
+
+impl fmt::Debug for dyn MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+/// Adds up `u64`s stored as 8 big-endian bytes, wrapping around on overflow. A missing value
+/// counts as 0, as does a value or operand of any other length.
+#[derive(Default, Clone, Copy)]
+pub struct U64AddMergeOperator();
+
+impl MergeOperator for U64AddMergeOperator {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+        let decode = |bytes: &[u8]| if bytes.len() == 8 { encode::decode_fixed64(bytes) } else { 0 };
+        let sum = operands.iter()
+            .fold(existing.map_or(0, decode), |sum, operand| sum.wrapping_add(decode(operand)));
+        encode::encode_fixed64_ret(sum).to_vec()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Options {
     pub db_name: String,
     pub cache_count: usize,
+    /// Tables level 0 may hold before it is compacted into level 1.
     pub level0_size: usize,
+    /// Tables in level 0 at which every write sleeps a millisecond first, so compaction catches
+    /// up before writes have to stop.
+    pub level0_slowdown_writes_trigger: usize,
+    /// Tables in level 0 at which writes wait until compaction has taken some of them away.
+    pub level0_stop_writes_trigger: usize,
+    /// How many times more bytes a level below level 1 holds than the level above it.
     pub size_factor: usize,
+    /// Bytes level 1 may hold before it is compacted into level 2.
+    pub level1_bytes: usize,
+    /// Levels of a partition, the last one is never compacted any further.
+    pub max_levels: usize,
     pub max_open_files: usize,
     pub table_size: usize,
+    /// Writes of a larger key are refused with `Error::InvalidArgument`.
     pub key_size_max: usize,
+    /// Writes of a larger value are refused with `Error::InvalidArgument`.
     pub value_size_max: usize,
+    pub background_threads: usize,
+    /// False positive rate of the bloom filter built into every table, filters are left out if
+    /// it is not within `(0, 1)`.
+    pub bloom_false_positive_rate: f64,
+    /// A partition whose tables take more than this many bytes on disk is split in two.
+    pub partition_size: usize,
+    pub partitioning: Partitioning,
+    /// Default of writes which do not pick a policy of their own.
+    pub sync_policy: SyncPolicy,
+    /// Compression of tables written from now on.
+    pub compression: Compression,
+    /// Checksum of tables written from now on.
+    pub checksum: Checksum,
+    /// Tables store every this many keys in full, the keys between only store the part they do
+    /// not share with the key before them.
+    pub restart_interval: usize,
+    /// Consulted for every entry compaction writes, flushes leave entries as they are.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// What `Db::open` does about a corrupt table: fail if set, otherwise rename it to
+    /// `<table>.corrupt` and open without it, losing the keys it held.
+    pub paranoid_checks: bool,
+    /// A table which this many lookups have searched in vain is compacted into the level below
+    /// even if its level is not full, so keys are not looked for in many tables over and over. 0,
+    /// the default, compacts by size only.
+    pub read_compaction_threshold: usize,
+    /// Reads every table back in memory before writing it, so corruption in memory is reported
+    /// rather than written to disk. Costs a pass over every table written.
+    pub verify_on_write: bool,
+    /// Memtables created from now on are kept this way.
+    pub memtable_kind: MemTableKind,
+    /// Bytes per second compactions write their tables at, on average. 0 for no limit. Flushes
+    /// and the log are never held back, so writes do not wait on it.
+    pub compaction_rate_limit: usize,
+    /// What the table cache keeps of the tables it holds, `cache_count` of them.
+    pub table_loading: TableLoading,
+    /// Bytes the values lookups read from tables cached without them may take in memory, 0 reads
+    /// them from disk every time. Unused unless `table_loading` is `TableLoading::IndexOnly`.
+    pub block_cache_bytes: usize,
+    /// A log segment which has grown to this many bytes is synced and closed, the writes after it
+    /// go to a new one. A memtable may thus be logged to several segments, all removed once it is
+    /// flushed.
+    pub log_segment_size: usize,
+    /// A memtable no write has reached for this long is flushed even if it is far from full, so a
+    /// partition which stops being written to does not keep its last writes in the log only.
+    /// Memtables are checked every half of it. `None` flushes full memtables only.
+    pub memtable_idle_timeout: Option<Duration>,
+    /// Folds the operands of `Db::merge`, which refuses them without one.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
 }
 
 impl Options {
@@ -51,52 +401,447 @@ impl Options {
             db_name: db_name.to_string(),
             cache_count,
             level0_size,
+            level0_slowdown_writes_trigger: 20,
+            level0_stop_writes_trigger: 36,
             size_factor,
+            level1_bytes: 10 * 1024 * 1024,
+            max_levels: 7,
             max_open_files,
             table_size,
             key_size_max,
             value_size_max,
+            background_threads: 1,
+            bloom_false_positive_rate: 0.01,
+            partition_size: 64 * 1024 * 1024,
+            partitioning: Partitioning::Range,
+            sync_policy: SyncPolicy::NoSync,
+            compression: Compression::None,
+            checksum: Checksum::Ieee,
+            restart_interval: table::builder::DEFAULT_RESTART_INTERVAL,
+            compaction_filter: None,
+            paranoid_checks: true,
+            read_compaction_threshold: 0,
+            verify_on_write: false,
+            memtable_kind: MemTableKind::BTree,
+            compaction_rate_limit: 0,
+            table_loading: TableLoading::Whole,
+            block_cache_bytes: 8 * 1024 * 1024,
+            log_segment_size: 4 * 1024 * 1024,
+            memtable_idle_timeout: None,
+            merge_operator: None,
         }
     }
 
-    fn level_size(&self, level: usize) -> usize {
-        self.level0_size * self.size_factor.pow(level as u32)
+    /// Checks that the options make sense together, `Db::open` refuses options which do not.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |reason: String| Err(Error::invalid_options(reason.into()));
+        if self.cache_count == 0 {
+            return invalid("cache_count must be nonzero".to_string())
+        }
+        if self.max_open_files == 0 {
+            return invalid("max_open_files must be nonzero".to_string())
+        }
+        if self.background_threads == 0 {
+            return invalid("background_threads must be nonzero".to_string())
+        }
+        if self.level0_size == 0 {
+            return invalid("level0_size must be nonzero".to_string())
+        }
+        if self.level0_stop_writes_trigger <= self.level0_size {
+            return invalid(format!("level0_stop_writes_trigger must be above level0_size ({}), got {}",
+                                   self.level0_size, self.level0_stop_writes_trigger))
+        }
+        if self.level0_slowdown_writes_trigger > self.level0_stop_writes_trigger {
+            return invalid(format!("level0_slowdown_writes_trigger must be at most level0_stop_writes_trigger ({}), got {}",
+                                   self.level0_stop_writes_trigger, self.level0_slowdown_writes_trigger))
+        }
+        if self.size_factor < 2 {
+            return invalid(format!("size_factor must be at least 2, got {}", self.size_factor))
+        }
+        if self.level1_bytes == 0 {
+            return invalid("level1_bytes must be nonzero".to_string())
+        }
+        if self.max_levels < 2 {
+            return invalid(format!("max_levels must be at least 2, got {}", self.max_levels))
+        }
+        if self.table_size < tablefmt::TABLE_MIN_SIZE {
+            return invalid(format!("table_size must be at least {} (TABLE_MIN_SIZE), got {}",
+                                   tablefmt::TABLE_MIN_SIZE, self.table_size))
+        }
+        if self.partition_size < self.table_size {
+            return invalid(format!("partition_size must be at least table_size ({}), got {}",
+                                   self.table_size, self.partition_size))
+        }
+        if self.partitioning == (Partitioning::Hash { partitions: 0 }) {
+            return invalid("Partitioning::Hash needs a nonzero number of partitions".to_string())
+        }
+        if self.restart_interval == 0 {
+            return invalid("restart_interval must be nonzero".to_string())
+        }
+        if self.sync_policy == SyncPolicy::EveryN(0) {
+            return invalid("SyncPolicy::EveryN needs a nonzero count".to_string())
+        }
+        if self.memtable_idle_timeout == Some(Duration::from_secs(0)) {
+            return invalid("memtable_idle_timeout must be nonzero".to_string())
+        }
+        Ok(())
     }
-}
 
-use crate::io::IOManager;
-use crate::table::cache::TableCacheManager;
-use crate::partition::ArcPartition;
+    pub fn with_db_name(mut self, db_name: impl ToString) -> Self {
+        self.db_name = db_name.to_string();
+        self
+    }
 
-pub struct ScottDB<'a, Comp: 'static + Comparator> {
-    phantom: PhantomData<Comp>,
+    pub fn with_cache_count(mut self, cache_count: usize) -> Self {
+        self.cache_count = cache_count;
+        self
+    }
 
-    options: Options,
-    seq: AtomicU64,
-    partitions: VecDeque<ArcPartition<'a, Comp>>,
-    cache_manager: TableCacheManager,
-    io_manager: IOManager,
-}
+    pub fn with_level0_size(mut self, level0_size: usize) -> Self {
+        self.level0_size = level0_size;
+        self
+    }
 
-impl<'a, Comp: 'static + Comparator> ScottDB<'a, Comp> {
-    pub fn new(options: Options) -> Self {
-        let cache_count = options.cache_count;
-        let max_open_files = options.max_open_files;
-        Self {
-            phantom: PhantomData,
-            options,
-            seq: AtomicU64::new(0),
-            partitions: VecDeque::new(),
-            cache_manager: TableCacheManager::new(cache_count),
-            io_manager: IOManager::new(max_open_files),
+    pub fn with_level0_slowdown_writes_trigger(mut self, level0_slowdown_writes_trigger: usize) -> Self {
+        self.level0_slowdown_writes_trigger = level0_slowdown_writes_trigger;
+        self
+    }
+
+    pub fn with_level0_stop_writes_trigger(mut self, level0_stop_writes_trigger: usize) -> Self {
+        self.level0_stop_writes_trigger = level0_stop_writes_trigger;
+        self
+    }
+
+    pub fn with_size_factor(mut self, size_factor: usize) -> Self {
+        self.size_factor = size_factor;
+        self
+    }
+
+    pub fn with_level1_bytes(mut self, level1_bytes: usize) -> Self {
+        self.level1_bytes = level1_bytes;
+        self
+    }
+
+    pub fn with_max_levels(mut self, max_levels: usize) -> Self {
+        self.max_levels = max_levels;
+        self
+    }
+
+    pub fn with_max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    pub fn with_table_size(mut self, table_size: usize) -> Self {
+        self.table_size = table_size;
+        self
+    }
+
+    pub fn with_key_size_max(mut self, key_size_max: usize) -> Self {
+        self.key_size_max = key_size_max;
+        self
+    }
+
+    pub fn with_value_size_max(mut self, value_size_max: usize) -> Self {
+        self.value_size_max = value_size_max;
+        self
+    }
+
+    pub fn with_background_threads(mut self, background_threads: usize) -> Self {
+        self.background_threads = background_threads;
+        self
+    }
+
+    pub fn with_bloom_false_positive_rate(mut self, bloom_false_positive_rate: f64) -> Self {
+        self.bloom_false_positive_rate = bloom_false_positive_rate;
+        self
+    }
+
+    pub fn with_partition_size(mut self, partition_size: usize) -> Self {
+        self.partition_size = partition_size;
+        self
+    }
+
+    pub fn with_partitioning(mut self, partitioning: Partitioning) -> Self {
+        self.partitioning = partitioning;
+        self
+    }
+
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn with_restart_interval(mut self, restart_interval: usize) -> Self {
+        self.restart_interval = restart_interval;
+        self
+    }
+
+    pub fn with_compaction_filter(mut self, compaction_filter: impl CompactionFilter + 'static) -> Self {
+        self.compaction_filter = Some(Arc::new(compaction_filter));
+        self
+    }
+
+    pub fn with_paranoid_checks(mut self, paranoid_checks: bool) -> Self {
+        self.paranoid_checks = paranoid_checks;
+        self
+    }
+
+    pub fn with_read_compaction_threshold(mut self, read_compaction_threshold: usize) -> Self {
+        self.read_compaction_threshold = read_compaction_threshold;
+        self
+    }
+
+    pub fn with_verify_on_write(mut self, verify_on_write: bool) -> Self {
+        self.verify_on_write = verify_on_write;
+        self
+    }
+
+    pub fn with_memtable_kind(mut self, memtable_kind: MemTableKind) -> Self {
+        self.memtable_kind = memtable_kind;
+        self
+    }
+
+    pub fn with_compaction_rate_limit(mut self, compaction_rate_limit: usize) -> Self {
+        self.compaction_rate_limit = compaction_rate_limit;
+        self
+    }
+
+    pub fn with_table_loading(mut self, table_loading: TableLoading) -> Self {
+        self.table_loading = table_loading;
+        self
+    }
+
+    pub fn with_block_cache_bytes(mut self, block_cache_bytes: usize) -> Self {
+        self.block_cache_bytes = block_cache_bytes;
+        self
+    }
+
+    pub fn with_log_segment_size(mut self, log_segment_size: usize) -> Self {
+        self.log_segment_size = log_segment_size;
+        self
+    }
+
+    pub fn with_memtable_idle_timeout(mut self, memtable_idle_timeout: Option<Duration>) -> Self {
+        self.memtable_idle_timeout = memtable_idle_timeout;
+        self
+    }
+
+    pub fn with_merge_operator(mut self, merge_operator: impl MergeOperator + 'static) -> Self {
+        self.merge_operator = Some(Arc::new(merge_operator));
+        self
+    }
+
+    /// What `level` may hold before it is compacted into the next one: a number of tables for
+    /// level 0, whose tables overlap and all have to be read, bytes for the levels below it.
+    fn level_size(&self, level: usize) -> usize {
+        if level == 0 {
+            return self.level0_size
         }
+        (1..level).fold(self.level1_bytes, |size, _| size.saturating_mul(self.size_factor))
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new("scottdb", 32, 4, 10, 64, 2 * 1024 * 1024, 4096, 1024 * 1024)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cmp::Ordering;
+    use std::time::Duration;
+
+    use crate::{Options, Partitioning, SyncPolicy, Error, Db, Comparator, DefaultComparator, ReverseComparator, BigEndianU64Comparator};
+    use crate::encode::encode_fixed64_ret;
+    use crate::tablefmt::TABLE_MIN_SIZE;
+    use crate::testutil::TestDir;
+
+    /// Every key of up to `max_len` bytes drawn from a few bytes around the edges.
+    fn small_keys(max_len: usize) -> Vec<Vec<u8>> {
+        let mut ret = vec![Vec::new()];
+        let mut last = vec![Vec::new()];
+        for _ in 0..max_len {
+            last = last.iter()
+                .flat_map(|key: &Vec<u8>| [0x00, 0x01, 0x7F, 0xFE, 0xFF].iter().map(move |&byte| {
+                    let mut key = key.clone();
+                    key.push(byte);
+                    key
+                }))
+                .collect();
+            ret.extend(last.iter().cloned());
+        }
+        ret
+    }
+
+    /// The successor of every key is after it, and no shorter key is.
+    fn check_successors(comparator: &impl Comparator, keys: &[Vec<u8>]) {
+        for key in keys {
+            let successor = match comparator.successor(key) {
+                Some(successor) => successor,
+                None => {
+                    assert!(keys.iter().all(|other| comparator.compare(other, key) != Ordering::Greater));
+                    continue
+                }
+            };
+            assert_eq!(comparator.compare(&successor, key), Ordering::Greater, "{:?} -> {:?}", key, successor);
+            assert!(keys.iter()
+                        .filter(|other| other.len() < successor.len())
+                        .all(|other| comparator.compare(other, key) != Ordering::Greater),
+                    "a key shorter than {:?} is after {:?}", successor, key);
+        }
+    }
+
+    /// The separator of every pair of keys is between them, and no shorter key is.
+    fn check_separators(comparator: &impl Comparator, keys: &[Vec<u8>]) {
+        for lhs in keys {
+            for rhs in keys.iter().filter(|rhs| comparator.compare(lhs, rhs) == Ordering::Less) {
+                let separator = comparator.separator(lhs, rhs);
+                assert_ne!(comparator.compare(&separator, lhs), Ordering::Less, "{:?} {:?} -> {:?}", lhs, rhs, separator);
+                assert_eq!(comparator.compare(&separator, rhs), Ordering::Less, "{:?} {:?} -> {:?}", lhs, rhs, separator);
+                assert!(keys.iter()
+                            .filter(|other| other.len() < separator.len())
+                            .all(|other| comparator.compare(other, lhs) == Ordering::Less
+                                         || comparator.compare(other, rhs) != Ordering::Less),
+                        "a key shorter than {:?} is between {:?} and {:?}", separator, lhs, rhs);
+            }
+        }
+    }
+
+    #[test]
+    fn test_separator() {
+        let comparator = DefaultComparator();
+        assert_eq!(comparator.separator(b"the quick brown fox", b"the who"), b"the r".to_vec());
+        assert_eq!(comparator.separator(b"key019", b"key020"), b"key02".to_vec());
+        assert_eq!(comparator.separator(b"key012", b"key013"), b"key012".to_vec());
+        assert_eq!(comparator.separator(b"abc1\xff\xff\x05", b"abd"), b"abc2".to_vec());
+        assert_eq!(comparator.separator(&[b'a', b'b', b'c', 0xFF, 0xFF, 0x05], b"abd"), vec![b'a', b'b', b'c', 0xFF, 0xFF, 0x05]);
+        assert_eq!(comparator.separator(b"abc", b"abcdef"), b"abc".to_vec());
+        // not before `lhs`
+        assert_eq!(comparator.separator(b"b", b"a"), b"b".to_vec());
+        assert_eq!(ReverseComparator().separator(b"the who", b"the quick brown fox"), b"the w".to_vec());
+
+        let keys = small_keys(3);
+        check_separators(&DefaultComparator(), &keys);
+        check_separators(&ReverseComparator(), &keys);
+
+        let comparator = BigEndianU64Comparator();
+        let mut keys = small_keys(2);
+        for &number in [0, 1, 2, 255, 256, u64::max_value()].iter() {
+            for rest in small_keys(2) {
+                keys.push([&encode_fixed64_ret(number)[..], &rest[..]].concat());
+            }
+        }
+        check_separators(&comparator, &keys);
+        let key = |number: u64, rest: &[u8]| [&encode_fixed64_ret(number)[..], rest].concat();
+        assert_eq!(comparator.separator(&key(1, b"abc"), &key(5, b"")), key(2, b""));
+        assert_eq!(comparator.separator(&key(1, b"abc"), &key(2, b"")), key(1, b"b"));
+        assert_eq!(comparator.separator(&key(7, b"apple"), &key(7, b"banana")), key(7, b"b"));
+        assert_eq!(comparator.separator(b"zz", &key(0, b"")), b"{".to_vec());
+    }
+
+    #[test]
+    fn test_successor() {
+        assert_eq!(DefaultComparator().successor(b"user:"), Some(b"v".to_vec()));
+        assert_eq!(DefaultComparator().successor(&[0xFF, 0xFF, 0x01, 0x02]), Some(vec![0xFF, 0xFF, 0x02]));
+        assert_eq!(DefaultComparator().successor(&[0xFF, 0xFF]), Some(vec![0xFF, 0xFF, 0x00]));
+        assert_eq!(DefaultComparator().successor(b""), Some(vec![0x00]));
+        assert_eq!(ReverseComparator().successor(b"user:"), Some(Vec::new()));
+        assert_eq!(ReverseComparator().successor(b""), None);
+
+        let keys = small_keys(3);
+        check_successors(&DefaultComparator(), &keys);
+        check_successors(&ReverseComparator(), &keys);
+
+        let comparator = BigEndianU64Comparator();
+        let mut keys = small_keys(2);
+        keys.push(vec![0xFF; 7]);
+        for &number in [0, 1, 255, 256, u64::max_value() - 1, u64::max_value()].iter() {
+            for rest in small_keys(2) {
+                let mut key = encode_fixed64_ret(number).to_vec();
+                key.extend_from_slice(&rest);
+                keys.push(key);
+            }
+        }
+        check_successors(&comparator, &keys);
+        assert_eq!(comparator.successor(&[0xFF; 7]), Some(vec![0; 8]));
+        let max = encode_fixed64_ret(u64::max_value()).to_vec();
+        assert_eq!(comparator.successor(&[&max[..], b"a"].concat()), Some([&max[..], b"b"].concat()));
+        assert_eq!(comparator.successor(&[&encode_fixed64_ret(5)[..], b"abc"].concat()),
+                   Some(encode_fixed64_ret(6).to_vec()));
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn is_invalid(options: Options) -> bool {
+        match options.validate() {
+            Err(Error::InvalidOptions { .. }) => true,
+            _ => false
+        }
+    }
+
+    #[test]
+    fn test_options_builder() {
+        let options = Options::default()
+            .with_db_name("built")
+            .with_table_size(4096)
+            .with_cache_count(8)
+            .with_background_threads(2)
+            .with_sync_policy(SyncPolicy::EveryN(16));
+        assert!(Options::default().validate().is_ok());
+        assert!(options.validate().is_ok());
+        assert_eq!(options.db_name, "built");
+        assert_eq!(options.table_size, 4096);
+        assert_eq!(options.cache_count, 8);
+        assert_eq!(options.background_threads, 2);
+        assert_eq!(options.sync_policy, SyncPolicy::EveryN(16));
+    }
+
+    #[test]
+    fn test_level_size() {
+        let options = Options::default();
+        assert_eq!((options.level0_size, options.size_factor, options.max_levels), (4, 10, 7));
+        assert_eq!(options.level_size(0), 4);
+        assert_eq!(options.level_size(1), 10 * 1024 * 1024);
+        assert_eq!(options.level_size(2), 100 * 1024 * 1024);
+        assert_eq!(options.level_size(3), 1000 * 1024 * 1024);
+        assert_eq!(options.with_level1_bytes(usize::max_value() / 2).level_size(3), usize::max_value());
+    }
+
+    #[test]
+    fn test_options_rejected() {
+        assert!(is_invalid(Options::default().with_cache_count(0)));
+        assert!(is_invalid(Options::default().with_max_open_files(0)));
+        assert!(is_invalid(Options::default().with_background_threads(0)));
+        assert!(is_invalid(Options::default().with_table_size(TABLE_MIN_SIZE - 1)));
+        assert!(!is_invalid(Options::default().with_table_size(TABLE_MIN_SIZE).with_partition_size(TABLE_MIN_SIZE)));
+        assert!(is_invalid(Options::default().with_partition_size(1024)));
+        assert!(is_invalid(Options::default().with_partitioning(Partitioning::Hash { partitions: 0 })));
+        assert!(is_invalid(Options::default().with_size_factor(1)));
+        assert!(is_invalid(Options::default().with_level1_bytes(0)));
+        assert!(is_invalid(Options::default().with_max_levels(1)));
+        assert!(is_invalid(Options::default().with_restart_interval(0)));
+        assert!(is_invalid(Options::default().with_sync_policy(SyncPolicy::EveryN(0))));
+        assert!(is_invalid(Options::default().with_memtable_idle_timeout(Some(Duration::from_secs(0)))));
+        assert!(is_invalid(Options::default().with_level0_stop_writes_trigger(4)));
+        assert!(is_invalid(Options::default().with_level0_slowdown_writes_trigger(37)));
+        assert!(!is_invalid(Options::default().with_level0_slowdown_writes_trigger(36)));
+
+        let dir = TestDir::new("lib_options_rejected");
+        assert!(Db::open(dir.path(), Options::default().with_cache_count(0), DefaultComparator()).is_err());
+    }
 }
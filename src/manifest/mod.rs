@@ -0,0 +1,379 @@
+//! Manifest format
+//! ```raw
+//! +-HEAD---------------------------------------+
+//! | 4byte crc of everything after it           |
+//! | 8byte last sequence number                 |
+//! | 4byte partition count                      |
+//! +-PARTITION, partition count times-----------+
+//! | 4byte partition id                         |
+//! | 1byte has bounds                           |
+//! | lower and upper bound, if it has bounds    |
+//! | 4byte table count                          |
+//! +-TABLE, table count times-------------------+
+//! | 4byte level                                |
+//! | 8byte file number                          |
+//! | 8byte file size                            |
+//! | lower and upper bound                      |
+//! +-RANGE TOMBSTONES---------------------------+
+//! | 4byte range tombstone count                |
+//! +-RANGE TOMBSTONE, count times---------------+
+//! | 4byte partition id                         |
+//! | 8byte sequence number                      |
+//! | 8byte fence                                |
+//! | start and end                              |
+//! +-PARTITIONING-------------------------------+
+//! | 4byte partitions of Partitioning::Hash     |
+//! +--------------------------------------------+
+//! ```
+//!
+//! The last sequence number is at least the one of every entry in the tables, a restart without
+//! logs to replay continues from it. Every bound is a 4byte size followed by the key. Tables of a
+//! level are listed in the order the level keeps them. The manifest is never changed in place: a
+//! new one is written next to it and renamed over it, so a crash leaves either the old or the new
+//! one. The partitioning is 0 for `Partitioning::Range`, a database is only opened with the one it
+//! was created with. Manifests written before the partitioning was recorded end after the range
+//! tombstones, or after the partitions if they are older than range tombstones, and are all from
+//! databases partitioned by range.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crc::crc32;
+
+use crate::Partitioning;
+use crate::encode::{decode_fixed32, decode_fixed64, encode_fixed32_ret, encode_fixed64_ret};
+use crate::error::Error;
+use crate::io::IOManager;
+use crate::table::sctable::ScTableFile;
+
+pub(crate) const MANIFEST_FILE_NAME: &'static str = "MANIFEST";
+
+pub(crate) struct TableRecord {
+    pub(crate) level: u32,
+    pub(crate) table_file: ScTableFile,
+    pub(crate) file_size: usize,
+    pub(crate) lower_bound: Vec<u8>,
+    pub(crate) upper_bound: Vec<u8>
+}
+
+/// A range tombstone of a flushed memtable, see `RangeTombstones`.
+pub(crate) struct RangeTombstoneRecord {
+    pub(crate) seq: u64,
+    pub(crate) fence: u64,
+    pub(crate) start: Vec<u8>,
+    pub(crate) end: Vec<u8>
+}
+
+pub(crate) struct PartitionRecord {
+    pub(crate) partition_id: u32,
+    pub(crate) bounds: Option<(Vec<u8>, Vec<u8>)>,
+    pub(crate) tables: Vec<TableRecord>,
+    pub(crate) range_tombstones: Vec<RangeTombstoneRecord>
+}
+
+/// The tables of every live partition as last written to disk. Partitions only ever replace their
+/// own record, so concurrent flushes and compactions of different partitions do not step on each
+/// other.
+pub(crate) struct Manifest {
+    partitions: Mutex<BTreeMap<u32, PartitionRecord>>,
+    /// Written with every record, so a database is not opened with another one.
+    partitioning: Partitioning
+}
+
+impl Manifest {
+    pub(crate) fn new(partitioning: Partitioning) -> Self {
+        Self { partitions: Mutex::new(BTreeMap::new()), partitioning }
+    }
+
+    /// The last sequence number, the partitioning and the records of the manifest on disk, or
+    /// `None` if the database has none yet.
+    pub(crate) fn load(io_manager: &IOManager) -> Result<Option<(u64, Partitioning, Vec<PartitionRecord>)>, Error> {
+        if !io_manager.list_files()?.iter().any(|file_name| file_name == MANIFEST_FILE_NAME) {
+            return Ok(None)
+        }
+        let raw = io_manager.acquire_quota().read_file(MANIFEST_FILE_NAME.to_string())?;
+        decode_manifest(&raw).map(Some)
+    }
+
+    /// Makes `records` the live partitions and writes them out. Every write takes `last_seq`, the
+    /// sequence number of the database at the time.
+    pub(crate) fn reset(&self,
+                        records: Vec<PartitionRecord>,
+                        last_seq: u64,
+                        io_manager: &IOManager) -> Result<(), Error> {
+        let mut partitions = self.partitions.lock().unwrap();
+        *partitions = records.into_iter().map(|record| (record.partition_id, record)).collect();
+        self.write(&partitions, last_seq, io_manager)
+    }
+
+    /// Replaces the record of a live partition. A partition split away in the meantime is not
+    /// brought back, its record is dropped.
+    pub(crate) fn update(&self, record: PartitionRecord, last_seq: u64, io_manager: &IOManager) -> Result<(), Error> {
+        let mut partitions = self.partitions.lock().unwrap();
+        if !partitions.contains_key(&record.partition_id) {
+            return Ok(())
+        }
+        partitions.insert(record.partition_id, record);
+        self.write(&partitions, last_seq, io_manager)
+    }
+
+    /// Replaces the records of several live partitions in a single write, as `update` does one. If
+    /// the write fails, the records already in the manifest stay the live ones.
+    pub(crate) fn update_all(&self, records: Vec<PartitionRecord>, last_seq: u64, io_manager: &IOManager) -> Result<(), Error> {
+        let mut partitions = self.partitions.lock().unwrap();
+        let mut replaced = Vec::new();
+        for record in records {
+            if let Some(live) = partitions.get_mut(&record.partition_id) {
+                replaced.push(std::mem::replace(live, record));
+            }
+        }
+        let result = self.write(&partitions, last_seq, io_manager);
+        if result.is_err() {
+            for record in replaced {
+                partitions.insert(record.partition_id, record);
+            }
+        }
+        result
+    }
+
+    /// Replaces partition `partition_id` by the partitions it was split into, in a single write.
+    pub(crate) fn replace(&self,
+                          partition_id: u32,
+                          records: Vec<PartitionRecord>,
+                          last_seq: u64,
+                          io_manager: &IOManager) -> Result<(), Error> {
+        let mut partitions = self.partitions.lock().unwrap();
+        partitions.remove(&partition_id);
+        for record in records {
+            partitions.insert(record.partition_id, record);
+        }
+        self.write(&partitions, last_seq, io_manager)
+    }
+
+    fn write(&self, partitions: &BTreeMap<u32, PartitionRecord>, last_seq: u64, io_manager: &IOManager) -> Result<(), Error> {
+        if !io_manager.is_persistent() {
+            return Ok(())
+        }
+        let raw = encode_manifest(last_seq, self.partitioning, partitions.values());
+        io_manager.acquire_quota().write_atomic(MANIFEST_FILE_NAME.to_string(), &raw)
+    }
+}
+
+fn encode_manifest<'a>(last_seq: u64,
+                       partitioning: Partitioning,
+                       records: impl ExactSizeIterator<Item=&'a PartitionRecord> + Clone) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(&[0u8; 4]);
+    buffer.extend_from_slice(&encode_fixed64_ret(last_seq));
+    buffer.extend_from_slice(&encode_fixed32_ret(records.len() as u32));
+    for record in records.clone() {
+        buffer.extend_from_slice(&encode_fixed32_ret(record.partition_id));
+        match record.bounds.as_ref() {
+            Some((lower_bound, upper_bound)) => {
+                buffer.push(1);
+                encode_key(&mut buffer, lower_bound);
+                encode_key(&mut buffer, upper_bound);
+            },
+            None => buffer.push(0)
+        }
+        buffer.extend_from_slice(&encode_fixed32_ret(record.tables.len() as u32));
+        for table in record.tables.iter() {
+            buffer.extend_from_slice(&encode_fixed32_ret(table.level));
+            buffer.extend_from_slice(&encode_fixed64_ret(table.table_file.file_number()));
+            buffer.extend_from_slice(&encode_fixed64_ret(table.file_size as u64));
+            encode_key(&mut buffer, &table.lower_bound);
+            encode_key(&mut buffer, &table.upper_bound);
+        }
+    }
+    let range_tombstones: Vec<(u32, &RangeTombstoneRecord)> = records
+        .flat_map(|record| record.range_tombstones.iter().map(move |tombstone| (record.partition_id, tombstone)))
+        .collect();
+    buffer.extend_from_slice(&encode_fixed32_ret(range_tombstones.len() as u32));
+    for (partition_id, tombstone) in range_tombstones {
+        buffer.extend_from_slice(&encode_fixed32_ret(partition_id));
+        buffer.extend_from_slice(&encode_fixed64_ret(tombstone.seq));
+        buffer.extend_from_slice(&encode_fixed64_ret(tombstone.fence));
+        encode_key(&mut buffer, &tombstone.start);
+        encode_key(&mut buffer, &tombstone.end);
+    }
+    let hash_partitions = match partitioning {
+        Partitioning::Range => 0,
+        Partitioning::Hash { partitions } => partitions as u32
+    };
+    buffer.extend_from_slice(&encode_fixed32_ret(hash_partitions));
+    let crc = crc32::checksum_ieee(&buffer[4..]);
+    buffer[0..4].copy_from_slice(&encode_fixed32_ret(crc));
+    buffer
+}
+
+fn encode_key(buffer: &mut Vec<u8>, key: &[u8]) {
+    buffer.extend_from_slice(&encode_fixed32_ret(key.len() as u32));
+    buffer.extend_from_slice(key);
+}
+
+fn decode_manifest(raw: &[u8]) -> Result<(u64, Partitioning, Vec<PartitionRecord>), Error> {
+    let corrupt = || Error::decode_error("corrupt manifest".into());
+    if raw.len() < 16 || crc32::checksum_ieee(&raw[4..]) != decode_fixed32(&raw[0..4]) {
+        return Err(corrupt())
+    }
+    let mut reader = Reader(&raw[4..]);
+    let last_seq = reader.u64().ok_or_else(corrupt)?;
+    let mut ret = Vec::new();
+    for _ in 0..reader.u32().ok_or_else(corrupt)? {
+        ret.push(reader.partition().ok_or_else(corrupt)?);
+    }
+    if !reader.0.is_empty() {
+        for _ in 0..reader.u32().ok_or_else(corrupt)? {
+            let partition_id = reader.u32().ok_or_else(corrupt)?;
+            let tombstone = reader.range_tombstone().ok_or_else(corrupt)?;
+            let record = ret.iter_mut().find(|record| record.partition_id == partition_id).ok_or_else(corrupt)?;
+            record.range_tombstones.push(tombstone);
+        }
+    }
+    let mut partitioning = Partitioning::Range;
+    if !reader.0.is_empty() {
+        partitioning = match reader.u32().ok_or_else(corrupt)? {
+            0 => Partitioning::Range,
+            partitions => Partitioning::Hash { partitions: partitions as usize }
+        };
+    }
+    if !reader.0.is_empty() {
+        return Err(corrupt())
+    }
+    Ok((last_seq, partitioning, ret))
+}
+
+/// Reads the manifest from the front, `None` if it ends early.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None
+        }
+        let (ret, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(ret)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(decode_fixed32)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(decode_fixed64)
+    }
+
+    fn key(&mut self) -> Option<Vec<u8>> {
+        let len = self.u32()? as usize;
+        self.take(len).map(|key| key.to_vec())
+    }
+
+    fn partition(&mut self) -> Option<PartitionRecord> {
+        let partition_id = self.u32()?;
+        let bounds = match self.take(1)?[0] {
+            0 => None,
+            1 => Some((self.key()?, self.key()?)),
+            _ => return None
+        };
+        let mut tables = Vec::new();
+        for _ in 0..self.u32()? {
+            let level = self.u32()?;
+            let table_file = ScTableFile::new(self.u64()?);
+            let file_size = self.u64()? as usize;
+            let (lower_bound, upper_bound) = (self.key()?, self.key()?);
+            tables.push(TableRecord { level, table_file, file_size, lower_bound, upper_bound });
+        }
+        Some(PartitionRecord { partition_id, bounds, tables, range_tombstones: Vec::new() })
+    }
+
+    fn range_tombstone(&mut self) -> Option<RangeTombstoneRecord> {
+        let (seq, fence) = (self.u64()?, self.u64()?);
+        let (start, end) = (self.key()?, self.key()?);
+        Some(RangeTombstoneRecord { seq, fence, start, end })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Partitioning;
+    use crate::io::IOManager;
+    use crate::manifest::{Manifest, PartitionRecord, RangeTombstoneRecord, TableRecord, MANIFEST_FILE_NAME};
+    use crate::table::sctable::ScTableFile;
+    use crate::testutil::TestDir;
+
+    fn record(partition_id: u32, bounds: Option<(&[u8], &[u8])>, table_count: u64) -> PartitionRecord {
+        PartitionRecord {
+            partition_id,
+            bounds: bounds.map(|(lower, upper)| (lower.to_vec(), upper.to_vec())),
+            tables: (0..table_count).map(|i| TableRecord {
+                level: i as u32 % 2,
+                table_file: ScTableFile::new(partition_id as u64 * 100 + i + 1),
+                file_size: 100 * i as usize,
+                lower_bound: format!("{}{}", partition_id, i).into_bytes(),
+                upper_bound: format!("{}{}z", partition_id, i).into_bytes()
+            }).collect(),
+            range_tombstones: (0..table_count / 2).map(|i| RangeTombstoneRecord {
+                seq: i + 1,
+                fence: partition_id as u64 * 100 + table_count + 1,
+                start: format!("{}{}", partition_id, i).into_bytes(),
+                end: format!("{}{}m", partition_id, i).into_bytes()
+            }).collect()
+        }
+    }
+
+    fn summary(records: &[PartitionRecord]) -> Vec<(u32, Option<(Vec<u8>, Vec<u8>)>, Vec<String>)> {
+        records.iter().map(|record| {
+            let tables = record.tables.iter().map(|table| {
+                format!("{} {} {} {:?} {:?}", table.level, table.table_file.file_name(), table.file_size,
+                        table.lower_bound, table.upper_bound)
+            });
+            let range_tombstones = record.range_tombstones.iter().map(|tombstone| {
+                format!("{} {} {:?} {:?}", tombstone.seq, tombstone.fence, tombstone.start, tombstone.end)
+            });
+            (record.partition_id, record.bounds.clone(), tables.chain(range_tombstones).collect())
+        }).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = TestDir::new("manifest_round_trip");
+        let io_manager = IOManager::new(dir.path(), 4);
+        assert!(Manifest::load(&io_manager).unwrap().is_none());
+
+        let manifest = Manifest::new(Partitioning::Hash { partitions: 3 });
+        manifest.reset(vec![record(0, None, 0)], 0, &io_manager).unwrap();
+        let (last_seq, _, records) = Manifest::load(&io_manager).unwrap().unwrap();
+        assert_eq!((last_seq, summary(&records)), (0, summary(&[record(0, None, 0)])));
+
+        manifest.update(record(0, Some((b"a", b"z")), 3), 10, &io_manager).unwrap();
+        manifest.replace(0, vec![record(1, Some((b"a", b"f")), 2), record(2, Some((b"m", b"z")), 1)],
+                         20, &io_manager).unwrap();
+        // partition 0 is gone, its late update must not bring it back
+        manifest.update(record(0, Some((b"a", b"z")), 4), 30, &io_manager).unwrap();
+        manifest.update(record(2, Some((b"g", b"z")), 5), 40, &io_manager).unwrap();
+
+        let expected = [record(1, Some((b"a", b"f")), 2), record(2, Some((b"g", b"z")), 5)];
+        let (last_seq, partitioning, records) = Manifest::load(&io_manager).unwrap().unwrap();
+        assert_eq!((last_seq, summary(&records)), (40, summary(&expected)));
+        assert_eq!(partitioning, Partitioning::Hash { partitions: 3 });
+        assert!(!io_manager.list_files().unwrap().iter().any(|file_name| file_name != MANIFEST_FILE_NAME));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let dir = TestDir::new("manifest_corrupt");
+        let io_manager = IOManager::new(dir.path(), 4);
+        Manifest::new(Partitioning::Range).reset(vec![record(3, Some((b"a", b"z")), 2)], 7, &io_manager).unwrap();
+        let raw = io_manager.acquire_quota().read_file(MANIFEST_FILE_NAME.to_string()).unwrap();
+
+        for len in 0..raw.len() {
+            io_manager.acquire_quota().write_file(MANIFEST_FILE_NAME.to_string(), &raw[..len]).unwrap();
+            assert!(Manifest::load(&io_manager).is_err());
+        }
+        let mut flipped = raw.clone();
+        flipped[raw.len() / 2] ^= 0xFF;
+        io_manager.acquire_quota().write_file(MANIFEST_FILE_NAME.to_string(), &flipped).unwrap();
+        assert!(Manifest::load(&io_manager).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+
+/// Size of the blocks byte strings are copied into.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Copies the keys and values of a memtable into large blocks, so it takes a few allocations in
+/// all rather than one per key and value. Nothing is freed before the arena is dropped together
+/// with its memtable, a replaced or removed entry keeps its bytes until then. Copying only takes
+/// `&self`, threads inserting into a skiplist at once lock the arena just to claim their room.
+pub(crate) struct Arena {
+    blocks: Mutex<Blocks>
+}
+
+struct Blocks {
+    /// Every block allocated with its size, freed on drop.
+    blocks: Vec<(*mut u8, usize)>,
+    /// Where the room left in the block being filled starts.
+    current: *mut u8,
+    remaining: usize,
+    allocated: usize,
+    /// Bytes copied in so far.
+    used: usize
+}
+
+/// The blocks are only reached through the arena, and parts handed out are never written again.
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+impl Arena {
+    pub(crate) fn new() -> Self {
+        let blocks = Blocks { blocks: Vec::new(), current: ptr::null_mut(), remaining: 0, allocated: 0, used: 0 };
+        Self { blocks: Mutex::new(blocks) }
+    }
+
+    /// Copies `data` into the arena, the copy lives as long as the arena.
+    pub(crate) fn copy(&self, data: &[u8]) -> &[u8] {
+        if data.is_empty() {
+            return &[]
+        }
+        let dest = {
+            let mut blocks = self.blocks.lock().unwrap();
+            blocks.used += data.len();
+            blocks.claim(data.len())
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), dest, data.len());
+            slice::from_raw_parts(dest, data.len())
+        }
+    }
+
+    /// Bytes copied into the arena so far, those of entries replaced or removed since included.
+    pub(crate) fn used(&self) -> usize {
+        self.blocks.lock().unwrap().used
+    }
+
+    /// Bytes of the blocks allocated so far.
+    #[cfg(test)]
+    pub(crate) fn allocated(&self) -> usize {
+        self.blocks.lock().unwrap().allocated
+    }
+}
+
+impl Blocks {
+    fn claim(&mut self, len: usize) -> *mut u8 {
+        // a large item gets a block of its own, the block being filled keeps its room
+        if len > BLOCK_SIZE / 4 {
+            return self.allocate(len)
+        }
+        if len > self.remaining {
+            self.current = self.allocate(BLOCK_SIZE);
+            self.remaining = BLOCK_SIZE;
+        }
+        let claimed = self.current;
+        self.current = unsafe { self.current.add(len) };
+        self.remaining -= len;
+        claimed
+    }
+
+    fn allocate(&mut self, size: usize) -> *mut u8 {
+        let block = Box::into_raw(vec![0u8; size].into_boxed_slice()) as *mut u8;
+        self.blocks.push((block, size));
+        self.allocated += size;
+        block
+    }
+}
+
+impl Drop for Blocks {
+    fn drop(&mut self) {
+        for &(block, size) in self.blocks.iter() {
+            drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(block, size)) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::partition::arena::{Arena, BLOCK_SIZE};
+
+    #[test]
+    fn test_copies_stay_intact() {
+        let arena = Arena::new();
+        assert_eq!(arena.allocated(), 0);
+        assert_eq!(arena.copy(b""), b"");
+        assert_eq!((arena.allocated(), arena.used()), (0, 0));
+
+        let copies: Vec<(Vec<u8>, &[u8])> = (0..20000u32).map(|i| {
+            let data = format!("entry{}", i).into_bytes();
+            let copy = arena.copy(&data);
+            (data, copy)
+        }).collect();
+        assert!(copies.iter().all(|(data, copy)| data.as_slice() == *copy));
+        assert_eq!(arena.used(), copies.iter().map(|(data, _)| data.len()).sum::<usize>());
+        let small_blocks = arena.allocated();
+        assert_eq!(small_blocks % BLOCK_SIZE, 0);
+        assert!(small_blocks <= 3 * BLOCK_SIZE);
+
+        // a large one goes into a block of its own, the block being filled is not given up
+        let large = vec![42u8; BLOCK_SIZE];
+        assert_eq!(arena.copy(&large), large.as_slice());
+        assert_eq!(arena.allocated(), small_blocks + BLOCK_SIZE);
+        arena.copy(b"small");
+        assert_eq!(arena.allocated(), small_blocks + BLOCK_SIZE);
+        assert!(copies.iter().all(|(data, copy)| data.as_slice() == *copy));
+    }
+
+    #[test]
+    fn test_concurrent_copies() {
+        let arena = Arc::new(Arena::new());
+        let threads: Vec<_> = (0..8u32).map(|t| {
+            let arena = arena.clone();
+            thread::spawn(move || {
+                let copies: Vec<&[u8]> = (0..5000u32).map(|i| arena.copy(format!("{}-{}", t, i).as_bytes())).collect();
+                // copied bytes are never moved nor overwritten by the other threads
+                for (i, copy) in copies.iter().enumerate() {
+                    assert_eq!(*copy, format!("{}-{}", t, i).as_bytes());
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
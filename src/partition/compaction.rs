@@ -0,0 +1,504 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::iter::Peekable;
+use crate::{Comparator, Error, Options, CompactionFilter, FilterDecision, MergeOperator};
+use crate::partition::{Entry, InternalKey, UserKey};
+use crate::partition::merge::MergeState;
+use crate::partition::range_tombstone::RangeTombstone;
+use crate::table::builder::ScTableBuilder;
+
+pub(crate) struct CompactionOutput<Comp: Comparator> {
+    pub(crate) buffer: Vec<u8>,
+    pub(crate) lower_bound: UserKey<'static, Comp>,
+    pub(crate) upper_bound: UserKey<'static, Comp>
+}
+
+/// Drops the versions no reader can see anymore from entries sorted in `InternalKey` order, where
+/// the versions of a user key come newest first. `snapshots` are the sequence numbers of the live
+/// snapshots, ascending, and reads without a snapshot see the newest version. The snapshots cut
+/// the versions of a user key into stripes, the versions in a stripe are seen by the same
+/// snapshots and only the newest of them is kept.
+///
+/// In the oldest stripe, seen by every snapshot, a tombstone is dropped as well if
+/// `drop_tombstones` is set, which is only correct when no level below the output may hold an
+/// older version. Values there then go through `filter`, if there is one. A version one of
+/// `range_tombstones` hides is dropped in any stripe, together with the older ones of its stripe.
+///
+/// With a `merge_operator`, the merge operands of a stripe are folded into a value if the stripe
+/// also holds what lies below them, a value or a tombstone, or if nothing older may be left below
+/// the output. Otherwise they are kept as they are.
+pub(crate) struct CompactionIterator<'a, Comp: Comparator, I: Iterator<Item=Entry<Comp>>> {
+    entries: Peekable<I>,
+    snapshots: &'a [u64],
+    drop_tombstones: bool,
+    filter: Option<&'a dyn CompactionFilter>,
+    range_tombstones: &'a [RangeTombstone<Comp>],
+    merge_operator: Option<&'a dyn MergeOperator>,
+    /// Merge operands which could not be folded, returned before reading on.
+    pending: VecDeque<Entry<Comp>>,
+    last_user_key: Option<UserKey<'static, Comp>>,
+    /// The stripe of the last version of `last_user_key`, `0` being the oldest one.
+    last_stripe: usize
+}
+
+impl<'a, Comp, I> CompactionIterator<'a, Comp, I> where Comp: Comparator, I: Iterator<Item=Entry<Comp>> {
+    pub(crate) fn new(entries: I,
+                      snapshots: &'a [u64],
+                      drop_tombstones: bool,
+                      filter: Option<&'a dyn CompactionFilter>) -> Self {
+        debug_assert!(snapshots.windows(2).all(|pair| pair[0] < pair[1]));
+        Self {
+            entries: entries.peekable(),
+            snapshots,
+            drop_tombstones,
+            filter,
+            range_tombstones: &[],
+            merge_operator: None,
+            pending: VecDeque::new(),
+            last_user_key: None,
+            last_stripe: 0
+        }
+    }
+
+    pub(crate) fn with_range_tombstones(mut self, range_tombstones: &'a [RangeTombstone<Comp>]) -> Self {
+        self.range_tombstones = range_tombstones;
+        self
+    }
+
+    pub(crate) fn with_merge_operator(mut self, merge_operator: Option<&'a dyn MergeOperator>) -> Self {
+        self.merge_operator = merge_operator;
+        self
+    }
+
+    /// Takes the merge operand `key` and the older versions of its stripe up to the first one which
+    /// is not a merge operand. Returns them folded into a value, or `None` after queueing them up
+    /// in `pending` if what lies below them is unknown.
+    fn merge_stripe(&mut self,
+                    key: InternalKey<'static, Comp>,
+                    value: Vec<u8>,
+                    stripe: usize,
+                    merge_operator: &dyn MergeOperator) -> Option<Entry<Comp>> {
+        let (snapshots, range_tombstones) = (self.snapshots, self.range_tombstones);
+        let mut versions = vec![(key, value)];
+        let mut hidden = false;
+        loop {
+            let in_stripe = match self.entries.peek() {
+                Some((next, _)) => next.user_key == versions[0].0.user_key
+                    && snapshots.partition_point(|&snapshot| snapshot < next.seq) == stripe,
+                None => false
+            };
+            if !in_stripe {
+                break;
+            }
+            let (next, next_value) = self.entries.next().unwrap();
+            if range_tombstones.iter().any(|tombstone| tombstone.hides(&next, snapshots)) {
+                hidden = true;
+                break;
+            }
+            let is_merge = next.is_merge();
+            versions.push((next, next_value));
+            if !is_merge {
+                break;
+            }
+        }
+        let complete = hidden || !versions.last().unwrap().0.is_merge() || (stripe == 0 && self.drop_tombstones);
+        if !complete {
+            self.pending.extend(versions);
+            return None
+        }
+        let (seq, user_key) = (versions[0].0.seq, versions[0].0.user_key.clone());
+        let mut state = MergeState::new();
+        for (key, value) in versions {
+            state.push(&key, value);
+        }
+        if hidden {
+            state.push_deleted();
+        }
+        let merged = state.finish(user_key.key(), merge_operator)?;
+        Some((InternalKey::new(seq, user_key), merged))
+    }
+}
+
+impl<'a, Comp, I> Iterator for CompactionIterator<'a, Comp, I> where Comp: Comparator, I: Iterator<Item=Entry<Comp>> {
+    type Item = Entry<Comp>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.pending.pop_front() {
+            return Some(entry)
+        }
+        while let Some((mut key, mut value)) = self.entries.next() {
+            let stripe = self.snapshots.partition_point(|&snapshot| snapshot < key.seq);
+            if self.last_user_key.as_ref() == Some(&key.user_key) {
+                if stripe == self.last_stripe {
+                    continue;
+                }
+            } else {
+                self.last_user_key.replace(key.user_key.clone());
+            }
+            self.last_stripe = stripe;
+            if self.range_tombstones.iter().any(|tombstone| tombstone.hides(&key, self.snapshots)) {
+                continue;
+            }
+            if let (true, Some(merge_operator)) = (key.is_merge(), self.merge_operator) {
+                match self.merge_stripe(key, value, stripe, merge_operator) {
+                    Some((merged_key, merged_value)) => {
+                        key = merged_key;
+                        value = merged_value;
+                    }
+                    None => return self.pending.pop_front()
+                }
+            }
+            if stripe > 0 {
+                return Some((key, value))
+            }
+            if key.is_deletion() {
+                if self.drop_tombstones {
+                    continue;
+                }
+            } else if let Some(filter) = self.filter {
+                match filter.filter(key.user_key.key(), &value) {
+                    FilterDecision::Keep => (),
+                    // the older versions are shadowed by this one all the same and go as well
+                    FilterDecision::Remove => continue,
+                    FilterDecision::ChangeValue(new_value) => value = new_value
+                }
+            }
+            return Some((key, value))
+        }
+        None
+    }
+}
+
+/// Sorts the entries gathered from all compaction inputs and runs them through a
+/// `CompactionIterator`.
+pub(crate) fn merge_entries<Comp>(mut entries: Vec<Entry<Comp>>,
+                                  drop_tombstones: bool,
+                                  snapshots: &[u64],
+                                  filter: Option<&dyn CompactionFilter>,
+                                  range_tombstones: &[RangeTombstone<Comp>],
+                                  merge_operator: Option<&dyn MergeOperator>) -> Vec<Entry<Comp>>
+    where Comp: Comparator {
+    entries.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+    CompactionIterator::new(entries.into_iter(), snapshots, drop_tombstones, filter)
+        .with_range_tombstones(range_tombstones)
+        .with_merge_operator(merge_operator)
+        .collect()
+}
+
+/// Sorts `entries` into `InternalKey` order and cuts them into runs of about `run_size` bytes of
+/// keys and values. A run only ends where the user key changes, so the runs are merged and built
+/// into tables on their own, and the tables of one run never overlap those of another.
+pub(crate) fn split_runs<Comp>(mut entries: Vec<Entry<Comp>>, run_size: usize) -> Vec<Vec<Entry<Comp>>>
+    where Comp: Comparator {
+    entries.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+    let mut runs = Vec::new();
+    let mut run: Vec<Entry<Comp>> = Vec::new();
+    let mut size = 0;
+    for (key, value) in entries {
+        if size >= run_size && run.last().map_or(false, |(last, _)| last.user_key != key.user_key) {
+            runs.push(std::mem::take(&mut run));
+            size = 0;
+        }
+        size += key.user_key.key().len() + value.len();
+        run.push((key, value));
+    }
+    if !run.is_empty() {
+        runs.push(run);
+    }
+    runs
+}
+
+/// Splits sorted entries into tables of at most `options.table_size` bytes. All versions of a user
+/// key go into the same table so tables of one level stay disjoint, even if that exceeds the table
+/// size. The table size is the size before compression. A table followed by another one is bounded
+/// as `table_boundary` picks between its last key and the first key of the next, which is often
+/// shorter than the key itself.
+pub(crate) fn build_tables<Comp>(entries: &[Entry<Comp>], options: &Options) -> Result<Vec<CompactionOutput<Comp>>, Error>
+    where Comp: Comparator {
+    let table_size = options.table_size;
+    let mut ret = Vec::new();
+    let mut builder = ScTableBuilder::from_options(options);
+    let mut lower_bound: Option<&UserKey<Comp>> = None;
+    for (i, (key, value)) in entries.iter().enumerate() {
+        if !builder.is_empty() && builder.size_with(key.user_key.key(), value) > table_size
+           && entries[i - 1].0.user_key != key.user_key {
+            let (last, comparator) = (entries[i - 1].0.user_key.key(), key.user_key.comparator());
+            ret.push(CompactionOutput {
+                buffer: builder.finish()?,
+                lower_bound: lower_bound.take().unwrap().clone(),
+                upper_bound: UserKey::new_owned(table_boundary(&**comparator, last, key.user_key.key()), comparator)
+            });
+            builder = ScTableBuilder::from_options(options);
+        }
+        if lower_bound.is_none() {
+            lower_bound.replace(&key.user_key);
+        }
+        builder.add(key, value);
+    }
+    if !builder.is_empty() {
+        ret.push(CompactionOutput {
+            buffer: builder.finish()?,
+            lower_bound: lower_bound.unwrap().clone(),
+            upper_bound: entries.last().unwrap().0.user_key.clone()
+        });
+    }
+    Ok(ret)
+}
+
+/// The shorter of `Comparator::separator` of `last` and `next` and `Comparator::successor` of
+/// `last`, the latter only if it is between them, which a comparator relying on the bytewise
+/// default of `successor` need not get. Comparators may override either of them.
+fn table_boundary<Comp: Comparator>(comparator: &Comp, last: &[u8], next: &[u8]) -> Vec<u8> {
+    let separator = comparator.separator(last, next);
+    match comparator.successor(last) {
+        Some(successor) if successor.len() < separator.len()
+                           && comparator.compare(&successor, last) == Ordering::Greater
+                           && comparator.compare(&successor, next) == Ordering::Less => successor,
+        _ => separator
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+    use crate::{Comparator, DefaultComparator, ReverseComparator, Options, CompactionFilter, FilterDecision, U64AddMergeOperator};
+    use crate::partition::{Entry, InternalKey, UserKey};
+    use crate::partition::compaction::{merge_entries, build_tables, split_runs, table_boundary, CompactionIterator};
+    use crate::partition::range_tombstone::RangeTombstone;
+    use crate::table::cache::{ScTableCache, TableCacheManager};
+    use crate::testutil::default_comparator;
+
+    fn value(seq: u64, key: &str, value: &str) -> Entry<DefaultComparator> {
+        (InternalKey::new(seq, UserKey::new_owned(key.as_bytes().to_vec(), &default_comparator())), value.as_bytes().to_vec())
+    }
+
+    fn deletion(seq: u64, key: &str) -> Entry<DefaultComparator> {
+        (InternalKey::new_deletion(seq, UserKey::new_owned(key.as_bytes().to_vec(), &default_comparator())), Vec::new())
+    }
+
+    fn merge(seq: u64, key: &str, operand: u64) -> Entry<DefaultComparator> {
+        (InternalKey::new_merge(seq, UserKey::new_owned(key.as_bytes().to_vec(), &default_comparator())), operand.to_be_bytes().to_vec())
+    }
+
+    fn summary(entries: &[Entry<DefaultComparator>]) -> Vec<(u64, bool, Vec<u8>)> {
+        entries.iter().map(|(k, _)| (k.seq, k.is_deletion(), k.user_key.key().to_vec())).collect()
+    }
+
+    #[test]
+    fn test_merge_keeps_newest_version() {
+        let entries = vec![
+            value(1, "a", "a1"), value(3, "b", "b3"), deletion(5, "c"),
+            value(4, "a", "a4"), value(2, "b", "b2"), value(1, "c", "c1"),
+        ];
+        let merged = merge_entries(entries, false, &[], None, &[], None);
+        assert_eq!(summary(&merged), vec![(4, false, b"a".to_vec()),
+                                          (3, false, b"b".to_vec()),
+                                          (5, true, b"c".to_vec())]);
+        assert_eq!(merged[0].1, b"a4".to_vec());
+    }
+
+    #[test]
+    fn test_merge_drops_tombstones() {
+        let entries = vec![value(1, "a", "a1"), deletion(2, "a"), value(1, "b", "b1")];
+        let merged = merge_entries(entries, true, &[], None, &[], None);
+        assert_eq!(summary(&merged), vec![(1, false, b"b".to_vec())]);
+    }
+
+    #[test]
+    fn test_merge_drops_range_deleted() {
+        let comparator = default_comparator();
+        let user_key = |key: &str| UserKey::new_owned(key.as_bytes().to_vec(), &comparator);
+        let range_tombstones = [RangeTombstone::new(5, user_key("b"), user_key("d"))];
+        let entries = || vec![
+            value(1, "a", "a1"), value(2, "b", "b2"), value(6, "b", "b6"), value(3, "c", "c3"),
+            value(1, "c", "c1"), value(4, "d", "d4"),
+        ];
+        let merged = merge_entries(entries(), false, &[], None, &range_tombstones, None);
+        assert_eq!(summary(&merged), vec![(1, false, b"a".to_vec()),
+                                          (6, false, b"b".to_vec()),
+                                          (4, false, b"d".to_vec())]);
+        // snapshot 2 still reads b2 and c1, c3 is hidden from every reader
+        let merged = merge_entries(entries(), false, &[2], None, &range_tombstones, None);
+        assert_eq!(summary(&merged), vec![(1, false, b"a".to_vec()),
+                                          (6, false, b"b".to_vec()),
+                                          (2, false, b"b".to_vec()),
+                                          (1, false, b"c".to_vec()),
+                                          (4, false, b"d".to_vec())]);
+    }
+
+    #[test]
+    fn test_build_tables_respects_table_size() {
+        let entries: Vec<_> = (0..100).map(|i| value(i, &format!("key{:03}", i), "some value")).collect();
+        let outputs = build_tables(&entries, &Options::default().with_table_size(512)).unwrap();
+        assert!(outputs.len() > 1);
+
+        let cache_manager = TableCacheManager::new(1);
+        let mut count = 0;
+        for (n, output) in outputs.iter().enumerate() {
+            assert!(output.buffer.len() <= 512);
+            let table = ScTableCache::from_raw(&output.buffer, cache_manager.acquire_quota()).unwrap();
+            assert_eq!(table.nth_item(0).1, output.lower_bound.key());
+            let largest_key = table.nth_item(table.catalog_size() - 1).1.to_vec();
+            match outputs.get(n + 1) {
+                // between the last key of this table and the first of the next
+                Some(next) => {
+                    assert!(largest_key.as_slice() <= output.upper_bound.key());
+                    assert!(output.upper_bound < next.lower_bound);
+                },
+                None => assert_eq!(largest_key, output.upper_bound.key())
+            }
+            count += table.catalog_size();
+        }
+        assert_eq!(count, 100);
+        // a table ending where keys differ before their last byte, like key019 and key020, gets a
+        // shorter bound than its keys
+        let entries: Vec<_> = (0..40).map(|i| value(i, &format!("key{:03}", i), &"v".repeat(20))).collect();
+        let outputs = build_tables(&entries, &Options::default().with_table_size(20 * 50 + 100)).unwrap();
+        let upper_bounds: Vec<_> = outputs.iter().map(|output| output.upper_bound.key().to_vec()).collect();
+        assert!(upper_bounds.iter().any(|upper_bound| upper_bound.len() < 6), "{:?}", upper_bounds);
+    }
+
+    #[test]
+    fn test_merge_keeps_versions_visible_to_snapshot() {
+        let entries = vec![
+            value(1, "a", "a1"), value(3, "a", "a3"), value(6, "a", "a6"),
+            value(2, "b", "b2"), deletion(4, "b"), deletion(7, "c"), value(1, "c", "c1"),
+        ];
+        let merged = merge_entries(entries, true, &[4], None, &[], None);
+        assert_eq!(summary(&merged), vec![(6, false, b"a".to_vec()),
+                                          (3, false, b"a".to_vec()),
+                                          (7, true, b"c".to_vec()),
+                                          (1, false, b"c".to_vec())]);
+    }
+
+    #[test]
+    fn test_compaction_iterator_keeps_newest_per_snapshot() {
+        let entries = || vec![
+            value(9, "a", "a9"), value(8, "a", "a8"), value(6, "a", "a6"), value(5, "a", "a5"),
+            value(4, "a", "a4"), value(2, "a", "a2"), value(1, "a", "a1"),
+            deletion(7, "b"), value(6, "b", "b6"), deletion(3, "b"), value(1, "b", "b1"),
+            deletion(2, "c"), value(1, "c", "c1"),
+            value(3, "d", "d3"), deletion(1, "d"),
+        ];
+        let snapshots = [2, 5];
+        let surviving = |drop_tombstones| {
+            summary(&CompactionIterator::new(entries().into_iter(), &snapshots, drop_tombstones, None)
+                .collect::<Vec<_>>())
+        };
+        // a: newest, then the newest each of snapshots 5 and 2 sees
+        // b: the tombstone at 3 is what snapshot 5 sees, snapshot 2 sees b1
+        // c: the tombstone is seen by every snapshot, nothing older survives it
+        // d: only the newest is seen by any reader
+        let kept_tombstones = vec![(9, false, b"a".to_vec()), (5, false, b"a".to_vec()), (2, false, b"a".to_vec()),
+                                   (7, true, b"b".to_vec()), (3, true, b"b".to_vec()), (1, false, b"b".to_vec()),
+                                   (2, true, b"c".to_vec()),
+                                   (3, false, b"d".to_vec()), (1, true, b"d".to_vec())];
+        assert_eq!(surviving(false), kept_tombstones);
+        let dropped_tombstones: Vec<_> = kept_tombstones.into_iter().filter(|&(seq, deletion, _)| !(deletion && seq <= 2))
+            .collect();
+        assert_eq!(surviving(true), dropped_tombstones);
+    }
+
+    struct ExpiryFilter();
+
+    impl CompactionFilter for ExpiryFilter {
+        fn filter(&self, key: &[u8], value: &[u8]) -> FilterDecision {
+            match (key, value) {
+                (_, b"expired") => FilterDecision::Remove,
+                (b"b", _) => FilterDecision::ChangeValue(b"changed".to_vec()),
+                _ => FilterDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_applies_filter() {
+        let entries = vec![
+            value(1, "a", "a1"), value(3, "a", "expired"), value(2, "b", "b2"),
+            value(4, "c", "c4"), value(6, "c", "expired"), deletion(5, "d"), value(1, "d", "expired"),
+        ];
+        let merged = merge_entries(entries, false, &[5], Some(&ExpiryFilter()), &[], None);
+        // versions a snapshot may still see above 5 are not filtered, nor are tombstones
+        assert_eq!(summary(&merged), vec![(2, false, b"b".to_vec()),
+                                          (6, false, b"c".to_vec()),
+                                          (4, false, b"c".to_vec()),
+                                          (5, true, b"d".to_vec())]);
+        assert_eq!(merged[0].1, b"changed".to_vec());
+        assert_eq!(merged[1].1, b"expired".to_vec());
+    }
+
+    #[test]
+    fn test_merge_folds_operands() {
+        let entries = || vec![
+            merge(6, "a", 1), value(5, "a", "\0\0\0\0\0\0\0\x0a"), merge(2, "a", 100),
+            merge(6, "b", 1), merge(3, "b", 2), merge(2, "b", 3), deletion(1, "b"),
+            merge(4, "c", 1), merge(3, "c", 2),
+        ];
+        let operator = U64AddMergeOperator();
+        let merged = merge_entries(entries(), false, &[4], None, &[], Some(&operator));
+        let folded: Vec<_> = merged.iter()
+            .map(|(key, value)| (key.seq, key.is_merge(), key.user_key.key().to_vec(), value.clone()))
+            .collect();
+        // an operand is folded only with a value or a tombstone below it in the same stripe, as
+        // versions further down may be missing from the inputs
+        assert_eq!(folded, vec![(6, false, b"a".to_vec(), 11u64.to_be_bytes().to_vec()),
+                                (2, true, b"a".to_vec(), 100u64.to_be_bytes().to_vec()),
+                                (6, true, b"b".to_vec(), 1u64.to_be_bytes().to_vec()),
+                                (3, false, b"b".to_vec(), 5u64.to_be_bytes().to_vec()),
+                                (4, true, b"c".to_vec(), 1u64.to_be_bytes().to_vec()),
+                                (3, true, b"c".to_vec(), 2u64.to_be_bytes().to_vec())]);
+
+        // nothing may be left below the last level
+        let merged = merge_entries(entries(), true, &[], None, &[], Some(&operator));
+        let values: Vec<_> = merged.iter().map(|(key, value)| (key.is_merge(), value.clone())).collect();
+        assert_eq!(values, vec![(false, 11u64.to_be_bytes().to_vec()),
+                                (false, 6u64.to_be_bytes().to_vec()),
+                                (false, 3u64.to_be_bytes().to_vec())]);
+    }
+
+    /// Bytewise, with a successor but no separator shorter than the keys.
+    struct SuccessorOnlyComparator();
+
+    impl Comparator for SuccessorOnlyComparator {
+        fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+            lhs.cmp(rhs)
+        }
+
+        fn separator(&self, lhs: &[u8], _rhs: &[u8]) -> Vec<u8> {
+            lhs.to_vec()
+        }
+    }
+
+    #[test]
+    fn test_table_boundary() {
+        assert_eq!(table_boundary(&DefaultComparator(), b"key019", b"key020"), b"key02".to_vec());
+        assert_eq!(table_boundary(&DefaultComparator(), b"abc", b"bz"), b"b".to_vec());
+        // the empty successor comes after every key in reverse order
+        assert_eq!(table_boundary(&ReverseComparator(), b"b", b"a"), b"b".to_vec());
+        assert_eq!(table_boundary(&SuccessorOnlyComparator(), b"abc", b"bz"), b"b".to_vec());
+        assert_eq!(table_boundary(&SuccessorOnlyComparator(), b"abc", b"b"), b"abc".to_vec());
+        assert_eq!(table_boundary(&SuccessorOnlyComparator(), b"abc", b"abd"), b"abc".to_vec());
+    }
+
+    #[test]
+    fn test_build_tables_keeps_versions_together() {
+        let entries: Vec<_> = (0..20).rev().map(|i| value(i, "key", "some value")).collect();
+        let outputs = build_tables(&entries, &Options::default().with_table_size(64)).unwrap();
+        assert_eq!(outputs.len(), 1);
+    }
+
+    #[test]
+    fn test_split_runs() {
+        let mut entries: Vec<_> = (0..30).map(|i| value(i, &format!("key{:03}", i / 3), "value")).collect();
+        entries.reverse();
+        let runs = split_runs(entries, 20);
+        assert!(runs.len() > 1);
+        assert_eq!(runs.iter().map(Vec::len).sum::<usize>(), 30);
+        for (run, next) in runs.iter().zip(runs.iter().skip(1)) {
+            // sorted, and the versions of a key stay in one run
+            assert!(run.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            assert!(run.last().unwrap().0.user_key < next[0].0.user_key);
+        }
+        assert!(split_runs::<DefaultComparator>(Vec::new(), 20).is_empty());
+    }
+}
@@ -0,0 +1,479 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::{Comparator, MergeOperator};
+use crate::db::DbContext;
+use crate::error::Error;
+use crate::partition::{Entry, InternalKey, UserKey};
+use crate::partition::memtable::{MemTableImpl, MemTableIterator};
+use crate::partition::merge::MergeState;
+use crate::partition::range_tombstone::RangeDelAggregator;
+use crate::table::Table;
+use crate::table::cache::{ScTableCache, KeyCursor};
+
+/// A source of entries in `InternalKey` order which can be moved to another user key.
+pub(crate) trait EntrySource<Comp: Comparator>: Iterator<Item = Result<Entry<Comp>, Error>> {
+    /// Moves to the first entry whose user key is not less than `user_key`, backwards or forwards.
+    /// Entries the source left out from the start, below its lower bound, do not come back.
+    fn seek(&mut self, user_key: &[u8]);
+}
+
+pub(crate) type EntryIterator<'a, Comp> = Box<dyn EntrySource<Comp> + 'a>;
+
+fn seek_key<'a, Comp: Comparator>(user_key: &'a [u8], comparator: &Arc<Comp>) -> InternalKey<'a, Comp> {
+    InternalKey::new(u64::max_value(), UserKey::new_borrow(user_key, comparator))
+}
+
+/// Iterates a copy of a memtable from `lower_bound` on, taken while the partition was locked.
+/// Without `keys_only` the values are copied as well, otherwise every entry comes with an empty one.
+pub(crate) struct MemTableSource<Comp: 'static + Comparator> {
+    table: BTreeMap<InternalKey<'static, Comp>, Vec<u8>>,
+    comparator: Arc<Comp>,
+    /// Where the next entry is looked up, everything before it was yielded already.
+    position: Bound<InternalKey<'static, Comp>>
+}
+
+impl<Comp: 'static + Comparator> MemTableSource<Comp> {
+    pub(crate) fn new(table: &dyn MemTableImpl<Comp>,
+                      lower_bound: Option<&[u8]>,
+                      comparator: &Arc<Comp>,
+                      keys_only: bool) -> Self {
+        let table = MemTableIterator::new(table, lower_bound, comparator, false)
+            .map(|(k, v)| (k.make_owned(), if keys_only { Vec::new() } else { v.to_vec() }))
+            .collect();
+        Self { table, comparator: comparator.clone(), position: Bound::Unbounded }
+    }
+}
+
+impl<Comp: 'static + Comparator> Iterator for MemTableSource<Comp> {
+    type Item = Result<Entry<Comp>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.table.range::<InternalKey<'static, Comp>, _>((self.position.as_ref(), Bound::Unbounded))
+            .next()?;
+        let (key, value) = (key.make_owned(), value.clone());
+        self.position = Bound::Excluded(key.make_owned());
+        Some(Ok((key, value)))
+    }
+}
+
+impl<Comp: 'static + Comparator> EntrySource<Comp> for MemTableSource<Comp> {
+    fn seek(&mut self, user_key: &[u8]) {
+        self.position = Bound::Included(seek_key(user_key, &self.comparator).make_owned());
+    }
+}
+
+/// Iterates the entries of one table starting from `lower_bound`. The table is only loaded when
+/// the first entry is requested. With `keys_only` only its keys are loaded, every entry comes with
+/// an empty value.
+pub(crate) struct TableIterator<Comp: 'static + Comparator> {
+    table: Arc<dyn Table<Comp>>,
+    context: Arc<DbContext>,
+    comparator: Arc<Comp>,
+    lower_bound: Option<Vec<u8>>,
+    keys_only: bool,
+    cache: Option<Arc<ScTableCache>>,
+    position: usize,
+    keys: KeyCursor
+}
+
+impl<Comp: 'static + Comparator> TableIterator<Comp> {
+    pub(crate) fn new(table: Arc<dyn Table<Comp>>,
+                      context: Arc<DbContext>,
+                      comparator: Arc<Comp>,
+                      lower_bound: Option<Vec<u8>>,
+                      keys_only: bool) -> Self {
+        Self { table, context, comparator, lower_bound, keys_only, cache: None, position: 0, keys: KeyCursor::default() }
+    }
+}
+
+impl<Comp: 'static + Comparator> Iterator for TableIterator<Comp> {
+    type Item = Result<Entry<Comp>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cache.is_none() {
+            let cache = if self.keys_only {
+                self.table.load_keys(&self.context.cache_manager, &self.context.io_manager)
+            } else {
+                self.table.load(&self.context.cache_manager, &self.context.io_manager)
+            };
+            let cache = match cache {
+                Ok(cache) => cache,
+                Err(e) => {
+                    self.position = usize::max_value();
+                    return Some(Err(e))
+                }
+            };
+            if let Some(lower_bound) = &self.lower_bound {
+                self.position = cache.seek(&seek_key(lower_bound, &self.comparator));
+            }
+            self.cache.replace(cache);
+        }
+        let cache = self.cache.as_ref().unwrap();
+        if self.position >= cache.catalog_size() {
+            return None
+        }
+        let key = self.keys.nth_key(cache, self.position, &self.comparator);
+        let value = if self.keys_only { Vec::new() } else { cache.nth_value(self.position).to_vec() };
+        self.position += 1;
+        Some(Ok((key, value)))
+    }
+}
+
+impl<Comp: 'static + Comparator> EntrySource<Comp> for TableIterator<Comp> {
+    fn seek(&mut self, user_key: &[u8]) {
+        match &self.cache {
+            Some(cache) => self.position = cache.seek(&seek_key(user_key, &self.comparator)),
+            // the table is positioned once it is loaded
+            None => self.position = 0
+        }
+        self.lower_bound.replace(user_key.to_vec());
+    }
+}
+
+/// Iterates the tables of a level whose tables are disjoint and sorted by bounds, one after the
+/// other. Only the table the iteration is in is open.
+pub(crate) struct LevelIterator<Comp: 'static + Comparator> {
+    tables: Vec<Arc<dyn Table<Comp>>>,
+    context: Arc<DbContext>,
+    comparator: Arc<Comp>,
+    lower_bound: Option<Vec<u8>>,
+    keys_only: bool,
+    /// The table opened next.
+    next_table: usize,
+    current: Option<TableIterator<Comp>>
+}
+
+impl<Comp: 'static + Comparator> LevelIterator<Comp> {
+    pub(crate) fn new(tables: Vec<Arc<dyn Table<Comp>>>,
+                      context: Arc<DbContext>,
+                      comparator: Arc<Comp>,
+                      lower_bound: Option<Vec<u8>>,
+                      keys_only: bool) -> Self {
+        Self { tables, context, comparator, lower_bound, keys_only, next_table: 0, current: None }
+    }
+}
+
+impl<Comp: 'static + Comparator> Iterator for LevelIterator<Comp> {
+    type Item = Result<Entry<Comp>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let table = self.tables.get(self.next_table)?.clone();
+                self.next_table += 1;
+                self.current.replace(TableIterator::new(table, self.context.clone(), self.comparator.clone(),
+                                                        self.lower_bound.clone(), self.keys_only));
+            }
+            match self.current.as_mut().unwrap().next() {
+                Some(entry) => return Some(entry),
+                None => self.current = None
+            }
+        }
+    }
+}
+
+impl<Comp: 'static + Comparator> EntrySource<Comp> for LevelIterator<Comp> {
+    fn seek(&mut self, user_key: &[u8]) {
+        let user_key_ref = UserKey::new_borrow(user_key, &self.comparator);
+        self.next_table = self.tables.iter().position(|table| table.upper_bound() >= &user_key_ref)
+            .unwrap_or(self.tables.len());
+        self.current = None;
+        self.lower_bound.replace(user_key.to_vec());
+    }
+}
+
+struct HeapEntry<Comp: Comparator> {
+    key: InternalKey<'static, Comp>,
+    value: Vec<u8>,
+    source: usize
+}
+
+/// Entries with equal keys are ordered by source, sources listed first are the newer ones.
+impl<Comp: Comparator> Ord for HeapEntry<Comp> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key).then(self.source.cmp(&other.source))
+    }
+}
+
+impl<Comp: Comparator> PartialOrd for HeapEntry<Comp> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Comp: Comparator> PartialEq for HeapEntry<Comp> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<Comp: Comparator> Eq for HeapEntry<Comp> {}
+
+/// Merges sorted entry sources into the live user key/value pairs they describe as of `read_seq`:
+/// versions newer than `read_seq` are skipped, for every user key only the newest remaining version
+/// counts, and a tombstone hides the key altogether, as does a newer range tombstone covering it.
+/// A newest version which is a merge operand is folded with the older ones by the merge operator,
+/// without one it counts as a value of its own. Sources must be listed newest first. An error from
+/// any source ends the iteration and is kept for `status`.
+pub(crate) struct MergingIterator<'a, Comp: Comparator> {
+    sources: Vec<EntryIterator<'a, Comp>>,
+    heap: BinaryHeap<Reverse<HeapEntry<Comp>>>,
+    read_seq: u64,
+    /// Asked about every version found, if there are range tombstones.
+    range_del: Option<RangeDelAggregator<Comp>>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    error: Option<Error>
+}
+
+impl<'a, Comp: Comparator> MergingIterator<'a, Comp> {
+    pub(crate) fn new(sources: Vec<EntryIterator<'a, Comp>>, read_seq: u64) -> Self {
+        let mut ret = Self {
+            sources,
+            heap: BinaryHeap::new(),
+            read_seq,
+            range_del: None,
+            merge_operator: None,
+            error: None
+        };
+        for source in 0..ret.sources.len() {
+            ret.pull(source);
+        }
+        ret
+    }
+
+    /// `range_del` must read as of `read_seq` as well.
+    pub(crate) fn with_range_del(mut self, range_del: Option<RangeDelAggregator<Comp>>) -> Self {
+        self.range_del = range_del;
+        self
+    }
+
+    pub(crate) fn with_merge_operator(mut self, merge_operator: Option<Arc<dyn MergeOperator>>) -> Self {
+        self.merge_operator = merge_operator;
+        self
+    }
+
+    /// Moves every source to the first entry whose user key is not less than `user_key` and
+    /// merges on from there, so `next` returns the first live pair from `user_key` on. An error
+    /// seen before is kept.
+    pub(crate) fn seek(&mut self, user_key: &[u8]) {
+        self.heap.clear();
+        for source in 0..self.sources.len() {
+            self.sources[source].seek(user_key);
+            self.pull(source);
+        }
+    }
+
+    pub(crate) fn status(&self) -> Result<(), Error> {
+        match &self.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(())
+        }
+    }
+
+    fn pull(&mut self, source: usize) {
+        loop {
+            match self.sources[source].next() {
+                Some(Ok((key, _))) if key.seq > self.read_seq => continue,
+                Some(Ok((key, value))) => self.heap.push(Reverse(HeapEntry { key, value, source })),
+                Some(Err(e)) => { self.error.get_or_insert(e); },
+                None => {}
+            }
+            return
+        }
+    }
+
+    fn pop(&mut self) -> Option<HeapEntry<Comp>> {
+        let Reverse(entry) = self.heap.pop()?;
+        self.pull(entry.source);
+        Some(entry)
+    }
+}
+
+impl<'a, Comp: Comparator> Iterator for MergingIterator<'a, Comp> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.error.is_some() {
+                return None
+            }
+            let HeapEntry { key, mut value, .. } = self.pop()?;
+            // a newest version which is a merge operand takes the older ones along
+            let mut merge_state = None;
+            if key.is_merge() && self.merge_operator.is_some() {
+                let mut state = MergeState::new();
+                state.push(&key, std::mem::take(&mut value));
+                merge_state = Some(state);
+            }
+            while self.heap.peek().map_or(false, |Reverse(next)| next.key.user_key == key.user_key) {
+                let older = self.pop().unwrap();
+                if let Some(state) = merge_state.as_mut() {
+                    state.push_unless_deleted(&older.key, older.value, self.range_del.as_ref());
+                }
+            }
+            if self.error.is_some() {
+                return None
+            }
+            let range_deleted = self.range_del.as_ref()
+                .map_or(false, |range_del| range_del.should_delete(&key.user_key, key.seq));
+            if key.is_deletion() || range_deleted {
+                continue;
+            }
+            let user_key = key.user_key.key().to_vec();
+            if let (Some(state), Some(merge_operator)) = (merge_state, &self.merge_operator) {
+                value = state.finish(&user_key, merge_operator.as_ref()).unwrap_or_default();
+            }
+            return Some((user_key, value))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::DefaultComparator;
+    use crate::error::Error;
+    use crate::partition::{Entry, InternalKey, UserKey};
+    use crate::partition::memtable::{BTreeMemTable, MemTableImpl};
+    use crate::partition::iterator::{EntryIterator, EntrySource, MemTableSource, MergingIterator};
+    use crate::partition::range_tombstone::{RangeTombstone, RangeTombstones};
+    use crate::testutil::default_comparator;
+
+    fn source(entries: &[(u64, &str, Option<&str>)]) -> EntryIterator<'static, DefaultComparator> {
+        let comparator = default_comparator();
+        let mut table = BTreeMemTable::new();
+        for &(seq, key, value) in entries.iter() {
+            let user_key = UserKey::new_owned(key.as_bytes().to_vec(), &comparator);
+            match value {
+                Some(value) => table.insert(&InternalKey::new(seq, user_key), value.as_bytes()),
+                None => table.insert(&InternalKey::new_deletion(seq, user_key), b"")
+            };
+        }
+        Box::new(MemTableSource::new(&table, None, &comparator, false))
+    }
+
+    struct FailingSource;
+
+    impl Iterator for FailingSource {
+        type Item = Result<Entry<DefaultComparator>, Error>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            Some(Err(Error::sc_table_corrupt("broken".into())))
+        }
+    }
+
+    impl EntrySource<DefaultComparator> for FailingSource {
+        fn seek(&mut self, _user_key: &[u8]) {}
+    }
+
+    fn collect(iter: MergingIterator<DefaultComparator>) -> Vec<(String, String)> {
+        iter.map(|(k, v)| (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap())).collect()
+    }
+
+    #[test]
+    fn test_merge_newest_wins() {
+        let iter = MergingIterator::new(vec![
+            source(&[(9, "b", Some("b9")), (8, "d", None)]),
+            source(&[(5, "a", Some("a5")), (6, "c", Some("c6"))]),
+            source(&[(1, "a", Some("a1")), (2, "b", Some("b2")), (3, "c", Some("c3")), (4, "d", Some("d4")),
+                     (7, "e", Some("e7"))]),
+        ], u64::max_value());
+        assert_eq!(collect(iter), vec![
+            ("a".to_string(), "a5".to_string()),
+            ("b".to_string(), "b9".to_string()),
+            ("c".to_string(), "c6".to_string()),
+            ("e".to_string(), "e7".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_merge_tie_break() {
+        // a newer version in a source listed later still wins
+        let iter = MergingIterator::new(vec![
+            source(&[(1, "a", Some("old"))]),
+            source(&[(2, "a", Some("new"))]),
+        ], u64::max_value());
+        assert_eq!(collect(iter), vec![("a".to_string(), "new".to_string())]);
+
+        // the very same version in two sources comes from the source listed first
+        let iter = MergingIterator::new(vec![
+            source(&[(3, "a", Some("first"))]),
+            source(&[(3, "a", Some("second"))]),
+        ], u64::max_value());
+        assert_eq!(collect(iter), vec![("a".to_string(), "first".to_string())]);
+    }
+
+    #[test]
+    fn test_merge_stops_on_error() {
+        let failing: EntryIterator<DefaultComparator> = Box::new(FailingSource);
+        let mut iter = MergingIterator::new(vec![source(&[(1, "a", Some("a1"))]), failing], u64::max_value());
+        assert_eq!(iter.next(), None);
+        assert!(iter.status().is_err());
+        iter.seek(b"a");
+        assert_eq!(iter.next(), None);
+        assert!(iter.status().is_err());
+    }
+
+    #[test]
+    fn test_merge_at_read_seq() {
+        let sources = || vec![
+            source(&[(5, "a", None), (6, "b", Some("b6")), (4, "c", Some("c4"))]),
+            source(&[(1, "a", Some("a1")), (2, "b", Some("b2")), (3, "c", None)]),
+        ];
+        assert_eq!(collect(MergingIterator::new(sources(), 6)), vec![
+            ("b".to_string(), "b6".to_string()),
+            ("c".to_string(), "c4".to_string()),
+        ]);
+        assert_eq!(collect(MergingIterator::new(sources(), 3)), vec![
+            ("a".to_string(), "a1".to_string()),
+            ("b".to_string(), "b2".to_string()),
+        ]);
+        assert_eq!(collect(MergingIterator::new(sources(), 0)), vec![]);
+    }
+
+    #[test]
+    fn test_merge_range_deleted() {
+        let comparator = default_comparator();
+        let user_key = |key: &str| UserKey::new_owned(key.as_bytes().to_vec(), &comparator);
+        let sources = || vec![
+            source(&[(7, "b", Some("b7"))]),
+            source(&[(1, "a", Some("a1")), (2, "b", Some("b2")), (3, "c", Some("c3")), (4, "d", Some("d4"))]),
+        ];
+        let mut range_tombstones = RangeTombstones::new();
+        range_tombstones.add_flushed(RangeTombstone::new(5, user_key("b"), user_key("d")), 0);
+        assert_eq!(collect(MergingIterator::new(sources(), 9).with_range_del(range_tombstones.aggregator(9))), vec![
+            ("a".to_string(), "a1".to_string()),
+            ("b".to_string(), "b7".to_string()),
+            ("d".to_string(), "d4".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut iter = MergingIterator::new(vec![
+            source(&[(9, "b", Some("b9")), (8, "d", None), (10, "f", Some("f10"))]),
+            source(&[(5, "a", Some("a5")), (6, "c", Some("c6"))]),
+            source(&[(1, "a", Some("a1")), (2, "b", Some("b2")), (3, "c", Some("c3")), (4, "d", Some("d4")),
+                     (7, "e", Some("e7"))]),
+        ], 9);
+        let pair = |key: &str, value: &str| Some((key.as_bytes().to_vec(), value.as_bytes().to_vec()));
+
+        // older versions and deleted keys stay hidden after a seek, versions past read_seq too
+        iter.seek(b"b");
+        assert_eq!(iter.next(), pair("b", "b9"));
+        iter.seek(b"bb");
+        assert_eq!(iter.next(), pair("c", "c6"));
+        iter.seek(b"d");
+        assert_eq!(iter.next(), pair("e", "e7"));
+        assert_eq!(iter.next(), None);
+        // backwards
+        iter.seek(b"");
+        assert_eq!(iter.next(), pair("a", "a5"));
+        assert_eq!(iter.next(), pair("b", "b9"));
+        iter.seek(b"z");
+        assert_eq!(iter.next(), None);
+        assert!(iter.status().is_ok());
+    }
+}
@@ -1,31 +1,168 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
 use crate::table::Table;
 use crate::Comparator;
-use crate::table::sctable::ScTable;
+use crate::table::sctable::{ScTable, ScTableFile};
+use crate::error::Error;
+use crate::partition::{InternalKey, UserKey, LookupResult};
 
 pub struct Level<Comp: 'static + Comparator> {
-    tables: Vec<Box<dyn Table<Comp>>>,
-    file_id: u64
+    tables: Vec<Arc<dyn Table<Comp>>>,
+    size: usize,
+    /// Tables of level 1 and above are disjoint and sorted by bounds, those of level 0 may overlap
+    /// and are in the order they were added.
+    disjoint: bool
 }
 
 impl<Comp: 'static + Comparator> Level<Comp> {
-    pub(crate) fn new() -> Self {
+    /// An empty level `level_number` of a partition.
+    pub(crate) fn new(level_number: usize) -> Self {
         Self {
             tables: Vec::new(),
-            file_id: 1
+            size: 0,
+            disjoint: level_number != 0
         }
     }
 
     pub(crate) fn add_file(&mut self, table_file: ScTable<Comp>) {
-        self.tables.push(Box::new(table_file));
+        self.add_table(Arc::new(table_file));
+    }
+
+    /// Adds a table which may be shared with another partition.
+    pub(crate) fn add_table(&mut self, table: Arc<dyn Table<Comp>>) {
+        self.size += table.file_size();
+        self.tables.push(table);
+    }
+
+    pub(crate) fn remove_files(&mut self, table_files: &[ScTableFile]) {
+        let size = &mut self.size;
+        self.tables.retain(|table| {
+            let keep = !table_files.contains(&table.table_file());
+            if !keep {
+                *size -= table.file_size();
+            }
+            keep
+        });
+    }
+
+    /// Restores the order of a level whose tables are disjoint (level 1 and above).
+    pub(crate) fn sort_by_bounds(&mut self) {
+        self.tables.sort_by(|lhs, rhs| lhs.lower_bound().cmp(rhs.lower_bound()));
+    }
+
+    pub(crate) fn tables(&self) -> &[Arc<dyn Table<Comp>>] {
+        &self.tables
+    }
+
+    /// The tables whose bounds include `user_key`, newest first. A disjoint level has at most one,
+    /// which is binary searched for.
+    pub(crate) fn overlapping_key(&self, user_key: &UserKey<Comp>) -> Vec<&Arc<dyn Table<Comp>>> {
+        if !self.disjoint {
+            return self.tables.iter().rev().filter(|table| table.cmp_key(user_key) == Ordering::Equal).collect()
+        }
+        match self.tables.binary_search_by(|table| table.cmp_key(user_key).reverse()) {
+            Ok(idx) => vec![&self.tables[idx]],
+            Err(_) => Vec::new()
+        }
+    }
+
+    /// The tables which may hold keys in `[lower, upper]`, in the order of the level. The tables of
+    /// a disjoint level doing so are next to each other, the first one is binary searched for.
+    pub(crate) fn overlapping(&self, lower: &UserKey<Comp>, upper: &UserKey<Comp>) -> Vec<&Arc<dyn Table<Comp>>> {
+        if !self.disjoint {
+            return self.tables.iter().filter(|table| table.overlaps(lower, upper)).collect()
+        }
+        let first = self.tables.partition_point(|table| table.upper_bound() < lower);
+        self.tables[first..].iter().take_while(|table| table.lower_bound() <= upper).collect()
+    }
+
+    pub(crate) fn contains(&self, table_file: ScTableFile) -> bool {
+        self.tables.iter().any(|table| table.table_file() == table_file)
     }
 
     pub(crate) fn table_count(&self) -> usize {
         self.tables.len()
     }
 
-    pub(crate) fn level_next_file_id(&mut self) -> u64 {
-        let ret = self.file_id;
-        self.file_id += 1;
-        ret
+    /// Bytes the tables of this level take on disk.
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Asks the tables whose bounds include the key of `key`, newest first, until one has it.
+    pub(crate) fn get<T>(&self,
+                         key: &InternalKey<Comp>,
+                         mut lookup: impl FnMut(&Arc<dyn Table<Comp>>) -> Result<LookupResult<T>, Error>)
+        -> Result<LookupResult<T>, Error> {
+        for table in self.overlapping_key(&key.user_key) {
+            if let Some(value) = lookup(table)? {
+                return Ok(Some(value))
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::DefaultComparator;
+    use crate::partition::UserKey;
+    use crate::partition::level::Level;
+    use crate::table::sctable::{ScTable, ScTableFile};
+    use crate::testutil::default_comparator;
+
+    fn level(level_number: usize, bounds: &[(&str, &str)]) -> Level<DefaultComparator> {
+        let comparator = default_comparator();
+        let mut level = Level::new(level_number);
+        for (n, (lower, upper)) in bounds.iter().enumerate() {
+            level.add_file(ScTable::new(ScTableFile::new(n as u64 + 1), 100,
+                                        UserKey::new_owned(lower.as_bytes().to_vec(), &comparator),
+                                        UserKey::new_owned(upper.as_bytes().to_vec(), &comparator)));
+        }
+        level
+    }
+
+    fn key_numbers(level: &Level<DefaultComparator>, key: &str) -> Vec<u64> {
+        let key = UserKey::new_borrow(key.as_bytes(), &default_comparator());
+        level.overlapping_key(&key).iter().map(|table| table.table_file().file_number()).collect()
+    }
+
+    fn range_numbers(level: &Level<DefaultComparator>, lower: &str, upper: &str) -> Vec<u64> {
+        let comparator = default_comparator();
+        let (lower, upper) = (UserKey::new_borrow(lower.as_bytes(), &comparator),
+                              UserKey::new_borrow(upper.as_bytes(), &comparator));
+        level.overlapping(&lower, &upper).iter().map(|table| table.table_file().file_number()).collect()
+    }
+
+    #[test]
+    fn test_overlapping_level0() {
+        let level = level(0, &[("b", "f"), ("a", "c"), ("e", "h"), ("x", "z")]);
+        // newest first
+        assert_eq!(key_numbers(&level, "b"), vec![2, 1]);
+        assert_eq!(key_numbers(&level, "e"), vec![3, 1]);
+        assert_eq!(key_numbers(&level, "f"), vec![3, 1]);
+        assert_eq!(key_numbers(&level, "i"), Vec::<u64>::new());
+        assert_eq!(key_numbers(&level, "0"), Vec::<u64>::new());
+
+        assert_eq!(range_numbers(&level, "c", "e"), vec![1, 2, 3]);
+        assert_eq!(range_numbers(&level, "g", "y"), vec![3, 4]);
+        assert_eq!(range_numbers(&level, "i", "w"), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_overlapping_disjoint() {
+        let level = level(1, &[("a", "c"), ("e", "g"), ("h", "k"), ("x", "z")]);
+        assert_eq!(key_numbers(&level, "a"), vec![1]);
+        assert_eq!(key_numbers(&level, "g"), vec![2]);
+        assert_eq!(key_numbers(&level, "h"), vec![3]);
+        assert_eq!(key_numbers(&level, "d"), Vec::<u64>::new());
+        assert_eq!(key_numbers(&level, "zz"), Vec::<u64>::new());
+
+        assert_eq!(range_numbers(&level, "b", "e"), vec![1, 2]);
+        assert_eq!(range_numbers(&level, "d", "d"), Vec::<u64>::new());
+        assert_eq!(range_numbers(&level, "f", "y"), vec![2, 3, 4]);
+        assert_eq!(range_numbers(&level, "l", "w"), Vec::<u64>::new());
+        assert_eq!(range_numbers(&level, "0", "zz"), vec![1, 2, 3, 4]);
     }
 }
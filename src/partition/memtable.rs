@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::Comparator;
+use crate::partition::{InternalKey, UserKey};
+use crate::partition::arena::Arena;
+use crate::partition::skiplist::SkipListMemTable;
+
+/// What a partition needs of a memtable: entries kept in `InternalKey` order, found and iterated
+/// from any key on. `BTreeMemTable` is the one partitions use, others only have to keep the same
+/// order to take its place. Keys and values are copied into the memtable, into an `Arena` for both
+/// kinds there are.
+pub(crate) trait MemTableImpl<Comp: Comparator>: Send + Sync {
+    /// Inserts a copy of an entry. Returns whether the very same version was there, whose value is
+    /// replaced then.
+    fn insert(&mut self, key: &InternalKey<'static, Comp>, value: &[u8]) -> bool;
+
+    /// Returns whether the entry was there.
+    fn remove(&mut self, key: &InternalKey<'static, Comp>) -> bool;
+
+    /// The first entry not ordered before `key`, which is the version of its user key `key` reads if
+    /// the user keys match.
+    fn get<'a>(&'a self, key: &InternalKey<'a, Comp>) -> Option<(&'a InternalKey<'a, Comp>, &'a [u8])>;
+
+    /// The entries in order, from `lower_bound` on if given.
+    fn iter<'a>(&'a self, lower_bound: Option<&InternalKey<'a, Comp>>)
+        -> Box<dyn Iterator<Item = (&'a InternalKey<'a, Comp>, &'a [u8])> + 'a>;
+
+    fn first_key(&self) -> Option<&InternalKey<'_, Comp>>;
+
+    fn last_key(&self) -> Option<&InternalKey<'_, Comp>>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes of the keys and values held, what they take in a table apart from the catalog.
+    fn approximate_size(&self) -> usize;
+
+    /// Bytes of the keys and values copied in so far. Entries replaced or removed keep theirs until
+    /// the memtable is dropped, so unlike `approximate_size` this never shrinks.
+    fn arena_size(&self) -> usize;
+
+    /// An empty memtable of the same kind, to take over once this one is full.
+    fn new_empty(&self) -> Box<dyn MemTableImpl<Comp>>;
+
+    /// The memtable as a skiplist writers may insert into at the same time through
+    /// `SkipListMemTable::insert_concurrently`, `None` if it only takes inserts through `insert`.
+    fn concurrent(&self) -> Option<&SkipListMemTable<Comp>> {
+        None
+    }
+}
+
+/// Copies `key` and `value` into `arena`. They are only valid as long as the arena, which the
+/// `'static` they come with does not tell: the memtable owning the arena must hand them out
+/// borrowed from itself only, and drop them before the arena.
+pub(crate) unsafe fn copy_entry<Comp: Comparator>(arena: &Arena,
+                                                  key: &InternalKey<Comp>,
+                                                  value: &[u8]) -> (InternalKey<'static, Comp>, &'static [u8]) {
+    let user_key = &*(arena.copy(key.user_key.key()) as *const [u8]);
+    let value = &*(arena.copy(value) as *const [u8]);
+    let user_key = UserKey::new_borrow(user_key, key.user_key.comparator());
+    (InternalKey { seq: key.seq, kind: key.kind, user_key }, value)
+}
+
+/// A memtable kept in a `BTreeMap`.
+pub(crate) struct BTreeMemTable<Comp: 'static + Comparator> {
+    /// Keys and values in `arena`, declared first so they are dropped before it.
+    map: BTreeMap<InternalKey<'static, Comp>, &'static [u8]>,
+    data_size: usize,
+    arena: Arena
+}
+
+impl<Comp: 'static + Comparator> BTreeMemTable<Comp> {
+    pub(crate) fn new() -> Self {
+        Self { map: BTreeMap::new(), data_size: 0, arena: Arena::new() }
+    }
+}
+
+impl<Comp: 'static + Comparator> MemTableImpl<Comp> for BTreeMemTable<Comp> {
+    fn insert(&mut self, key: &InternalKey<'static, Comp>, value: &[u8]) -> bool {
+        self.data_size += value.len();
+        if let Some(old_value) = self.map.get_mut(key) {
+            self.data_size -= old_value.len();
+            *old_value = unsafe { &*(self.arena.copy(value) as *const [u8]) };
+            return true
+        }
+        self.data_size += key.user_key.key().len();
+        let (key, value) = unsafe { copy_entry(&self.arena, key, value) };
+        self.map.insert(key, value);
+        false
+    }
+
+    fn remove(&mut self, key: &InternalKey<'static, Comp>) -> bool {
+        match self.map.remove(key) {
+            Some(value) => {
+                self.data_size -= key.user_key.key().len() + value.len();
+                true
+            },
+            None => false
+        }
+    }
+
+    fn get<'a>(&'a self, key: &InternalKey<'a, Comp>) -> Option<(&'a InternalKey<'a, Comp>, &'a [u8])> {
+        self.map.range(key..).next().map(|(key, value)| (key, *value))
+    }
+
+    fn iter<'a>(&'a self, lower_bound: Option<&InternalKey<'a, Comp>>)
+        -> Box<dyn Iterator<Item = (&'a InternalKey<'a, Comp>, &'a [u8])> + 'a> {
+        match lower_bound {
+            Some(lower_bound) => Box::new(self.map.range((Bound::Included(lower_bound), Bound::Unbounded))
+                .map(|(key, value)| (key, *value))),
+            None => Box::new(self.map.iter().map(|(key, value)| (key, *value)))
+        }
+    }
+
+    fn first_key(&self) -> Option<&InternalKey<'_, Comp>> {
+        self.map.keys().next()
+    }
+
+    fn last_key(&self) -> Option<&InternalKey<'_, Comp>> {
+        self.map.keys().next_back()
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.data_size
+    }
+
+    fn arena_size(&self) -> usize {
+        self.arena.used()
+    }
+
+    fn new_empty(&self) -> Box<dyn MemTableImpl<Comp>> {
+        Box::new(Self::new())
+    }
+}
+
+/// Iterates a memtable in `InternalKey` order, optionally starting from a user key. With
+/// `latest_only` set, only the newest version of each user key is yielded, tombstones included,
+/// so the result reads as the logical content of the memtable.
+pub(crate) struct MemTableIterator<'a, Comp: Comparator> {
+    inner: Box<dyn Iterator<Item = (&'a InternalKey<'a, Comp>, &'a [u8])> + 'a>,
+    latest_only: bool,
+    last_user_key: Option<&'a UserKey<'a, Comp>>
+}
+
+impl<'a, Comp: Comparator> MemTableIterator<'a, Comp> {
+    pub(crate) fn new(table: &'a dyn MemTableImpl<Comp>,
+                      lower_bound: Option<&'a [u8]>,
+                      comparator: &Arc<Comp>,
+                      latest_only: bool) -> Self {
+        let seek_key = lower_bound.map(|lower_bound| {
+            InternalKey::new(u64::max_value(), UserKey::new_borrow(lower_bound, comparator))
+        });
+        let inner = table.iter(seek_key.as_ref());
+        Self { inner, latest_only, last_user_key: None }
+    }
+}
+
+impl<'a, Comp: Comparator> Iterator for MemTableIterator<'a, Comp> {
+    type Item = (&'a InternalKey<'a, Comp>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = self.inner.next()?;
+            if self.latest_only {
+                if self.last_user_key == Some(&key.user_key) {
+                    continue;
+                }
+                self.last_user_key.replace(&key.user_key);
+            }
+            return Some((key, value))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::DefaultComparator;
+    use crate::partition::{InternalKey, UserKey};
+    use crate::partition::memtable::{BTreeMemTable, MemTableImpl, MemTableIterator};
+    use crate::testutil::default_comparator;
+
+    fn memtable() -> BTreeMemTable<DefaultComparator> {
+        let comparator = default_comparator();
+        let mut table = BTreeMemTable::new();
+        for &(seq, key, value) in [(1, "b", "b1"), (4, "b", "b4"), (2, "a", "a2"), (3, "c", "c3"), (5, "a", "")].iter() {
+            let key = UserKey::new_owned(key.as_bytes().to_vec(), &comparator);
+            let key = if value.is_empty() { InternalKey::new_deletion(seq, key) } else { InternalKey::new(seq, key) };
+            table.insert(&key, value.as_bytes());
+        }
+        table
+    }
+
+    fn collect(iter: MemTableIterator<DefaultComparator>) -> Vec<(u64, Vec<u8>, Vec<u8>)> {
+        iter.map(|(k, v)| (k.seq, k.user_key.key().to_vec(), v.to_vec())).collect()
+    }
+
+    #[test]
+    fn test_btree_memtable() {
+        let comparator = default_comparator();
+        let mut table = memtable();
+        assert_eq!(table.len(), 5);
+        // keys and values: b1, b4, a2, c3 and the tombstone of a
+        assert_eq!(table.approximate_size(), 3 + 3 + 3 + 3 + 1);
+        assert_eq!(table.first_key().map(|key| (key.seq, key.user_key.key())), Some((5, b"a".as_ref())));
+        assert_eq!(table.last_key().map(|key| (key.seq, key.user_key.key())), Some((3, b"c".as_ref())));
+
+        // a lookup finds the newest version at or below its seq, or the next key
+        let get = |table: &BTreeMemTable<DefaultComparator>, seq, key: &[u8]| {
+            table.get(&InternalKey::new(seq, UserKey::new_borrow(key, &comparator)))
+                .map(|(k, v)| (k.seq, k.user_key.key().to_vec(), v.to_vec()))
+        };
+        assert_eq!(get(&table, 3, b"b"), Some((1, b"b".to_vec(), b"b1".to_vec())));
+        assert_eq!(get(&table, 0, b"b"), Some((3, b"c".to_vec(), b"c3".to_vec())));
+        assert_eq!(get(&table, 9, b"d"), None);
+
+        let key = InternalKey::new(1, UserKey::new_owned(b"b".to_vec(), &comparator));
+        assert!(table.insert(&key, b"b1-again"));
+        assert_eq!(get(&table, 1, b"b"), Some((1, b"b".to_vec(), b"b1-again".to_vec())));
+        assert_eq!(table.approximate_size(), 9 + 3 + 3 + 3 + 1);
+        assert!(table.remove(&key));
+        assert!(!table.remove(&key));
+        assert_eq!((table.len(), table.approximate_size()), (4, 3 + 3 + 3 + 1));
+
+        let empty = table.new_empty();
+        assert!(empty.is_empty());
+        assert_eq!((empty.approximate_size(), empty.iter(None).count()), (0, 0));
+    }
+
+    #[test]
+    fn test_iterate_all_versions() {
+        let table = memtable();
+        assert_eq!(collect(MemTableIterator::new(&table, None, &default_comparator(), false)), vec![
+            (5, b"a".to_vec(), b"".to_vec()),
+            (2, b"a".to_vec(), b"a2".to_vec()),
+            (4, b"b".to_vec(), b"b4".to_vec()),
+            (1, b"b".to_vec(), b"b1".to_vec()),
+            (3, b"c".to_vec(), b"c3".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_iterate_latest_from_lower_bound() {
+        let table = memtable();
+        assert_eq!(collect(MemTableIterator::new(&table, None, &default_comparator(), true)), vec![
+            (5, b"a".to_vec(), b"".to_vec()),
+            (4, b"b".to_vec(), b"b4".to_vec()),
+            (3, b"c".to_vec(), b"c3".to_vec()),
+        ]);
+        assert_eq!(collect(MemTableIterator::new(&table, Some(b"aa"), &default_comparator(), true)), vec![
+            (4, b"b".to_vec(), b"b4".to_vec()),
+            (3, b"c".to_vec(), b"c3".to_vec()),
+        ]);
+        assert_eq!(collect(MemTableIterator::new(&table, Some(b"b"), &default_comparator(), false)).len(), 3);
+        assert!(collect(MemTableIterator::new(&table, Some(b"d"), &default_comparator(), true)).is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{Comparator, MergeOperator};
+use crate::partition::{InternalKey, ValueKind};
+use crate::partition::range_tombstone::RangeDelAggregator;
+
+/// Gathers the versions of one user key, newest first, until they make up its value: merge
+/// operands are collected until a value or a tombstone below them is reached, or a version hidden
+/// by a range tombstone, which counts as a tombstone.
+pub(crate) struct MergeState {
+    /// Newest first, the merge operator takes them the other way round.
+    operands: Vec<Vec<u8>>,
+    /// `Some(None)` once a tombstone is reached.
+    base: Option<Option<Vec<u8>>>,
+    newest_seq: Option<u64>
+}
+
+impl MergeState {
+    pub(crate) fn new() -> Self {
+        Self { operands: Vec::new(), base: None, newest_seq: None }
+    }
+
+    /// Takes the next older version. Versions after the one completing the value are ignored.
+    pub(crate) fn push<Comp: Comparator>(&mut self, key: &InternalKey<Comp>, value: Vec<u8>) {
+        if self.is_done() {
+            return
+        }
+        self.newest_seq.get_or_insert(key.seq);
+        match key.kind {
+            ValueKind::Merge => self.operands.push(value),
+            ValueKind::Value => self.base = Some(Some(value)),
+            ValueKind::Deletion | ValueKind::RangeDeletion => self.base = Some(None)
+        }
+    }
+
+    /// Like `push`, but takes a tombstone instead if `range_del` deletes the version.
+    pub(crate) fn push_unless_deleted<Comp: Comparator>(&mut self,
+                                                        key: &InternalKey<Comp>,
+                                                        value: Vec<u8>,
+                                                        range_del: Option<&RangeDelAggregator<Comp>>) {
+        if range_del.map_or(false, |range_del| range_del.should_delete(&key.user_key, key.seq)) {
+            self.push_deleted();
+        } else {
+            self.push(key, value);
+        }
+    }
+
+    /// Takes the next older version, which a range tombstone hides.
+    pub(crate) fn push_deleted(&mut self) {
+        if !self.is_done() {
+            self.base = Some(None);
+        }
+    }
+
+    /// Whether a value or a tombstone has been reached, older versions do not matter anymore.
+    pub(crate) fn is_done(&self) -> bool {
+        self.base.is_some()
+    }
+
+    /// The sequence number of the newest version taken.
+    pub(crate) fn newest_seq(&self) -> Option<u64> {
+        self.newest_seq
+    }
+
+    /// The value the versions taken make up, anything below them counts as missing. `None` if
+    /// there is no operand and no value.
+    pub(crate) fn finish(self, user_key: &[u8], merge_operator: &dyn MergeOperator) -> Option<Vec<u8>> {
+        let base = self.base.flatten();
+        if self.operands.is_empty() {
+            return base
+        }
+        let operands: Vec<Vec<u8>> = self.operands.into_iter().rev().collect();
+        Some(merge_operator.merge(user_key, base.as_deref(), &operands))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{DefaultComparator, U64AddMergeOperator};
+    use crate::partition::{InternalKey, UserKey};
+    use crate::partition::merge::MergeState;
+    use crate::testutil::default_comparator;
+
+    fn key() -> UserKey<'static, DefaultComparator> {
+        UserKey::new_owned(b"counter".to_vec(), &default_comparator())
+    }
+
+    fn number(value: u64) -> Vec<u8> {
+        value.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_fold_operands() {
+        let mut state = MergeState::new();
+        state.push(&InternalKey::new_merge(5, key()), number(2));
+        state.push(&InternalKey::new_merge(4, key()), number(3));
+        assert!(!state.is_done());
+        state.push(&InternalKey::new(3, key()), number(10));
+        assert!(state.is_done());
+        // hidden by the value below the operands
+        state.push(&InternalKey::new(2, key()), number(100));
+        assert_eq!(state.newest_seq(), Some(5));
+        assert_eq!(state.finish(b"counter", &U64AddMergeOperator()), Some(number(15)));
+
+        let mut state = MergeState::new();
+        state.push(&InternalKey::new_merge(5, key()), number(2));
+        state.push(&InternalKey::new_deletion(4, key()), Vec::new());
+        assert_eq!(state.finish(b"counter", &U64AddMergeOperator()), Some(number(2)));
+
+        let mut state = MergeState::new();
+        state.push_deleted();
+        assert_eq!(state.finish(b"counter", &U64AddMergeOperator()), None);
+        assert_eq!(MergeState::new().finish(b"counter", &U64AddMergeOperator()), None);
+    }
+}
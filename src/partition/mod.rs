@@ -1,51 +1,87 @@
-use std::collections::BTreeMap;
-use std::sync::{Mutex, atomic::AtomicU64, Condvar, Arc};
-use std::marker::PhantomData;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard, Condvar, Arc, RwLock, RwLockReadGuard};
 use std::cmp::Ordering;
-use std::ptr::NonNull;
+use std::ops::Range;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::{Comparator, Options, DefaultComparator};
+use crate::{Comparator, MemTableKind, MergeOperator, Partitioning, SyncPolicy};
+use crate::db::{DbContext, PartitionInfo, PinnedValue};
 use crate::table::tablefmt::{TABLE_CATALOG_ITEM_SIZE, TABLE_MIN_SIZE};
 use crate::table::builder::ScTableBuilder;
-use crate::table::cache::TableCacheManager;
+use crate::table::cache::{TableCacheManager, KeyCursor, TableLayout, assign_seq, assign_seq_to_catalog,
+                          catalog_end, table_layout};
 use crate::io::IOManager;
+use crate::encode::{decode_fixed32, decode_fixed64, encode_fixed32_ret, encode_fixed64_ret};
 use crate::error::Error;
 use crate::partition::level::Level;
 use crate::table::sctable::{ScTable, ScTableFile};
-use std::sync::atomic::AtomicBool;
+use crate::table::Table;
+use crate::partition::compaction::{merge_entries, build_tables, split_runs};
+use crate::partition::merge::MergeState;
+use crate::partition::range_tombstone::{RangeTombstone, RangeTombstones};
+use crate::partition::iterator::{MergingIterator, TableIterator, LevelIterator, MemTableSource, EntryIterator};
+use crate::partition::memtable::{BTreeMemTable, MemTableImpl};
+use crate::partition::skiplist::SkipListMemTable;
+use crate::wal::{BatchParts, LogWriter, log_file_name, parse_log_file_name, decode_records};
+use crate::manifest::{PartitionRecord, RangeTombstoneRecord, TableRecord};
+use std::sync::atomic::{self, AtomicBool};
 
+mod arena;
 mod level;
+mod compaction;
+mod merge;
+mod range_tombstone;
+pub(crate) mod memtable;
+mod skiplist;
+pub(crate) mod iterator;
+pub(crate) mod set;
 
-pub(crate) enum UserKey<Comp: Comparator> {
-    Owned(Vec<u8>, PhantomData<Comp>),
-    Borrow(NonNull<[u8]>)
+/// A user key, ordered by the comparator it carries. Every key of a database shares the same
+/// comparator instance. Lookups borrow the key they are given instead of copying it, and memtables
+/// borrow their keys from their arena. Everything else a partition stores is `Owned`.
+pub(crate) enum UserKey<'a, Comp: Comparator> {
+    Owned(Vec<u8>, Arc<Comp>),
+    Borrow(&'a [u8], Arc<Comp>)
 }
 
-impl<Comp: Comparator> Clone for UserKey<Comp> {
+impl<Comp: Comparator> Clone for UserKey<'_, Comp> {
     fn clone(&self) -> Self {
         match self {
-            UserKey::Owned(data, _) => UserKey::Owned(data.clone(), PhantomData),
-            UserKey::Borrow(ptr) => UserKey::Borrow(ptr.clone())
+            UserKey::Owned(data, comparator) => UserKey::Owned(data.clone(), comparator.clone()),
+            UserKey::Borrow(data, comparator) => UserKey::Borrow(data, comparator.clone())
         }
     }
 }
 
-impl<Comp: Comparator> UserKey<Comp> {
-    pub(crate) fn new_owned(vec: Vec<u8>) -> Self {
-        UserKey::Owned(vec, PhantomData)
+impl<Comp: Comparator> UserKey<'static, Comp> {
+    pub(crate) fn new_owned(vec: Vec<u8>, comparator: &Arc<Comp>) -> Self {
+        UserKey::Owned(vec, comparator.clone())
     }
+}
 
-    pub(crate) fn new_borrow(slice: &[u8]) -> Self {
-        UserKey::Borrow(unsafe { NonNull::new_unchecked(slice as *const [u8] as _) })
+impl<'a, Comp: Comparator> UserKey<'a, Comp> {
+    pub(crate) fn new_borrow(slice: &'a [u8], comparator: &Arc<Comp>) -> Self {
+        UserKey::Borrow(slice, comparator.clone())
     }
 
-    fn key(&self) -> &[u8] {
+    pub(crate) fn key(&self) -> &[u8] {
         match self {
             UserKey::Owned(k, _) => k.as_slice(),
-            UserKey::Borrow(b) => unsafe { b.as_ref() }
+            UserKey::Borrow(b, _) => b
+        }
+    }
+
+    pub(crate) fn comparator(&self) -> &Arc<Comp> {
+        match self {
+            UserKey::Owned(_, comparator) | UserKey::Borrow(_, comparator) => comparator
         }
     }
 
+    pub(crate) fn make_owned(&self) -> UserKey<'static, Comp> {
+        UserKey::new_owned(self.key().to_vec(), self.comparator())
+    }
+
     fn is_owned(&self) -> bool {
         if let UserKey::Owned(_, _) = self {
             true
@@ -55,343 +91,2224 @@ impl<Comp: Comparator> UserKey<Comp> {
     }
 }
 
-impl<Comp: Comparator> Ord for UserKey<Comp> {
+impl<Comp: Comparator> Ord for UserKey<'_, Comp> {
     fn cmp(&self, other: &Self) -> Ordering {
-        Comp::compare(&self.key(), &other.key())
+        self.comparator().compare(&self.key(), &other.key())
     }
 }
 
-impl<Comp: Comparator> PartialOrd for UserKey<Comp> {
+impl<Comp: Comparator> PartialOrd for UserKey<'_, Comp> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<Comp: Comparator> PartialEq for UserKey<Comp> {
+impl<Comp: Comparator> PartialEq for UserKey<'_, Comp> {
     fn eq(&self, other: &Self) -> bool {
         self.cmp(other) == Ordering::Equal
     }
 }
 
-impl<Comp: Comparator> Eq for UserKey<Comp> {}
+impl<Comp: Comparator> Eq for UserKey<'_, Comp> {}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum ValueKind {
+    Deletion = 0,
+    Value = 1,
+    /// Deletes every user key from its own up to the one in its value, exclusive. Only ever logged,
+    /// the partition keeps it apart from the memtable, see `RangeTombstones`.
+    RangeDeletion = 2,
+    /// An operand of `Db::merge`, folded over the older versions by `Options::merge_operator`.
+    Merge = 3
+}
+
+impl ValueKind {
+    /// The kind logs and tables store as `byte`, `None` if it is none of ours.
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ValueKind::Deletion),
+            1 => Some(ValueKind::Value),
+            2 => Some(ValueKind::RangeDeletion),
+            3 => Some(ValueKind::Merge),
+            _ => None
+        }
+    }
+
+    /// Kinds are ordered by this rather than by their number, which is what logs and tables store.
+    fn rank(self) -> u8 {
+        match self {
+            ValueKind::Deletion => 0,
+            ValueKind::Merge => 1,
+            ValueKind::Value => 2,
+            ValueKind::RangeDeletion => 3
+        }
+    }
+}
+
+impl Ord for ValueKind {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
+
+impl PartialOrd for ValueKind {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Bytes of an encoded `InternalKey` in front of its user key.
+pub(crate) const INTERNAL_KEY_HEAD_SIZE: usize = 13;
+/// Bytes of the sequence number and the kind, which table catalog items start with as well.
+pub(crate) const SEQ_KIND_SIZE: usize = 9;
+
+/// Appends `seq` and `kind` the way `InternalKey::encode_into` starts.
+pub(crate) fn encode_seq_kind(seq: u64, kind: ValueKind, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&encode_fixed64_ret(seq));
+    buf.push(kind as u8);
+}
+
+/// Reads what `encode_seq_kind` wrote at the start of `bytes`.
+pub(crate) fn decode_seq_kind(bytes: &[u8]) -> Result<(u64, ValueKind), Error> {
+    if bytes.len() < SEQ_KIND_SIZE {
+        return Err(Error::decode_error("internal key cut short".into()))
+    }
+    let kind = ValueKind::from_byte(bytes[8])
+        .ok_or_else(|| Error::decode_error(format!("unknown value kind {}", bytes[8]).into()))?;
+    Ok((decode_fixed64(&bytes[0..8]), kind))
+}
+
+pub(crate) struct InternalKey<'a, Comp: Comparator> {
+    pub(crate) seq: u64,
+    pub(crate) kind: ValueKind,
+    pub(crate) user_key: UserKey<'a, Comp>
+}
+
+impl<'a, Comp: Comparator> InternalKey<'a, Comp> {
+    pub(crate) fn new(seq: u64, user_key: UserKey<'a, Comp>) -> Self {
+        Self { seq, kind: ValueKind::Value, user_key }
+    }
+
+    pub(crate) fn new_deletion(seq: u64, user_key: UserKey<'a, Comp>) -> Self {
+        Self { seq, kind: ValueKind::Deletion, user_key }
+    }
+
+    /// Goes with the end of the range as its value.
+    pub(crate) fn new_range_deletion(seq: u64, start: UserKey<'a, Comp>) -> Self {
+        Self { seq, kind: ValueKind::RangeDeletion, user_key: start }
+    }
+
+    pub(crate) fn new_merge(seq: u64, user_key: UserKey<'a, Comp>) -> Self {
+        Self { seq, kind: ValueKind::Merge, user_key }
+    }
+
+    pub(crate) fn make_owned(&self) -> InternalKey<'static, Comp> {
+        InternalKey { seq: self.seq, kind: self.kind, user_key: self.user_key.make_owned() }
+    }
+
+    pub(crate) fn is_deletion(&self) -> bool {
+        self.kind == ValueKind::Deletion
+    }
+
+    pub(crate) fn is_merge(&self) -> bool {
+        self.kind == ValueKind::Merge
+    }
 
-type DefaultUserKey = UserKey<DefaultComparator>;
+    /// Appends the encoding logs store entries under, all numbers big-endian:
+    ///
+    /// ```text
+    /// | seq: u64 | kind: u8 | key_length: u32 | key |
+    /// ```
+    ///
+    /// Table catalog items start with the same `seq` and `kind`, their keys are prefix compressed
+    /// in the data region instead.
+    pub(crate) fn encode_into(&self, buf: &mut Vec<u8>) {
+        let user_key = self.user_key.key();
+        encode_seq_kind(self.seq, self.kind, buf);
+        buf.extend_from_slice(&encode_fixed32_ret(user_key.len() as u32));
+        buf.extend_from_slice(user_key);
+    }
 
-pub(crate) struct InternalKey<Comp: Comparator> {
-    seq: u64,
-    pub(crate) user_key: UserKey<Comp>
+    /// The number of bytes `encode_into` appends.
+    pub(crate) fn encoded_size(&self) -> usize {
+        INTERNAL_KEY_HEAD_SIZE + self.user_key.key().len()
+    }
 }
 
-impl<Comp: Comparator> InternalKey<Comp> {
-    pub(crate) fn new(seq: u64, user_key: UserKey<Comp>) -> Self {
-        Self { seq, user_key }
+impl<Comp: Comparator> InternalKey<'static, Comp> {
+    /// Decodes a key written by `encode_into` at the start of `bytes`, returning it with the number
+    /// of bytes it took.
+    pub(crate) fn decode(bytes: &[u8], comparator: &Arc<Comp>) -> Result<(Self, usize), Error> {
+        let (seq, kind) = decode_seq_kind(bytes)?;
+        if bytes.len() < INTERNAL_KEY_HEAD_SIZE {
+            return Err(Error::decode_error("internal key cut short".into()))
+        }
+        let key_end = INTERNAL_KEY_HEAD_SIZE + decode_fixed32(&bytes[SEQ_KIND_SIZE..INTERNAL_KEY_HEAD_SIZE]) as usize;
+        if bytes.len() < key_end {
+            return Err(Error::decode_error("internal key cut short".into()))
+        }
+        let user_key = UserKey::new_owned(bytes[INTERNAL_KEY_HEAD_SIZE..key_end].to_vec(), comparator);
+        Ok((InternalKey { seq, kind, user_key }, key_end))
     }
 }
 
-impl<Comp: Comparator> Ord for InternalKey<Comp> {
+/// Internal keys are ordered by user key first, and newer versions (larger `seq`) of the same
+/// user key come before older ones, so a lookup always meets the newest visible version first.
+/// At the same `seq` a value sorts before a merge operand and a deletion, so a lookup key built by
+/// `InternalKey::new` never skips an entry it should see.
+impl<Comp: Comparator> Ord for InternalKey<'_, Comp> {
     fn cmp(&self, other: &Self) -> Ordering {
-        let ord =  self.seq.cmp(&other.seq);
-        if ord == Ordering::Equal {
-            self.user_key.cmp(&other.user_key)
-        } else {
+        let ord = self.user_key.cmp(&other.user_key);
+        if ord != Ordering::Equal {
             ord
+        } else if self.seq != other.seq {
+            other.seq.cmp(&self.seq)
+        } else {
+            other.kind.cmp(&self.kind)
         }
     }
 }
 
-impl<Comp: Comparator> PartialOrd for InternalKey<Comp> {
+impl<Comp: Comparator> PartialOrd for InternalKey<'_, Comp> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<Comp: Comparator> PartialEq for InternalKey<Comp> {
+impl<Comp: Comparator> PartialEq for InternalKey<'_, Comp> {
     fn eq(&self, other: &Self) -> bool {
         self.cmp(other) == Ordering::Equal
     }
 }
 
-impl<Comp: Comparator> Eq for InternalKey<Comp> {}
+impl<Comp: Comparator> Eq for InternalKey<'_, Comp> {}
+
+/// Versions of a user key are ordered newest first. The active memtable only keeps the versions
+/// some reader can still see: the newest one, and older ones pinned by a snapshot taken before the
+/// next newer version was written. Overwriting a key again and again therefore does not make the
+/// memtable grow, unless snapshots are taken in between. A skiplist, which writers insert into at
+/// the same time, keeps the hidden versions until it is flushed, see `Partition::put_entries`.
+type MemTable<Comp> = Box<dyn MemTableImpl<Comp>>;
+
+fn new_memtable<Comp: 'static + Comparator>(kind: MemTableKind) -> MemTable<Comp> {
+    match kind {
+        MemTableKind::BTree => Box::new(BTreeMemTable::new()),
+        MemTableKind::SkipList => Box::new(SkipListMemTable::new())
+    }
+}
+
+pub(crate) type Entry<Comp> = (InternalKey<'static, Comp>, Vec<u8>);
+
+/// Lookups on every layer of a partition yield `Some(Some(value))` for a live value, `Some(None)`
+/// for a tombstone, which hides older layers, and `None` if the key is not there at all. Lookups
+/// which only ask whether the key exists yield `()` instead of the value.
+pub(crate) type LookupResult<T = Vec<u8>> = Option<Option<T>>;
+
+fn memtable_lookup<Comp, T>(table: &dyn MemTableImpl<Comp>,
+                            key: &InternalKey<Comp>,
+                            read_value: impl Fn(&InternalKey<Comp>, &[u8]) -> T) -> LookupResult<T>
+    where Comp: Comparator {
+    table.get(key)
+        .filter(|(k, _)| k.user_key == key.user_key)
+        .map(|(k, v)| if k.is_deletion() { None } else { Some(read_value(k, v)) })
+}
+
+/// Entries a writer inserts into a skiplist memtable after unlocking its partition, at the same
+/// time as other writers insert theirs. Until they are in, the guard keeps the memtable from being
+/// read or replaced, see `Partition::inserting`.
+struct PendingInserts<'a, Comp: 'static + Comparator> {
+    skiplist: *const SkipListMemTable<Comp>,
+    entries: Vec<Entry<Comp>>,
+    _inserting: RwLockReadGuard<'a, ()>
+}
+
+impl<Comp: 'static + Comparator> PendingInserts<'_, Comp> {
+    fn insert(self) {
+        // the memtable is neither replaced nor dropped while the guard is held
+        let skiplist = unsafe { &*self.skiplist };
+        for (key, value) in self.entries.iter() {
+            skiplist.insert_concurrently(key, value);
+        }
+    }
+}
+
+/// A table `Db::bulk_load` wrote for a partition, not linked into it yet. Every entry has the
+/// sequence number 0 until `ArcPartition::link` gives them theirs.
+pub(crate) struct StagedTable<Comp: 'static + Comparator> {
+    table_file: ScTableFile,
+    file_size: usize,
+    layout: TableLayout,
+    catalog_end: usize,
+    lower: UserKey<'static, Comp>,
+    upper: UserKey<'static, Comp>
+}
+
+impl<Comp: 'static + Comparator> StagedTable<Comp> {
+    /// Writes the table `raw`, holding the keys `[lower, upper]`, to a new table file.
+    pub(crate) fn write(context: &DbContext,
+                        raw: &[u8],
+                        lower: UserKey<'static, Comp>,
+                        upper: UserKey<'static, Comp>) -> Result<Self, Error> {
+        let layout = table_layout(raw)?;
+        let table_file = context.new_table_file();
+        if let Err(e) = context.io_manager.acquire_quota().write_atomic(table_file.file_name(), raw) {
+            let _ = context.io_manager.remove_file(table_file.file_name());
+            return Err(e)
+        }
+        context.stats.record_bytes_written(raw.len());
+        let catalog_end = catalog_end(raw, &layout);
+        Ok(StagedTable { table_file, file_size: raw.len(), layout, catalog_end, lower, upper })
+    }
+
+    pub(crate) fn file_name(&self) -> String {
+        self.table_file.file_name()
+    }
 
-type MemTable<Comp> = BTreeMap<InternalKey<Comp>, Vec<u8>>;
+    /// `assign_seq` on the file, rewriting nothing past its catalog.
+    fn assign_seq(&self, seq: u64, io_manager: &IOManager) -> Result<(), Error> {
+        let mut head = io_manager.acquire_quota().read_range(self.file_name(), 0, self.catalog_end)?;
+        assign_seq_to_catalog(&mut head, &self.layout, seq);
+        io_manager.acquire_quota().write_range(self.file_name(), 0, &head)
+    }
+}
 
-pub(crate) struct Partition<'a, Comp: 'static + Comparator> {
-    data: Mutex<PartitionData<'a, Comp>>,
+pub(crate) struct Partition<Comp: 'static + Comparator> {
+    data: Mutex<PartitionData<Comp>>,
     condvar: Condvar,
+    /// Writers waiting for the immutable memtable to be flushed. They are woken one at a time, and
+    /// each one leaving the wait wakes the next.
+    room: Condvar,
+    /// Held shared by writers inserting into a skiplist memtable after unlocking `data`, see
+    /// `PendingInserts`. Whoever reads the memtable or replaces it takes this exclusively once while
+    /// holding `data`, which waits for them and keeps new ones from starting until `data` is unlocked.
+    inserting: RwLock<()>,
 
     under_explode: AtomicBool,
 
     partition_id: u32,
 
-    seq: &'a AtomicU64,
-    cache_manager: &'a TableCacheManager,
-    io_manager: &'a IOManager,
-    options: &'a Options
+    comparator: Arc<Comp>,
+    context: Arc<DbContext>
 }
 
-impl<'a, Comp: 'static + Comparator> Partition<'a, Comp> {
-    fn new(options: &'a Options,
-           partition_id: u32,
-           seq: &'a AtomicU64,
-           cache_manager: &'a TableCacheManager,
-           io_manager: &'a IOManager) -> Self {
+impl<Comp: 'static + Comparator> Partition<Comp> {
+    pub(crate) fn new(context: Arc<DbContext>, partition_id: u32, comparator: Arc<Comp>) -> Self {
         Self {
-            data: Mutex::new(PartitionData::new(options)),
+            data: Mutex::new(PartitionData::new(context.clone())),
             condvar: Condvar::new(),
+            room: Condvar::new(),
+            inserting: RwLock::new(()),
             under_explode: AtomicBool::new(false),
             partition_id,
-            seq,
-            cache_manager,
-            io_manager,
-            options
+            comparator,
+            context
         }
     }
 
-    fn debug_never_eq_sanity_check(&self, other: &Self) -> bool {
-        if self as *const Self == other as *const Self {
-            return false;
+    /// Locks the partition once no writer is inserting into its memtable anymore.
+    fn lock(&self) -> MutexGuard<'_, PartitionData<Comp>> {
+        let data = self.data.lock().unwrap();
+        self.wait_for_inserts();
+        data
+    }
+
+    /// Waits on `condvar` like `lock` locks, the writers which got in meanwhile are done after it.
+    fn wait<'a>(&self, data: MutexGuard<'a, PartitionData<Comp>>) -> MutexGuard<'a, PartitionData<Comp>> {
+        let data = self.condvar.wait(data).unwrap();
+        self.wait_for_inserts();
+        data
+    }
+
+    /// Removes `table_files` along with their cached tables, once the manifest lists them no more.
+    fn remove_table_files(&self, table_files: Vec<(ScTableFile, usize)>) -> u64 {
+        let context = &self.context;
+        let mut reclaimed = 0;
+        for (table_file, file_size) in table_files {
+            context.cache_manager.evict(table_file);
+            if context.io_manager.remove_file(table_file.file_name()).is_ok() {
+                reclaimed += file_size as u64;
+            }
         }
+        context.stats.record_reclaimed(reclaimed);
+        reclaimed
+    }
+
+    /// Waits for the writers inserting into the memtable, with `data` locked by the caller.
+    fn wait_for_inserts(&self) {
+        drop(self.inserting.write().unwrap());
+    }
 
-        let g1 = self.data.lock().unwrap();
-        let g2 = other.data.lock().unwrap();
-        let (self_lower, self_upper) = g1.bounds();
-        let (other_lower, other_upper) = g2.bounds();
-        if self_lower.is_some() && self_upper.is_some()
-            && other_lower.is_some() && other_upper.is_some()
-            && (self_lower.unwrap().cmp(other_lower.unwrap()) == Ordering::Equal
-            || self_upper.unwrap().cmp(other_upper.unwrap()) == Ordering::Equal) {
-            return false;
+    /// Applies `entries`, which are logged already, to the locked `data`. A skiplist memtable only
+    /// gets the bookkeeping and the range tombstones right away, its other entries are returned to
+    /// be inserted once `data` is unlocked, so writers do not wait for each other to insert.
+    /// Versions they hide stay in the skiplist until it is flushed, removing them would take it
+    /// exclusively.
+    fn put_entries(&self, data: &mut PartitionData<Comp>, entries: &mut Vec<Entry<Comp>>) -> Option<PendingInserts<'_, Comp>> {
+        let skiplist = match data.mem_table.concurrent() {
+            Some(skiplist) => skiplist as *const SkipListMemTable<Comp>,
+            None => {
+                for (key, value) in entries.drain(..) {
+                    data.memtable_put(key, value);
+                }
+                return None
+            }
+        };
+        let mut pending = Vec::with_capacity(entries.len());
+        for (key, value) in entries.drain(..) {
+            if key.kind == ValueKind::RangeDeletion {
+                data.memtable_put(key, value);
+            } else {
+                data.record_write(&key);
+                data.extend_bounds(&key.user_key, &key.user_key);
+                pending.push((key, value));
+            }
         }
+        Some(PendingInserts { skiplist, entries: pending, _inserting: self.inserting.read().unwrap() })
+    }
 
-        true
+    /// A copy of the bounds, so two partitions never need to be locked at the same time.
+    fn bounds(&self) -> Option<(UserKey<'static, Comp>, UserKey<'static, Comp>)> {
+        let data = self.lock();
+        match data.bounds() {
+            (Some(lower_bound), Some(upper_bound)) => Some((lower_bound.clone(), upper_bound.clone())),
+            _ => None
+        }
     }
 }
 
-impl<'a, Comp: Comparator> PartialOrd for Partition<'a, Comp> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let g1 = self.data.lock().unwrap();
-        let g2 = other.data.lock().unwrap();
-        let (self_lower, self_upper) = g1.bounds();
-        let (other_lower, other_upper) = g2.bounds();
-
-        if self_upper.unwrap().cmp(&other_lower.unwrap()) == Ordering::Less {
-            return Some(Ordering::Less)
-        } else if self_lower.unwrap().cmp(&other_upper.unwrap()) == Ordering::Greater {
-            return Some(Ordering::Greater)
-        } else {
-            None
+impl<Comp: 'static + Comparator> Drop for Partition<Comp> {
+    /// A partition split away is dropped once nothing reads it anymore, which lets go of the last
+    /// references to the tables it was split from. The files of those and of the tables
+    /// compactions replaced are removed then, like `remove_obsolete_files` would.
+    fn drop(&mut self) {
+        if !self.under_explode.load(atomic::Ordering::SeqCst) {
+            return
         }
+        let unused_files = match self.data.get_mut() {
+            Ok(data) if data.background_error().is_ok() => {
+                data.levels.clear();
+                data.take_unused_tables()
+            },
+            _ => return
+        };
+        self.remove_table_files(unused_files);
+    }
+}
+
+/// Partitions are ordered by their bounds, which are disjoint for the partitions of one database,
+/// so this is the order of the keys they hold. A partition holding nothing yet has no bounds and
+/// comes before every other one. Two partitions are thus equal if they are the same one or have
+/// identical bounds, as a partition and its restored copy do; equality agrees with the order, so
+/// partitions behave in sets and sorted containers.
+impl<Comp: Comparator> PartialOrd for Partition<Comp> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
-impl<'a, Comp: Comparator> Ord for Partition<'a, Comp> {
+impl<Comp: Comparator> Ord for Partition<Comp> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        if self as *const Self == other as *const Self {
+            return Ordering::Equal
+        }
+        match (self.bounds(), other.bounds()) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some((self_lower, self_upper)), Some((other_lower, other_upper))) =>
+                self_lower.cmp(&other_lower).then_with(|| self_upper.cmp(&other_upper))
+        }
     }
 }
 
-impl<'a, Comp: Comparator> PartialEq for Partition<'a, Comp> {
+impl<Comp: Comparator> PartialEq for Partition<Comp> {
     fn eq(&self, other: &Self) -> bool {
-        debug_assert!(Self::debug_never_eq_sanity_check(self, other));
-        false
+        self.cmp(other) == Ordering::Equal
     }
 }
 
-impl<'a, Comp: Comparator> Eq for Partition<'a, Comp> {}
+impl<Comp: Comparator> Eq for Partition<Comp> {}
 
 fn kv_pair_size<Comp>(key: &InternalKey<Comp>, value: &[u8]) -> usize
     where Comp: Comparator {
     key.user_key.key().len() + value.len() + TABLE_CATALOG_ITEM_SIZE
 }
 
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq)]
-pub(crate) struct ArcPartition<'a, Comp: 'static + Comparator>(Arc<Partition<'a, Comp>>);
+pub(crate) struct ArcPartition<Comp: 'static + Comparator>(Arc<Partition<Comp>>);
+
+impl<Comp: 'static + Comparator> PartialOrd for ArcPartition<Comp> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.0.partial_cmp(&other.0)
+    }
+}
+
+impl<Comp: 'static + Comparator> Ord for ArcPartition<Comp> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl<Comp: 'static + Comparator> PartialEq for ArcPartition<Comp> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq(&other.0)
+    }
+}
+
+impl<Comp: 'static + Comparator> Eq for ArcPartition<Comp> {}
+
+impl<Comp: 'static + Comparator> Clone for ArcPartition<Comp> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
 
-impl<'a, Comp: 'static + Comparator> ArcPartition<'a, Comp> {
-    pub(crate) fn new(partition: Partition<'a, Comp>) -> Self {
+impl<Comp: 'static + Comparator> ArcPartition<Comp> {
+    pub(crate) fn new(partition: Partition<Comp>) -> Self {
         Self(Arc::new(partition))
     }
 
-    pub(crate) fn write(&self, key: InternalKey<Comp>, value: Vec<u8>) -> Result<(), Error> {
-        let partition = &self.0;
-        let mut data = partition.data.lock().unwrap();
-        data.background_error()?;
-        loop {
-            if false /* TODO add proper condition here */ {
-                return Err(Error::requires_explode())
+    /// The partition `record` describes, as a previous run left it in the manifest. A table
+    /// shared by several partitions is taken from `tables` if another partition restored it
+    /// already, so it stays shared and is not removed while one of them still uses it.
+    pub(crate) fn restore(context: Arc<DbContext>,
+                          comparator: Arc<Comp>,
+                          record: &PartitionRecord,
+                          tables: &mut HashMap<ScTableFile, Arc<dyn Table<Comp>>>) -> Self {
+        let partition = Partition::new(context, record.partition_id, comparator.clone());
+        {
+            let mut data = partition.lock();
+            if let Some((lower_bound, upper_bound)) = record.bounds.as_ref() {
+                data.extend_bounds(&UserKey::new_owned(lower_bound.clone(), &comparator),
+                                   &UserKey::new_owned(upper_bound.clone(), &comparator));
             }
-            if data.memtable_size() + kv_pair_size(&key, &value) <= partition.options.table_size {
-                break;
-            } else if data.has_imm() {
-                data = partition.condvar.wait(data).unwrap();
-            } else {
-                data.convert_mem_to_imm();
-                // TODO run self.do_compaction() at background
-                break;
+            for table_record in record.tables.iter() {
+                let table_file = table_record.table_file;
+                let level = table_record.level as usize;
+                while data.levels.len() <= level {
+                    let level_number = data.levels.len();
+                    data.levels.push(Level::new(level_number));
+                }
+                let table = tables.entry(table_file).or_insert_with(|| {
+                    Arc::new(ScTable::new(table_file,
+                                          table_record.file_size,
+                                          UserKey::new_owned(table_record.lower_bound.clone(), &comparator),
+                                          UserKey::new_owned(table_record.upper_bound.clone(), &comparator)))
+                }).clone();
+                data.add_table(level, table);
+            }
+            for tombstone in record.range_tombstones.iter() {
+                let start = UserKey::new_owned(tombstone.start.clone(), &comparator);
+                let end = UserKey::new_owned(tombstone.end.clone(), &comparator);
+                data.range_tombstones.add_flushed(RangeTombstone::new(tombstone.seq, start, end), tombstone.fence);
             }
         }
-        data.memtable_put(key, value);
-        Ok(())
+        Self::new(partition)
     }
 
-    pub(crate) fn explode(&self) -> (ArcPartition<'a, Comp>, ArcPartition<'a, Comp>) {
-        let partition = &self.0;
-        let data = partition.data.lock().unwrap();
-        // TODO
-        // 1. explode last level
-        // 2. explode
-        unimplemented!()
+    /// Applies `entries` under a single lock acquisition after logging them as one record, so
+    /// they are recovered all together or not at all. `entries` is drained if they are written,
+    /// and left as it is on an error. Their sequence numbers must ascend and come after every one
+    /// this partition has applied so far, as they do in a log being replayed.
+    pub(crate) fn write(&self, entries: &mut Vec<Entry<Comp>>, sync: SyncPolicy) -> Result<(), Error> {
+        self.write_impl(entries, Some(sync), false)
     }
 
-    fn compact_memtable(&self) {
+    /// Like `write`, but numbers `entries` with the next sequence numbers of the database first.
+    /// They are taken while the partition is locked, so each partition applies them in order even
+    /// if writers take them concurrently. Without a `sync` policy the entries are not logged at all,
+    /// they are lost on a crash before the memtable is flushed.
+    pub(crate) fn write_next(&self, entries: &mut Vec<Entry<Comp>>, sync: Option<SyncPolicy>) -> Result<(), Error> {
+        self.write_impl(entries, sync, true)
+    }
+
+    /// Like `write_next` for a single entry, but writes it only if its key has no live value,
+    /// which is checked under the same lock the entry is applied under. Returns whether it wrote.
+    pub(crate) fn put_if_absent(&self, entry: Entry<Comp>, sync: Option<SyncPolicy>) -> Result<bool, Error> {
         let partition = &self.0;
-        let buffer;
-        let file_number;
-        let imm_bounds;
-        {
-            let mut data = partition.data.lock().unwrap();
-            if data.background_error().is_err() {
-                return;
-            }
-            imm_bounds = data.imm_bounds();
-            let mut builder = ScTableBuilder::new();
-            for (k, v) in data.imm_table.as_ref().unwrap().iter() {
-                builder.add_kv(k.seq, k.user_key.key(), &v);
-            }
-            buffer = builder.build();
-            if data.levels.len() == 0 {
-                data.levels.push(Level::new());
+        let mut data = self.lock_with_room(kv_pair_size(&entry.0, &entry.1))?;
+        partition.wait_for_inserts();
+        let key = InternalKey::new(u64::max_value(), entry.0.user_key.clone());
+        if self.contains_locked(&mut data, &key)? {
+            return Ok(false)
+        }
+        let mut entries = vec![entry];
+        entries[0].0.seq = partition.context.seq.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+        data.log(partition.partition_id, &entries, sync)?;
+        let pending = partition.put_entries(&mut data, &mut entries);
+        drop(data);
+        if let Some(pending) = pending {
+            pending.insert();
+        }
+        Ok(true)
+    }
+
+    /// Applies the entries of a batch going to several partitions, `parts` holding those of each,
+    /// as one write. Every partition is locked, in the order of their ids so concurrent batches do
+    /// not deadlock, before the entries are numbered with consecutive sequence numbers. Every part
+    /// is logged before any is applied, each record naming the log segments of the others, and
+    /// recovery replays the batch only if all of them hold it. Returns a partition which has to be
+    /// split first, without having written anything.
+    pub(crate) fn write_parts(parts: &mut [(ArcPartition<Comp>, Vec<Entry<Comp>>)],
+                              sync: Option<SyncPolicy>) -> Result<Option<ArcPartition<Comp>>, Error> {
+        parts.sort_by_key(|(partition, _)| partition.partition_id());
+        let partitions: Vec<_> = parts.iter().map(|(partition, _)| partition.clone()).collect();
+        let context = partitions[0].0.context.clone();
+        let mut locked = Vec::with_capacity(partitions.len());
+        'lock: loop {
+            locked.clear();
+            for (partition, (_, entries)) in partitions.iter().zip(parts.iter()) {
+                let batch_size = entries.iter().map(|(key, value)| kv_pair_size(key, value)).sum();
+                match partition.try_lock_with_room(batch_size) {
+                    Ok(Some(data)) => locked.push(data),
+                    Ok(None) => {
+                        // wait for room without holding the others, they may be needed to make it
+                        locked.clear();
+                        match partition.lock_with_room(batch_size) {
+                            Ok(_) => continue 'lock,
+                            Err(Error::RequiresExplode) => return Ok(Some(partition.clone())),
+                            Err(e) => return Err(e)
+                        }
+                    },
+                    Err(Error::RequiresExplode) => return Ok(Some(partition.clone())),
+                    Err(e) => return Err(e)
+                }
             }
-            file_number = data.levels[0].level_next_file_id();
+            break;
         }
-        let table_file = ScTableFile::new(partition.partition_id, 0, file_number);
-        if let Err(e) = partition.io_manager.acquire_quota().write_file(table_file.file_name(), &buffer) {
-            partition.data.lock().unwrap().record_background_error(e);
-            partition.condvar.notify_one();
-            return;
+
+        let count = parts.iter().map(|(_, entries)| entries.len() as u64).sum();
+        let first_seq = context.seq.fetch_add(count, atomic::Ordering::SeqCst) + 1;
+        for ((key, _), seq) in parts.iter_mut().flat_map(|(_, entries)| entries.iter_mut()).zip(first_seq..) {
+            key.seq = seq;
         }
-        let (imm_lower, imm_upper) = imm_bounds;
-        let table = ScTable::new(table_file, imm_lower, imm_upper);
-        {
-            let mut data = partition.data.lock().unwrap();
-            data.levels[0].add_file(table);
-            // TODO flush metadata onto disk
-            let _ = data.imm_table.take();
+        for (data, (_, entries)) in locked.iter().zip(parts.iter()) {
+            data.check_seqs(entries)?;
         }
-        partition.condvar.notify_one();
-        self.schedule_compaction(0);
+        if let (Some(sync), true) = (sync, context.io_manager.is_persistent()) {
+            // a segment named by a part exists before any part is written, or it would look flushed
+            let mut segments = Vec::with_capacity(locked.len());
+            for (data, partition) in locked.iter_mut().zip(partitions.iter()) {
+                data.open_log(partition.partition_id())?;
+                segments.push((partition.partition_id(), data.log_number));
+            }
+            for (i, (data, (partition, entries))) in locked.iter_mut().zip(parts.iter()).enumerate() {
+                let others = segments.iter().enumerate()
+                    .filter(|&(j, _)| j != i)
+                    .map(|(_, &segment)| segment)
+                    .collect();
+                data.append_log(partition.partition_id(), entries, &BatchParts { batch: first_seq, others }, sync)?;
+            }
+        }
+        let pending: Vec<_> = locked.iter_mut().zip(partitions.iter()).zip(parts.iter_mut())
+            .filter_map(|((data, partition), (_, entries))| partition.0.put_entries(data, entries))
+            .collect();
+        drop(locked);
+        pending.into_iter().for_each(PendingInserts::insert);
+        Ok(None)
     }
 
-    fn schedule_compaction(&self, input_level: usize) {
-        let output_level = input_level + 1;
+    fn write_impl(&self, entries: &mut Vec<Entry<Comp>>, sync: Option<SyncPolicy>, assign_seqs: bool) -> Result<(), Error> {
         let partition = &self.0;
-        {
-            let data = partition.data.lock().unwrap();
-            if data.background_error().is_err() {
-                return;
-            }
-            if data.levels[input_level].table_count() <= partition.options.level_size(input_level) {
-                return;
+        let batch_size: usize = entries.iter().map(|(key, value)| kv_pair_size(key, value)).sum();
+        let mut data = self.lock_with_room(batch_size)?;
+        if assign_seqs {
+            let first_seq = partition.context.seq.fetch_add(entries.len() as u64, atomic::Ordering::SeqCst) + 1;
+            for ((key, _), seq) in entries.iter_mut().zip(first_seq..) {
+                key.seq = seq;
             }
-            // TODO
-            // 1. pick files to be compacted in input_level
-            // 2. find overlapping files in output_level
-            // 3. temporary unlock
-            // 4. perform compaction
-            // 5. re-lock
-            // 6. update metadata
-            // 7. schedule another compaction
-            // 8. un-lock
-            unimplemented!()
         }
+        data.log(partition.partition_id, entries, sync)?;
+        let pending = partition.put_entries(&mut data, entries);
+        drop(data);
+        if let Some(pending) = pending {
+            pending.insert();
+        }
+        Ok(())
     }
-}
-
-pub(crate) struct PartitionData<'a, Comp: 'static + Comparator> {
-    mem_table: MemTable<Comp>,
-    mem_table_data_size: usize,
 
-    imm_table: Option<MemTable<Comp>>,
-    levels: Vec<Level<Comp>>,
+    /// Locks the partition once its memtable has room for `batch_size` more bytes, stalling while
+    /// level 0 falls behind. Fails if the partition has to be split first.
+    fn lock_with_room(&self, batch_size: usize) -> Result<MutexGuard<'_, PartitionData<Comp>>, Error> {
+        self.lock_for_write(batch_size, true).map(|data| data.unwrap())
+    }
 
-    lower_bound: Option<UserKey<Comp>>,
-    upper_bound: Option<UserKey<Comp>>,
+    /// Like `lock_with_room`, but `None` where that would wait for a flush or a compaction, which
+    /// a writer holding the locks of other partitions must not.
+    fn try_lock_with_room(&self, batch_size: usize) -> Result<Option<MutexGuard<'_, PartitionData<Comp>>>, Error> {
+        self.lock_for_write(batch_size, false)
+    }
 
-    background_error: Option<Error>,
+    fn lock_for_write(&self, batch_size: usize, wait: bool) -> Result<Option<MutexGuard<'_, PartitionData<Comp>>>, Error> {
+        let partition = &self.0;
+        let options = &partition.context.options;
+        // writers inserting into the memtable are not waited for, it is only replaced after that
+        let mut data = partition.data.lock().unwrap();
+        if data.level0_table_count() >= options.level0_slowdown_writes_trigger {
+            // level 0 falls behind, give compaction some time before it has to stop writes
+            drop(data);
+            thread::sleep(Duration::from_millis(1));
+            data = partition.data.lock().unwrap();
+        }
+        let room = loop {
+            if let Err(e) = data.background_error() {
+                break Err(e)
+            }
+            if partition.under_explode.load(atomic::Ordering::SeqCst) || data.requires_split() {
+                break Err(Error::requires_explode())
+            }
+            if data.level0_table_count() >= options.level0_stop_writes_trigger {
+                if !wait {
+                    break Ok(false);
+                }
+                // a compaction taking tables out of level 0 wakes us up
+                data = partition.room.wait(data).unwrap();
+                continue;
+            }
+            if data.memtable_size() + batch_size <= options.table_size
+               || data.memtable_is_empty() {
+                break Ok(true);
+            } else if data.has_imm() {
+                if !wait {
+                    break Ok(false);
+                }
+                // the memtable is full and the one before it still being flushed
+                data = partition.room.wait(data).unwrap();
+            } else {
+                partition.wait_for_inserts();
+                data.convert_mem_to_imm();
+                let this = self.clone();
+                partition.context.background.submit(move || this.compact_memtable());
+                break Ok(true);
+            }
+        };
+        // every writer leaving passes the wakeup on, the next one may find room or an error as well
+        partition.room.notify_one();
+        room.map(|room| Some(data).filter(|_| room))
+    }
 
-    options: &'a Options
-}
+    /// Links the table `raw`, whose keys are within `[lower, upper]`, into this partition without
+    /// rewriting its data. Its entries all get the next sequence number of the database, so they
+    /// are newer than anything written before. A memtable holding keys of that range is flushed
+    /// first, then the table goes into the deepest level where neither that level nor one above it
+    /// has a table overlapping it, level 0 if already that one has.
+    pub(crate) fn ingest(&self,
+                         mut raw: Vec<u8>,
+                         lower: UserKey<'static, Comp>,
+                         upper: UserKey<'static, Comp>) -> Result<(), Error> {
+        let partition = &self.0;
+        let context = &partition.context;
+        let mut data = partition.lock();
+        // flushes and compactions replace tables without holding the lock all the time
+        loop {
+            data.background_error()?;
+            if partition.under_explode.load(atomic::Ordering::SeqCst) {
+                return Err(Error::requires_explode())
+            }
+            if data.has_imm() || data.compacting {
+                data = partition.wait(data);
+            } else if data.memtable_overlaps(&lower, &upper) {
+                data.convert_mem_to_imm();
+                let this = self.clone();
+                context.background.submit(move || this.compact_memtable());
+            } else {
+                break
+            }
+        }
+        // taken while the partition is locked, as writes take theirs
+        let seq = context.seq.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+        assign_seq(&mut raw, seq)?;
+        let table_file = context.new_table_file();
+        context.io_manager.acquire_quota().write_atomic(table_file.file_name(), &raw)?;
 
-impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
-    fn new(options: &'a Options) -> Self {
-        Self {
-            mem_table: MemTable::new(),
-            mem_table_data_size: 0,
-            imm_table: None,
-            levels: Vec::new(),
-            lower_bound: None,
-            upper_bound: None,
-            background_error: None,
-            options
+        let level = data.link_table(table_file, raw.len(), lower, upper);
+        if let Err(e) = data.save_manifest(partition.partition_id) {
+            data.levels[level].remove_files(&[table_file]);
+            let _ = context.io_manager.remove_file(table_file.file_name());
+            return Err(e)
         }
+        drop(data);
+        self.schedule_compaction(level);
+        Ok(())
     }
 
-    fn background_error(&self) -> Result<(), Error> {
-        if let Some(e) = &self.background_error {
-            Err(e.clone())
-        } else {
-            Ok(())
+    /// Links the tables `Db::bulk_load` wrote into the partitions they were written for, all of
+    /// them or none. They share one sequence number, newer than anything written before, each goes
+    /// as deep as `ingest` puts a table, and the manifest is written once for all of them. The
+    /// caller holds the partitions, so none is split meanwhile; one grown too large is split by the
+    /// next write to it. The files of tables it fails to link are left to the caller.
+    pub(crate) fn link(staged: &mut [(ArcPartition<Comp>, Vec<StagedTable<Comp>>)]) -> Result<(), Error> {
+        if staged.is_empty() {
+            return Ok(())
+        }
+        staged.sort_by_key(|(partition, _)| partition.partition_id());
+        let context = staged[0].0.0.context.clone();
+        let mut locked = Vec::with_capacity(staged.len());
+        // flushes and compactions replace tables without holding the lock all the time
+        'lock: loop {
+            locked.clear();
+            for (partition, tables) in staged.iter() {
+                let mut data = partition.0.lock();
+                data.background_error()?;
+                let mut busy = data.has_imm() || data.compacting;
+                if !busy && tables.iter().any(|table| data.memtable_overlaps(&table.lower, &table.upper)) {
+                    data.convert_mem_to_imm();
+                    let this = partition.clone();
+                    context.background.submit(move || this.compact_memtable());
+                    busy = true;
+                }
+                if busy {
+                    // wait without holding the others, their flushes and compactions go on
+                    locked.clear();
+                    while data.has_imm() || data.compacting {
+                        data = partition.0.wait(data);
+                        data.background_error()?;
+                    }
+                    continue 'lock;
+                }
+                locked.push(data);
+            }
+            break;
         }
-    }
 
-    fn record_background_error(&mut self, error: Error) {
-        self.background_error.replace(error);
+        // taken while the partitions are locked, as writes take theirs
+        let seq = context.seq.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+        for table in staged.iter().flat_map(|(_, tables)| tables.iter()) {
+            table.assign_seq(seq, &context.io_manager)?;
+        }
+        let mut linked = Vec::with_capacity(staged.len());
+        for (data, (_, tables)) in locked.iter_mut().zip(staged.iter()) {
+            linked.push(tables.iter().map(|table| {
+                let level = data.link_table(table.table_file, table.file_size, table.lower.clone(), table.upper.clone());
+                (level, table.table_file)
+            }).collect::<Vec<_>>());
+        }
+        let records = locked.iter().zip(staged.iter())
+            .map(|(data, (partition, _))| data.manifest_record(partition.partition_id()))
+            .collect();
+        let last_seq = context.seq.load(atomic::Ordering::SeqCst);
+        if let Err(e) = context.manifest.update_all(records, last_seq, &context.io_manager) {
+            for (data, tables) in locked.iter_mut().zip(linked.iter()) {
+                for &(level, table_file) in tables {
+                    data.levels[level].remove_files(&[table_file]);
+                }
+            }
+            return Err(e)
+        }
+        drop(locked);
+        for ((partition, _), tables) in staged.iter().zip(linked) {
+            let mut levels: Vec<_> = tables.into_iter().map(|(level, _)| level).collect();
+            levels.sort_unstable();
+            levels.dedup();
+            for level in levels {
+                partition.schedule_compaction(level);
+            }
+        }
+        Ok(())
     }
 
-    fn has_imm(&self) -> bool {
-        self.imm_table.is_some()
+    /// Writes the memtable into a table and waits until it is on disk and in the manifest, so the
+    /// writes acknowledged so far survive a crash without their log. A memtable holding nothing
+    /// but range tombstones has no table to write, they go into the manifest right away.
+    pub(crate) fn flush(&self) -> Result<(), Error> {
+        let partition = &self.0;
+        let mut data = partition.lock();
+        // a memtable still being written goes first, then ours, then we wait for it to land
+        let mut submitted = false;
+        loop {
+            data.background_error()?;
+            if data.has_imm() {
+                data = partition.wait(data);
+            } else if submitted {
+                return Ok(())
+            } else if data.memtable_is_empty() {
+                return data.flush_range_tombstones(partition.partition_id)
+            } else {
+                partition.wait_for_inserts();
+                data.convert_mem_to_imm();
+                let this = self.clone();
+                partition.context.background.submit(move || this.compact_memtable());
+                submitted = true;
+            }
+        }
     }
 
-    fn imm_bounds(&self) -> (UserKey<Comp>, UserKey<Comp>) {
-        let imm_table = self.imm_table.as_ref().unwrap();
-        let (lower_bound, _) = imm_table.first_key_value().unwrap();
-        let (upper_bound, _) = imm_table.last_key_value().unwrap();
-        (lower_bound.user_key.clone(), upper_bound.user_key.clone())
+    /// Starts a flush of the memtable if nothing has been written to it for `idle_timeout`, without
+    /// waiting for it. Nothing is done while the memtable before it is still being flushed, the
+    /// next check gets to it.
+    pub(crate) fn flush_if_idle(&self, idle_timeout: Duration) {
+        let partition = &self.0;
+        let mut data = partition.lock();
+        if data.background_error.is_some() || partition.under_explode.load(atomic::Ordering::SeqCst)
+           || data.has_imm() || data.memtable_is_empty() || data.last_write.elapsed() < idle_timeout {
+            return
+        }
+        data.convert_mem_to_imm();
+        let this = self.clone();
+        partition.context.background.submit(move || this.compact_memtable());
     }
 
-    fn memtable_put(&mut self, key: InternalKey<Comp>, value: Vec<u8>) {
-        debug_assert!(self.memtable_size() + kv_pair_size(&key, &value) <= self.options.table_size);
-        if self.lower_bound.is_none() && self.upper_bound.is_none() {
-            self.set_lower_bound(key.user_key.clone());
-            self.set_upper_bound(key.user_key.clone());
-        } else if &key.user_key < self.lower_bound.as_ref().unwrap() {
-            self.set_lower_bound(key.user_key.clone());
-        } else if &key.user_key > self.upper_bound.as_ref().unwrap() {
-            self.set_upper_bound(key.user_key.clone());
+    /// Flushes, then compacts the tables holding keys of `[start, end)` level by level down into
+    /// the deepest level holding tables, which they are compacted within, so nothing below keeps
+    /// tombstones and the versions they hide from being dropped. Waits for a compaction running
+    /// already to finish first, and blocks until the last level is done.
+    pub(crate) fn compact_range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> Result<(), Error> {
+        self.flush()?;
+        let partition = &self.0;
+        let start = start.map(|start| UserKey::new_borrow(start, &partition.comparator));
+        let end = end.map(|end| UserKey::new_borrow(end, &partition.comparator));
+        let in_range = |table: &Arc<dyn Table<Comp>>| {
+            start.as_ref().map_or(true, |start| table.upper_bound() >= start)
+                && end.as_ref().map_or(true, |end| table.lower_bound() < end)
+        };
+        let mut input_level = 0;
+        loop {
+            let output_level;
+            let inputs;
+            let drop_tombstones;
+            let snapshots;
+            let range_tombstones;
+            {
+                let mut data = partition.lock();
+                while data.compacting {
+                    data = partition.wait(data);
+                }
+                data.background_error()?;
+                let deepest = match data.levels.iter().rposition(|level| level.table_count() > 0) {
+                    Some(deepest) if deepest >= input_level => deepest,
+                    _ => return Ok(())
+                };
+                output_level = if input_level == deepest { deepest } else { input_level + 1 };
+                let tables = data.levels[input_level].tables();
+                let mut picked: Vec<Arc<dyn Table<Comp>>> = if !tables.iter().any(&in_range) {
+                    Vec::new()
+                } else if input_level == 0 {
+                    // tables of level 0 overlap each other, an older version left behind would
+                    // show through once the newer one is compacted below it
+                    tables.to_vec()
+                } else {
+                    tables.iter().filter(|table| in_range(table)).cloned().collect()
+                };
+                if !picked.is_empty() && output_level != input_level {
+                    let lower = picked.iter().map(|table| table.lower_bound()).min().unwrap().clone();
+                    let upper = picked.iter().map(|table| table.upper_bound()).max().unwrap().clone();
+                    picked.extend(data.levels[output_level].overlapping(&lower, &upper).into_iter().cloned());
+                }
+                inputs = picked;
+                if !inputs.is_empty() {
+                    drop_tombstones = data.levels[output_level + 1..].iter().all(|level| level.table_count() == 0);
+                    snapshots = partition.context.snapshots.seqs();
+                    range_tombstones = data.range_tombstones.visible(u64::max_value());
+                    data.compacting = true;
+                } else {
+                    drop_tombstones = false;
+                    snapshots = Vec::new();
+                    range_tombstones = Vec::new();
+                }
+            }
+            if !inputs.is_empty() {
+                let result = self.do_compaction(&inputs, drop_tombstones, &snapshots, &range_tombstones);
+                self.install_compaction(input_level, output_level, inputs, result, &snapshots, &range_tombstones);
+            }
+            input_level += 1;
         }
-        self.mem_table.insert(key, value);
     }
 
-    fn convert_mem_to_imm(&mut self) {
-        let new_imm = std::mem::replace(&mut self.mem_table, MemTable::new());
-        self.imm_table.replace(new_imm);
-    }
+    /// Replays the logs left behind by a previous run into the memtable, then removes them. Returns
+    /// the largest sequence number seen. Parts of the `unfinished` batches are skipped, see
+    /// `wal::unfinished_batches`. A read-only database puts the entries straight into the memtable
+    /// instead, leaving the logs as they are and the memtable unflushed however large.
+    pub(crate) fn recover(&self, unfinished: &HashSet<u64>) -> Result<u64, Error> {
+        let partition = &self.0;
+        let io_manager = &partition.context.io_manager;
+        let mut log_numbers: Vec<u64> = io_manager.list_files()?.iter()
+            .filter_map(|file_name| parse_log_file_name(file_name))
+            .filter(|&(partition_id, _)| partition_id == partition.partition_id)
+            .map(|(_, log_number)| log_number)
+            .collect();
+        log_numbers.sort();
+        if let Some(&last_log_number) = log_numbers.last() {
+            let mut data = partition.lock();
+            data.log_number = last_log_number + 1;
+            data.mem_log_number = last_log_number + 1;
+        }
 
-    fn memtable_size(&self) -> usize {
-        self.mem_table_data_size + self.mem_table.len() * TABLE_CATALOG_ITEM_SIZE + TABLE_MIN_SIZE
+        let mut max_seq = 0;
+        for &log_number in log_numbers.iter() {
+            let raw = io_manager.acquire_quota().read_file(log_file_name(partition.partition_id, log_number))?;
+            for record in decode_records(&raw, &partition.comparator)? {
+                if unfinished.contains(&record.parts.batch) {
+                    continue;
+                }
+                let mut entries = record.entries;
+                max_seq = entries.iter().map(|(key, _)| key.seq).fold(max_seq, u64::max);
+                // a flush during the replay saves the manifest, which must not go behind the tables
+                partition.context.seq.fetch_max(max_seq, atomic::Ordering::SeqCst);
+                if partition.context.read_only {
+                    let mut data = partition.lock();
+                    data.check_seqs(&entries)?;
+                    for (key, value) in entries {
+                        data.memtable_put(key, value);
+                    }
+                } else {
+                    self.write(&mut entries, SyncPolicy::NoSync)?;
+                }
+            }
+        }
+        if partition.context.read_only {
+            return Ok(max_seq)
+        }
+        // the replayed entries are in the new log by now
+        partition.lock().sync_log()?;
+        for log_number in log_numbers {
+            io_manager.remove_file(log_file_name(partition.partition_id, log_number))?;
+        }
+        Ok(max_seq)
     }
 
-    fn bounds(&self) -> (Option<&UserKey<Comp>>, Option<&UserKey<Comp>>) {
-        (self.lower_bound.as_ref(), self.upper_bound.as_ref())
+    /// Looks `key` up with `PartitionData::merged_get` if the database has a merge operator,
+    /// `None` without one, then merge operands are never written.
+    fn merged_get(&self, data: &mut PartitionData<Comp>, key: &InternalKey<Comp>) -> Option<Result<Option<(Vec<u8>, u64)>, Error>> {
+        let context = &self.0.context;
+        let merge_operator = context.options.merge_operator.as_deref()?;
+        let found = data.merged_get(key, merge_operator, &context.cache_manager, &context.io_manager)
+            .map(|(found, read_compaction)| {
+                self.schedule_read_compaction(read_compaction);
+                found
+            });
+        Some(found)
     }
 
-    fn set_lower_bound(&mut self, lower_bound: UserKey<Comp>) {
-        debug_assert!(lower_bound.is_owned());
-        self.lower_bound.replace(lower_bound);
+    pub(crate) fn get(&self, key: &InternalKey<Comp>) -> Result<Option<Vec<u8>>, Error> {
+        let partition = &self.0;
+        let mut data = partition.lock();
+        if let Some(found) = self.merged_get(&mut data, key) {
+            return found.map(|found| found.map(|(value, _)| value))
+        }
+        if data.range_deleted(key, &partition.context.cache_manager, &partition.context.io_manager)? {
+            return Ok(None)
+        }
+        if let Some(value) = data.memtable_get(key) {
+            return Ok(value)
+        }
+        if let Some(value) = data.imm_get(key) {
+            return Ok(value)
+        }
+        let (value, read_compaction) = data.levels_get(key, &partition.context.cache_manager,
+                                                       &partition.context.io_manager)?;
+        self.schedule_read_compaction(read_compaction);
+        Ok(value.and_then(|value| value))
     }
 
-    fn set_upper_bound(&mut self, upper_bound: UserKey<Comp>) {
-        debug_assert!(upper_bound.is_owned());
+    /// Like `get` for each of `keys`, under a single lock of the partition. The keys are visited in
+    /// sorted order, so those falling into the same table find it cached from the key before.
+    /// Results are in the order of `keys`.
+    pub(crate) fn multi_get(&self, keys: &[InternalKey<Comp>]) -> Vec<Result<Option<Vec<u8>>, Error>> {
+        let partition = &self.0;
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&lhs, &rhs| keys[lhs].cmp(&keys[rhs]));
+        let mut results: Vec<_> = keys.iter().map(|_| Ok(None)).collect();
+        let mut data = partition.lock();
+        for idx in order {
+            let key = &keys[idx];
+            if let Some(found) = self.merged_get(&mut data, key) {
+                results[idx] = found.map(|found| found.map(|(value, _)| value));
+                continue;
+            }
+            match data.range_deleted(key, &partition.context.cache_manager, &partition.context.io_manager) {
+                Ok(false) => (),
+                deleted => {
+                    results[idx] = deleted.map(|_| None);
+                    continue;
+                }
+            }
+            results[idx] = match data.memtables_lookup(key, |_, value| value.to_vec()) {
+                Some(value) => Ok(value),
+                None => data.levels_get(key, &partition.context.cache_manager, &partition.context.io_manager)
+                    .map(|(value, read_compaction)| {
+                        self.schedule_read_compaction(read_compaction);
+                        value.and_then(|value| value)
+                    })
+            };
+        }
+        results
+    }
+
+    /// Like `get`, but only tells whether a live value is there. Neither memtables nor tables copy
+    /// the value, and tables whose filter rules the key out are not searched at all.
+    pub(crate) fn contains(&self, key: &InternalKey<Comp>) -> Result<bool, Error> {
+        let mut data = self.0.lock();
+        self.contains_locked(&mut data, key)
+    }
+
+    fn contains_locked(&self, data: &mut PartitionData<Comp>, key: &InternalKey<Comp>) -> Result<bool, Error> {
+        let partition = &self.0;
+        if let Some(found) = self.merged_get(data, key) {
+            return found.map(|found| found.is_some())
+        }
+        if data.range_deleted(key, &partition.context.cache_manager, &partition.context.io_manager)? {
+            return Ok(false)
+        }
+        let mut found = data.memtables_lookup(key, |_, _| ());
+        if found.is_none() {
+            let (cache_manager, io_manager) = (&partition.context.cache_manager, &partition.context.io_manager);
+            let (value, read_compaction) = data.levels_lookup(key, |table| table.contains(key, cache_manager, io_manager))?;
+            self.schedule_read_compaction(read_compaction);
+            found = value;
+        }
+        Ok(found == Some(Some(())))
+    }
+
+    /// Like `get`, but a value found in a table is pinned there rather than copied.
+    pub(crate) fn get_pinned(&self, key: &InternalKey<Comp>) -> Result<Option<PinnedValue>, Error> {
+        let partition = &self.0;
+        let mut data = partition.lock();
+        if let Some(found) = self.merged_get(&mut data, key) {
+            return found.map(|found| found.map(|(value, _)| PinnedValue::copied(value)))
+        }
+        if data.range_deleted(key, &partition.context.cache_manager, &partition.context.io_manager)? {
+            return Ok(None)
+        }
+        let mut found = data.memtables_lookup(key, |_, v| PinnedValue::copied(v.to_vec()));
+        if found.is_none() {
+            let (cache_manager, io_manager) = (&partition.context.cache_manager, &partition.context.io_manager);
+            let (value, read_compaction) = data.levels_lookup(key, |table| table.get_pinned(key, cache_manager, io_manager))?;
+            self.schedule_read_compaction(read_compaction);
+            found = value;
+        }
+        Ok(found.and_then(|value| value))
+    }
+
+    /// Like `get`, but also returns the sequence number of the version holding the value.
+    pub(crate) fn get_with_seq(&self, key: &InternalKey<Comp>) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        let partition = &self.0;
+        let mut data = partition.lock();
+        if let Some(found) = self.merged_get(&mut data, key) {
+            return found
+        }
+        if data.range_deleted(key, &partition.context.cache_manager, &partition.context.io_manager)? {
+            return Ok(None)
+        }
+        let mut found = data.memtables_lookup(key, |k, v| (v.to_vec(), k.seq));
+        if found.is_none() {
+            let (cache_manager, io_manager) = (&partition.context.cache_manager, &partition.context.io_manager);
+            let (value, read_compaction) = data.levels_lookup(key, |table| table.get_with_seq(key, cache_manager, io_manager))?;
+            self.schedule_read_compaction(read_compaction);
+            found = value;
+        }
+        Ok(found.and_then(|value| value))
+    }
+
+    /// Iterates the key/value pairs of this partition live as of `read_seq`, from `lower_bound` on.
+    /// The memtables are copied while the partition is locked, tables are read lazily as the
+    /// iteration reaches them.
+    pub(crate) fn iter(&self, lower_bound: Option<&[u8]>, read_seq: u64) -> MergingIterator<'static, Comp> {
+        self.merging_iter(lower_bound, read_seq, false)
+    }
+
+    /// Like `iter`, but the values are neither copied from the memtables nor from the tables, every
+    /// key comes with an empty value.
+    pub(crate) fn iter_keys(&self, lower_bound: Option<&[u8]>, read_seq: u64) -> MergingIterator<'static, Comp> {
+        self.merging_iter(lower_bound, read_seq, true)
+    }
+
+    fn merging_iter(&self, lower_bound: Option<&[u8]>, read_seq: u64, keys_only: bool) -> MergingIterator<'static, Comp> {
+        let partition = &self.0;
+        let data = partition.lock();
+        let mut sources: Vec<EntryIterator<'static, Comp>> = Vec::new();
+        for table in std::iter::once(&data.mem_table).chain(data.imm_table.iter()) {
+            sources.push(Box::new(MemTableSource::new(table.as_ref(), lower_bound, &partition.comparator, keys_only)));
+        }
+        let lower_key = lower_bound.map(|lower_bound| UserKey::new_borrow(lower_bound, &partition.comparator));
+        let table_iter = |table: &Arc<dyn Table<Comp>>| {
+            TableIterator::new(table.clone(), partition.context.clone(), partition.comparator.clone(),
+                               lower_bound.map(|b| b.to_vec()), keys_only)
+        };
+        for (level_number, level) in data.levels.iter().enumerate() {
+            let tables = level.tables().iter()
+                .filter(|table| lower_key.as_ref().map_or(true, |lower_key| table.upper_bound() >= lower_key));
+            if level_number == 0 {
+                for table in tables.rev() {
+                    sources.push(Box::new(table_iter(table)));
+                }
+            } else {
+                sources.push(Box::new(LevelIterator::new(tables.cloned().collect(), partition.context.clone(),
+                                                         partition.comparator.clone(),
+                                                         lower_bound.map(|b| b.to_vec()), keys_only)));
+            }
+        }
+        // keys alone need no folding, merge operands always make up a value
+        let merge_operator = partition.context.options.merge_operator.clone().filter(|_| !keys_only);
+        MergingIterator::new(sources, read_seq)
+            .with_range_del(data.range_tombstones.aggregator(read_seq))
+            .with_merge_operator(merge_operator)
+    }
+
+    /// Where `user_key` lies relative to the bounds of this partition. A partition without bounds
+    /// accepts any key.
+    pub(crate) fn cmp_key(&self, user_key: &UserKey<Comp>) -> Ordering {
+        let data = self.0.lock();
+        match data.bounds() {
+            (Some(lower_bound), _) if user_key < lower_bound => Ordering::Less,
+            (_, Some(upper_bound)) if user_key > upper_bound => Ordering::Greater,
+            _ => Ordering::Equal
+        }
+    }
+
+    /// Splits this partition at a key close to the median of its data on disk into two new ones,
+    /// `left_id` holding the keys below the split key and `right_id` the rest. Tables entirely on
+    /// one side are shared with the new partitions, tables straddling the split key are rewritten.
+    /// This partition is left as it is for readers still holding it, but refuses writes from then
+    /// on. Returns `None` if there is no key to split at, the partition is then allowed to grow to
+    /// twice its size before the next try.
+    pub(crate) fn explode(&self, left_id: u32, right_id: u32)
+        -> Result<Option<(ArcPartition<Comp>, ArcPartition<Comp>)>, Error> {
+        let partition = &self.0;
+        let context = &partition.context;
+        let mut data = partition.lock();
+        // flushes and compactions replace tables without holding the lock all the time
+        while data.has_imm() || data.compacting {
+            data = partition.wait(data);
+        }
+        data.background_error()?;
+        let split_key = match data.pick_split_key(&partition.comparator, &context.cache_manager, &context.io_manager)? {
+            Some(split_key) => split_key,
+            None => {
+                data.split_size = data.split_size.saturating_mul(2);
+                return Ok(None)
+            }
+        };
+
+        let left = ArcPartition::new(Partition::new(context.clone(), left_id, partition.comparator.clone()));
+        let right = ArcPartition::new(Partition::new(context.clone(), right_id, partition.comparator.clone()));
+        let mut rewritten = Vec::new();
+        {
+            let mut left_data = left.0.lock();
+            let mut right_data = right.0.lock();
+            // both are set, there is a split key
+            let (lower_bound, upper_bound) = (data.lower_bound.clone().unwrap(), data.upper_bound.clone().unwrap());
+            left_data.extend_bounds(&lower_bound, &lower_bound);
+            right_data.extend_bounds(&split_key, &upper_bound);
+
+            for (level_number, level) in data.levels.iter().enumerate() {
+                left_data.levels.push(Level::new(level_number));
+                right_data.levels.push(Level::new(level_number));
+                for table in level.tables() {
+                    if table.upper_bound() < &split_key {
+                        left_data.add_table(level_number, table.clone());
+                    } else if table.lower_bound() >= &split_key {
+                        right_data.add_table(level_number, table.clone());
+                    } else {
+                        let cache = table.load(&context.cache_manager, &context.io_manager)?;
+                        let (mut below, mut above) = (Vec::new(), Vec::new());
+                        for (key, value) in cache.iter(&partition.comparator) {
+                            if key.user_key < split_key {
+                                below.push((key, value.to_vec()));
+                            } else {
+                                above.push((key, value.to_vec()));
+                            }
+                        }
+                        left_data.add_entries(level_number, &below)?;
+                        right_data.add_entries(level_number, &above)?;
+                        rewritten.push(table.clone());
+                    }
+                }
+            }
+            // both take every range tombstone, the rewritten tables may hold what they hide
+            let fence = context.file_number.load(atomic::Ordering::SeqCst);
+            for (tombstone, _) in data.range_tombstones.flushed() {
+                left_data.range_tombstones.add_flushed(tombstone.clone(), fence);
+                right_data.range_tombstones.add_flushed(tombstone.clone(), fence);
+            }
+            let (mut below, mut above): (Vec<_>, Vec<_>) = data.mem_table.iter(None)
+                .map(|(key, value)| (key.make_owned(), value.to_vec()))
+                .partition(|(key, _)| key.user_key < split_key);
+            for tombstone in data.range_tombstones.logged() {
+                let key = InternalKey::new_range_deletion(tombstone.seq, tombstone.start.clone());
+                below.push((key.make_owned(), tombstone.end.key().to_vec()));
+                above.push((key, tombstone.end.key().to_vec()));
+            }
+            // logged in the order they were written, as the new partitions check
+            below.sort_by_key(|(key, _)| key.seq);
+            above.sort_by_key(|(key, _)| key.seq);
+            left_data.log_and_put(left_id, &mut below, Some(SyncPolicy::Sync))?;
+            right_data.log_and_put(right_id, &mut above, Some(SyncPolicy::Sync))?;
+            // from here on a restart finds the new partitions, so the old log is not needed anymore
+            context.manifest.replace(partition.partition_id,
+                                     vec![left_data.manifest_record(left_id), right_data.manifest_record(right_id)],
+                                     context.seq.load(atomic::Ordering::SeqCst),
+                                     &context.io_manager)?;
+        }
+        // iterators still reading this partition hold them, their files go once it is dropped
+        data.obsolete_tables.extend(rewritten);
+
+        partition.under_explode.store(true, atomic::Ordering::SeqCst);
+        // the memtable is in the logs of the new partitions by now
+        let log_numbers = data.mem_log_numbers();
+        data.log.take();
+        for log_number in log_numbers {
+            let _ = context.io_manager.remove_file(log_file_name(partition.partition_id, log_number));
+        }
+        Ok(Some((left, right)))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn bounds(&self) -> Option<(UserKey<'static, Comp>, UserKey<'static, Comp>)> {
+        self.0.bounds()
+    }
+
+    pub(crate) fn manifest_record(&self) -> PartitionRecord {
+        self.0.lock().manifest_record(self.0.partition_id)
+    }
+
+    /// Bytes on disk of each level of this partition.
+    pub(crate) fn level_sizes(&self) -> Vec<usize> {
+        self.0.lock().levels.iter().map(|level| level.size()).collect()
+    }
+
+    pub(crate) fn level0_table_count(&self) -> usize {
+        self.0.lock().level0_table_count()
+    }
+
+    /// The bounds, levels and memtable of this partition, read under a single lock acquisition.
+    pub(crate) fn info(&self) -> PartitionInfo {
+        let data = self.0.lock();
+        let bounds = match data.bounds() {
+            (Some(lower_bound), Some(upper_bound)) => Some((lower_bound.key().to_vec(), upper_bound.key().to_vec())),
+            _ => None
+        };
+        PartitionInfo {
+            partition_id: self.0.partition_id,
+            bounds,
+            level_sizes: data.levels.iter().map(|level| level.size()).collect(),
+            table_count: data.levels.iter().map(Level::table_count).sum(),
+            memtable_size: data.mem_table.approximate_size()
+        }
+    }
+
+    /// Bytes of the keys and values in the memtable being written to.
+    pub(crate) fn memtable_data_size(&self) -> usize {
+        self.0.lock().mem_table.approximate_size()
+    }
+
+    /// Roughly the bytes the keys in `[start, end)` take in this partition. Tables entirely in the
+    /// range count in full, tables partly in it by the share of their data region in it, and the
+    /// memtables by what their entries in it would take in a table. A table which cannot be read
+    /// counts in full.
+    pub(crate) fn approximate_size(&self, start: &[u8], end: &[u8]) -> u64 {
+        let partition = &self.0;
+        let context = &partition.context;
+        let start_key = InternalKey::new(u64::max_value(), UserKey::new_borrow(start, &partition.comparator));
+        let end_key = InternalKey::new(u64::max_value(), UserKey::new_borrow(end, &partition.comparator));
+        let mut size = 0;
+        let mut straddling = Vec::new();
+        {
+            let data = partition.lock();
+            for table in std::iter::once(&data.mem_table).chain(data.imm_table.iter()) {
+                size += table.iter(Some(&start_key))
+                    .take_while(|(key, _)| key.user_key < end_key.user_key)
+                    .map(|(key, value)| key.user_key.key().len() + value.len() + TABLE_CATALOG_ITEM_SIZE)
+                    .sum::<usize>();
+            }
+            for table in data.levels.iter().flat_map(|level| level.tables().iter()) {
+                if table.upper_bound() < &start_key.user_key || table.lower_bound() >= &end_key.user_key {
+                    continue
+                }
+                if table.lower_bound() >= &start_key.user_key && table.upper_bound() < &end_key.user_key {
+                    size += table.file_size();
+                } else {
+                    straddling.push(table.clone());
+                }
+            }
+        }
+        for table in straddling {
+            size += match table.load(&context.cache_manager, &context.io_manager) {
+                Ok(cache) => {
+                    let in_range = cache.approximate_offset(&end_key) - cache.approximate_offset(&start_key);
+                    table.file_size() * in_range / cache.data_size().max(1)
+                },
+                Err(_) => table.file_size()
+            };
+        }
+        size as u64
+    }
+
+    pub(crate) fn partition_id(&self) -> u32 {
+        self.0.partition_id
+    }
+
+    pub(crate) fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    fn compact_memtable(&self) {
+        let partition = &self.0;
+        let buffer;
+        let imm_bounds;
+        let imm_log_numbers;
+        {
+            let mut data = partition.lock();
+            if data.background_error().is_err() {
+                return;
+            }
+            imm_bounds = data.imm_bounds();
+            let mut builder = ScTableBuilder::from_options(&partition.context.options);
+            for (k, v) in data.imm_table.as_ref().unwrap().iter(None) {
+                builder.add(k, &v);
+            }
+            buffer = builder.finish();
+            if data.levels.len() == 0 {
+                data.levels.push(Level::new(0));
+            }
+        }
+        let table_file = partition.context.new_table_file();
+        let written = buffer.and_then(|buffer| {
+            partition.context.io_manager.acquire_quota().write_atomic(table_file.file_name(), &buffer).map(|()| buffer)
+        });
+        let buffer = match written {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                partition.lock().record_background_error(e);
+                partition.condvar.notify_all();
+                partition.room.notify_one();
+                return;
+            }
+        };
+        let (imm_lower, imm_upper) = imm_bounds;
+        let table = ScTable::new(table_file, buffer.len(), imm_lower, imm_upper);
+        {
+            let mut data = partition.lock();
+            // a flush keeps whatever range tombstones hide in the memtable
+            data.range_tombstones.table_written(table_file.file_number(), table.lower_bound(), table.upper_bound(), |_| false);
+            data.levels[0].add_file(table);
+            partition.context.stats.record_flush(buffer.len());
+            let _ = data.imm_table.take();
+            let log_numbers = data.imm_log_numbers.take();
+            if let Some(last_log_number) = log_numbers.as_ref().and_then(|log_numbers| log_numbers.clone().last()) {
+                data.range_tombstones.flush_log(last_log_number);
+                data.drop_settled_range_tombstones();
+            }
+            // the log may only go once the manifest knows the table replacing it
+            imm_log_numbers = match data.save_manifest(partition.partition_id) {
+                Ok(()) => log_numbers.unwrap_or(0..0),
+                Err(e) => {
+                    data.record_background_error(e);
+                    0..0
+                }
+            };
+        }
+        for log_number in imm_log_numbers {
+            let _ = partition.context.io_manager.remove_file(log_file_name(partition.partition_id, log_number));
+        }
+        partition.condvar.notify_all();
+        partition.room.notify_one();
+        self.schedule_compaction(0);
+    }
+
+    /// Compacts the level a lookup has just found a table read in vain too often in, see
+    /// `PartitionData::read_compaction`.
+    fn schedule_read_compaction(&self, level: Option<usize>) {
+        if let Some(level) = level {
+            let this = self.clone();
+            self.0.context.background.submit(move || this.schedule_compaction(level));
+        }
+    }
+
+    fn schedule_compaction(&self, input_level: usize) {
+        let output_level = input_level + 1;
+        let partition = &self.0;
+        let inputs;
+        let drop_tombstones;
+        let snapshots;
+        let range_tombstones;
+        let moved;
+        {
+            let mut data = partition.lock();
+            if data.background_error().is_err() || data.compacting
+               || partition.under_explode.load(atomic::Ordering::SeqCst) {
+                return;
+            }
+            let options = &partition.context.options;
+            if data.levels.len() <= input_level || output_level >= options.max_levels {
+                return;
+            }
+            // a table read in vain too often is compacted however full its level is, unless a
+            // compaction since has taken it away already
+            let read_input = match data.read_compaction {
+                Some((level, table_file)) if level == input_level => {
+                    data.read_compaction = None;
+                    Some(table_file).filter(|&table_file| data.levels[input_level].contains(table_file))
+                },
+                _ => None
+            };
+            let level = &data.levels[input_level];
+            let used = if input_level == 0 { level.table_count() } else { level.size() };
+            if used <= options.level_size(input_level) && read_input.is_none() {
+                return;
+            }
+            if data.levels.len() == output_level {
+                data.levels.push(Level::new(output_level));
+            }
+            inputs = data.pick_compaction(input_level, read_input);
+            drop_tombstones = data.levels[output_level + 1..].iter().all(|level| level.table_count() == 0);
+            snapshots = partition.context.snapshots.seqs();
+            range_tombstones = data.range_tombstones.visible(u64::max_value());
+            // a table overlapping nothing below is moved there as it is, unless the compaction
+            // filter has to see its entries
+            moved = inputs.len() == 1 && options.compaction_filter.is_none();
+            if moved {
+                data.move_table(input_level, &inputs[0]);
+                if let Err(e) = data.save_manifest(partition.partition_id) {
+                    data.record_background_error(e);
+                    partition.room.notify_one();
+                }
+            } else {
+                data.compacting = true;
+            }
+        }
+        if moved {
+            partition.condvar.notify_all();
+            partition.room.notify_one();
+            self.schedule_next_compactions(input_level);
+            return;
+        }
+
+        let result = self.do_compaction(&inputs, drop_tombstones, &snapshots, &range_tombstones);
+        if self.install_compaction(input_level, output_level, inputs, result, &snapshots, &range_tombstones) {
+            self.schedule_next_compactions(input_level);
+        }
+    }
+
+    /// Replaces `inputs` in `input_level` and `output_level` by the tables a compaction has
+    /// written into `output_level`, then removes the files nothing needs anymore. An error is
+    /// recorded as the background error instead. Tells whether the compaction was installed.
+    /// `snapshots` and `range_tombstones` are those the compaction ran with, the range tombstones
+    /// left with nothing to delete are dropped.
+    fn install_compaction(&self,
+                          input_level: usize,
+                          output_level: usize,
+                          inputs: Vec<Arc<dyn Table<Comp>>>,
+                          result: Result<Vec<ScTable<Comp>>, Error>,
+                          snapshots: &[u64],
+                          range_tombstones: &[RangeTombstone<Comp>]) -> bool {
+        let partition = &self.0;
+        let input_files: Vec<ScTableFile> = inputs.iter().map(|table| table.table_file()).collect();
+        let unused_files;
+        {
+            let mut data = partition.lock();
+            data.compacting = false;
+            match result {
+                Ok(outputs) => {
+                    partition.context.stats.record_compaction(outputs.iter().map(|table| table.file_size()).sum());
+                    data.levels[input_level].remove_files(&input_files);
+                    data.levels[output_level].remove_files(&input_files);
+                    // a version a snapshot reads is kept however old, as is anything hidden by a
+                    // range tombstone written since the compaction started
+                    let applied = |tombstone: &RangeTombstone<Comp>| {
+                        range_tombstones.iter().any(|applied| applied.seq == tombstone.seq)
+                            && !snapshots.iter().any(|&snapshot| snapshot < tombstone.seq)
+                    };
+                    for table in outputs {
+                        data.range_tombstones.table_written(table.table_file().file_number(), table.lower_bound(),
+                                                            table.upper_bound(), applied);
+                        data.levels[output_level].add_file(table);
+                    }
+                    data.levels[output_level].sort_by_bounds();
+                    data.drop_settled_range_tombstones();
+                    data.obsolete_tables.extend(inputs);
+                    // the inputs are still listed by the manifest until it is saved, keep their files
+                    unused_files = match data.save_manifest(partition.partition_id) {
+                        Ok(()) => data.take_unused_tables(),
+                        Err(e) => {
+                            data.record_background_error(e);
+                            Vec::new()
+                        }
+                    };
+                },
+                Err(e) => {
+                    data.record_background_error(e);
+                    partition.condvar.notify_all();
+                    partition.room.notify_one();
+                    return false;
+                }
+            }
+        }
+        partition.condvar.notify_all();
+        // level 0 may have dropped below the count writes stop at
+        partition.room.notify_one();
+
+        self.0.remove_table_files(unused_files);
+        true
+    }
+
+    /// Removes the files of the tables compactions replaced which no iterator reads anymore and
+    /// returns the bytes that gave back. A table still read stays until its last iterator is
+    /// dropped, which calls this again. Nothing is removed after a manifest failed to save, it may
+    /// still list them.
+    pub(crate) fn remove_obsolete_files(&self) -> u64 {
+        let unused_files = {
+            let mut data = self.0.lock();
+            if data.obsolete_tables.is_empty() || data.background_error().is_err() {
+                return 0
+            }
+            data.take_unused_tables()
+        };
+        self.0.remove_table_files(unused_files)
+    }
+
+    /// After a compaction out of `input_level` the level below may be full now, the level itself
+    /// may still be, and a table read in vain too often may be waiting.
+    fn schedule_next_compactions(&self, input_level: usize) {
+        self.schedule_compaction(input_level + 1);
+        self.schedule_compaction(input_level);
+        // level 0 may have filled up while another level was compacted, and with writes stopped
+        // no flush comes along to compact it
+        if input_level != 0 {
+            self.schedule_compaction(0);
+        }
+        let pending = self.0.lock().read_compaction.map(|(level, _)| level);
+        if let Some(level) = pending {
+            self.schedule_compaction(level);
+        }
+    }
+
+    /// Merges `inputs` into new tables for the level below them. Runs without holding the partition lock,
+    /// readers keep using the input tables until the outputs are installed. The entries are cut into
+    /// runs of disjoint keys, which are merged, built into tables and written side by side on the
+    /// background workers. The caller installs the outputs only once all of them are on disk.
+    fn do_compaction(&self,
+                     inputs: &[Arc<dyn Table<Comp>>],
+                     drop_tombstones: bool,
+                     snapshots: &[u64],
+                     range_tombstones: &[RangeTombstone<Comp>]) -> Result<Vec<ScTable<Comp>>, Error> {
+        let partition = &self.0;
+        let context = &partition.context;
+        let mut entries = Vec::new();
+        let mut input_size = 0;
+        for table in inputs {
+            let cache = table.load(&context.cache_manager, &context.io_manager)?;
+            for (key, value) in cache.iter(&partition.comparator) {
+                input_size += key.user_key.key().len() + value.len();
+                entries.push((key, value.to_vec()));
+            }
+        }
+
+        // a run for every worker and the calling thread, each merged, built and written by a job
+        let options = &context.options;
+        let run_size = (input_size / (options.background_threads + 1)).max(options.table_size);
+        let snapshots = Arc::new(snapshots.to_vec());
+        let range_tombstones = Arc::new(range_tombstones.to_vec());
+        let mut jobs: Vec<Box<dyn FnOnce() -> Result<Vec<ScTable<Comp>>, Error> + Send>> = Vec::new();
+        for run in split_runs(entries, run_size) {
+            let (context, snapshots, range_tombstones) = (context.clone(), snapshots.clone(), range_tombstones.clone());
+            jobs.push(Box::new(move || {
+                let options = &context.options;
+                let merged = merge_entries(run, drop_tombstones, &snapshots, options.compaction_filter.as_deref(),
+                                           &range_tombstones, options.merge_operator.as_deref());
+                let mut tables = Vec::new();
+                for output in build_tables(&merged, options)? {
+                    let table_file = context.new_table_file();
+                    context.background.throttle(output.buffer.len());
+                    if let Err(e) = context.io_manager.acquire_quota().write_atomic(table_file.file_name(), &output.buffer) {
+                        let _ = context.io_manager.remove_file(table_file.file_name());
+                        remove_table_files(&context.io_manager, &tables);
+                        return Err(e)
+                    }
+                    tables.push(ScTable::new(table_file, output.buffer.len(), output.lower_bound, output.upper_bound));
+                }
+                Ok(tables)
+            }));
+        }
+        let mut tables = Vec::new();
+        let mut error = None;
+        for result in context.background.run_all(jobs) {
+            match result {
+                Ok(outputs) => tables.extend(outputs),
+                Err(e) => error = error.or(Some(e))
+            }
+        }
+        match error {
+            Some(e) => {
+                // nothing refers to the tables of the jobs which went through yet
+                remove_table_files(&context.io_manager, &tables);
+                Err(e)
+            },
+            None => Ok(tables)
+        }
+    }
+}
+
+/// Removes the files of `tables`, which nothing refers to, as far as it can.
+fn remove_table_files<Comp: Comparator>(io_manager: &IOManager, tables: &[ScTable<Comp>]) {
+    for table in tables {
+        let _ = io_manager.remove_file(table.table_file().file_name());
+    }
+}
+
+pub(crate) struct PartitionData<Comp: 'static + Comparator> {
+    mem_table: MemTable<Comp>,
+
+    imm_table: Option<MemTable<Comp>>,
+    levels: Vec<Level<Comp>>,
+    range_tombstones: RangeTombstones<Comp>,
+
+    log: Option<LogWriter>,
+    /// The log segment written to. The memtable is logged to the segments from `mem_log_number`
+    /// on, a new segment is started once one grows past `Options::log_segment_size`.
+    log_number: u64,
+    mem_log_number: u64,
+    /// The segments of the immutable memtable, removed once it is flushed.
+    imm_log_numbers: Option<Range<u64>>,
+
+    lower_bound: Option<UserKey<'static, Comp>>,
+    upper_bound: Option<UserKey<'static, Comp>>,
+
+    /// The largest sequence number applied to the memtables, what is written next comes after it.
+    last_seq: u64,
+    /// When the memtable was last written to, for `Options::memtable_idle_timeout`.
+    last_write: Instant,
+
+    background_error: Option<Error>,
+    compacting: bool,
+    /// A table `Options::read_compaction_threshold` lookups have searched in vain and its level,
+    /// compacted next. Only the first table found so is kept until it is compacted.
+    read_compaction: Option<(usize, ScTableFile)>,
+    /// The partition is split once its tables take more than this many bytes on disk.
+    split_size: usize,
+    /// Tables compacted away but maybe still read by an iterator, their files are removed once
+    /// nothing refers to them anymore.
+    obsolete_tables: Vec<Arc<dyn Table<Comp>>>,
+
+    context: Arc<DbContext>
+}
+
+impl<Comp: 'static + Comparator> PartitionData<Comp> {
+    fn new(context: Arc<DbContext>) -> Self {
+        Self {
+            mem_table: new_memtable(context.options.memtable_kind),
+            imm_table: None,
+            levels: Vec::new(),
+            range_tombstones: RangeTombstones::new(),
+            log: None,
+            log_number: 0,
+            mem_log_number: 0,
+            imm_log_numbers: None,
+            lower_bound: None,
+            upper_bound: None,
+            last_seq: 0,
+            last_write: Instant::now(),
+            background_error: None,
+            compacting: false,
+            read_compaction: None,
+            split_size: match context.options.partitioning {
+                Partitioning::Range => context.options.partition_size,
+                // the hash of a key picks its partition, a split would take keys away from it
+                Partitioning::Hash { .. } => usize::max_value()
+            },
+            obsolete_tables: Vec::new(),
+            context
+        }
+    }
+
+    fn background_error(&self) -> Result<(), Error> {
+        if let Some(e) = &self.background_error {
+            Err(e.clone())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn record_background_error(&mut self, error: Error) {
+        self.background_error.replace(error);
+    }
+
+    /// Forgets the obsolete tables no iterator holds anymore and returns their files with their
+    /// sizes. Iterators only pick up tables while the partition is locked, so no new reference to
+    /// them can appear.
+    fn take_unused_tables(&mut self) -> Vec<(ScTableFile, usize)> {
+        let (unused, in_use) = self.obsolete_tables.drain(..)
+            .partition(|table| Arc::strong_count(table) == 1);
+        self.obsolete_tables = in_use;
+        unused.iter().map(|table: &Arc<dyn Table<Comp>>| (table.table_file(), table.file_size())).collect()
+    }
+
+    fn has_imm(&self) -> bool {
+        self.imm_table.is_some()
+    }
+
+    fn level0_table_count(&self) -> usize {
+        self.levels.first().map_or(0, Level::table_count)
+    }
+
+    fn imm_bounds(&self) -> (UserKey<'static, Comp>, UserKey<'static, Comp>) {
+        let imm_table = self.imm_table.as_ref().unwrap();
+        let lower_bound = imm_table.first_key().unwrap();
+        let upper_bound = imm_table.last_key().unwrap();
+        (lower_bound.user_key.make_owned(), upper_bound.user_key.make_owned())
+    }
+
+    /// Fails unless the sequence numbers of `entries` ascend from after `last_seq` on. A stale one
+    /// could end up in the memtable above a newer version already flushed, and be read instead.
+    fn check_seqs(&self, entries: &[Entry<Comp>]) -> Result<(), Error> {
+        let mut last_seq = self.last_seq;
+        for (key, _) in entries {
+            if key.seq <= last_seq {
+                return Err(Error::invalid_argument(
+                    format!("sequence number {} is not after {}, the last one applied", key.seq, last_seq).into()
+                ))
+            }
+            last_seq = key.seq;
+        }
+        Ok(())
+    }
+
+    /// Logs `entries` as one record, unless there is no `sync` policy to log them with, then drains
+    /// them into the memtable.
+    fn log_and_put(&mut self, partition_id: u32, entries: &mut Vec<Entry<Comp>>, sync: Option<SyncPolicy>) -> Result<(), Error> {
+        self.log(partition_id, entries, sync)?;
+        for (key, value) in entries.drain(..) {
+            self.memtable_put(key, value);
+        }
+        Ok(())
+    }
+
+    /// Checks the sequence numbers of `entries` and logs them as one record, unless there is no
+    /// `sync` policy to log them with.
+    fn log(&mut self, partition_id: u32, entries: &[Entry<Comp>], sync: Option<SyncPolicy>) -> Result<(), Error> {
+        if entries.is_empty() {
+            return Ok(())
+        }
+        self.check_seqs(entries)?;
+        if let Some(sync) = sync {
+            self.append_log(partition_id, entries, &BatchParts::default(), sync)?;
+        }
+        Ok(())
+    }
+
+    /// Logs a write to the memtable before it is applied, as a part of a batch if `parts` names
+    /// other partitions. Databases kept in memory have nothing to recover and log nothing.
+    fn append_log(&mut self, partition_id: u32, entries: &[Entry<Comp>], parts: &BatchParts, sync: SyncPolicy) -> Result<(), Error> {
+        if !self.context.io_manager.is_persistent() {
+            return Ok(())
+        }
+        self.open_log(partition_id)?;
+        let log = self.log.as_mut().unwrap();
+        log.add_record(entries, parts, &self.context.io_manager)?;
+        if log.should_sync(sync) {
+            log.sync(&self.context.io_manager)?;
+        }
+        if log.size() >= self.context.options.log_segment_size {
+            // synced whatever the policy, a crash must not keep a segment but lose the end of the
+            // one before it
+            log.sync(&self.context.io_manager)?;
+            self.log.take();
+            self.log_number += 1;
+        }
+        Ok(())
+    }
+
+    /// Creates the log segment `log_number` names unless it exists already. The file is created on
+    /// first use, and before a batch names it.
+    fn open_log(&mut self, partition_id: u32) -> Result<(), Error> {
+        if self.log.is_none() {
+            let file_name = log_file_name(partition_id, self.log_number);
+            self.log.replace(LogWriter::create(file_name, &self.context.io_manager)?);
+        }
+        Ok(())
+    }
+
+    /// The log segments of the memtable which have been written to.
+    fn mem_log_numbers(&self) -> Range<u64> {
+        self.mem_log_number..self.log_number + self.log.is_some() as u64
+    }
+
+    /// Moves the range tombstones logged with the memtable into the manifest while the memtable
+    /// is empty, so its log holds nothing else and goes.
+    fn flush_range_tombstones(&mut self, partition_id: u32) -> Result<(), Error> {
+        if self.range_tombstones.logged().next().is_none() {
+            return Ok(())
+        }
+        self.range_tombstones.flush_log(self.log_number);
+        self.drop_settled_range_tombstones();
+        self.save_manifest(partition_id)?;
+        let log_numbers = self.mem_log_numbers();
+        self.log.take();
+        for log_number in log_numbers {
+            self.context.io_manager.remove_file(log_file_name(partition_id, log_number))?;
+        }
+        self.log_number += 1;
+        self.mem_log_number = self.log_number;
+        Ok(())
+    }
+
+    fn sync_log(&mut self) -> Result<(), Error> {
+        match self.log.as_mut() {
+            Some(log) => log.sync(&self.context.io_manager),
+            None => Ok(())
+        }
+    }
+
+    fn memtable_put(&mut self, key: InternalKey<'static, Comp>, value: Vec<u8>) {
+        self.record_write(&key);
+        if key.kind == ValueKind::RangeDeletion {
+            let end = UserKey::new_owned(value, key.user_key.comparator());
+            // tables written from here on move the fence as they are installed, if need be
+            let fence = self.context.file_number.load(atomic::Ordering::SeqCst);
+            self.range_tombstones.add_logged(RangeTombstone::new(key.seq, key.user_key, end), self.log_number, fence);
+            return
+        }
+        self.extend_bounds(&key.user_key, &key.user_key);
+        self.mem_table.insert(&key, &value);
+        self.drop_hidden_versions(key);
+    }
+
+    fn record_write(&mut self, key: &InternalKey<'static, Comp>) {
+        self.last_seq = self.last_seq.max(key.seq);
+        self.last_write = Instant::now();
+    }
+
+    /// Removes the versions of the user key of `key`, which was just written, from the memtable
+    /// which no snapshot sees: those whose next newer version was written before any snapshot was
+    /// taken in between. `key` is reused to look up the ones to remove, so nothing is copied.
+    fn drop_hidden_versions(&mut self, mut key: InternalKey<'static, Comp>) {
+        let mut hidden_versions = Vec::new();
+        {
+            let seek_key = InternalKey::new(u64::max_value(), UserKey::new_borrow(key.user_key.key(), key.user_key.comparator()));
+            let mut versions = self.mem_table.iter(Some(&seek_key))
+                .take_while(|(version, _)| version.user_key == key.user_key)
+                .map(|(version, _)| (version.seq, version.kind));
+            let mut newer = match versions.next() {
+                Some(newest) => newest,
+                None => return
+            };
+            // merge operands are folded over the versions below them, which stay until a newer
+            // value or tombstone seen by the same snapshots hides them
+            let mut hidden = newer.1 != ValueKind::Merge;
+            for version in versions {
+                if self.context.snapshots.pinned_between(version.0, newer.0) {
+                    hidden = version.1 != ValueKind::Merge;
+                } else if hidden {
+                    hidden_versions.push(version);
+                } else {
+                    hidden = version.1 != ValueKind::Merge;
+                }
+                newer = version;
+            }
+        }
+        for (seq, kind) in hidden_versions {
+            key.seq = seq;
+            key.kind = kind;
+            self.mem_table.remove(&key);
+        }
+    }
+
+    fn extend_bounds(&mut self, lower: &UserKey<'static, Comp>, upper: &UserKey<'static, Comp>) {
+        if self.lower_bound.as_ref().map_or(true, |lower_bound| lower < lower_bound) {
+            self.set_lower_bound(lower.clone());
+        }
+        if self.upper_bound.as_ref().map_or(true, |upper_bound| upper > upper_bound) {
+            self.set_upper_bound(upper.clone());
+        }
+        debug_assert!(self.debug_bounds_sanity_check());
+    }
+
+    /// Adds a table shared with the partition this one was split from.
+    fn add_table(&mut self, level: usize, table: Arc<dyn Table<Comp>>) {
+        self.extend_bounds(&table.lower_bound().make_owned(), &table.upper_bound().make_owned());
+        self.levels[level].add_table(table);
+    }
+
+    /// Adds a table newer than everything in the partition as deep as it goes: right above the
+    /// first level it overlaps, into level 0 if that is level 0, or else into the deepest level.
+    /// Returns the level it went to.
+    fn link_table(&mut self,
+                  table_file: ScTableFile,
+                  file_size: usize,
+                  lower: UserKey<'static, Comp>,
+                  upper: UserKey<'static, Comp>) -> usize {
+        let level = match self.levels.iter().position(|level| !level.overlapping(&lower, &upper).is_empty()) {
+            Some(0) => 0,
+            Some(level) => level - 1,
+            None => self.context.options.max_levels - 1
+        };
+        while self.levels.len() <= level {
+            let level_number = self.levels.len();
+            self.levels.push(Level::new(level_number));
+        }
+        // the table is newer than every range tombstone, none of them hides anything in it
+        self.range_tombstones.table_written(table_file.file_number(), &lower, &upper, |_| true);
+        self.add_table(level, Arc::new(ScTable::new(table_file, file_size, lower, upper)));
+        if level != 0 {
+            self.levels[level].sort_by_bounds();
+        }
+        level
+    }
+
+    /// Writes `entries` into new tables of `level`.
+    fn add_entries(&mut self, level: usize, entries: &[Entry<Comp>]) -> Result<(), Error> {
+        for output in build_tables(entries, &self.context.options)? {
+            let table_file = self.context.new_table_file();
+            self.context.io_manager.acquire_quota().write_atomic(table_file.file_name(), &output.buffer)?;
+            self.context.stats.record_bytes_written(output.buffer.len());
+            self.extend_bounds(&output.lower_bound, &output.upper_bound);
+            self.levels[level].add_file(ScTable::new(table_file, output.buffer.len(), output.lower_bound, output.upper_bound));
+        }
+        Ok(())
+    }
+
+    /// Drops the range tombstones of flushed memtables no table they may hide versions in
+    /// overlaps anymore.
+    fn drop_settled_range_tombstones(&mut self) {
+        let levels = &self.levels;
+        self.range_tombstones.drop_settled(|tombstone, fence| levels.iter().any(|level| {
+            level.overlapping(&tombstone.start, &tombstone.end).iter()
+                .any(|table| table.table_file().file_number() < fence)
+        }));
+    }
+
+    /// What the manifest keeps of this partition: its bounds, the tables of every level and the
+    /// range tombstones of flushed memtables.
+    fn manifest_record(&self, partition_id: u32) -> PartitionRecord {
+        let bounds = match self.bounds() {
+            (Some(lower_bound), Some(upper_bound)) => Some((lower_bound.key().to_vec(), upper_bound.key().to_vec())),
+            _ => None
+        };
+        let tables = self.levels.iter().enumerate().flat_map(|(level_number, level)| {
+            level.tables().iter().map(move |table| TableRecord {
+                level: level_number as u32,
+                table_file: table.table_file(),
+                file_size: table.file_size(),
+                lower_bound: table.lower_bound().key().to_vec(),
+                upper_bound: table.upper_bound().key().to_vec()
+            })
+        }).collect();
+        let range_tombstones = self.range_tombstones.flushed().map(|(tombstone, fence)| RangeTombstoneRecord {
+            seq: tombstone.seq,
+            fence,
+            start: tombstone.start.key().to_vec(),
+            end: tombstone.end.key().to_vec()
+        }).collect();
+        PartitionRecord { partition_id, bounds, tables, range_tombstones }
+    }
+
+    fn save_manifest(&self, partition_id: u32) -> Result<(), Error> {
+        let last_seq = self.context.seq.load(atomic::Ordering::SeqCst);
+        self.context.manifest.update(self.manifest_record(partition_id), last_seq, &self.context.io_manager)
+    }
+
+    fn disk_size(&self) -> usize {
+        self.levels.iter().map(|level| level.size()).sum()
+    }
+
+    fn requires_split(&self) -> bool {
+        self.disk_size() > self.split_size
+    }
+
+    /// A user key close to the median of the tables, by size on disk. It is above the lower bound,
+    /// so neither side of a split at it is empty. `None` if there is no such key.
+    fn pick_split_key(&self,
+                      comparator: &Arc<Comp>,
+                      cache_manager: &TableCacheManager,
+                      io_manager: &IOManager) -> Result<Option<UserKey<'static, Comp>>, Error> {
+        let lower_bound = match self.lower_bound.as_ref() {
+            Some(lower_bound) => lower_bound,
+            None => return Ok(None)
+        };
+        let mut tables: Vec<_> = self.levels.iter().flat_map(|level| level.tables().iter()).collect();
+        tables.sort_by(|lhs, rhs| lhs.lower_bound().cmp(rhs.lower_bound()));
+        let mut remaining = self.disk_size() / 2;
+        for table in tables {
+            if remaining >= table.file_size() {
+                remaining -= table.file_size();
+                continue;
+            }
+            let cache = table.load(cache_manager, io_manager)?;
+            let first = cache.catalog_size() * remaining / table.file_size();
+            let mut keys = KeyCursor::default();
+            return Ok((first..cache.catalog_size())
+                .map(|n| keys.nth_key(&cache, n, comparator).user_key)
+                .find(|user_key| user_key > lower_bound))
+        }
+        Ok(None)
+    }
+
+    /// Picks the tables of a compaction from `input_level` into the next level: every table of
+    /// level 0 since they may overlap each other, otherwise the first table, together with all
+    /// tables of the output level overlapping them.
+    fn pick_compaction(&self, input_level: usize, read_input: Option<ScTableFile>) -> Vec<Arc<dyn Table<Comp>>> {
+        let tables = self.levels[input_level].tables();
+        let mut inputs: Vec<Arc<dyn Table<Comp>>> = if input_level == 0 {
+            tables.to_vec()
+        } else {
+            let idx = read_input.and_then(|read_input| tables.iter().position(|table| table.table_file() == read_input));
+            vec![tables[idx.unwrap_or(0)].clone()]
+        };
+        let lower = inputs.iter().map(|table| table.lower_bound()).min().unwrap().clone();
+        let upper = inputs.iter().map(|table| table.upper_bound()).max().unwrap().clone();
+        inputs.extend(self.levels[input_level + 1].overlapping(&lower, &upper).into_iter().cloned());
+        inputs
+    }
+
+    /// Moves `table` from `level` to the level below without rewriting it, which it must not
+    /// overlap any table of. The table gets a new handle, so its wasted reads count afresh there.
+    fn move_table(&mut self, level: usize, table: &Arc<dyn Table<Comp>>) {
+        let table_file = table.table_file();
+        self.levels[level].remove_files(&[table_file]);
+        self.levels[level + 1].add_file(ScTable::new(table_file, table.file_size(),
+                                                     table.lower_bound().make_owned(),
+                                                     table.upper_bound().make_owned()));
+        self.levels[level + 1].sort_by_bounds();
+    }
+
+    fn memtable_get(&self, key: &InternalKey<Comp>) -> LookupResult {
+        memtable_lookup(self.mem_table.as_ref(), key, |_, v| v.to_vec())
+    }
+
+    fn imm_get(&self, key: &InternalKey<Comp>) -> LookupResult {
+        self.imm_table.as_ref().and_then(|imm_table| memtable_lookup(imm_table.as_ref(), key, |_, v| v.to_vec()))
+    }
+
+    /// Looks `key` up in the memtable, then in the immutable memtable. `read_value` turns the
+    /// version found and its value into what the caller is after.
+    fn memtables_lookup<T>(&self,
+                           key: &InternalKey<Comp>,
+                           read_value: impl Fn(&InternalKey<Comp>, &[u8]) -> T) -> LookupResult<T> {
+        memtable_lookup(self.mem_table.as_ref(), key, &read_value).or_else(
+            || self.imm_table.as_ref().and_then(|imm_table| memtable_lookup(imm_table.as_ref(), key, &read_value))
+        )
+    }
+
+    fn levels_get(&mut self,
+                  key: &InternalKey<Comp>,
+                  cache_manager: &TableCacheManager,
+                  io_manager: &IOManager) -> Result<(LookupResult, Option<usize>), Error> {
+        self.levels_lookup(key, |table| table.get(key, cache_manager, io_manager))
+    }
+
+    /// Asks the tables which may hold `key` level by level, newest first, until one of them has it.
+    /// Also returns the level of a table this lookup has made `read_compaction`, which is then
+    /// for the caller to compact.
+    fn levels_lookup<T>(&mut self,
+                        key: &InternalKey<Comp>,
+                        mut lookup: impl FnMut(&Arc<dyn Table<Comp>>) -> Result<LookupResult<T>, Error>)
+        -> Result<(LookupResult<T>, Option<usize>), Error> {
+        let options = &self.context.options;
+        // a read-only database has no background thread to compact with
+        let threshold = if self.context.read_only { 0 } else { options.read_compaction_threshold };
+        let mut wasted = None;
+        let mut value = None;
+        for (level_number, level) in self.levels.iter().enumerate() {
+            let mut lookup = |table: &Arc<dyn Table<Comp>>| {
+                let found = lookup(table)?;
+                if found.is_none() && threshold != 0 && level_number + 1 < options.max_levels
+                   && table.wasted_reads() >= threshold {
+                    wasted.get_or_insert((level_number, table.table_file()));
+                }
+                Ok(found)
+            };
+            value = level.get(key, &mut lookup)?;
+            if value.is_some() {
+                break
+            }
+        }
+        if self.read_compaction.is_some() || wasted.is_none() {
+            return Ok((value, None))
+        }
+        self.read_compaction = wasted;
+        Ok((value, wasted.map(|(level, _)| level)))
+    }
+
+    /// Looks `key` up like `memtables_lookup` and `levels_lookup` together, but folds merge operands
+    /// over the versions below them with `merge_operator`. The value comes with the sequence number
+    /// of the newest version it is made of. Range tombstones are taken care of as well.
+    fn merged_get(&mut self,
+                  key: &InternalKey<Comp>,
+                  merge_operator: &dyn MergeOperator,
+                  cache_manager: &TableCacheManager,
+                  io_manager: &IOManager) -> Result<(Option<(Vec<u8>, u64)>, Option<usize>), Error> {
+        let range_del = self.range_tombstones.aggregator(key.seq);
+        let mut state = MergeState::new();
+        let seek_key = key.make_owned();
+        for table in std::iter::once(&self.mem_table).chain(self.imm_table.iter()) {
+            for (version, value) in table.iter(Some(&seek_key)) {
+                if version.user_key != seek_key.user_key || state.is_done() {
+                    break
+                }
+                state.push_unless_deleted(version, value.to_vec(), range_del.as_ref());
+            }
+        }
+        let mut read_compaction = None;
+        if !state.is_done() {
+            read_compaction = self.levels_lookup(key, |table| {
+                for (version, value) in table.get_versions(key, cache_manager, io_manager)? {
+                    state.push_unless_deleted(&version, value, range_del.as_ref());
+                }
+                Ok(Some(Some(())).filter(|_| state.is_done()))
+            })?.1;
+        }
+        let newest_seq = state.newest_seq();
+        let value = state.finish(key.user_key.key(), merge_operator);
+        Ok((value.zip(newest_seq), read_compaction))
+    }
+
+    /// Asks the `RangeDelAggregator` read as of `key.seq` whether it deletes the version of `key` a
+    /// lookup finds. The key is only looked up once more, for the sequence number of its version,
+    /// if a tombstone covers it.
+    fn range_deleted(&mut self,
+                     key: &InternalKey<Comp>,
+                     cache_manager: &TableCacheManager,
+                     io_manager: &IOManager) -> Result<bool, Error> {
+        let range_del = match self.range_tombstones.aggregator(key.seq) {
+            Some(range_del) if range_del.covering_seq(&key.user_key).is_some() => range_del,
+            _ => return Ok(false)
+        };
+        let found = match self.memtables_lookup(key, |k, _| k.seq) {
+            Some(found) => found,
+            None => self.levels_lookup(key, |table| {
+                Ok(table.get_with_seq(key, cache_manager, io_manager)?.map(|value| value.map(|(_, seq)| seq)))
+            })?.0.flatten()
+        };
+        Ok(found.map_or(false, |seq| range_del.should_delete(&key.user_key, seq)))
+    }
+
+    fn convert_mem_to_imm(&mut self) {
+        let new_mem = self.mem_table.new_empty();
+        let new_imm = std::mem::replace(&mut self.mem_table, new_mem);
+        self.imm_table.replace(new_imm);
+        // the log of the old memtable is removed once the flush is done, the new one gets a new log
+        self.log.take();
+        self.imm_log_numbers.replace(self.mem_log_number..self.log_number + 1);
+        self.log_number += 1;
+        self.mem_log_number = self.log_number;
+    }
+
+    /// Whether the memtable holds a key of `[lower, upper]`.
+    fn memtable_overlaps(&self, lower: &UserKey<'static, Comp>, upper: &UserKey<Comp>) -> bool {
+        let seek_key = InternalKey::new(u64::max_value(), lower.clone());
+        self.mem_table.iter(Some(&seek_key)).next().map_or(false, |(key, _)| &key.user_key <= upper)
+    }
+
+    fn memtable_is_empty(&self) -> bool {
+        self.mem_table.is_empty()
+    }
+
+    /// What the memtable takes in a table, with the keys and values it holds counted as the bytes
+    /// copied into it. Versions overwritten or dropped keep taking memory until the memtable is
+    /// flushed, so overwriting the same key fills it as well.
+    fn memtable_size(&self) -> usize {
+        self.mem_table.arena_size() + self.mem_table.len() * TABLE_CATALOG_ITEM_SIZE + TABLE_MIN_SIZE
+    }
+
+    fn bounds(&self) -> (Option<&UserKey<'static, Comp>>, Option<&UserKey<'static, Comp>>) {
+        (self.lower_bound.as_ref(), self.upper_bound.as_ref())
+    }
+
+    fn set_lower_bound(&mut self, lower_bound: UserKey<'static, Comp>) {
+        debug_assert!(lower_bound.is_owned());
+        self.lower_bound.replace(lower_bound);
+    }
+
+    fn set_upper_bound(&mut self, upper_bound: UserKey<'static, Comp>) {
+        debug_assert!(upper_bound.is_owned());
         self.upper_bound.replace(upper_bound);
     }
 
@@ -399,3 +2316,586 @@ impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
         self.lower_bound.is_some() == self.upper_bound.is_some()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, BTreeSet, HashSet};
+    use std::sync::Arc;
+    use std::sync::atomic;
+
+    use crate::{Options, DefaultComparator, SyncPolicy};
+    use crate::db::{DbContext, Snapshot};
+    use crate::testutil::{TestDir, default_comparator};
+    use crate::error::Error;
+    use crate::partition::{Partition, ArcPartition, Entry, InternalKey, UserKey, ValueKind};
+    use crate::partition::level::Level;
+    use crate::wal::{BatchParts, LogWriter, log_file_name};
+
+    fn put(partition: &ArcPartition<DefaultComparator>, seq: u64, key: &[u8], value: &[u8]) {
+        let key = InternalKey::new(seq, UserKey::new_owned(key.to_vec(), &partition.0.comparator));
+        partition.write(&mut vec![(key, value.to_vec())], SyncPolicy::NoSync).unwrap();
+    }
+
+    fn delete(partition: &ArcPartition<DefaultComparator>, seq: u64, key: &[u8]) {
+        let key = InternalKey::new_deletion(seq, UserKey::new_owned(key.to_vec(), &partition.0.comparator));
+        partition.write(&mut vec![(key, Vec::new())], SyncPolicy::NoSync).unwrap();
+    }
+
+    fn delete_range(partition: &ArcPartition<DefaultComparator>, seq: u64, start: &[u8], end: &[u8]) {
+        let key = InternalKey::new_range_deletion(seq, UserKey::new_owned(start.to_vec(), &partition.0.comparator));
+        partition.write(&mut vec![(key, end.to_vec())], SyncPolicy::NoSync).unwrap();
+    }
+
+    /// Tests write with sequence numbers of their own, the snapshot pins `seq` nonetheless.
+    fn snapshot_at(partition: &ArcPartition<DefaultComparator>, seq: u64) -> Snapshot {
+        partition.0.context.seq.store(seq, atomic::Ordering::SeqCst);
+        Snapshot::new(partition.0.context.clone())
+    }
+
+    fn get(partition: &ArcPartition<DefaultComparator>, seq: u64, key: &[u8]) -> Option<Vec<u8>> {
+        partition.get(&InternalKey::new(seq, UserKey::new_borrow(key, &partition.0.comparator))).unwrap()
+    }
+
+    #[test]
+    fn test_trivial_move() {
+        let dir = TestDir::new("partition_trivial_move");
+        let mut options = Options::new("test_trivial_move", 4, 4, 100, 4, 4096, 64, 64);
+        options.level1_bytes = 100;
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context.clone(), 0, default_comparator()));
+        let entries = |keys: std::ops::Range<u64>| -> Vec<Entry<DefaultComparator>> {
+            keys.map(|i| {
+                let key = UserKey::new_owned(format!("key{:03}", i).into_bytes(), &partition.0.comparator);
+                (InternalKey::new(i + 1, key), format!("value{}", i).into_bytes())
+            }).collect()
+        };
+        let level_files = |level: usize| -> Vec<u64> {
+            let data = partition.0.lock();
+            data.levels[level].tables().iter().map(|table| table.table_file().file_number()).collect()
+        };
+        {
+            let mut data = partition.0.lock();
+            data.levels.extend((0..3).map(Level::new));
+            data.add_entries(1, &entries(0..10)).unwrap();
+            data.add_entries(2, &entries(50..60)).unwrap();
+        }
+        let (moved, below) = (level_files(1), level_files(2));
+        let bytes_written = context.stats.bytes_written();
+
+        // the table of level 1 overlaps nothing in level 2, so its file goes there as it is
+        partition.schedule_compaction(1);
+        assert!(level_files(1).is_empty());
+        assert_eq!(level_files(2), [moved.clone(), below.clone()].concat());
+        assert_eq!((context.stats.compactions(), context.stats.bytes_written()), (0, bytes_written));
+        assert!(context.io_manager.acquire_quota().read_file(format!("{:08}.sct", moved[0])).is_ok());
+        for i in (0..10).chain(50..60) {
+            let key = format!("key{:03}", i);
+            assert_eq!(get(&partition, u64::max_value(), key.as_bytes()), Some(format!("value{}", i).into_bytes()));
+        }
+
+        // one overlapping a table below is merged with it as before
+        partition.0.lock().add_entries(1, &entries(55..65)).unwrap();
+        partition.schedule_compaction(1);
+        assert!(level_files(1).is_empty());
+        assert_eq!(context.stats.compactions(), 1);
+        assert_eq!(level_files(2)[0], moved[0]);
+        assert!(!level_files(2).contains(&below[0]));
+    }
+
+    #[test]
+    fn test_stale_seq_rejected() {
+        let dir = TestDir::new("partition_stale_seq_rejected");
+        let options = Options::new("test_stale_seq_rejected", 4, 4, 10, 4, 4096, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+        let write = |entries: &[(u64, &[u8])]| {
+            let mut entries = entries.iter()
+                .map(|&(seq, key)| (InternalKey::new(seq, UserKey::new_owned(key.to_vec(), &partition.0.comparator)), b"value".to_vec()))
+                .collect();
+            partition.write(&mut entries, SyncPolicy::NoSync)
+        };
+
+        write(&[(5, b"a")]).unwrap();
+        for &(seq, key) in [(5, b"b"), (3, b"a"), (0, b"c")].iter() {
+            match write(&[(seq, key)]) {
+                Err(Error::InvalidArgument { .. }) => (),
+                result => panic!("a stale sequence number must be refused, got {:?}", result)
+            }
+        }
+        // a batch is refused as a whole if it goes back within itself
+        assert!(write(&[(6, b"b"), (8, b"c"), (7, b"d")]).is_err());
+        assert_eq!(get(&partition, u64::max_value(), b"b"), None);
+        write(&[(6, b"b"), (7, b"c")]).unwrap();
+        assert_eq!(get(&partition, u64::max_value(), b"c"), Some(b"value".to_vec()));
+
+        // so is a log going back, when it is replayed
+        drop(partition);
+        let context = Arc::new(DbContext::new(dir.path(), Options::new("test_stale_seq_rejected", 4, 4, 10, 4, 4096, 64, 64)));
+        let partition = ArcPartition::new(Partition::new(context.clone(), 0, default_comparator()));
+        assert_eq!(partition.recover(&HashSet::new()).unwrap(), 7);
+        let mut log = LogWriter::create(log_file_name(0, 100), &context.io_manager).unwrap();
+        log.add_record(&[(InternalKey::new(2, UserKey::new_owned(b"z".to_vec(), &partition.0.comparator)), Vec::new())],
+                       &BatchParts::default(), &context.io_manager).unwrap();
+        log.sync(&context.io_manager).unwrap();
+        drop(log);
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+        assert!(partition.recover(&HashSet::new()).is_err());
+    }
+
+    #[test]
+    fn test_overwrites_keep_memtable_bounded() {
+        let dir = TestDir::new("partition_overwrites_keep_memtable_bounded");
+        let options = Options::new("test_overwrites_keep_memtable_bounded", 4, 4, 10, 4, 1 << 20, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+        let memtable = || {
+            let data = partition.0.lock();
+            (data.mem_table.len(), data.mem_table.approximate_size())
+        };
+
+        for seq in 1..=5000 {
+            put(&partition, seq, b"key", format!("value{:04}", seq).as_bytes());
+        }
+        assert_eq!(memtable(), (1, 3 + 9));
+        // the versions dropped still take their bytes
+        assert_eq!(partition.0.lock().mem_table.arena_size(), 5000 * (3 + 9));
+        delete(&partition, 5001, b"key");
+        assert_eq!(memtable(), (1, 3));
+
+        // a snapshot keeps the version it sees, and only that one
+        put(&partition, 5002, b"key", b"seen");
+        let snapshot = snapshot_at(&partition, 5002);
+        for seq in 5003..=6000 {
+            put(&partition, seq, b"key", b"later");
+        }
+        assert_eq!(memtable(), (2, 3 + 4 + 3 + 5));
+        assert_eq!(get(&partition, snapshot.seq(), b"key"), Some(b"seen".to_vec()));
+        assert_eq!(get(&partition, u64::max_value(), b"key"), Some(b"later".to_vec()));
+
+        drop(snapshot);
+        put(&partition, 6001, b"key", b"last");
+        assert_eq!(memtable(), (1, 3 + 4));
+        assert_eq!(partition.0.context.stats.flushes(), 0);
+    }
+
+    #[test]
+    fn test_overwrites_fill_memtable() {
+        let dir = TestDir::new("partition_overwrites_fill_memtable");
+        let options = Options::new("test_overwrites_fill_memtable", 4, 4, 10, 4, 4096, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+
+        // a single key overwritten again and again is flushed once its copies reach the table size
+        for seq in 1..=5000 {
+            put(&partition, seq, b"key", format!("value{:04}", seq).as_bytes());
+            let data = partition.0.lock();
+            assert_eq!(data.mem_table.len(), 1);
+            assert!(data.memtable_size() <= 4096);
+        }
+        partition.0.context.background.wait_idle();
+        let arena_size = partition.0.lock().mem_table.arena_size();
+        assert!(arena_size > 0 && arena_size < 4096);
+        assert!(partition.0.context.stats.flushes() >= 5000 * (3 + 9) / 4096);
+        assert_eq!(get(&partition, u64::max_value(), b"key"), Some(b"value5000".to_vec()));
+    }
+
+    #[test]
+    fn test_memtable_size_counts_data() {
+        let dir = TestDir::new("partition_memtable_size_counts_data");
+        let options = Options::new("test_memtable_size_counts_data", 4, 4, 10, 4, 4096, 64, 1024);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+
+        put(&partition, 1, b"key", &[b'x'; 100]);
+        // writes refuse the very same version again, the memtable replaces it
+        let key = InternalKey::new(1, UserKey::new_owned(b"key".to_vec(), &partition.0.comparator));
+        partition.0.lock().memtable_put(key, vec![b'y'; 30]);
+        put(&partition, 2, b"other", &[b'z'; 10]);
+        assert_eq!(partition.0.lock().mem_table.approximate_size(), 3 + 30 + 5 + 10);
+
+        // large values reach the table size long before the catalog alone would
+        for seq in 3..23 {
+            put(&partition, seq, format!("key{:02}", seq).as_bytes(), &[b'v'; 1000]);
+            assert!(partition.0.lock().memtable_size() <= 4096);
+        }
+        partition.0.context.background.wait_idle();
+        assert!(partition.0.context.stats.flushes() >= 4);
+        for seq in 3..23 {
+            assert_eq!(get(&partition, u64::max_value(), format!("key{:02}", seq).as_bytes()), Some(vec![b'v'; 1000]));
+        }
+    }
+
+    #[test]
+    fn test_borrowed_key_outlived_by_owned_copy() {
+        let comparator = default_comparator();
+        let owned = {
+            let buffer = b"key".to_vec();
+            let borrowed = UserKey::new_borrow(&buffer, &comparator);
+            assert!(borrowed == UserKey::new_owned(b"key".to_vec(), &comparator));
+            assert!(!borrowed.is_owned());
+            borrowed.make_owned()
+        };
+        assert!(owned.is_owned());
+        assert_eq!(owned.key(), b"key");
+    }
+
+    #[test]
+    fn test_sort_empty_and_populated() {
+        let dir = TestDir::new("partition_sort_empty_and_populated");
+        let options = Options::new("test_sort_empty_and_populated", 4, 4, 10, 4, 4096, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let comparator = default_comparator();
+        let partition = |id| ArcPartition::new(Partition::new(context.clone(), id, comparator.clone()));
+
+        let populated_high = partition(0);
+        put(&populated_high, 1, b"x", b"value");
+        put(&populated_high, 2, b"z", b"value");
+        let empty = partition(1);
+        let populated_low = partition(2);
+        put(&populated_low, 3, b"a", b"value");
+        put(&populated_low, 4, b"c", b"value");
+        let other_empty = partition(3);
+
+        let mut partitions = vec![populated_high.clone(), other_empty.clone(), populated_low.clone(), empty.clone()];
+        partitions.sort();
+        let ids: Vec<_> = partitions.iter().map(|partition| partition.0.partition_id).collect();
+        // the sort is stable, and the partitions without bounds are equal
+        assert_eq!(ids, vec![3, 1, 2, 0]);
+        assert!(populated_low < populated_high);
+        assert!(empty < populated_low);
+        assert_eq!(empty.cmp(&other_empty), std::cmp::Ordering::Equal);
+        assert_eq!(empty.cmp(&empty), std::cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn test_eq_agrees_with_ord() {
+        let dir = TestDir::new("partition_eq_agrees_with_ord");
+        let options = Options::new("test_eq_agrees_with_ord", 4, 4, 10, 4, 4096, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let comparator = default_comparator();
+        let partition = |id| ArcPartition::new(Partition::new(context.clone(), id, comparator.clone()));
+
+        let low = partition(0);
+        put(&low, 1, b"a", b"value");
+        let high = partition(1);
+        put(&high, 2, b"x", b"value");
+        let empty = partition(2);
+        // another partition with the bounds of `low` under an id of its own
+        let same_bounds = partition(3);
+        put(&same_bounds, 3, b"a", b"value");
+        // another partition with the bounds and id of `low`, like a copy restored from the manifest
+        let low_copy = partition(0);
+        put(&low_copy, 4, b"a", b"value");
+
+        let partitions = [&low, &high, &empty, &same_bounds, &low_copy];
+        for lhs in partitions.iter() {
+            for rhs in partitions.iter() {
+                assert_eq!(lhs == rhs, lhs.cmp(rhs) == std::cmp::Ordering::Equal);
+                assert_eq!(lhs.cmp(rhs), rhs.cmp(lhs).reverse());
+            }
+        }
+        assert!(low == low.clone());
+        assert!(low == low_copy && low == same_bounds && empty == partition(4));
+        assert!(low != high && low != empty);
+
+        let set: BTreeSet<_> = partitions.iter().map(|&partition| partition.clone()).collect();
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(&low_copy));
+        let mut sorted: Vec<_> = partitions.iter().map(|&partition| partition.clone()).collect();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 3);
+    }
+
+    #[test]
+    fn test_internal_key_round_trip() {
+        let comparator = default_comparator();
+        let user_keys: [&[u8]; 4] = [b"", b"key", "ключ \u{1F511}".as_bytes(), &[0x00, 0xFF, 0x80, 0x00]];
+        let kinds = [ValueKind::Deletion, ValueKind::Value, ValueKind::RangeDeletion, ValueKind::Merge];
+        let mut buf = Vec::new();
+        let mut keys = Vec::new();
+        for &seq in &[0, 1, 1 << 56, u64::max_value() - 1, u64::max_value()] {
+            for &user_key in &user_keys {
+                for &kind in &kinds {
+                    let key = InternalKey { seq, kind, user_key: UserKey::new_owned(user_key.to_vec(), &comparator) };
+                    let start = buf.len();
+                    key.encode_into(&mut buf);
+                    assert_eq!(buf.len() - start, key.encoded_size());
+                    keys.push(key);
+                }
+            }
+        }
+
+        // keys are decoded one after the other
+        let mut rest = buf.as_slice();
+        for key in keys.iter() {
+            let (decoded, size) = InternalKey::decode(rest, &comparator).unwrap();
+            assert_eq!((decoded.seq, decoded.kind, decoded.user_key.key()), (key.seq, key.kind, key.user_key.key()));
+            assert_eq!(size, key.encoded_size());
+            rest = &rest[size..];
+        }
+        assert!(rest.is_empty());
+
+        let mut encoded = Vec::new();
+        keys[7].encode_into(&mut encoded);
+        for len in 0..encoded.len() {
+            assert!(matches!(InternalKey::decode(&encoded[..len], &comparator), Err(Error::DecodeError { .. })));
+        }
+        encoded[8] = 4;
+        assert!(matches!(InternalKey::decode(&encoded, &comparator), Err(Error::DecodeError { .. })));
+    }
+
+    #[test]
+    fn test_get_newer_version_wins() {
+        let dir = TestDir::new("partition_get_newer_version_wins");
+        let options = Options::new("test_get_newer_version_wins", 4, 4, 10, 4, 4096, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+
+        put(&partition, 1, b"key", b"old");
+        put(&partition, 2, b"other", b"value");
+        partition.0.lock().convert_mem_to_imm();
+        put(&partition, 3, b"key", b"new");
+
+        assert_eq!(get(&partition, u64::max_value(), b"key"), Some(b"new".to_vec()));
+        assert_eq!(get(&partition, 2, b"key"), Some(b"old".to_vec()));
+        assert_eq!(get(&partition, u64::max_value(), b"other"), Some(b"value".to_vec()));
+        assert_eq!(get(&partition, 1, b"other"), None);
+        assert_eq!(get(&partition, u64::max_value(), b"missing"), None);
+    }
+
+    #[test]
+    fn test_get_across_levels() {
+        let dir = TestDir::new("partition_get_across_levels");
+        let options = Options::new("test_get_across_levels", 4, 4, 10, 4, 4096, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+
+        put(&partition, 1, b"key", b"flushed");
+        put(&partition, 2, b"only_flushed", b"value");
+        partition.0.lock().convert_mem_to_imm();
+        partition.compact_memtable();
+        put(&partition, 3, b"key", b"in_memory");
+
+        assert_eq!(get(&partition, u64::max_value(), b"key"), Some(b"in_memory".to_vec()));
+        assert_eq!(get(&partition, 2, b"key"), Some(b"flushed".to_vec()));
+        assert_eq!(get(&partition, u64::max_value(), b"only_flushed"), Some(b"value".to_vec()));
+        assert_eq!(get(&partition, u64::max_value(), b"missing"), None);
+    }
+
+    #[test]
+    fn test_tombstone_hides_older_levels() {
+        let dir = TestDir::new("partition_tombstone_hides_older_levels");
+        let options = Options::new("test_tombstone_hides_older_levels", 4, 4, 10, 4, 4096, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+
+        put(&partition, 1, b"deleted_in_memory", b"value");
+        put(&partition, 2, b"deleted_on_disk", b"value");
+        // the older versions read below are only kept while a snapshot needs them
+        let _at_2 = snapshot_at(&partition, 2);
+        delete(&partition, 3, b"deleted_on_disk");
+        partition.0.lock().convert_mem_to_imm();
+        partition.compact_memtable();
+        let _at_3 = snapshot_at(&partition, 3);
+        delete(&partition, 4, b"deleted_in_memory");
+        put(&partition, 5, b"deleted_on_disk", b"revived");
+
+        assert_eq!(get(&partition, u64::max_value(), b"deleted_in_memory"), None);
+        assert_eq!(get(&partition, 3, b"deleted_in_memory"), Some(b"value".to_vec()));
+        assert_eq!(get(&partition, u64::max_value(), b"deleted_on_disk"), Some(b"revived".to_vec()));
+        assert_eq!(get(&partition, 4, b"deleted_on_disk"), None);
+        assert_eq!(get(&partition, 2, b"deleted_on_disk"), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_range_tombstone_hides_older_versions() {
+        let dir = TestDir::new("partition_range_tombstone_hides_older_versions");
+        let options = Options::new("test_range_tombstone_hides_older_versions", 4, 4, 10, 4, 4096, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+
+        put(&partition, 1, b"a", b"on disk");
+        put(&partition, 2, b"b", b"on disk");
+        partition.0.lock().convert_mem_to_imm();
+        partition.compact_memtable();
+        put(&partition, 3, b"c", b"in memory");
+        put(&partition, 4, b"x", b"out of range");
+        let _at_4 = snapshot_at(&partition, 4);
+        delete_range(&partition, 5, b"a", b"d");
+        put(&partition, 6, b"b", b"written after");
+
+        // a version written before the tombstone is gone, one written after is there
+        assert_eq!(get(&partition, u64::max_value(), b"a"), None);
+        assert_eq!(get(&partition, u64::max_value(), b"c"), None);
+        assert_eq!(get(&partition, u64::max_value(), b"b"), Some(b"written after".to_vec()));
+        assert_eq!(get(&partition, u64::max_value(), b"x"), Some(b"out of range".to_vec()));
+        let latest: Vec<_> = partition.iter(None, u64::max_value()).map(|(k, _)| k).collect();
+        assert_eq!(latest, vec![b"b".to_vec(), b"x".to_vec()]);
+        // a read from before the tombstone does not see it
+        assert_eq!(get(&partition, 4, b"c"), Some(b"in memory".to_vec()));
+        assert_eq!(get(&partition, 5, b"b"), None);
+        let before: Vec<_> = partition.iter(None, 4).map(|(k, _)| k).collect();
+        assert_eq!(before, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"x".to_vec()]);
+    }
+
+    #[test]
+    fn test_flush_full_memtable() {
+        let dir = TestDir::new("partition_flush_full_memtable");
+        let options = Options::new("test_flush_full_memtable", 4, 4, 10, 4, 256, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+
+        for i in 0..20u64 {
+            put(&partition, i + 1, format!("key{:02}", i).as_bytes(), format!("value{:02}", i).as_bytes());
+        }
+        partition.0.context.background.wait_idle();
+        {
+            let data = partition.0.lock();
+            assert!(!data.has_imm());
+            assert!(data.levels[0].table_count() >= 1);
+            assert!(data.mem_table.len() < 20);
+        }
+        for i in 0..20u64 {
+            assert_eq!(get(&partition, u64::max_value(), format!("key{:02}", i).as_bytes()),
+                       Some(format!("value{:02}", i).into_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_compaction_keeps_data_readable() {
+        let dir = TestDir::new("partition_compaction_keeps_data_readable");
+        let options = Options::new("test_compaction_keeps_data_readable", 4, 1, 4, 4, 512, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+
+        let mut seq = 0;
+        for round in 0..4 {
+            for i in 0..100u64 {
+                seq += 1;
+                if round == 3 && i % 3 == 0 {
+                    delete(&partition, seq, format!("key{:03}", i).as_bytes());
+                } else {
+                    put(&partition, seq, format!("key{:03}", i).as_bytes(), format!("value{}_{}", round, i).as_bytes());
+                }
+            }
+        }
+        partition.0.context.background.wait_idle();
+
+        {
+            let data = partition.0.lock();
+            assert!(data.background_error().is_ok());
+            assert!(data.levels.len() >= 2);
+            assert!(data.levels[0].table_count() <= 1);
+            for level in data.levels[1..].iter() {
+                for pair in level.tables().windows(2) {
+                    assert!(pair[0].upper_bound() < pair[1].lower_bound());
+                }
+            }
+            let table_count: usize = data.levels.iter().map(|level| level.table_count()).sum();
+            let file_count = std::fs::read_dir(dir.path()).unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == "sct")
+                .count();
+            assert_eq!(file_count, table_count);
+            for level in data.levels.iter() {
+                assert!(level.tables().iter().all(|table| table.table_file().path(dir.path()).exists()));
+            }
+        }
+        for i in 0..100u64 {
+            let expected = if i % 3 == 0 { None } else { Some(format!("value3_{}", i).into_bytes()) };
+            assert_eq!(get(&partition, u64::max_value(), format!("key{:03}", i).as_bytes()), expected);
+        }
+    }
+
+    #[test]
+    fn test_iter_across_levels() {
+        let dir = TestDir::new("partition_iter_across_levels");
+        let options = Options::new("test_iter_across_levels", 4, 1, 4, 4, 512, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+
+        let mut expected = BTreeMap::new();
+        let mut seq = 0;
+        for round in 0..3 {
+            for i in (0..120u64).rev() {
+                seq += 1;
+                let key = format!("key{:03}", i);
+                if (i + round) % 5 == 0 {
+                    delete(&partition, seq, key.as_bytes());
+                    expected.remove(&key.into_bytes());
+                } else {
+                    let value = format!("value{}_{}", round, i);
+                    put(&partition, seq, key.as_bytes(), value.as_bytes());
+                    expected.insert(key.into_bytes(), value.into_bytes());
+                }
+            }
+        }
+        partition.0.context.background.wait_idle();
+        assert!(partition.0.lock().levels.len() >= 2);
+
+        let all: Vec<_> = partition.iter(None, seq).collect();
+        assert_eq!(all, expected.clone().into_iter().collect::<Vec<_>>());
+        let from_middle: Vec<_> = partition.iter(Some(b"key060"), seq).collect();
+        assert_eq!(from_middle, expected.range(b"key060".to_vec()..).map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>());
+
+        // the same keys without their values
+        let keys: Vec<_> = partition.iter_keys(None, seq).collect();
+        assert_eq!(keys, expected.keys().map(|k| (k.clone(), Vec::new())).collect::<Vec<_>>());
+        let keys: Vec<_> = partition.iter_keys(Some(b"key060"), seq).map(|(k, _)| k).collect();
+        assert_eq!(keys, expected.range(b"key060".to_vec()..).map(|(k, _)| k.clone()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_iter_seek_pagination() {
+        let dir = TestDir::new("partition_iter_seek_pagination");
+        let options = Options::new("test_iter_seek_pagination", 4, 1, 4, 4, 512, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let partition = ArcPartition::new(Partition::new(context, 0, default_comparator()));
+
+        let mut expected = BTreeMap::new();
+        let mut seq = 0;
+        for round in 0..3 {
+            for i in 0..90u64 {
+                seq += 1;
+                let key = format!("key{:03}", i * 7 % 90);
+                if (i + round) % 4 == 0 {
+                    delete(&partition, seq, key.as_bytes());
+                    expected.remove(&key.into_bytes());
+                } else {
+                    let value = format!("value{}_{}", round, i);
+                    put(&partition, seq, key.as_bytes(), value.as_bytes());
+                    expected.insert(key.into_bytes(), value.into_bytes());
+                }
+            }
+        }
+        partition.0.context.background.wait_idle();
+        assert!(partition.0.lock().levels.len() >= 2);
+
+        // pages of 5, every page restarts right past the last key of the one before
+        let mut pages: Vec<Vec<(Vec<u8>, Vec<u8>)>> = Vec::new();
+        loop {
+            let mut iter = partition.iter(None, seq);
+            if let Some((last_key, _)) = pages.last().and_then(|page| page.last()) {
+                let mut past_last = last_key.clone();
+                past_last.push(0);
+                iter.seek(&past_last);
+            }
+            let page: Vec<_> = iter.by_ref().take(5).collect();
+            assert!(iter.status().is_ok());
+            if page.is_empty() {
+                break;
+            }
+            pages.push(page);
+        }
+        assert!(pages.len() > 2);
+        assert_eq!(pages.concat(), expected.clone().into_iter().collect::<Vec<_>>());
+
+        // a single iterator sought back and forth
+        let mut iter = partition.iter(None, seq);
+        for key in [b"key050", b"key010", b"key089", b"key000"].iter() {
+            iter.seek(&key[..]);
+            let rest: Vec<_> = iter.by_ref().take(3).collect();
+            assert_eq!(rest, expected.range(key.to_vec()..).take(3).map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::sync::Arc;
+
+use crate::Comparator;
+use crate::partition::{InternalKey, UserKey};
+
+/// Deletes every version older than `seq` of the user keys in `[start, end)`.
+pub(crate) struct RangeTombstone<Comp: Comparator> {
+    pub(crate) seq: u64,
+    pub(crate) start: UserKey<'static, Comp>,
+    pub(crate) end: UserKey<'static, Comp>
+}
+
+impl<Comp: Comparator> Clone for RangeTombstone<Comp> {
+    fn clone(&self) -> Self {
+        Self { seq: self.seq, start: self.start.clone(), end: self.end.clone() }
+    }
+}
+
+impl<Comp: Comparator> RangeTombstone<Comp> {
+    pub(crate) fn new(seq: u64, start: UserKey<'static, Comp>, end: UserKey<'static, Comp>) -> Self {
+        Self { seq, start, end }
+    }
+
+    pub(crate) fn covers(&self, user_key: &UserKey<Comp>) -> bool {
+        &self.start <= user_key && user_key < &self.end
+    }
+
+    /// Whether `key` is deleted by this tombstone for every reader which sees the tombstone, and
+    /// not seen by any other: no snapshot of `snapshots`, ascending, is taken between the two.
+    pub(crate) fn hides(&self, key: &InternalKey<Comp>, snapshots: &[u64]) -> bool {
+        self.covers(&key.user_key) && key.seq < self.seq
+            && snapshots.partition_point(|&snapshot| snapshot < key.seq)
+               == snapshots.partition_point(|&snapshot| snapshot < self.seq)
+    }
+}
+
+/// The range tombstones of a partition. They are kept apart from the memtables and tables, so
+/// they cover the keys of every layer at once. One is logged with the memtable it was written to,
+/// and goes into the manifest once that memtable is flushed.
+///
+/// Compactions drop the versions a tombstone hides from their outputs, but a table written
+/// otherwise may still hold them: a flush, a compaction which started before the tombstone was
+/// written, or one keeping a version a snapshot still reads. The tombstone is dropped once none of
+/// the tables numbered below its fence, the file number of the first table written since which
+/// holds none of them, overlaps its range.
+pub(crate) struct RangeTombstones<Comp: Comparator> {
+    tombstones: Vec<TrackedTombstone<Comp>>,
+    /// The tombstones cut into fragments for `RangeDelAggregator`, cut anew whenever one comes or
+    /// goes.
+    fragments: Arc<Vec<Fragment<Comp>>>
+}
+
+struct TrackedTombstone<Comp: Comparator> {
+    tombstone: RangeTombstone<Comp>,
+    /// The log of the memtable the tombstone was written to, until that memtable is flushed.
+    log_number: Option<u64>,
+    fence: u64
+}
+
+impl<Comp: Comparator> RangeTombstones<Comp> {
+    pub(crate) fn new() -> Self {
+        Self { tombstones: Vec::new(), fragments: Arc::new(Vec::new()) }
+    }
+
+    /// Adds a tombstone just written to the memtable logged in `log_number`. A tombstone replayed
+    /// from a log which outlived the flush of its memtable is known already, it is then logged
+    /// anew.
+    pub(crate) fn add_logged(&mut self, tombstone: RangeTombstone<Comp>, log_number: u64, fence: u64) {
+        match self.tombstones.iter_mut().find(|tracked| tracked.tombstone.seq == tombstone.seq) {
+            Some(tracked) => tracked.log_number = Some(log_number),
+            None => {
+                self.tombstones.push(TrackedTombstone { tombstone, log_number: Some(log_number), fence });
+                self.fragment();
+            }
+        }
+    }
+
+    /// Adds a tombstone as the manifest keeps it.
+    pub(crate) fn add_flushed(&mut self, tombstone: RangeTombstone<Comp>, fence: u64) {
+        self.tombstones.push(TrackedTombstone { tombstone, log_number: None, fence });
+        self.fragment();
+    }
+
+    /// The tombstones still logged with a memtable.
+    pub(crate) fn logged(&self) -> impl Iterator<Item=&RangeTombstone<Comp>> {
+        self.tombstones.iter().filter(|tracked| tracked.log_number.is_some()).map(|tracked| &tracked.tombstone)
+    }
+
+    /// The tombstones of flushed memtables, with their fences.
+    pub(crate) fn flushed(&self) -> impl Iterator<Item=(&RangeTombstone<Comp>, u64)> {
+        self.tombstones.iter().filter(|tracked| tracked.log_number.is_none())
+            .map(|tracked| (&tracked.tombstone, tracked.fence))
+    }
+
+    /// The tombstones a read as of `read_seq` sees.
+    pub(crate) fn visible(&self, read_seq: u64) -> Vec<RangeTombstone<Comp>> {
+        self.tombstones.iter().map(|tracked| &tracked.tombstone)
+            .filter(|tombstone| tombstone.seq <= read_seq)
+            .cloned()
+            .collect()
+    }
+
+    /// What a read as of `read_seq` asks whether a version is deleted, `None` if there is no
+    /// tombstone at all.
+    pub(crate) fn aggregator(&self, read_seq: u64) -> Option<RangeDelAggregator<Comp>> {
+        if self.fragments.is_empty() {
+            return None
+        }
+        Some(RangeDelAggregator { fragments: self.fragments.clone(), read_seq })
+    }
+
+    /// The memtable logged in `log_number` is flushed, and every one logged before it.
+    pub(crate) fn flush_log(&mut self, log_number: u64) {
+        for tracked in self.tombstones.iter_mut() {
+            if tracked.log_number.map_or(false, |logged| logged <= log_number) {
+                tracked.log_number = None;
+            }
+        }
+    }
+
+    /// A table numbered `file_number` holding keys of `[lower, upper]` was written. The fences of
+    /// the tombstones overlapping it are moved past it unless `applied` tells the table holds
+    /// none of the versions they hide.
+    pub(crate) fn table_written(&mut self,
+                                file_number: u64,
+                                lower: &UserKey<Comp>,
+                                upper: &UserKey<Comp>,
+                                applied: impl Fn(&RangeTombstone<Comp>) -> bool) {
+        for tracked in self.tombstones.iter_mut() {
+            let tombstone = &tracked.tombstone;
+            if &tombstone.start <= upper && lower < &tombstone.end && !applied(tombstone) {
+                tracked.fence = tracked.fence.max(file_number + 1);
+            }
+        }
+    }
+
+    /// Drops the tombstones of flushed memtables for which `may_hide` tells no table numbered
+    /// below the fence is left overlapping them. Tells whether any was dropped.
+    pub(crate) fn drop_settled(&mut self, may_hide: impl Fn(&RangeTombstone<Comp>, u64) -> bool) -> bool {
+        let count = self.tombstones.len();
+        self.tombstones.retain(|tracked| tracked.log_number.is_some() || may_hide(&tracked.tombstone, tracked.fence));
+        if self.tombstones.len() == count {
+            return false
+        }
+        self.fragment();
+        true
+    }
+
+    /// Cuts the range of the tombstones at every start and end of one of them, the pieces in
+    /// between are each covered by the same tombstones all along.
+    fn fragment(&mut self) {
+        let tombstones: Vec<&RangeTombstone<Comp>> = self.tombstones.iter().map(|tracked| &tracked.tombstone).collect();
+        let mut cuts: Vec<&UserKey<Comp>> = tombstones.iter().flat_map(|tombstone| [&tombstone.start, &tombstone.end]).collect();
+        cuts.sort();
+        cuts.dedup();
+        let fragments = cuts.into_iter().map(|start| {
+            let mut seqs: Vec<u64> = tombstones.iter()
+                .filter(|tombstone| tombstone.covers(start))
+                .map(|tombstone| tombstone.seq)
+                .collect();
+            seqs.sort_by(|lhs, rhs| rhs.cmp(lhs));
+            Fragment { start: start.clone(), seqs }
+        }).collect();
+        self.fragments = Arc::new(fragments);
+    }
+}
+
+/// A piece of the key range of the range tombstones which the same ones cover all along, from
+/// `start` up to where the next fragment starts. The last fragment starts at the end of the last
+/// tombstone and is covered by none.
+struct Fragment<Comp: Comparator> {
+    start: UserKey<'static, Comp>,
+    /// The sequence numbers of the tombstones covering the fragment, newest first.
+    seqs: Vec<u64>
+}
+
+/// Tells a read as of `read_seq` whether the range tombstones it sees delete a version of a user
+/// key. The tombstones are taken as fragments ordered by key, so a question is a binary search
+/// however many of them overlap. Cheap to clone, the fragments are shared with the partition.
+pub(crate) struct RangeDelAggregator<Comp: Comparator> {
+    fragments: Arc<Vec<Fragment<Comp>>>,
+    read_seq: u64
+}
+
+impl<Comp: Comparator> Clone for RangeDelAggregator<Comp> {
+    fn clone(&self) -> Self {
+        Self { fragments: self.fragments.clone(), read_seq: self.read_seq }
+    }
+}
+
+impl<Comp: Comparator> RangeDelAggregator<Comp> {
+    /// The sequence number of the newest tombstone seen covering `user_key`, versions of the key
+    /// older than it are deleted.
+    pub(crate) fn covering_seq(&self, user_key: &UserKey<Comp>) -> Option<u64> {
+        let idx = self.fragments.partition_point(|fragment| &fragment.start <= user_key);
+        let fragment = &self.fragments[idx.checked_sub(1)?];
+        fragment.seqs.iter().copied().find(|&seq| seq <= self.read_seq)
+    }
+
+    /// Whether the version of `user_key` numbered `seq` is deleted.
+    pub(crate) fn should_delete(&self, user_key: &UserKey<Comp>, seq: u64) -> bool {
+        self.covering_seq(user_key).map_or(false, |tombstone_seq| seq < tombstone_seq)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::DefaultComparator;
+    use crate::partition::{InternalKey, UserKey};
+    use crate::partition::range_tombstone::{RangeTombstone, RangeTombstones};
+    use crate::testutil::default_comparator;
+
+    fn tombstone(seq: u64, start: &str, end: &str) -> RangeTombstone<DefaultComparator> {
+        let comparator = default_comparator();
+        RangeTombstone::new(seq, UserKey::new_owned(start.as_bytes().to_vec(), &comparator),
+                            UserKey::new_owned(end.as_bytes().to_vec(), &comparator))
+    }
+
+    fn key(seq: u64, user_key: &str) -> InternalKey<'static, DefaultComparator> {
+        InternalKey::new(seq, UserKey::new_owned(user_key.as_bytes().to_vec(), &default_comparator()))
+    }
+
+    #[test]
+    fn test_hides() {
+        let tombstone = tombstone(5, "b", "d");
+        assert!(tombstone.hides(&key(4, "b"), &[]));
+        assert!(tombstone.hides(&key(1, "c"), &[]));
+        // the end is not covered, nor are newer versions
+        assert!(!tombstone.hides(&key(4, "d"), &[]));
+        assert!(!tombstone.hides(&key(4, "a"), &[]));
+        assert!(!tombstone.hides(&key(6, "c"), &[]));
+        // snapshot 3 still sees version 2, but not version 4
+        assert!(!tombstone.hides(&key(2, "c"), &[3]));
+        assert!(tombstone.hides(&key(4, "c"), &[3]));
+        assert!(tombstone.hides(&key(2, "c"), &[1, 6]));
+    }
+
+    #[test]
+    fn test_tracking() {
+        let comparator = default_comparator();
+        let user_key = |key: &str| UserKey::new_owned(key.as_bytes().to_vec(), &comparator);
+        let mut tombstones = RangeTombstones::new();
+        tombstones.add_flushed(tombstone(3, "a", "c"), 10);
+        tombstones.add_logged(tombstone(7, "b", "e"), 2, 12);
+        tombstones.add_logged(tombstone(7, "b", "e"), 3, 14);
+
+        assert_eq!(tombstones.visible(5).len(), 1);
+        assert_eq!(tombstones.logged().map(|tombstone| tombstone.seq).collect::<Vec<_>>(), vec![7]);
+
+        // a table holding versions the tombstone at 3 hides keeps it, a table beside it does not
+        tombstones.table_written(20, &user_key("b"), &user_key("b"), |tombstone| tombstone.seq == 7);
+        tombstones.table_written(21, &user_key("x"), &user_key("z"), |_| false);
+        let fences: Vec<_> = tombstones.flushed().map(|(tombstone, fence)| (tombstone.seq, fence)).collect();
+        assert_eq!(fences, vec![(3, 21)]);
+
+        // one logged with a memtable is kept until the memtable is flushed
+        assert!(!tombstones.drop_settled(|_, _| true));
+        tombstones.flush_log(2);
+        assert_eq!(tombstones.logged().count(), 1);
+        tombstones.flush_log(3);
+        assert!(tombstones.drop_settled(|tombstone, fence| tombstone.seq == 3 && fence > 20));
+        let fences: Vec<_> = tombstones.flushed().map(|(tombstone, fence)| (tombstone.seq, fence)).collect();
+        assert_eq!(fences, vec![(3, 21)]);
+        tombstones.drop_settled(|_, _| false);
+        assert!(tombstones.visible(u64::max_value()).is_empty());
+        assert!(tombstones.aggregator(u64::max_value()).is_none());
+    }
+
+    #[test]
+    fn test_aggregator() {
+        let comparator = default_comparator();
+        let user_key = |key: &str| UserKey::new_owned(key.as_bytes().to_vec(), &comparator);
+        let mut tombstones = RangeTombstones::new();
+        tombstones.add_flushed(tombstone(3, "b", "f"), 0);
+        tombstones.add_logged(tombstone(7, "d", "h"), 1, 0);
+        tombstones.add_logged(tombstone(5, "a", "c"), 1, 0);
+        tombstones.add_logged(tombstone(9, "m", "p"), 1, 0);
+
+        let latest = tombstones.aggregator(u64::max_value()).unwrap();
+        let covering: Vec<_> = ["", "a", "b", "c", "d", "f", "g", "h", "k", "m", "o", "p", "z"].iter()
+            .map(|key| latest.covering_seq(&user_key(key)))
+            .collect();
+        assert_eq!(covering, vec![None, Some(5), Some(5), Some(3), Some(7), Some(7), Some(7), None, None,
+                                  Some(9), Some(9), None, None]);
+        // a version written before the tombstone covering it is deleted, one written after is not
+        assert!(latest.should_delete(&user_key("e"), 6));
+        assert!(!latest.should_delete(&user_key("e"), 8));
+        assert!(!latest.should_delete(&user_key("h"), 1));
+
+        // a read at 6 sees neither the tombstone at 7 nor the one at 9
+        let at_6 = tombstones.aggregator(6).unwrap();
+        assert_eq!(at_6.covering_seq(&user_key("e")), Some(3));
+        assert_eq!(at_6.covering_seq(&user_key("g")), None);
+        assert!(!at_6.should_delete(&user_key("n"), 1));
+    }
+}
@@ -0,0 +1,208 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crc::crc32;
+
+use crate::{Comparator, Partitioning};
+use crate::db::DbContext;
+use crate::partition::{ArcPartition, Partition, UserKey};
+
+/// The partitions of a database, sorted by their bounds under `Partitioning::Range`, by their ids
+/// under `Partitioning::Hash`.
+pub(crate) struct PartitionSet<Comp: 'static + Comparator> {
+    partitions: Vec<ArcPartition<Comp>>,
+    next_partition_id: u32,
+    partitioning: Partitioning,
+    comparator: Arc<Comp>
+}
+
+impl<Comp: 'static + Comparator> PartitionSet<Comp> {
+    /// A database without any partition yet starts with a single one without bounds, which takes
+    /// every key until it is split. Under `Partitioning::Hash` there is always one partition for
+    /// every hash value, whose id is that value, the missing ones are created empty.
+    pub(crate) fn new(context: &Arc<DbContext>, comparator: &Arc<Comp>, partitions: Vec<ArcPartition<Comp>>) -> Self {
+        let mut partitions = partitions;
+        let partitioning = context.options.partitioning;
+        match partitioning {
+            Partitioning::Range => {
+                if partitions.is_empty() {
+                    partitions.push(ArcPartition::new(Partition::new(context.clone(), 0, comparator.clone())));
+                }
+                partitions.sort();
+            },
+            Partitioning::Hash { partitions: count } => {
+                for partition_id in 0..count as u32 {
+                    if !partitions.iter().any(|partition| partition.partition_id() == partition_id) {
+                        partitions.push(ArcPartition::new(Partition::new(context.clone(), partition_id, comparator.clone())));
+                    }
+                }
+                partitions.sort_by_key(|partition| partition.partition_id());
+            }
+        }
+        let next_partition_id = partitions.iter().map(|partition| partition.partition_id()).max().unwrap() + 1;
+        Self { partitions, next_partition_id, partitioning, comparator: comparator.clone() }
+    }
+
+    /// The partition responsible for `user_key`. Under `Partitioning::Range` it is the last one
+    /// whose lower bound is not above it. Keys below every partition go to the first one, keys in
+    /// between two partitions to the lower one.
+    pub(crate) fn route(&self, user_key: &[u8]) -> &ArcPartition<Comp> {
+        &self.partitions[self.route_idx(user_key)]
+    }
+
+    /// The partitions responsible for the keys of `[start, end)`, in order. These are all of them
+    /// under `Partitioning::Hash`.
+    pub(crate) fn route_range(&self, start: &[u8], end: &[u8]) -> &[ArcPartition<Comp>] {
+        match self.partitioning {
+            Partitioning::Range => &self.partitions[self.route_idx(start)..=self.route_idx(end)],
+            Partitioning::Hash { .. } => &self.partitions
+        }
+    }
+
+    fn route_idx(&self, user_key: &[u8]) -> usize {
+        if let Partitioning::Hash { partitions } = self.partitioning {
+            return crc32::checksum_ieee(user_key) as usize % partitions
+        }
+        let user_key = UserKey::new_borrow(user_key, &self.comparator);
+        // never `Equal`, so the search ends right after the last partition starting at or below the key
+        let idx = self.partitions.binary_search_by(|partition| match partition.cmp_key(&user_key) {
+            Ordering::Less => Ordering::Greater,
+            _ => Ordering::Less
+        }).unwrap_err();
+        idx.saturating_sub(1)
+    }
+
+    pub(crate) fn contains(&self, partition: &ArcPartition<Comp>) -> bool {
+        self.partitions.iter().any(|p| p.ptr_eq(partition))
+    }
+
+    /// Adds a partition where its bounds put it.
+    pub(crate) fn insert(&mut self, partition: ArcPartition<Comp>) {
+        self.next_partition_id = self.next_partition_id.max(partition.partition_id() + 1);
+        let idx = self.partitions.binary_search(&partition).unwrap_or_else(|idx| idx);
+        self.partitions.insert(idx, partition);
+    }
+
+    pub(crate) fn remove(&mut self, partition: &ArcPartition<Comp>) -> bool {
+        match self.partitions.iter().position(|p| p.ptr_eq(partition)) {
+            Some(idx) => {
+                self.partitions.remove(idx);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub(crate) fn next_partition_id(&mut self) -> u32 {
+        let ret = self.next_partition_id;
+        self.next_partition_id += 1;
+        ret
+    }
+
+    pub(crate) fn partitions(&self) -> &[ArcPartition<Comp>] {
+        &self.partitions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{Options, DefaultComparator, Partitioning, SyncPolicy};
+    use crate::db::DbContext;
+    use crate::testutil::{TestDir, default_comparator};
+    use crate::partition::{Partition, ArcPartition, InternalKey, UserKey};
+    use crate::partition::set::PartitionSet;
+
+    fn put(partition: &ArcPartition<DefaultComparator>, seq: u64, key: &[u8]) {
+        let key = InternalKey::new(seq, UserKey::new_owned(key.to_vec(), &partition.0.comparator));
+        partition.write(&mut vec![(key, b"value".to_vec())], SyncPolicy::NoSync).unwrap();
+    }
+
+    fn route(set: &PartitionSet<DefaultComparator>, key: &[u8]) -> u32 {
+        set.route(key).partition_id()
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let dir = TestDir::new("partition_set_bootstrap");
+        let options = Options::new("test_bootstrap", 4, 4, 10, 4, 4096, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let mut set = PartitionSet::new(&context, &default_comparator(), Vec::new());
+
+        assert_eq!(set.partitions().len(), 1);
+        assert!(set.route(b"anything").bounds().is_none());
+        assert_eq!(route(&set, b""), 0);
+        assert_eq!(route(&set, b"zzz"), 0);
+        assert_eq!(set.next_partition_id(), 1);
+    }
+
+    #[test]
+    fn test_route_and_insert() {
+        let dir = TestDir::new("partition_set_route_and_insert");
+        let options = Options::new("test_route_and_insert", 4, 4, 10, 4, 4096, 64, 64);
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let comparator = default_comparator();
+        let partition = |id, keys: &[&[u8]]| {
+            let partition = ArcPartition::new(Partition::new(context.clone(), id, comparator.clone()));
+            for (seq, key) in keys.iter().enumerate() {
+                put(&partition, seq as u64 + 1, key);
+            }
+            partition
+        };
+
+        let middle = partition(2, &[b"g", b"k"]);
+        let mut set = PartitionSet::new(&context, &comparator, vec![partition(3, &[b"p", b"t"]), middle.clone()]);
+        set.insert(partition(1, &[b"c", b"e"]));
+        set.insert(partition(4, &[b"x", b"z"]));
+        let ids: Vec<_> = set.partitions().iter().map(|partition| partition.partition_id()).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        assert_eq!(set.next_partition_id(), 5);
+
+        assert_eq!(route(&set, b"a"), 1);
+        assert_eq!(route(&set, b"c"), 1);
+        assert_eq!(route(&set, b"f"), 1);
+        assert_eq!(route(&set, b"g"), 2);
+        assert_eq!(route(&set, b"h"), 2);
+        assert_eq!(route(&set, b"n"), 2);
+        assert_eq!(route(&set, b"t"), 3);
+        assert_eq!(route(&set, b"y"), 4);
+        assert_eq!(route(&set, b"zz"), 4);
+        let route_range = |start: &[u8], end: &[u8]| -> Vec<u32> {
+            set.route_range(start, end).iter().map(|partition| partition.partition_id()).collect()
+        };
+        assert_eq!(route_range(b"a", b"b"), vec![1]);
+        assert_eq!(route_range(b"h", b"q"), vec![2, 3]);
+        assert_eq!(route_range(b"a", b"zz"), vec![1, 2, 3, 4]);
+
+        assert!(set.contains(&middle));
+        assert!(set.remove(&middle));
+        assert!(!set.remove(&middle));
+        assert_eq!(route(&set, b"h"), 1);
+    }
+
+    #[test]
+    fn test_hash_partitioning() {
+        let dir = TestDir::new("partition_set_hash_partitioning");
+        let options = Options::new("test_hash_partitioning", 4, 4, 10, 4, 4096, 64, 64)
+            .with_partitioning(Partitioning::Hash { partitions: 4 });
+        let context = Arc::new(DbContext::new(dir.path(), options));
+        let comparator = default_comparator();
+        let restored = ArcPartition::new(Partition::new(context.clone(), 2, comparator.clone()));
+        let set = PartitionSet::new(&context, &comparator, vec![restored.clone()]);
+        let ids: Vec<_> = set.partitions().iter().map(|partition| partition.partition_id()).collect();
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+        assert!(set.partitions()[2].ptr_eq(&restored));
+
+        let mut counts = vec![0; 4];
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            let id = route(&set, key.as_bytes());
+            // routing depends on the key alone
+            assert_eq!(route(&set, key.as_bytes()), id);
+            counts[id as usize] += 1;
+        }
+        assert!(counts.iter().all(|&count| count > 150), "{:?}", counts);
+        assert_eq!(set.route_range(b"key0000", b"key0001").len(), 4);
+    }
+}
@@ -0,0 +1,377 @@
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use rand::{thread_rng, Rng};
+
+use crate::Comparator;
+use crate::partition::InternalKey;
+use crate::partition::arena::Arena;
+use crate::partition::memtable::{copy_entry, MemTableImpl};
+
+const MAX_HEIGHT: usize = 12;
+/// One in this many nodes of a level is linked into the level above as well.
+const BRANCHING: u32 = 4;
+
+struct Node<Comp: 'static + Comparator> {
+    /// `None` only for the head of the list. Key and value are in the arena of the list.
+    entry: Option<(InternalKey<'static, Comp>, &'static [u8])>,
+    next: Box<[AtomicPtr<Node<Comp>>]>
+}
+
+impl<Comp: 'static + Comparator> Node<Comp> {
+    fn new(entry: Option<(InternalKey<'static, Comp>, &'static [u8])>, height: usize) -> *mut Self {
+        let next = (0..height).map(|_| AtomicPtr::new(ptr::null_mut())).collect();
+        Box::into_raw(Box::new(Self { entry, next }))
+    }
+
+    fn key(&self) -> &InternalKey<'static, Comp> {
+        &self.entry.as_ref().unwrap().0
+    }
+
+    fn next(&self, level: usize) -> *mut Self {
+        self.next[level].load(Ordering::Acquire)
+    }
+}
+
+/// A memtable kept in a skiplist, which any number of threads may insert into at the same time
+/// through `insert_concurrently`, while others read it. Nodes are linked level by level from the
+/// bottom with compare-and-swap, a thread losing the race for a link searches that level again
+/// from where it was. Nodes are only unlinked through `remove`, which takes the list exclusively,
+/// so a node reached by a reader stays valid as long as the list is borrowed.
+pub(crate) struct SkipListMemTable<Comp: 'static + Comparator> {
+    head: *mut Node<Comp>,
+    len: AtomicUsize,
+    data_size: AtomicUsize,
+    /// Dropped after the nodes, which `Drop` frees first.
+    arena: Arena
+}
+
+/// Nodes are only reached through the list and what they hold is in its arena, and the comparator
+/// inside the keys is `Send + Sync` itself.
+unsafe impl<Comp: 'static + Comparator> Send for SkipListMemTable<Comp> {}
+unsafe impl<Comp: 'static + Comparator> Sync for SkipListMemTable<Comp> {}
+
+impl<Comp: 'static + Comparator> SkipListMemTable<Comp> {
+    pub(crate) fn new() -> Self {
+        Self {
+            head: Node::new(None, MAX_HEIGHT),
+            len: AtomicUsize::new(0),
+            data_size: AtomicUsize::new(0),
+            arena: Arena::new()
+        }
+    }
+
+    fn random_height() -> usize {
+        let mut height = 1;
+        while height < MAX_HEIGHT && thread_rng().gen_ratio(1, BRANCHING) {
+            height += 1;
+        }
+        height
+    }
+
+    /// Walks `level` from `start` to the last node ordered before `key`, returns it and the node
+    /// after it, which is null at the end of the level.
+    fn find_in_level(&self, start: *mut Node<Comp>, level: usize, key: &InternalKey<Comp>)
+        -> (*mut Node<Comp>, *mut Node<Comp>) {
+        let mut node = start;
+        loop {
+            let next = unsafe { (*node).next(level) };
+            if next.is_null() || unsafe { (*next).key() } >= key {
+                return (node, next)
+            }
+            node = next;
+        }
+    }
+
+    /// The last node before `key` and the node after it on every level.
+    fn find_splice(&self, key: &InternalKey<Comp>) -> ([*mut Node<Comp>; MAX_HEIGHT], [*mut Node<Comp>; MAX_HEIGHT]) {
+        let mut preds = [ptr::null_mut(); MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+        let mut node = self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            let (pred, succ) = self.find_in_level(node, level, key);
+            preds[level] = pred;
+            succs[level] = succ;
+            node = pred;
+        }
+        (preds, succs)
+    }
+
+    fn is_key(&self, node: *mut Node<Comp>, key: &InternalKey<Comp>) -> bool {
+        !node.is_null() && unsafe { (*node).key() } == key
+    }
+
+    /// Inserts a copy of an entry while other threads may insert or read as well. Returns false,
+    /// leaving the list as it is, if the very same version is there already.
+    pub(crate) fn insert_concurrently(&self, key: &InternalKey<Comp>, value: &[u8]) -> bool {
+        let (mut preds, mut succs) = self.find_splice(key);
+        if self.is_key(succs[0], key) {
+            return false
+        }
+        let data_size = key.user_key.key().len() + value.len();
+        let height = Self::random_height();
+        let node = Node::new(Some(unsafe { copy_entry(&self.arena, key, value) }), height);
+        let key = unsafe { (*node).key() };
+        for level in 0..height {
+            loop {
+                unsafe { (*node).next[level].store(succs[level], Ordering::Release) };
+                let link = unsafe { &(*preds[level]).next[level] };
+                if link.compare_exchange(succs[level], node, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                    break
+                }
+                // another node went in between, nodes only come and never go while shared
+                let (pred, succ) = self.find_in_level(preds[level], level, key);
+                preds[level] = pred;
+                succs[level] = succ;
+                // the level linked first decides, so losing there leaves nothing to undo
+                if level == 0 && self.is_key(succ, key) {
+                    drop(unsafe { Box::from_raw(node) });
+                    return false
+                }
+            }
+        }
+        self.len.fetch_add(1, Ordering::Relaxed);
+        self.data_size.fetch_add(data_size, Ordering::Relaxed);
+        true
+    }
+
+    /// The first node not ordered before `key`, null if there is none.
+    fn seek(&self, key: &InternalKey<Comp>) -> *mut Node<Comp> {
+        self.find_splice(key).1[0]
+    }
+}
+
+impl<Comp: 'static + Comparator> Drop for SkipListMemTable<Comp> {
+    fn drop(&mut self) {
+        let mut node = self.head;
+        while !node.is_null() {
+            let next = unsafe { (*node).next(0) };
+            drop(unsafe { Box::from_raw(node) });
+            node = next;
+        }
+    }
+}
+
+impl<Comp: 'static + Comparator> MemTableImpl<Comp> for SkipListMemTable<Comp> {
+    fn insert(&mut self, key: &InternalKey<'static, Comp>, value: &[u8]) -> bool {
+        let node = self.seek(key);
+        if !self.is_key(node, key) {
+            self.insert_concurrently(key, value);
+            return false
+        }
+        // the list is not shared right now, so the value may be swapped in place
+        let (_, old_value) = unsafe { (*node).entry.as_mut().unwrap() };
+        self.data_size.fetch_add(value.len(), Ordering::Relaxed);
+        self.data_size.fetch_sub(old_value.len(), Ordering::Relaxed);
+        *old_value = unsafe { &*(self.arena.copy(value) as *const [u8]) };
+        true
+    }
+
+    fn remove(&mut self, key: &InternalKey<'static, Comp>) -> bool {
+        let (preds, succs) = self.find_splice(key);
+        let node = succs[0];
+        if !self.is_key(node, key) {
+            return false
+        }
+        let height = unsafe { (&(*node).next).len() };
+        for level in 0..height {
+            unsafe { (*preds[level]).next[level].store((*node).next(level), Ordering::Release) };
+        }
+        let (key, value) = unsafe { Box::from_raw(node) }.entry.take().unwrap();
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        self.data_size.fetch_sub(key.user_key.key().len() + value.len(), Ordering::Relaxed);
+        true
+    }
+
+    fn get<'a>(&'a self, key: &InternalKey<'a, Comp>) -> Option<(&'a InternalKey<'a, Comp>, &'a [u8])> {
+        let node = self.seek(key);
+        if node.is_null() {
+            return None
+        }
+        let (key, value) = unsafe { (*node).entry.as_ref().unwrap() };
+        Some((key, *value))
+    }
+
+    fn iter<'a>(&'a self, lower_bound: Option<&InternalKey<'a, Comp>>)
+        -> Box<dyn Iterator<Item = (&'a InternalKey<'a, Comp>, &'a [u8])> + 'a> {
+        let node = match lower_bound {
+            Some(lower_bound) => self.seek(lower_bound),
+            None => unsafe { (*self.head).next(0) }
+        };
+        Box::new(SkipListIter { node, _list: PhantomData })
+    }
+
+    fn first_key(&self) -> Option<&InternalKey<'_, Comp>> {
+        let node = unsafe { (*self.head).next(0) };
+        if node.is_null() { None } else { Some(unsafe { (*node).key() }) }
+    }
+
+    fn last_key(&self) -> Option<&InternalKey<'_, Comp>> {
+        let mut node = self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            loop {
+                let next = unsafe { (*node).next(level) };
+                if next.is_null() {
+                    break
+                }
+                node = next;
+            }
+        }
+        if node == self.head { None } else { Some(unsafe { (*node).key() }) }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.data_size.load(Ordering::Relaxed)
+    }
+
+    fn arena_size(&self) -> usize {
+        self.arena.used()
+    }
+
+    fn new_empty(&self) -> Box<dyn MemTableImpl<Comp>> {
+        Box::new(Self::new())
+    }
+
+    fn concurrent(&self) -> Option<&SkipListMemTable<Comp>> {
+        Some(self)
+    }
+}
+
+struct SkipListIter<'a, Comp: 'static + Comparator> {
+    node: *mut Node<Comp>,
+    _list: PhantomData<&'a SkipListMemTable<Comp>>
+}
+
+impl<'a, Comp: 'static + Comparator> Iterator for SkipListIter<'a, Comp> {
+    type Item = (&'a InternalKey<'a, Comp>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.node.is_null() {
+            return None
+        }
+        let node = unsafe { &*self.node };
+        self.node = node.next(0);
+        let (key, value) = node.entry.as_ref().unwrap();
+        Some((key, *value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::DefaultComparator;
+    use crate::partition::{InternalKey, UserKey};
+    use crate::partition::memtable::{BTreeMemTable, MemTableImpl};
+    use crate::partition::skiplist::SkipListMemTable;
+    use crate::testutil::default_comparator;
+
+    fn entry(key: &InternalKey<DefaultComparator>, value: &[u8]) -> (u64, Vec<u8>, Vec<u8>) {
+        (key.seq, key.user_key.key().to_vec(), value.to_vec())
+    }
+
+    fn entries(table: &dyn MemTableImpl<DefaultComparator>) -> Vec<(u64, Vec<u8>, Vec<u8>)> {
+        table.iter(None).map(|(k, v)| entry(k, v)).collect()
+    }
+
+    #[test]
+    fn test_same_as_btree() {
+        let comparator = default_comparator();
+        let mut skiplist = SkipListMemTable::new();
+        let mut btree = BTreeMemTable::new();
+        for i in 0..2000u64 {
+            let key = format!("key{}", i * 7919 % 500).into_bytes();
+            let key = if i % 5 == 0 {
+                InternalKey::new_deletion(i % 300, UserKey::new_owned(key, &comparator))
+            } else {
+                InternalKey::new(i % 300, UserKey::new_owned(key, &comparator))
+            };
+            let value = format!("value{}", i).into_bytes();
+            assert_eq!(skiplist.insert(&key, &value), btree.insert(&key, &value));
+            if i % 3 == 0 {
+                assert_eq!(skiplist.remove(&key), btree.remove(&key));
+            }
+        }
+        assert_eq!(entries(&skiplist), entries(&btree));
+        assert_eq!((skiplist.len(), skiplist.approximate_size()), (btree.len(), btree.approximate_size()));
+        assert_eq!(skiplist.first_key().map(|k| entry(k, b"")), btree.first_key().map(|k| entry(k, b"")));
+        assert_eq!(skiplist.last_key().map(|k| entry(k, b"")), btree.last_key().map(|k| entry(k, b"")));
+
+        for i in 0..600u64 {
+            let key = InternalKey::new(i % 300, UserKey::new_owned(format!("key{}", i % 510).into_bytes(), &comparator));
+            let found = |table: &dyn MemTableImpl<DefaultComparator>| {
+                table.get(&key).map(|(k, v)| entry(k, v))
+            };
+            assert_eq!(found(&skiplist), found(&btree));
+            let from = |table: &dyn MemTableImpl<DefaultComparator>| {
+                table.iter(Some(&key)).take(3).map(|(k, v)| entry(k, v)).collect::<Vec<_>>()
+            };
+            assert_eq!(from(&skiplist), from(&btree));
+        }
+
+        let empty = skiplist.new_empty();
+        assert!(empty.is_empty() && empty.first_key().is_none() && empty.last_key().is_none());
+    }
+
+    const THREADS: u64 = 8;
+    const KEYS: u64 = 2000;
+
+    /// The user key and seq of the `i`th version thread `t` writes. Every thread writes every user
+    /// key, and every fourth version is written by all threads at once.
+    fn version(t: u64, i: u64) -> (Vec<u8>, u64) {
+        if i % 4 == 0 {
+            (format!("key{:05}", i * 31 % KEYS).into_bytes(), i)
+        } else {
+            (format!("key{:05}", (i * 31 + t) % KEYS).into_bytes(), t * KEYS + i)
+        }
+    }
+
+    #[test]
+    fn test_concurrent_inserts() {
+        let skiplist = Arc::new(SkipListMemTable::<DefaultComparator>::new());
+        let threads: Vec<_> = (0..THREADS).map(|t| {
+            let skiplist = skiplist.clone();
+            thread::spawn(move || {
+                let comparator = default_comparator();
+                let mut inserted = 0;
+                for i in 0..KEYS {
+                    let (user_key, seq) = version(t, i);
+                    let key = InternalKey::new(seq, UserKey::new_owned(user_key, &comparator));
+                    if skiplist.insert_concurrently(&key, format!("{}", seq).as_bytes()) {
+                        inserted += 1;
+                    }
+                }
+                inserted
+            })
+        }).collect();
+        let inserted: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+
+        let entries = entries(skiplist.as_ref());
+        assert_eq!(entries.len(), inserted);
+        assert_eq!(skiplist.len(), inserted);
+        assert_eq!(skiplist.approximate_size(), entries.iter().map(|(_, k, v)| k.len() + v.len()).sum::<usize>());
+        let comparator = default_comparator();
+        let keys: Vec<_> = entries.iter()
+            .map(|(seq, key, _)| InternalKey::new(*seq, UserKey::new_owned(key.clone(), &comparator)))
+            .collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+        for t in 0..THREADS {
+            for i in 0..KEYS {
+                let (user_key, seq) = version(t, i);
+                let key = InternalKey::new(seq, UserKey::new_owned(user_key.clone(), &comparator));
+                let (found, value) = skiplist.get(&key).unwrap();
+                assert_eq!((found.seq, found.user_key.key()), (seq, user_key.as_slice()));
+                assert_eq!(value, format!("{}", seq).as_bytes());
+            }
+        }
+        // the versions all threads raced for went in once each
+        assert_eq!(inserted as u64, THREADS * KEYS - (THREADS - 1) * (KEYS / 4));
+    }
+}
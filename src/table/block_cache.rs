@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+
+use crate::table::sctable::ScTableFile;
+
+/// A block is named by its table and where it starts in the table file. Tables never change once
+/// written, so a block read at an offset is the same every time.
+type BlockKey = (ScTableFile, u64);
+
+struct Blocks {
+    lru: LruCache<BlockKey, Arc<[u8]>>,
+    /// The offsets of the blocks cached of every table, so the blocks of a table are found without
+    /// going over all of them.
+    tables: HashMap<ScTableFile, HashSet<u64>>,
+    bytes: usize
+}
+
+impl Blocks {
+    fn forget(&mut self, (table_file, offset): BlockKey, block: &[u8]) {
+        self.bytes -= block.len();
+        if let Some(offsets) = self.tables.get_mut(&table_file) {
+            offsets.remove(&offset);
+            if offsets.is_empty() {
+                self.tables.remove(&table_file);
+            }
+        }
+    }
+}
+
+/// Parts of tables read from disk on demand, the values lookups find in tables cached without
+/// them. Unlike the table cache it is bounded by the bytes of the blocks it holds, not by their
+/// number, since a block may be anything from empty to as large as a value may be. Blocks are
+/// shared with the readers which got them, rather than copied out.
+pub(crate) struct BlockCache {
+    blocks: Mutex<Blocks>,
+    capacity: usize
+}
+
+impl BlockCache {
+    /// A cache of at most `capacity` bytes, 0 caches nothing.
+    pub(crate) fn new(capacity: usize) -> Self {
+        let blocks = Blocks { lru: LruCache::unbounded(), tables: HashMap::new(), bytes: 0 };
+        Self { blocks: Mutex::new(blocks), capacity }
+    }
+
+    pub(crate) fn get(&self, table_file: ScTableFile, offset: u64) -> Option<Arc<[u8]>> {
+        self.blocks.lock().unwrap().lru.get(&(table_file, offset)).cloned()
+    }
+
+    /// Whether a block of `size` bytes is cached at all, blocks larger than the whole cache are not.
+    pub(crate) fn admits(&self, size: usize) -> bool {
+        size <= self.capacity
+    }
+
+    /// Caches `block`, evicting the least recently used blocks until it fits. A block larger than
+    /// the whole cache is not cached.
+    pub(crate) fn insert(&self, table_file: ScTableFile, offset: u64, block: Arc<[u8]>) {
+        if !self.admits(block.len()) {
+            return
+        }
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.bytes += block.len();
+        if let Some(replaced) = blocks.lru.put((table_file, offset), block) {
+            blocks.bytes -= replaced.len();
+        }
+        blocks.tables.entry(table_file).or_insert_with(HashSet::new).insert(offset);
+        while blocks.bytes > self.capacity {
+            let (key, evicted) = blocks.lru.pop_lru().unwrap();
+            blocks.forget(key, &evicted);
+        }
+    }
+
+    /// Drops the blocks of `table_file`, once the table is gone none of them is asked for again.
+    pub(crate) fn evict_table(&self, table_file: ScTableFile) {
+        let mut blocks = self.blocks.lock().unwrap();
+        let offsets = match blocks.tables.remove(&table_file) {
+            Some(offsets) => offsets,
+            None => return
+        };
+        for offset in offsets {
+            let evicted = blocks.lru.pop(&(table_file, offset)).unwrap();
+            blocks.bytes -= evicted.len();
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.lru.clear();
+        blocks.tables.clear();
+        blocks.bytes = 0;
+    }
+
+    /// Bytes of the blocks cached.
+    pub(crate) fn bytes(&self) -> usize {
+        self.blocks.lock().unwrap().bytes
+    }
+
+    /// Bytes the blocks cached may take at most.
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::table::block_cache::BlockCache;
+    use crate::table::sctable::ScTableFile;
+
+    fn block(byte: u8, len: usize) -> Arc<[u8]> {
+        vec![byte; len].into()
+    }
+
+    #[test]
+    fn test_evicts_by_bytes() {
+        let block_cache = BlockCache::new(100);
+        let (table, other) = (ScTableFile::new(1), ScTableFile::new(2));
+        block_cache.insert(table, 0, block(0, 40));
+        block_cache.insert(table, 40, block(1, 40));
+        block_cache.insert(other, 0, block(2, 10));
+        assert_eq!(block_cache.bytes(), 90);
+        // shared, not copied
+        let first = block_cache.get(table, 0).unwrap();
+        assert!(Arc::ptr_eq(&first, &block_cache.get(table, 0).unwrap()));
+        // touching the first block makes the second one the least recently used
+        assert_eq!(*block_cache.get(table, 0).unwrap(), [0; 40][..]);
+        block_cache.insert(other, 10, block(3, 30));
+        assert_eq!(block_cache.bytes(), 80);
+        assert!(block_cache.get(table, 40).is_none());
+        assert!(block_cache.get(table, 0).is_some());
+        assert!(block_cache.get(other, 0).is_some());
+
+        for offset in 0..20 {
+            block_cache.insert(table, 100 + offset, block(4, 15));
+            assert!(block_cache.bytes() <= block_cache.capacity());
+        }
+        assert!(block_cache.get(other, 10).is_none());
+        assert!(block_cache.get(table, 119).is_some());
+
+        // too large to ever fit
+        assert!(!block_cache.admits(101));
+        block_cache.insert(other, 40, block(5, 101));
+        assert!(block_cache.get(other, 40).is_none());
+
+        block_cache.insert(other, 0, block(2, 10));
+        block_cache.evict_table(table);
+        assert_eq!(block_cache.bytes(), 10);
+        assert!((100..120).all(|offset| block_cache.get(table, offset).is_none()));
+        assert!(block_cache.get(other, 0).is_some());
+        // evicting a table twice, or one whose blocks made room already, gives no bytes back twice
+        block_cache.evict_table(table);
+        block_cache.insert(table, 0, block(6, 95));
+        assert_eq!(block_cache.bytes(), 95);
+        block_cache.evict_table(other);
+        assert_eq!(block_cache.bytes(), 95);
+        block_cache.clear();
+        assert_eq!(block_cache.bytes(), 0);
+        assert!(block_cache.get(other, 0).is_none());
+    }
+}
@@ -0,0 +1,146 @@
+//! Bloom filter format
+//! ```raw
+//! +-FILTER-------------------------------------+
+//! | bit array, a multiple of 8 bits            |
+//! +--------------------------------------------+
+//! | 1byte probe count                          |
+//! +--------------------------------------------+
+//! ```
+
+use crate::encode::decode_fixed32;
+
+const BLOOM_MIN_BITS: usize = 64;
+const BLOOM_MAX_PROBES: usize = 30;
+
+pub(crate) struct BloomFilter {
+    bits: Vec<u8>,
+    probes: usize
+}
+
+impl BloomFilter {
+    /// Bits spent on every key to reach `false_positive_rate`, or `None` if no filter should be
+    /// built at all.
+    pub(crate) fn bits_per_key(false_positive_rate: f64) -> Option<usize> {
+        if false_positive_rate <= 0.0 || false_positive_rate >= 1.0 {
+            return None
+        }
+        let ln2 = std::f64::consts::LN_2;
+        Some((-false_positive_rate.ln() / (ln2 * ln2)).ceil() as usize)
+    }
+
+    /// Size of the serialized filter holding `key_count` keys.
+    pub(crate) fn serialized_size(key_count: usize, bits_per_key: usize) -> usize {
+        Self::bit_count(key_count, bits_per_key) / 8 + 1
+    }
+
+    pub(crate) fn build<'a>(keys: impl Iterator<Item=&'a [u8]>, key_count: usize, bits_per_key: usize) -> Self {
+        let bit_count = Self::bit_count(key_count, bits_per_key);
+        let probes = ((bits_per_key as f64 * std::f64::consts::LN_2) as usize).max(1).min(BLOOM_MAX_PROBES);
+        let mut ret = Self { bits: vec![0; bit_count / 8], probes };
+        for key in keys {
+            for bit in probe_bits(key, bit_count, probes) {
+                ret.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        ret
+    }
+
+    pub(crate) fn serialize(&self, dest: &mut Vec<u8>) {
+        dest.extend_from_slice(&self.bits);
+        dest.push(self.probes as u8);
+    }
+
+    /// An empty region means the table was built without a filter.
+    pub(crate) fn deserialize(from: &[u8]) -> Option<Self> {
+        let (&probes, bits) = from.split_last()?;
+        Some(Self { bits: bits.to_vec(), probes: probes as usize })
+    }
+
+    /// `false` only if `key` is definitely not in the filter.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        if self.bits.is_empty() || self.probes == 0 || self.probes > BLOOM_MAX_PROBES {
+            // not something we build, be conservative
+            return true
+        }
+        probe_bits(key, self.bits.len() * 8, self.probes).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn bit_count(key_count: usize, bits_per_key: usize) -> usize {
+        let bit_count = (key_count * bits_per_key).max(BLOOM_MIN_BITS);
+        (bit_count + 7) / 8 * 8
+    }
+}
+
+/// Double hashing, as described by Kirsch and Mitzenmacher.
+fn probe_bits(key: &[u8], bit_count: usize, probes: usize) -> impl Iterator<Item=usize> {
+    let mut h = bloom_hash(key);
+    let delta = h.rotate_right(17);
+    (0..probes).map(move |_| {
+        let bit = h as usize % bit_count;
+        h = h.wrapping_add(delta);
+        bit
+    })
+}
+
+/// Murmur-like hash, filters depend on it so it must never change.
+fn bloom_hash(key: &[u8]) -> u32 {
+    const SEED: u32 = 0xBC9F1D34;
+    const M: u32 = 0xC6A4A793;
+    let mut h = SEED ^ (key.len() as u32).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(4);
+    for chunk in &mut chunks {
+        h = h.wrapping_add(decode_fixed32(chunk)).wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &byte) in rest.iter().enumerate() {
+            h = h.wrapping_add((byte as u32) << (8 * i as u32));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
+
+#[cfg(test)]
+mod test {
+    use crate::table::bloom::BloomFilter;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_no_false_negatives() {
+        let bits_per_key = BloomFilter::bits_per_key(0.01).unwrap();
+        for &count in [1u32, 10, 100, 1000].iter() {
+            let keys: Vec<Vec<u8>> = (0..count).map(key).collect();
+            let filter = BloomFilter::build(keys.iter().map(|k| k.as_slice()), keys.len(), bits_per_key);
+            let mut buffer = Vec::new();
+            filter.serialize(&mut buffer);
+            assert_eq!(buffer.len(), BloomFilter::serialized_size(keys.len(), bits_per_key));
+
+            let filter = BloomFilter::deserialize(&buffer).unwrap();
+            for k in keys.iter() {
+                assert!(filter.may_contain(k));
+            }
+        }
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let bits_per_key = BloomFilter::bits_per_key(0.01).unwrap();
+        let keys: Vec<Vec<u8>> = (0..10000).map(key).collect();
+        let filter = BloomFilter::build(keys.iter().map(|k| k.as_slice()), keys.len(), bits_per_key);
+        let false_positives = (10000..20000).filter(|&i| filter.may_contain(&key(i))).count();
+        assert!(false_positives < 200, "{} false positives out of 10000", false_positives);
+    }
+
+    #[test]
+    fn test_disabled() {
+        assert_eq!(BloomFilter::bits_per_key(0.0), None);
+        assert_eq!(BloomFilter::bits_per_key(1.0), None);
+        assert!(BloomFilter::deserialize(&[]).is_none());
+    }
+}
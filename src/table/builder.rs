@@ -1,12 +1,34 @@
-use crc::crc32;
-
-use crate::table::tablefmt::{TABLE_MAGIC, TABLE_MIN_SIZE, TABLE_CATALOG_ITEM_SIZE};
+use crate::table::tablefmt::{TABLE_MAGIC, TABLE_MIN_SIZE, TABLE_CATALOG_ITEM_SIZE, TABLE_HEAD_SIZE,
+                             TABLE_HEAD_SIZE_COMPRESSED, TABLE_MAGIC_COMPRESSED, TABLE_CODEC_LZ4,
+                             TABLE_CHECKSUM_IEEE, TABLE_CHECKSUM_CASTAGNOLI};
 use crate::encode::{encode_fixed32_ret, encode_fixed32};
-use crate::table::cache::ScTableCatalogItem;
+use crate::table::bloom::BloomFilter;
+use crate::table::lz4;
+use crate::table::cache::{ScTableCatalogItem, ScTableCache, TableCacheManager};
+use crate::{Checksum, Comparator, Compression, Error, Options};
+use crate::partition::{InternalKey, UserKey, ValueKind};
+
+/// Default of `Options::restart_interval`.
+pub(crate) const DEFAULT_RESTART_INTERVAL: usize = 16;
 
 pub(crate) struct ScTableBuilder {
+    /// Value offsets are relative to `values` until the table is built, the keys go in front of them.
     indexes: Vec<ScTableCatalogItem>,
-    data: Vec<u8>
+    keys: Vec<u8>,
+    values: Vec<u8>,
+    bits_per_key: Option<usize>,
+    distinct_key_count: usize,
+    compression: Compression,
+    checksum: Checksum,
+    restart_interval: usize,
+    /// The full key of the last entry, the data only holds what it does not share.
+    last_key: Vec<u8>,
+    /// Keys added so far, each distinct key once, for the filter.
+    filter_keys: Vec<Vec<u8>>,
+    verify_on_write: bool,
+    /// Flips a byte of what `finish` built, as memory going bad would.
+    #[cfg(test)]
+    corrupt_output: bool
 }
 
 impl Default for ScTableBuilder {
@@ -17,48 +39,239 @@ impl Default for ScTableBuilder {
 
 impl ScTableBuilder {
     pub(crate) fn new() -> Self {
-        Self { indexes: Vec::new(), data: Vec::new() }
+        Self {
+            indexes: Vec::new(),
+            keys: Vec::new(),
+            values: Vec::new(),
+            bits_per_key: None,
+            distinct_key_count: 0,
+            compression: Compression::None,
+            checksum: Checksum::Ieee,
+            restart_interval: DEFAULT_RESTART_INTERVAL,
+            last_key: Vec::new(),
+            filter_keys: Vec::new(),
+            verify_on_write: false,
+            #[cfg(test)]
+            corrupt_output: false
+        }
+    }
+
+    /// A builder for tables as `options` asks for them.
+    pub(crate) fn from_options(options: &Options) -> Self {
+        Self::with_filter(options.bloom_false_positive_rate)
+            .with_compression(options.compression)
+            .with_checksum(options.checksum)
+            .with_restart_interval(options.restart_interval)
+            .with_verify_on_write(options.verify_on_write)
+    }
+
+    /// A builder whose table carries a bloom filter over its user keys. No filter is built if
+    /// `false_positive_rate` is not within `(0, 1)`.
+    pub(crate) fn with_filter(false_positive_rate: f64) -> Self {
+        Self { bits_per_key: BloomFilter::bits_per_key(false_positive_rate), ..Self::new() }
+    }
+
+    /// Compresses the values of the table with `compression`, the keys are stored as they are. The
+    /// values are stored as they are too if compressing does not make them smaller.
+    pub(crate) fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub(crate) fn with_checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Stores every `restart_interval`th key in full, the keys between only store what they do
+    /// not share with the key before them.
+    pub(crate) fn with_restart_interval(mut self, restart_interval: usize) -> Self {
+        assert!(restart_interval > 0, "restart_interval must be nonzero");
+        self.restart_interval = restart_interval;
+        self
+    }
+
+    /// Has `finish` read the table back before handing it out, so a table gone bad in memory is
+    /// not written to disk.
+    pub(crate) fn with_verify_on_write(mut self, verify_on_write: bool) -> Self {
+        self.verify_on_write = verify_on_write;
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_corrupt_output(mut self) -> Self {
+        self.corrupt_output = true;
+        self
+    }
+
+    /// Adds an entry, which must sort after every entry added before it. Tables built this way
+    /// can be binary searched by `ScTableCache`.
+    pub(crate) fn add<Comp: Comparator>(&mut self, key: &InternalKey<Comp>, value: &[u8]) {
+        if let Some(last) = self.indexes.last() {
+            let last_user_key = UserKey::new_borrow(&self.last_key, key.user_key.comparator());
+            let last_key = InternalKey { seq: last.key_seq, kind: last.kind, user_key: last_user_key };
+            assert!(last_key < *key, "keys must be added to ScTableBuilder in ascending order");
+        }
+        match key.kind {
+            ValueKind::Deletion => self.add_deletion(key.seq, key.user_key.key()),
+            kind => self.add_item(key.seq, kind, key.user_key.key(), value)
+        }
     }
 
     pub(crate) fn add_kv(&mut self, key_seq: u64, key: &[u8], value: &[u8]) {
-        let key_off = self.data.len() as u32;
-        let key_size = key.len() as u32;
-        self.data.extend_from_slice(key);
+        self.add_item(key_seq, ValueKind::Value, key, value);
+    }
 
-        let value_off = self.data.len() as u32;
+    fn add_item(&mut self, key_seq: u64, kind: ValueKind, key: &[u8], value: &[u8]) {
+        let (key_off, key_size, key_shared) = self.add_key(key);
+        let value_off = self.values.len() as u32;
         let value_size = value.len() as u32;
-        self.data.extend_from_slice(value);
+        self.values.extend_from_slice(value);
+
+        self.indexes.push(ScTableCatalogItem::new(key_seq, kind, key_off, key_size, value_off, value_size,
+                                                  key_shared));
+    }
 
-        self.indexes.push(ScTableCatalogItem::new(key_seq, key_off, key_size, value_off, value_size));
+    pub(crate) fn add_deletion(&mut self, key_seq: u64, key: &[u8]) {
+        let (key_off, key_size, key_shared) = self.add_key(key);
+        let value_off = self.values.len() as u32;
+        self.indexes.push(ScTableCatalogItem::new(key_seq, ValueKind::Deletion, key_off, key_size, value_off, 0,
+                                                  key_shared));
+    }
+
+    /// Puts the part of `key` not shared with the last key into the keys, restart points share
+    /// nothing. Returns the offset and size of what was put there and the size of the shared prefix.
+    fn add_key(&mut self, key: &[u8]) -> (u32, u32, u32) {
+        if self.indexes.is_empty() || self.last_key != key {
+            self.distinct_key_count += 1;
+            if self.bits_per_key.is_some() {
+                self.filter_keys.push(key.to_vec());
+            }
+        }
+        let key_shared = if self.indexes.len() % self.restart_interval == 0 {
+            0
+        } else {
+            self.last_key.iter().zip(key.iter()).take_while(|(lhs, rhs)| lhs == rhs).count()
+        };
+        let key_off = self.keys.len() as u32;
+        self.keys.extend_from_slice(&key[key_shared..]);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        (key_off, (key.len() - key_shared) as u32, key_shared as u32)
     }
 
+    /// Tables always hold at least one entry, so their smallest and largest keys exist. The data
+    /// region holds every key, then every value.
     pub(crate) fn build(&self) -> Vec<u8> {
+        assert!(!self.is_empty(), "an empty table must not be built");
+        let compressed = match self.compression {
+            Compression::None => None,
+            Compression::Lz4 => Some((TABLE_CODEC_LZ4, lz4::compress(&self.values)))
+        }.filter(|(_, compressed)| compressed.len() < self.values.len());
+        let (values, head_size) = match compressed.as_ref() {
+            Some((_, compressed)) => (compressed, TABLE_HEAD_SIZE_COMPRESSED),
+            None => (&self.values, TABLE_HEAD_SIZE)
+        };
+        let catalog_size = self.indexes.len() * TABLE_CATALOG_ITEM_SIZE;
+        let data_size = self.keys.len() + values.len();
+
         let mut ret = Vec::with_capacity(self.size());
-        ret.extend_from_slice(&encode_fixed32_ret((self.indexes.len() * TABLE_CATALOG_ITEM_SIZE) as u32));
-        ret.extend_from_slice(&encode_fixed32_ret(self.data.len() as u32));
-        for _ in 0..4 {
+        ret.extend_from_slice(&encode_fixed32_ret(catalog_size as u32));
+        ret.extend_from_slice(&encode_fixed32_ret(data_size as u32));
+        for _ in 0..8 {
             ret.push(0)
         }
-        ret.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&self.data)));
+        let filter = self.build_filter();
+        ret.extend_from_slice(&encode_fixed32_ret(filter.len() as u32));
+        ret.extend_from_slice(&encode_fixed32_ret(self.checksum.checksum(&filter)));
+        ret.extend_from_slice(&encode_fixed32_ret(match self.checksum {
+            Checksum::Ieee => TABLE_CHECKSUM_IEEE,
+            Checksum::Castagnoli => TABLE_CHECKSUM_CASTAGNOLI
+        }));
+        if let Some((codec, _)) = compressed.as_ref() {
+            ret.extend_from_slice(&encode_fixed32_ret(*codec));
+            ret.extend_from_slice(&encode_fixed32_ret((self.keys.len() + self.values.len()) as u32));
+            ret.extend_from_slice(&encode_fixed32_ret(self.keys.len() as u32));
+        }
+        let values_off = self.keys.len() as u32;
         for index in self.indexes.iter() {
-            index.serialize(&mut ret)
+            let ScTableCatalogItem { key_seq, kind, key_off, key_len, value_off, value_len, key_shared } = *index;
+            ScTableCatalogItem::new(key_seq, kind, key_off, key_len, values_off + value_off, value_len, key_shared)
+                .serialize(&mut ret)
         }
-        ret.extend_from_slice(&self.data);
-        let index_checksum = crc32::checksum_ieee(&ret[16..16 + self.indexes.len() * TABLE_CATALOG_ITEM_SIZE]);
+        ret.extend_from_slice(&self.keys);
+        ret.extend_from_slice(values);
+        let index_checksum = self.checksum.checksum(&ret[head_size..head_size + catalog_size]);
         encode_fixed32(&mut ret[8..12], index_checksum);
-        ret.extend_from_slice(TABLE_MAGIC);
+        let data_checksum = self.checksum.checksum(&ret[head_size + catalog_size..]);
+        encode_fixed32(&mut ret[12..16], data_checksum);
+        ret.extend_from_slice(&filter);
+        ret.extend_from_slice(if compressed.is_some() { TABLE_MAGIC_COMPRESSED } else { TABLE_MAGIC });
         ret
     }
 
+    /// Builds the table to be written to disk. If asked to verify on write, the table is parsed
+    /// the way it is read back, and an error is returned if that fails.
+    pub(crate) fn finish(&self) -> Result<Vec<u8>, Error> {
+        #[allow(unused_mut)]
+        let mut ret = self.build();
+        #[cfg(test)]
+        {
+            if self.corrupt_output {
+                let middle = ret.len() / 2;
+                ret[middle] ^= 0xFF;
+            }
+        }
+        if self.verify_on_write {
+            let cache_manager = TableCacheManager::new(1);
+            ScTableCache::from_raw(&ret, cache_manager.acquire_quota())?;
+        }
+        Ok(ret)
+    }
+
+    fn build_filter(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        if let Some(bits_per_key) = self.bits_per_key {
+            let keys = self.filter_keys.iter().map(Vec::as_slice);
+            BloomFilter::build(keys, self.distinct_key_count, bits_per_key).serialize(&mut ret);
+        }
+        ret
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Size of the table built without compression, compressed tables end up smaller.
     pub(crate) fn size(&self) -> usize {
-        TABLE_MIN_SIZE + self.indexes.len() * TABLE_CATALOG_ITEM_SIZE + self.data.len()
+        self.size_of(self.indexes.len(), self.keys.len() + self.values.len(), self.distinct_key_count)
+    }
+
+    /// Size of the table if one more entry with a new user key were added, an upper bound for
+    /// adding any entry of that size.
+    pub(crate) fn size_with(&self, key: &[u8], value: &[u8]) -> usize {
+        self.size_of(self.indexes.len() + 1, self.keys.len() + self.values.len() + key.len() + value.len(), self.distinct_key_count + 1)
+    }
+
+    fn size_of(&self, entry_count: usize, data_size: usize, distinct_key_count: usize) -> usize {
+        let filter_size = self.bits_per_key.map_or(0, |bits_per_key| {
+            BloomFilter::serialized_size(distinct_key_count, bits_per_key)
+        });
+        TABLE_MIN_SIZE + entry_count * TABLE_CATALOG_ITEM_SIZE + data_size + filter_size
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::borrow::Cow;
+
+    use rand::{thread_rng, Rng};
+
+    use crate::DefaultComparator;
     use crate::table::builder::ScTableBuilder;
     use crate::table::cache::{ScTableCache, TableCacheManager};
+    use crate::partition::{InternalKey, UserKey};
+    use crate::testutil::default_comparator;
 
     #[test]
     fn test_builder_1() {
@@ -90,4 +303,121 @@ mod test {
             assert_eq!(value1, value);
         }
     }
+
+    #[test]
+    fn test_builder_round_trip() {
+        for _ in 0..16 {
+            let mut keys: Vec<Vec<u8>> = (0..thread_rng().gen_range(1, 256))
+                .map(|_| (0..thread_rng().gen_range(0, 16)).map(|_| thread_rng().gen()).collect())
+                .collect();
+            keys.sort();
+            keys.dedup();
+
+            let mut builder = ScTableBuilder::with_filter(0.01);
+            let mut expected = Vec::new();
+            for (i, key) in keys.iter().enumerate() {
+                let seq = thread_rng().gen_range(0, u64::max_value());
+                let value: Vec<u8> = (0..thread_rng().gen_range(0, 64)).map(|_| thread_rng().gen()).collect();
+                builder.add(&InternalKey::<DefaultComparator>::new(seq, UserKey::new_borrow(key, &default_comparator())), &value);
+                expected.push((i, seq, value));
+            }
+            let buffer = builder.build();
+            assert_eq!(buffer.len(), builder.size());
+
+            let cache_manager = TableCacheManager::new(1);
+            let table = ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).unwrap();
+            assert_eq!(table.catalog_size(), keys.len());
+            for (i, seq, value) in expected {
+                assert_eq!(table.nth_item(i), (seq, Cow::from(keys[i].as_slice()), value.as_slice()));
+            }
+        }
+    }
+
+    fn build_and_check(keys: &[Vec<u8>], restart_interval: usize) -> usize {
+        let mut builder = ScTableBuilder::with_filter(0.01).with_restart_interval(restart_interval);
+        for (i, key) in keys.iter().enumerate() {
+            builder.add_kv(i as u64, key, format!("value{}", i).as_bytes());
+        }
+        let buffer = builder.build();
+        assert_eq!(buffer.len(), builder.size());
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            let value = format!("value{}", i).into_bytes();
+            assert_eq!(table.nth_item(i), (i as u64, Cow::from(key.as_slice()), value.as_slice()));
+            let lookup_key = InternalKey::<DefaultComparator>::new(u64::max_value(), UserKey::new_borrow(key, &default_comparator()));
+            assert_eq!(table.get(&lookup_key), Some(Some(value)));
+            assert!(table.may_contain(key));
+        }
+        buffer.len()
+    }
+
+    #[test]
+    fn test_prefix_compression_shared_keys() {
+        let keys: Vec<Vec<u8>> = (0..500).map(|i| format!("users/by-id/{:08}/profile", i).into_bytes()).collect();
+        let full = build_and_check(&keys, 1);
+        let compressed = build_and_check(&keys, 16);
+        assert!(compressed < full * 3 / 4);
+        // restarting more often costs a few full keys
+        assert!(build_and_check(&keys, 4) > compressed);
+    }
+
+    #[test]
+    fn test_prefix_compression_distinct_keys() {
+        // nothing is shared, so nothing is saved either
+        let keys: Vec<Vec<u8>> = (0..=255u8).map(|i| vec![i, i.wrapping_mul(31), i.wrapping_mul(7)]).collect();
+        assert_eq!(build_and_check(&keys, 1), build_and_check(&keys, 16));
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let keys = vec![Vec::new(), b"a".to_vec(), b"ab".to_vec()];
+        build_and_check(&keys, 1);
+        build_and_check(&keys, 16);
+    }
+
+    #[test]
+    fn test_verify_on_write() {
+        let build = |builder: ScTableBuilder| {
+            let mut builder = builder;
+            for i in 0..100u64 {
+                builder.add_kv(i, format!("key{:04}", i).as_bytes(), format!("value{}", i).as_bytes());
+            }
+            builder.finish()
+        };
+        let buffer = build(ScTableBuilder::new().with_verify_on_write(true)).unwrap();
+        assert_eq!(buffer, build(ScTableBuilder::new()).unwrap());
+
+        // without verifying, the corruption would only show once the table is read back
+        let corrupt = build(ScTableBuilder::new().with_corrupt_output()).unwrap();
+        let cache_manager = TableCacheManager::new(1);
+        assert!(ScTableCache::from_raw(&corrupt, cache_manager.acquire_quota()).is_err());
+        match build(ScTableBuilder::new().with_verify_on_write(true).with_corrupt_output()) {
+            Err(e) => assert!(e.to_string().contains("crc"), "{}", e),
+            Ok(_) => panic!("a corrupt table must not pass verification")
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_builder_rejects_unordered() {
+        let mut builder = ScTableBuilder::new();
+        builder.add(&InternalKey::<DefaultComparator>::new(1, UserKey::new_borrow(b"b", &default_comparator())), b"");
+        builder.add(&InternalKey::<DefaultComparator>::new(2, UserKey::new_borrow(b"a", &default_comparator())), b"");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_builder_rejects_older_version_first() {
+        let mut builder = ScTableBuilder::new();
+        builder.add(&InternalKey::<DefaultComparator>::new(1, UserKey::new_borrow(b"a", &default_comparator())), b"");
+        builder.add(&InternalKey::<DefaultComparator>::new(2, UserKey::new_borrow(b"a", &default_comparator())), b"");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_builder_rejects_empty() {
+        ScTableBuilder::new().build();
+    }
 }
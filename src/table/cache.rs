@@ -1,149 +1,602 @@
-use std::sync::{Arc, Mutex};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{self, AtomicU64};
 use std::ptr::NonNull;
-use std_semaphore::Semaphore;
+use std::cmp::Ordering;
+use std::ops::Range;
 
-use lru::LruCache;
 use crc::crc32;
+use lru::LruCache;
 
 use crate::table::sctable::ScTableFile;
+use crate::table::block_cache::BlockCache;
 
-use crate::table::tablefmt::{TABLE_MIN_SIZE, TABLE_MAGIC_SIZE, TABLE_MAGIC, TABLE_CATALOG_ITEM_SIZE,
-                             TABLE_HEAD_SIZE, TABLE_MAX_SIZE, TABLE_DELETION_BITMASK};
+use crate::table::tablefmt::{TABLE_MAGIC_SIZE, TABLE_MAGIC, TABLE_CATALOG_ITEM_SIZE,
+                             TABLE_HEAD_SIZE, TABLE_MAX_SIZE, TABLE_DELETION_BITMASK,
+                             TABLE_MAGIC_V1, TABLE_HEAD_SIZE_V1, TABLE_MIN_SIZE_V1, TABLE_MAGIC_V2,
+                             TABLE_CATALOG_ITEM_SIZE_V2, TABLE_MAGIC_COMPRESSED, TABLE_HEAD_SIZE_COMPRESSED,
+                             TABLE_CODEC_LZ4, TABLE_MAGIC_V3, TABLE_MAGIC_COMPRESSED_V3,
+                             TABLE_CATALOG_ITEM_SIZE_V3, TABLE_MAGIC_V4, TABLE_MAGIC_COMPRESSED_V4, TABLE_HEAD_SIZE_V4,
+                             TABLE_HEAD_SIZE_COMPRESSED_V4, TABLE_MAGIC_COMPRESSED_V5, TABLE_HEAD_SIZE_COMPRESSED_V5, TABLE_CHECKSUM_IEEE, TABLE_CHECKSUM_CASTAGNOLI};
+use crate::table::lz4;
+use crate::table::bloom::BloomFilter;
 use crate::encode::{encode_fixed32_ret, decode_fixed32, decode_fixed64, encode_fixed64_ret};
 use crate::error::Error;
-use crate::Comparator;
-use crate::partition::{InternalKey, UserKey};
+use crate::io::IOManager;
+use crate::{Checksum, Comparator};
+use crate::partition::{InternalKey, UserKey, LookupResult, ValueKind, decode_seq_kind, encode_seq_kind};
 
 pub(crate) struct ScTableCatalogItem {
     pub(crate) key_seq: u64,
+    pub(crate) kind: ValueKind,
     pub(crate) key_off: u32,
     pub(crate) key_len: u32,
     pub(crate) value_off: u32,
-    pub(crate) value_len: u32
+    pub(crate) value_len: u32,
+    /// Bytes the key shares with the key before it, `key_off` and `key_len` only cover the rest.
+    pub(crate) key_shared: u32
 }
 
 impl ScTableCatalogItem {
-    pub(crate) fn new(key_seq: u64, key_off: u32, key_len: u32, value_off: u32, value_len: u32) -> Self {
-        Self { key_seq, key_off, key_len, value_off, value_len }
+    pub(crate) fn new(key_seq: u64,
+                      kind: ValueKind,
+                      key_off: u32,
+                      key_len: u32,
+                      value_off: u32,
+                      value_len: u32,
+                      key_shared: u32) -> Self {
+        Self { key_seq, kind, key_off, key_len, value_off, value_len, key_shared }
     }
 
     pub(crate) fn serialize(&self, dest: &mut Vec<u8>) {
-        dest.extend_from_slice(&encode_fixed64_ret(self.key_seq));
+        encode_seq_kind(self.key_seq, self.kind, dest);
         dest.extend_from_slice(&encode_fixed32_ret(self.key_off));
         dest.extend_from_slice(&encode_fixed32_ret(self.key_len));
         dest.extend_from_slice(&encode_fixed32_ret(self.value_off));
         dest.extend_from_slice(&encode_fixed32_ret(self.value_len));
+        dest.extend_from_slice(&encode_fixed32_ret(self.key_shared));
     }
 
-    pub(crate) fn deserialize(from: &[u8]) -> Self {
-        debug_assert_eq!(from.len(), TABLE_CATALOG_ITEM_SIZE);
+    pub(crate) fn is_deletion(&self) -> bool {
+        self.kind == ValueKind::Deletion
+    }
+
+    /// `None` if the kind byte is none of ours. Items of `TABLE_CATALOG_ITEM_SIZE_V3` share no key
+    /// prefix.
+    pub(crate) fn deserialize(from: &[u8]) -> Option<Self> {
+        debug_assert!(from.len() == TABLE_CATALOG_ITEM_SIZE || from.len() == TABLE_CATALOG_ITEM_SIZE_V3);
+        let (key_seq, kind) = match decode_seq_kind(from) {
+            // range tombstones are kept apart from tables
+            Ok((_, ValueKind::RangeDeletion)) | Err(_) => return None,
+            Ok(seq_kind) => seq_kind
+        };
+        Some(Self {
+            key_seq,
+            kind,
+            key_off: decode_fixed32(&from[9..13]),
+            key_len: decode_fixed32(&from[13..17]),
+            value_off: decode_fixed32(&from[17..21]),
+            value_len: decode_fixed32(&from[21..25]),
+            key_shared: if from.len() == TABLE_CATALOG_ITEM_SIZE { decode_fixed32(&from[25..29]) } else { 0 }
+        })
+    }
+
+    /// Catalog items of tables older than `TABLE_MAGIC_V3`, which mark a tombstone with the highest
+    /// bit of its value offset.
+    pub(crate) fn deserialize_v2(from: &[u8]) -> Self {
+        debug_assert_eq!(from.len(), TABLE_CATALOG_ITEM_SIZE_V2);
+        let value_off = decode_fixed32(&from[16..20]);
+        let kind = if value_off & TABLE_DELETION_BITMASK != 0 { ValueKind::Deletion } else { ValueKind::Value };
         Self {
             key_seq: decode_fixed64(&from[0..8]),
+            kind,
             key_off: decode_fixed32(&from[8..12]),
             key_len: decode_fixed32(&from[12..16]),
-            value_off: decode_fixed32(&from[16..20]),
+            value_off: value_off & !TABLE_DELETION_BITMASK,
             value_len: decode_fixed32(&from[20..24]),
+            key_shared: 0
         }
     }
 }
 
 pub(crate) struct ScTableCache {
     catalog: Vec<ScTableCatalogItem>,
+    /// Indexes of the catalog items whose key is stored in full, ascending and starting with 0.
+    /// Searches go over these first, then scan forward expanding the keys in between.
+    restarts: Vec<usize>,
     data: Vec<u8>,
+    filter: Option<BloomFilter>,
+    /// Where the data region starts in the table file if only the keys were kept, `data` then
+    /// holds just them and values are read from the file on demand, see `from_raw_index`.
+    values_offset: Option<u64>,
+    /// The IEEE crc of every value in catalog order if only the keys were kept, so a value read
+    /// from the file later is checked against what the table held when its crcs were checked.
+    value_crcs: Vec<u32>,
+    /// Given back when the table is dropped, that is once its last `Arc` is gone, no matter if the
+    /// LRU still lists it. So the quotas taken always match the tables alive.
+    #[allow(dead_code)]
     quota: CacheQuota
 }
 
 impl ScTableCache {
     pub(crate) fn from_raw(raw: &[u8], quota: CacheQuota) -> Result<ScTableCache, Error> {
-        if raw.len() < TABLE_MIN_SIZE {
-            return Err(Error::sc_table_corrupt("too small to be a table file".into()))
-        } else if raw.len() > TABLE_MAX_SIZE {
-            return Err(Error::sc_table_corrupt("too large to be a table file".into()))
-        }
+        Self::parse(raw, quota, true)
+    }
 
-        if &raw[raw.len()-TABLE_MAGIC_SIZE .. raw.len()] != TABLE_MAGIC {
-            return Err(Error::sc_table_corrupt("incorrect table magic".into()))
+    /// Like `from_raw`, but keeps the catalog, keys and filter only, for iterating keys. Values are
+    /// never decompressed, except those of tables of `TABLE_MAGIC_COMPRESSED_V5` and older, whose
+    /// keys are compressed along with them. The table has no values to look up, so it must not be
+    /// cached.
+    pub(crate) fn from_raw_keys(raw: &[u8], quota: CacheQuota) -> Result<ScTableCache, Error> {
+        let mut table = Self::parse(raw, quota, false)?;
+        if table.data.len() > table.keys_size() {
+            table.keep_keys();
         }
+        Ok(table)
+    }
 
+    /// `from_raw`, which leaves the values of a table whose keys are apart from its compressed
+    /// values compressed if `values` is `false`, `data` then only holds the keys.
+    fn parse(raw: &[u8], quota: CacheQuota, values: bool) -> Result<ScTableCache, Error> {
+        let TableLayout { head_size, item_size, has_filter, codec_off, plain_keys, checksum } = table_layout(raw)?;
         let kv_catalog_size = decode_fixed32(&raw[0..4]) as usize;
         let data_size = decode_fixed32(&raw[4..8]) as usize;
+        let filter_size = if has_filter { decode_fixed32(&raw[16..20]) as usize } else { 0 };
 
-        if kv_catalog_size % TABLE_CATALOG_ITEM_SIZE != 0 {
-            return Err(Error::sc_table_corrupt("catalog size should be multiplication of 16".into()))
+        if kv_catalog_size % item_size != 0 {
+            return Err(Error::sc_table_corrupt(format!("catalog size should be multiplication of {}", item_size).into()))
+        } else if kv_catalog_size == 0 {
+            return Err(Error::sc_table_corrupt("empty table".into()))
         }
 
-        if (kv_catalog_size + data_size + TABLE_MIN_SIZE) != raw.len() {
+        if (kv_catalog_size + data_size + filter_size + head_size + TABLE_MAGIC_SIZE) != raw.len() {
             return Err(Error::sc_table_corrupt("incorrect table size".into()))
         }
 
         let kv_catalog_crc = decode_fixed32(&raw[8..12]);
         let data_crc = decode_fixed32(&raw[12..16]);
 
-        let kv_catalog = &raw[TABLE_HEAD_SIZE..TABLE_HEAD_SIZE+ kv_catalog_size];
-        let data = &raw[TABLE_HEAD_SIZE+ kv_catalog_size..TABLE_HEAD_SIZE+ kv_catalog_size +data_size];
+        let kv_catalog = &raw[head_size..head_size + kv_catalog_size];
+        let data = &raw[head_size + kv_catalog_size..head_size + kv_catalog_size + data_size];
+        let filter = &raw[head_size + kv_catalog_size + data_size..raw.len() - TABLE_MAGIC_SIZE];
 
-        if crc32::checksum_ieee(kv_catalog) != kv_catalog_crc {
+        if checksum.checksum(kv_catalog) != kv_catalog_crc {
             return Err(Error::sc_table_corrupt("incorrect kv_catalog crc".into()))
         }
 
-        if crc32::checksum_ieee(data) != data_crc {
+        if checksum.checksum(data) != data_crc {
             return Err(Error::sc_table_corrupt("incorrect data crc".into()))
         }
 
+        if has_filter && checksum.checksum(filter) != decode_fixed32(&raw[20..24]) {
+            return Err(Error::sc_table_corrupt("incorrect filter crc".into()))
+        }
+
+        let (data, data_size) = if let Some(codec_off) = codec_off {
+            let uncompressed_size = decode_fixed32(&raw[codec_off + 4..codec_off + 8]) as usize;
+            let keys_size = if plain_keys { decode_fixed32(&raw[codec_off + 8..codec_off + 12]) as usize } else { 0 };
+            if keys_size > data.len() || keys_size > uncompressed_size {
+                return Err(Error::sc_table_corrupt("incorrect keys size".into()))
+            }
+            let (keys, compressed) = data.split_at(keys_size);
+            if plain_keys && !values {
+                (keys.to_vec(), uncompressed_size)
+            } else {
+                let mut data = keys.to_vec();
+                match decode_fixed32(&raw[codec_off..codec_off + 4]) {
+                    TABLE_CODEC_LZ4 => data.extend(lz4::decompress(compressed, uncompressed_size - keys_size)
+                        .ok_or_else(|| Error::sc_table_corrupt("incorrect compressed data".into()))?),
+                    _ => return Err(Error::sc_table_corrupt("unknown compression codec".into()))
+                }
+                (data, uncompressed_size)
+            }
+        } else {
+            (data.to_vec(), data.len())
+        };
+
+        let mut last_key_len = 0;
+        let mut restarts = Vec::new();
         let mut catalog_item = Vec::new();
-        for i in 0..kv_catalog_size / TABLE_CATALOG_ITEM_SIZE {
-            let base = i * TABLE_CATALOG_ITEM_SIZE;
-            let index =
-                ScTableCatalogItem::deserialize(&kv_catalog[base..base + TABLE_CATALOG_ITEM_SIZE]);
-            if index.value_off & TABLE_DELETION_BITMASK != 0 {
-            } else if (index.key_off + index.key_len) as usize > data.len()
-                      || (index.value_off + index.value_len) as usize > data.len() {
+        for raw_item in kv_catalog.chunks_exact(item_size) {
+            let index = if item_size == TABLE_CATALOG_ITEM_SIZE_V2 {
+                ScTableCatalogItem::deserialize_v2(raw_item)
+            } else {
+                ScTableCatalogItem::deserialize(raw_item)
+                    .ok_or_else(|| Error::sc_table_corrupt("incorrect value kind".into()))?
+            };
+            if index.is_deletion() {
+                if index.key_off as usize + index.key_len as usize > data.len() {
+                    return Err(Error::sc_table_corrupt("incorrect key/value catalog data".into()))
+                }
+            } else if index.key_off as usize + index.key_len as usize > data.len()
+                      || index.value_off as usize + index.value_len as usize > data_size {
                 return Err(Error::sc_table_corrupt("incorrect key/value catalog data".into()))
             }
+            if index.key_shared as usize > last_key_len {
+                return Err(Error::sc_table_corrupt("incorrect shared key prefix".into()))
+            } else if index.key_shared == 0 {
+                restarts.push(catalog_item.len());
+            }
+            last_key_len = index.key_shared as usize + index.key_len as usize;
             catalog_item.push(index)
         }
 
-        Ok(Self { catalog: catalog_item, data: data.to_vec(), quota })
+        Ok(Self {
+            catalog: catalog_item,
+            restarts,
+            data,
+            filter: BloomFilter::deserialize(filter),
+            values_offset: None,
+            value_crcs: Vec::new(),
+            quota
+        })
     }
 
-    pub(crate) fn get<Comp: Comparator>(&self, key: &InternalKey<Comp>) -> Option<Vec<u8>> {
-        if let Ok(idx) = self.catalog.binary_search_by(
-            |catalog_item| {
-                let seq = catalog_item.key_seq;
-                let user_key = self.key(catalog_item);
-                let lookup_key = InternalKey::new(seq, UserKey::new_borrow(user_key));
-                // TODO this is buggy.
-                key.cmp(&lookup_key)
-            }) {
-            if self.catalog[idx].value_off & TABLE_DELETION_BITMASK != 0 {
-                None
-            } else {
-                Some(self.value(&self.catalog[idx]).to_vec())
-            }
-        } else {
+    /// Like `from_raw`, but keeps the catalog, keys and filter only. The crcs are checked over the
+    /// whole table, values read later are checked against a crc of each value taken meanwhile.
+    /// Values of a compressed table are not where they are in the file, and values out of catalog
+    /// order cannot be told apart by their offset, so such tables are kept whole.
+    pub(crate) fn from_raw_index(raw: &[u8], quota: CacheQuota) -> Result<ScTableCache, Error> {
+        let TableLayout { head_size, codec_off, .. } = table_layout(raw)?;
+        let mut table = Self::from_raw(raw, quota)?;
+        if codec_off.is_some() || table.catalog.windows(2).any(|items| items[0].value_off > items[1].value_off) {
+            return Ok(table)
+        }
+        table.value_crcs = table.catalog.iter()
+            .map(|catalog_item| crc32::checksum_ieee(&table.data[table.value_range(catalog_item)]))
+            .collect();
+        table.keep_keys();
+        table.values_offset = Some((head_size + decode_fixed32(&raw[0..4]) as usize) as u64);
+        Ok(table)
+    }
+
+    /// Bytes of `data` up to the end of the last key.
+    fn keys_size(&self) -> usize {
+        self.catalog.iter()
+            .map(|catalog_item| (catalog_item.key_off + catalog_item.key_len) as usize)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Drops everything but the keys from `data`, their offsets then point to where they are moved.
+    fn keep_keys(&mut self) {
+        let mut keys = Vec::new();
+        for catalog_item in self.catalog.iter_mut() {
+            let key_off = catalog_item.key_off as usize;
+            let key_len = catalog_item.key_len as usize;
+            catalog_item.key_off = keys.len() as u32;
+            keys.extend_from_slice(&self.data[key_off..key_off + key_len]);
+        }
+        self.data = keys;
+    }
+
+    /// Where the data region starts in the table file, if the values were left there. Ranges
+    /// `get_range` returns are then relative to it.
+    pub(crate) fn values_offset(&self) -> Option<u64> {
+        self.values_offset
+    }
+
+    /// The crc the value at `range` of the data region had, if only the keys were kept. Values
+    /// ascend in catalog order, and only one value which is not empty starts at an offset.
+    pub(crate) fn value_crc(&self, range: &Range<usize>) -> Option<u32> {
+        let start = self.catalog.partition_point(|catalog_item| (catalog_item.value_off as usize) < range.start);
+        self.catalog[start..].iter()
+            .take_while(|catalog_item| catalog_item.value_off as usize == range.start)
+            .position(|catalog_item| self.value_range(catalog_item) == *range)
+            .and_then(|idx| self.value_crcs.get(start + idx).copied())
+    }
+
+    pub(crate) fn has_filter(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    /// `false` only if the table surely holds no version of `user_key`. Tables without a filter
+    /// may contain anything.
+    pub(crate) fn may_contain(&self, user_key: &[u8]) -> bool {
+        self.filter.as_ref().map_or(true, |filter| filter.may_contain(user_key))
+    }
+
+    /// Looks up the newest version of `key.user_key` whose sequence is not greater than
+    /// `key.seq`. The catalog is sorted in `InternalKey` order, so all versions of a user key are
+    /// adjacent with the newest one first.
+    #[cfg(test)]
+    pub(crate) fn get<Comp: Comparator>(&self, key: &InternalKey<Comp>) -> LookupResult {
+        self.find(key).map(|catalog_item| catalog_item.map(|catalog_item| self.value(catalog_item).to_vec()))
+    }
+
+    /// Like `get`, but returns where the value is in the data region instead of a copy of it, see
+    /// `data_range`.
+    pub(crate) fn get_range<Comp: Comparator>(&self, key: &InternalKey<Comp>) -> LookupResult<Range<usize>> {
+        self.find(key).map(|catalog_item| catalog_item.map(|catalog_item| self.value_range(catalog_item)))
+    }
+
+    /// Like `get_range`, but also returns the sequence number of the version holding the value.
+    pub(crate) fn get_range_with_seq<Comp: Comparator>(&self, key: &InternalKey<Comp>) -> LookupResult<(Range<usize>, u64)> {
+        self.find(key).map(|catalog_item| {
+            catalog_item.map(|catalog_item| (self.value_range(catalog_item), catalog_item.key_seq))
+        })
+    }
+
+    /// Like `get`, but only looks at the catalog, the value stays where it is.
+    pub(crate) fn contains<Comp: Comparator>(&self, key: &InternalKey<Comp>) -> LookupResult<()> {
+        self.find(key).map(|catalog_item| catalog_item.map(|_| ()))
+    }
+
+    /// The catalog item of the newest version of `key` visible at its sequence number, `None` inside
+    /// for a tombstone.
+    fn find<Comp: Comparator>(&self, key: &InternalKey<Comp>) -> LookupResult<&ScTableCatalogItem> {
+        let idx = self.seek(key);
+        if idx == self.catalog.len() {
+            return None
+        }
+        let catalog_item = &self.catalog[idx];
+        if key.user_key.comparator().compare(&self.key(idx), key.user_key.key()) != Ordering::Equal {
             None
+        } else if catalog_item.is_deletion() {
+            Some(None)
+        } else {
+            Some(Some(catalog_item))
         }
     }
 
+    /// Index of the first catalog item not less than `key`, or `catalog_size()` if there is none.
+    /// Binary searches the restart points for the last one less than `key`, then scans the items
+    /// after it up to the next restart point.
+    pub(crate) fn seek<Comp: Comparator>(&self, key: &InternalKey<Comp>) -> usize {
+        let comparator = key.user_key.comparator();
+        let restart = self.restarts.partition_point(|&n| self.nth_key(n, comparator) < *key);
+        if restart == 0 {
+            return 0
+        }
+        let end = self.restarts.get(restart).copied().unwrap_or(self.catalog.len());
+        let mut user_key = Vec::new();
+        for n in self.restarts[restart - 1]..end {
+            self.expand_key(n, &mut user_key);
+            let catalog_item = &self.catalog[n];
+            let nth_key = InternalKey {
+                seq: catalog_item.key_seq,
+                kind: catalog_item.kind,
+                user_key: UserKey::new_borrow(&user_key, comparator)
+            };
+            if nth_key >= *key {
+                return n
+            }
+        }
+        end
+    }
+
     pub(crate) fn catalog_size(&self) -> usize {
         self.catalog.len()
     }
 
-    pub(crate) fn nth_item(&self, n: usize) -> (u64, &[u8], &[u8]) {
+    /// Bytes of the data region, uncompressed.
+    pub(crate) fn data_size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Where the entries not less than `key` start in the data region, `data_size()` if there are
+    /// none. Entries are stored in key order, so the data between two offsets holds the keys between.
+    pub(crate) fn approximate_offset<Comp: Comparator>(&self, key: &InternalKey<Comp>) -> usize {
+        debug_assert!(self.values_offset.is_none());
+        match self.catalog.get(self.seek(key)) {
+            Some(catalog_item) => catalog_item.key_off as usize,
+            None => self.data.len()
+        }
+    }
+
+    /// The bytes of the data region in `range`, as `get_range` has found them.
+    pub(crate) fn data_range(&self, range: Range<usize>) -> &[u8] {
+        &self.data[range]
+    }
+
+    #[cfg(test)]
+    pub(crate) fn nth_item(&self, n: usize) -> (u64, Cow<'_, [u8]>, &[u8]) {
         assert!(n < self.catalog_size());
         let catalog_item = &self.catalog[n];
-        (catalog_item.key_seq, self.key(catalog_item), self.value(catalog_item))
+        (catalog_item.key_seq, self.key(n), self.value(catalog_item))
+    }
+
+    /// The value of the `n`th entry, empty for a tombstone. Unlike `nth_item` the key is left alone.
+    pub(crate) fn nth_value(&self, n: usize) -> &[u8] {
+        self.value(&self.catalog[n])
+    }
+
+    /// Where the value of the `n`th entry is in the data region, see `data_range`.
+    pub(crate) fn nth_value_range(&self, n: usize) -> Range<usize> {
+        self.value_range(&self.catalog[n])
     }
 
-    fn key(&self, catalog_item: &ScTableCatalogItem) -> &[u8] {
+    /// The user key of the first catalog item.
+    pub(crate) fn smallest_key(&self) -> Cow<'_, [u8]> {
+        self.key(0)
+    }
+
+    /// The user key of the last catalog item.
+    pub(crate) fn largest_key(&self) -> Cow<'_, [u8]> {
+        self.key(self.catalog.len() - 1)
+    }
+
+    /// The entries of the table in catalog order, which is sorted, with owned keys. A tombstone
+    /// comes with an empty value. Each key is expanded from the one before it, see `KeyCursor`.
+    pub(crate) fn iter<'a, Comp: Comparator>(&'a self, comparator: &'a Arc<Comp>)
+        -> impl Iterator<Item=(InternalKey<'static, Comp>, &'a [u8])> + 'a {
+        let mut keys = KeyCursor::default();
+        (0..self.catalog_size()).map(move |n| (keys.nth_key(self, n, comparator), self.nth_value(n)))
+    }
+
+    /// The internal key of the `n`th entry. The user key is borrowed from this cache if it is
+    /// stored in full, otherwise it is expanded from the restart point before it.
+    pub(crate) fn nth_key<Comp: Comparator>(&self, n: usize, comparator: &Arc<Comp>) -> InternalKey<'_, Comp> {
+        let catalog_item = &self.catalog[n];
+        let user_key = match self.key(n) {
+            Cow::Borrowed(key) => UserKey::new_borrow(key, comparator),
+            Cow::Owned(key) => UserKey::Owned(key, comparator.clone())
+        };
+        InternalKey { seq: catalog_item.key_seq, kind: catalog_item.kind, user_key }
+    }
+
+    fn key(&self, n: usize) -> Cow<'_, [u8]> {
+        if self.catalog[n].key_shared == 0 {
+            return Cow::Borrowed(self.suffix(&self.catalog[n]))
+        }
+        let restart = self.restarts[self.restarts.partition_point(|&restart| restart <= n) - 1];
+        let mut key = Vec::new();
+        for m in restart..=n {
+            self.expand_key(m, &mut key);
+        }
+        Cow::Owned(key)
+    }
+
+    /// Turns `key`, the key of item `n - 1`, into the key of item `n`.
+    fn expand_key(&self, n: usize, key: &mut Vec<u8>) {
+        let catalog_item = &self.catalog[n];
+        key.truncate(catalog_item.key_shared as usize);
+        key.extend_from_slice(self.suffix(catalog_item));
+    }
+
+    /// The part of the key stored with the item, all of it for restart points.
+    fn suffix(&self, catalog_item: &ScTableCatalogItem) -> &[u8] {
         &self.data[catalog_item.key_off as usize .. (catalog_item.key_off + catalog_item.key_len) as usize]
     }
 
     fn value(&self, catalog_item: &ScTableCatalogItem) -> &[u8] {
-        &self.data[catalog_item.value_off as usize .. (catalog_item.value_off + catalog_item.value_len) as usize]
+        debug_assert!(self.values_offset.is_none());
+        &self.data[self.value_range(catalog_item)]
+    }
+
+    fn value_range(&self, catalog_item: &ScTableCatalogItem) -> Range<usize> {
+        if catalog_item.is_deletion() {
+            return 0..0
+        }
+        catalog_item.value_off as usize .. (catalog_item.value_off + catalog_item.value_len) as usize
     }
 }
 
+/// Decodes the keys of a table one entry after the other. Stepping to the entry right after the one
+/// decoded last only applies the part of the key stored with it, so a scan goes over every key
+/// once, any other entry is expanded from its restart point.
+#[derive(Default)]
+pub(crate) struct KeyCursor {
+    user_key: Vec<u8>,
+    decoded: Option<usize>
+}
+
+impl KeyCursor {
+    /// The internal key of the `n`th entry of `cache`, which has to be the table of every call.
+    pub(crate) fn nth_key<Comp: Comparator>(&mut self,
+                                            cache: &ScTableCache,
+                                            n: usize,
+                                            comparator: &Arc<Comp>) -> InternalKey<'static, Comp> {
+        match self.decoded {
+            Some(decoded) if decoded == n => {},
+            Some(decoded) if decoded + 1 == n => cache.expand_key(n, &mut self.user_key),
+            _ => {
+                self.user_key.clear();
+                self.user_key.extend_from_slice(&cache.key(n));
+            }
+        }
+        self.decoded = Some(n);
+        let catalog_item = &cache.catalog[n];
+        InternalKey {
+            seq: catalog_item.key_seq,
+            kind: catalog_item.kind,
+            user_key: UserKey::new_owned(self.user_key.clone(), comparator)
+        }
+    }
+}
+
+/// Where the parts of a table are and how its crcs are computed, as the magic at its end and the
+/// header tell.
+pub(crate) struct TableLayout {
+    pub(crate) head_size: usize,
+    pub(crate) item_size: usize,
+    /// Tables of `TABLE_MAGIC_V1` have neither a filter size nor a filter crc.
+    pub(crate) has_filter: bool,
+    /// Offset of the codec in the header of a table with compressed data, the uncompressed data
+    /// size follows it.
+    pub(crate) codec_off: Option<usize>,
+    /// Whether the keys of a compressed table are stored apart from its values and not
+    /// compressed, their size then follows the uncompressed data size.
+    pub(crate) plain_keys: bool,
+    pub(crate) checksum: Checksum
+}
+
+pub(crate) fn table_layout(raw: &[u8]) -> Result<TableLayout, Error> {
+    if raw.len() < TABLE_MIN_SIZE_V1 {
+        return Err(Error::sc_table_corrupt("too small to be a table file".into()))
+    } else if raw.len() > TABLE_MAX_SIZE {
+        return Err(Error::sc_table_corrupt("too large to be a table file".into()))
+    }
+
+    let magic = &raw[raw.len()-TABLE_MAGIC_SIZE .. raw.len()];
+    // the checksum, then the codec follow the filter crc, older tables have no checksum
+    let (head_size, item_size, checksum_off, codec_off) = if magic == TABLE_MAGIC {
+        (TABLE_HEAD_SIZE, TABLE_CATALOG_ITEM_SIZE, Some(24), None)
+    } else if magic == TABLE_MAGIC_COMPRESSED {
+        (TABLE_HEAD_SIZE_COMPRESSED, TABLE_CATALOG_ITEM_SIZE, Some(24), Some(28))
+    } else if magic == TABLE_MAGIC_COMPRESSED_V5 {
+        (TABLE_HEAD_SIZE_COMPRESSED_V5, TABLE_CATALOG_ITEM_SIZE, Some(24), Some(28))
+    } else if magic == TABLE_MAGIC_V4 {
+        (TABLE_HEAD_SIZE_V4, TABLE_CATALOG_ITEM_SIZE, None, None)
+    } else if magic == TABLE_MAGIC_COMPRESSED_V4 {
+        (TABLE_HEAD_SIZE_COMPRESSED_V4, TABLE_CATALOG_ITEM_SIZE, None, Some(24))
+    } else if magic == TABLE_MAGIC_V3 {
+        (TABLE_HEAD_SIZE_V4, TABLE_CATALOG_ITEM_SIZE_V3, None, None)
+    } else if magic == TABLE_MAGIC_COMPRESSED_V3 {
+        (TABLE_HEAD_SIZE_COMPRESSED_V4, TABLE_CATALOG_ITEM_SIZE_V3, None, Some(24))
+    } else if magic == TABLE_MAGIC_V2 {
+        (TABLE_HEAD_SIZE_V4, TABLE_CATALOG_ITEM_SIZE_V2, None, None)
+    } else if magic == TABLE_MAGIC_V1 {
+        (TABLE_HEAD_SIZE_V1, TABLE_CATALOG_ITEM_SIZE_V2, None, None)
+    } else {
+        return Err(Error::sc_table_corrupt("incorrect table magic".into()))
+    };
+    if raw.len() < head_size + TABLE_MAGIC_SIZE {
+        return Err(Error::sc_table_corrupt("too small to be a table file".into()))
+    }
+
+    let checksum = match checksum_off.map(|off| decode_fixed32(&raw[off..off + 4])) {
+        None | Some(TABLE_CHECKSUM_IEEE) => Checksum::Ieee,
+        Some(TABLE_CHECKSUM_CASTAGNOLI) => Checksum::Castagnoli,
+        Some(_) => return Err(Error::sc_table_corrupt("unknown checksum".into()))
+    };
+    Ok(TableLayout {
+        head_size,
+        item_size,
+        has_filter: magic != TABLE_MAGIC_V1,
+        codec_off,
+        plain_keys: magic == TABLE_MAGIC_COMPRESSED,
+        checksum
+    })
+}
+
+/// Gives every entry of the table `raw` the sequence number `seq` and fixes the crc of its catalog,
+/// the data region is left as it is. `raw` has to have passed `ScTableCache::from_raw`.
+pub(crate) fn assign_seq(raw: &mut [u8], seq: u64) -> Result<(), Error> {
+    let layout = table_layout(raw)?;
+    assign_seq_to_catalog(raw, &layout, seq);
+    Ok(())
+}
+
+/// Where the catalog of the table `raw` of layout `layout` ends. Everything `assign_seq` rewrites
+/// comes before it.
+pub(crate) fn catalog_end(raw: &[u8], layout: &TableLayout) -> usize {
+    layout.head_size + decode_fixed32(&raw[0..4]) as usize
+}
+
+/// `assign_seq` on the first `catalog_end` bytes of a table alone, which is all it needs.
+pub(crate) fn assign_seq_to_catalog(head: &mut [u8], layout: &TableLayout, seq: u64) {
+    let TableLayout { head_size, item_size, checksum, .. } = *layout;
+    let kv_catalog_size = decode_fixed32(&head[0..4]) as usize;
+    let kv_catalog = &mut head[head_size..head_size + kv_catalog_size];
+    for raw_item in kv_catalog.chunks_exact_mut(item_size) {
+        raw_item[0..8].copy_from_slice(&encode_fixed64_ret(seq));
+    }
+    let kv_catalog_crc = checksum.checksum(kv_catalog);
+    head[8..12].copy_from_slice(&encode_fixed32_ret(kv_catalog_crc));
+}
+
 pub(crate) struct CacheQuota {
     cache_manager: NonNull<TableCacheManager>
 }
@@ -154,6 +607,11 @@ impl CacheQuota {
     }
 }
 
+/// The quota only ever touches the counter of its `TableCacheManager`, which is itself safe to
+/// share, so quotas (and the caches holding them) may move between threads freely.
+unsafe impl Send for CacheQuota {}
+unsafe impl Sync for CacheQuota {}
+
 impl Drop for CacheQuota {
     fn drop(&mut self) {
         unsafe { self.cache_manager.as_ref().on_cache_released() }
@@ -162,7 +620,20 @@ impl Drop for CacheQuota {
 
 pub(crate) struct TableCacheManager {
     lru: Mutex<LruCache<ScTableFile, Arc<ScTableCache>>>,
-    sem: Semaphore
+    cache_count: usize,
+    current_cache_count: Mutex<usize>,
+    condvar: Condvar,
+    /// Tables being read from disk right now, others asking for them wait instead of reading too.
+    loading: Mutex<HashSet<ScTableFile>>,
+    loaded: Condvar,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    filter_negatives: AtomicU64,
+    filter_false_positives: AtomicU64,
+    /// Tables are cached through `from_raw_index` rather than whole, see `get_or_load_whole`.
+    index_only: bool,
+    /// The values read from tables cached without them.
+    block_cache: BlockCache
 }
 
 /// Warning: make sure all `CacheQuota`s are dropped before the `TableCacheManager` drops.
@@ -171,13 +642,76 @@ impl TableCacheManager {
     pub(crate) fn new(cache_count: usize) -> Self {
         TableCacheManager {
             lru: Mutex::new(LruCache::new(cache_count)),
-            sem: Semaphore::new(cache_count as isize)
+            cache_count,
+            current_cache_count: Mutex::new(0),
+            condvar: Condvar::new(),
+            loading: Mutex::new(HashSet::new()),
+            loaded: Condvar::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            filter_negatives: AtomicU64::new(0),
+            filter_false_positives: AtomicU64::new(0),
+            index_only: false,
+            block_cache: BlockCache::new(0)
         }
     }
 
+    pub(crate) fn with_index_only(mut self, index_only: bool) -> Self {
+        self.index_only = index_only;
+        self
+    }
+
+    pub(crate) fn with_block_cache_bytes(mut self, block_cache_bytes: usize) -> Self {
+        self.block_cache = BlockCache::new(block_cache_bytes);
+        self
+    }
+
+    pub(crate) fn block_cache(&self) -> &BlockCache {
+        &self.block_cache
+    }
+
+    /// Every cached table holds a quota, so when none is left, evict cached tables from the least
+    /// recently used one on. An evicted table still in use (by an iterator, for example) keeps its
+    /// quota until it is dropped, only when nothing is left to evict do we wait for that.
     pub(crate) fn acquire_quota(&self) -> CacheQuota {
-        self.sem.acquire();
-        CacheQuota::new(self)
+        loop {
+            {
+                let mut current_cache_count = self.current_cache_count.lock().unwrap();
+                if *current_cache_count < self.cache_count {
+                    *current_cache_count += 1;
+                    return CacheQuota::new(self)
+                }
+            }
+            let evicted = self.lru.lock().unwrap().pop_lru();
+            if evicted.is_none() {
+                let mut current_cache_count = self.current_cache_count.lock().unwrap();
+                while *current_cache_count >= self.cache_count {
+                    current_cache_count = self.condvar.wait(current_cache_count).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Drops `table_file` and its blocks from the cache. Its quota is given back right away, unless
+    /// the table is still in use, then once it is dropped.
+    pub(crate) fn evict(&self, table_file: ScTableFile) {
+        let evicted = self.lru.lock().unwrap().pop(&table_file);
+        drop(evicted);
+        self.block_cache.evict_table(table_file);
+    }
+
+    /// Drops every cached table and block, quotas are given back as with `evict`. Tables loaded
+    /// meanwhile may stay cached.
+    pub(crate) fn clear(&self) {
+        let mut evicted = Vec::new();
+        {
+            let mut lru = self.lru.lock().unwrap();
+            while let Some((_, table)) = lru.pop_lru() {
+                evicted.push(table);
+            }
+        }
+        drop(evicted);
+        self.block_cache.clear();
     }
 
     pub(crate) fn add_cache(&self, table_file: ScTableFile, table_cache: ScTableCache) -> Arc<ScTableCache> {
@@ -190,7 +724,788 @@ impl TableCacheManager {
         self.lru.lock().unwrap().get(&table_file).and_then(|arc| Some(arc.clone()))
     }
 
+    /// The cached table, read from disk on a miss. Only one of several threads missing the same
+    /// table reads it, the others wait for it to be cached.
+    pub(crate) fn get_or_load(&self, table_file: ScTableFile, io_manager: &IOManager) -> Result<Arc<ScTableCache>, Error> {
+        {
+            let mut loading = self.loading.lock().unwrap();
+            loop {
+                // a table is cached before it is taken out of `loading`, so checking here misses nothing
+                if let Some(cache) = self.get_cache(table_file) {
+                    self.hits.fetch_add(1, atomic::Ordering::Relaxed);
+                    return Ok(cache)
+                }
+                if !loading.contains(&table_file) {
+                    self.misses.fetch_add(1, atomic::Ordering::Relaxed);
+                    break;
+                }
+                loading = self.loaded.wait(loading).unwrap();
+            }
+            loading.insert(table_file);
+        }
+
+        let cache_quota = self.acquire_quota();
+        let result = io_manager.acquire_quota().read_file(table_file.file_name())
+            .and_then(|raw| if self.index_only {
+                ScTableCache::from_raw_index(&raw, cache_quota)
+            } else {
+                ScTableCache::from_raw(&raw, cache_quota)
+            })
+            .map(|cache| self.add_cache(table_file, cache));
+        self.loading.lock().unwrap().remove(&table_file);
+        self.loaded.notify_all();
+        result
+    }
+
+    /// Like `get_or_load`, but the table comes with its values. If only the keys of tables are
+    /// cached, it is read from disk every time unless the cached one happens to be whole, and it
+    /// is not cached, so scans and compactions do not push the keys of other tables out.
+    pub(crate) fn get_or_load_whole(&self, table_file: ScTableFile, io_manager: &IOManager) -> Result<Arc<ScTableCache>, Error> {
+        if !self.index_only {
+            return self.get_or_load(table_file, io_manager)
+        }
+        if let Some(cache) = self.get_cache(table_file).filter(|cache| cache.values_offset().is_none()) {
+            return Ok(cache)
+        }
+        let cache_quota = self.acquire_quota();
+        io_manager.acquire_quota().read_file(table_file.file_name())
+            .and_then(|raw| ScTableCache::from_raw(&raw, cache_quota))
+            .map(Arc::new)
+    }
+
+    /// Like `get_or_load`, but only the keys of the table are wanted. A cached table is taken
+    /// whether it is whole or not, otherwise the table read from disk only keeps its keys, see
+    /// `ScTableCache::from_raw_keys`, and is not cached.
+    pub(crate) fn get_or_load_keys(&self, table_file: ScTableFile, io_manager: &IOManager) -> Result<Arc<ScTableCache>, Error> {
+        if let Some(cache) = self.get_cache(table_file) {
+            return Ok(cache)
+        }
+        let cache_quota = self.acquire_quota();
+        io_manager.acquire_quota().read_file(table_file.file_name())
+            .and_then(|raw| ScTableCache::from_raw_keys(&raw, cache_quota))
+            .map(Arc::new)
+    }
+
+    /// Lookups through `get_or_load` which found the table cached, including those waiting for
+    /// another thread to load it.
+    pub(crate) fn cache_hits(&self) -> u64 {
+        self.hits.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Lookups through `get_or_load` which read the table from disk.
+    pub(crate) fn cache_misses(&self) -> u64 {
+        self.misses.load(atomic::Ordering::Relaxed)
+    }
+
+    /// A table's filter ruled out the key looked for, the table was not searched.
+    pub(crate) fn record_filter_negative(&self) {
+        self.filter_negatives.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    /// A table's filter let the key looked for through, but the table did not hold it.
+    pub(crate) fn record_filter_false_positive(&self) {
+        self.filter_false_positives.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn filter_negatives(&self) -> u64 {
+        self.filter_negatives.load(atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn filter_false_positives(&self) -> u64 {
+        self.filter_false_positives.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Number of tables alive, cached or not.
+    pub(crate) fn current_cache_count(&self) -> usize {
+        *self.current_cache_count.lock().unwrap()
+    }
+
+    /// Number of tables which may be alive at once.
+    pub(crate) fn cache_count(&self) -> usize {
+        self.cache_count
+    }
+
     fn on_cache_released(&self) {
-        self.sem.release()
+        *self.current_cache_count.lock().unwrap() -= 1;
+        self.condvar.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use crc::crc32;
+
+    use crate::{DefaultComparator, Compression, Checksum};
+    use crate::error::Error;
+    use crate::encode::{encode_fixed32_ret, encode_fixed64_ret, decode_fixed32};
+    use crate::table::tablefmt::{TABLE_HEAD_SIZE, TABLE_CATALOG_ITEM_SIZE, TABLE_MAGIC_SIZE, TABLE_MAGIC_V1,
+                                 TABLE_MAGIC_V2, TABLE_DELETION_BITMASK, TABLE_MAGIC, TABLE_MAGIC_COMPRESSED,
+                                 TABLE_HEAD_SIZE_COMPRESSED, TABLE_MAGIC_V3, TABLE_CATALOG_ITEM_SIZE_V3,
+                                 TABLE_HEAD_SIZE_V4, TABLE_MAGIC_V4, TABLE_CHECKSUM_IEEE, TABLE_CHECKSUM_CASTAGNOLI,
+                                 TABLE_MAGIC_COMPRESSED_V5, TABLE_HEAD_SIZE_COMPRESSED_V5};
+    use crate::table::builder::ScTableBuilder;
+    use crate::table::lz4;
+    use crate::table::cache::{ScTableCache, TableCacheManager, KeyCursor, assign_seq};
+    use crate::table::sctable::ScTableFile;
+    use crate::io::IOManager;
+    use crate::partition::{InternalKey, UserKey, LookupResult};
+    use crate::testutil::{TestDir, default_comparator};
+
+    fn lookup(table: &ScTableCache, seq: u64, key: &[u8]) -> LookupResult {
+        table.get::<DefaultComparator>(&InternalKey::new(seq, UserKey::new_borrow(key, &default_comparator())))
+    }
+
+    /// A table without filter in the layout of `TABLE_MAGIC_V2`, or of `TABLE_MAGIC_V1` which also
+    /// lacks the filter size and crc. `None` values are tombstones.
+    fn legacy_table(entries: &[(u64, &[u8], Option<&[u8]>)], v1: bool) -> Vec<u8> {
+        let mut catalog = Vec::new();
+        let mut data = Vec::new();
+        for &(seq, key, value) in entries {
+            catalog.extend_from_slice(&encode_fixed64_ret(seq));
+            catalog.extend_from_slice(&encode_fixed32_ret(data.len() as u32));
+            catalog.extend_from_slice(&encode_fixed32_ret(key.len() as u32));
+            data.extend_from_slice(key);
+            let value_off = data.len() as u32;
+            match value {
+                Some(value) => {
+                    catalog.extend_from_slice(&encode_fixed32_ret(value_off));
+                    catalog.extend_from_slice(&encode_fixed32_ret(value.len() as u32));
+                    data.extend_from_slice(value);
+                },
+                None => {
+                    catalog.extend_from_slice(&encode_fixed32_ret(value_off | TABLE_DELETION_BITMASK));
+                    catalog.extend_from_slice(&encode_fixed32_ret(0));
+                }
+            }
+        }
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&encode_fixed32_ret(catalog.len() as u32));
+        ret.extend_from_slice(&encode_fixed32_ret(data.len() as u32));
+        ret.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&catalog)));
+        ret.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&data)));
+        if !v1 {
+            ret.extend_from_slice(&encode_fixed32_ret(0));
+            ret.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&[])));
+        }
+        ret.extend_from_slice(&catalog);
+        ret.extend_from_slice(&data);
+        ret.extend_from_slice(if v1 { TABLE_MAGIC_V1 } else { TABLE_MAGIC_V2 });
+        ret
+    }
+
+    #[test]
+    fn test_get_multiple_versions() {
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"apple", b"apple1");
+        builder.add_kv(9, b"banana", b"banana9");
+        builder.add_kv(5, b"banana", b"banana5");
+        builder.add_kv(2, b"banana", b"banana2");
+        builder.add_kv(3, b"cherry", b"cherry3");
+        builder.add_deletion(7, b"durian");
+        builder.add_kv(6, b"durian", b"durian6");
+        let buffer = builder.build();
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).unwrap();
+
+        assert_eq!(lookup(&table, u64::max_value(), b"apple"), Some(Some(b"apple1".to_vec())));
+        assert_eq!(lookup(&table, u64::max_value(), b"banana"), Some(Some(b"banana9".to_vec())));
+        assert_eq!(lookup(&table, 9, b"banana"), Some(Some(b"banana9".to_vec())));
+        assert_eq!(lookup(&table, 8, b"banana"), Some(Some(b"banana5".to_vec())));
+        assert_eq!(lookup(&table, 4, b"banana"), Some(Some(b"banana2".to_vec())));
+        assert_eq!(lookup(&table, 1, b"banana"), None);
+        assert_eq!(lookup(&table, 2, b"cherry"), None);
+        assert_eq!(lookup(&table, u64::max_value(), b"aardvark"), None);
+        assert_eq!(lookup(&table, u64::max_value(), b"blueberry"), None);
+        assert_eq!(lookup(&table, u64::max_value(), b"durian"), Some(None));
+        assert_eq!(lookup(&table, 6, b"durian"), Some(Some(b"durian6".to_vec())));
+        assert_eq!(lookup(&table, u64::max_value(), b"elderberry"), None);
+    }
+
+    #[test]
+    fn test_assign_seq() {
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"apple", b"apple1");
+        builder.add_deletion(7, b"banana");
+        let mut tables = vec![builder.build(), legacy_table(&[(3, b"apple", Some(b"red")), (4, b"banana", None)], true)];
+        let cache_manager = TableCacheManager::new(1);
+        for raw in tables.iter_mut() {
+            assign_seq(raw, 42).unwrap();
+            let table = ScTableCache::from_raw(raw, cache_manager.acquire_quota()).unwrap();
+            assert_eq!(lookup(&table, 41, b"apple"), None);
+            assert!(lookup(&table, 42, b"apple").unwrap().is_some());
+            assert_eq!(lookup(&table, 42, b"banana"), Some(None));
+        }
+    }
+
+    #[test]
+    fn test_contains() {
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"apple", b"apple1");
+        builder.add_deletion(7, b"durian");
+        builder.add_kv(6, b"durian", b"durian6");
+        let buffer = builder.build();
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).unwrap();
+        let contains = |seq, key: &[u8]| {
+            table.contains::<DefaultComparator>(&InternalKey::new(seq, UserKey::new_borrow(key, &default_comparator())))
+        };
+        assert_eq!(contains(u64::max_value(), b"apple"), Some(Some(())));
+        assert_eq!(contains(u64::max_value(), b"banana"), None);
+        assert_eq!(contains(u64::max_value(), b"durian"), Some(None));
+        assert_eq!(contains(6, b"durian"), Some(Some(())));
+    }
+
+    #[test]
+    fn test_value_ends_at_data_end() {
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"key", b"");
+        builder.add_kv(1, b"last", b"final value");
+        let buffer = builder.build();
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).unwrap();
+        assert_eq!(table.nth_item(1), (1, Cow::from(b"last".as_ref()), b"final value".as_ref()));
+    }
+
+    #[test]
+    fn test_smallest_and_largest_key() {
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(3, b"apple", b"apple3");
+        builder.add_kv(2, b"durian", b"");
+        builder.add_kv(2, b"mango", b"mango2");
+        builder.add_kv(1, b"mango", b"mango1");
+        let buffer = builder.build();
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).unwrap();
+        assert_eq!(table.smallest_key(), b"apple".as_ref());
+        assert_eq!(table.largest_key(), b"mango".as_ref());
+    }
+
+    #[test]
+    fn test_empty_table_rejected() {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&encode_fixed32_ret(0));
+        buffer.extend_from_slice(&encode_fixed32_ret(0));
+        buffer.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&[])));
+        buffer.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&[])));
+
+        let cache_manager = TableCacheManager::new(1);
+        assert!(ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).is_err());
+    }
+
+    #[test]
+    fn test_catalog_offset_overflow() {
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"key", b"value");
+        let mut buffer = builder.build();
+        // key_off = 0xFFFFFFFF, key_len = 8: wraps around to 7 in u32 arithmetic
+        buffer[TABLE_HEAD_SIZE + 9..TABLE_HEAD_SIZE + 13].copy_from_slice(&[0xFF; 4]);
+        buffer[TABLE_HEAD_SIZE + 13..TABLE_HEAD_SIZE + 17].copy_from_slice(&encode_fixed32_ret(8));
+        let catalog_crc = crc32::checksum_ieee(&buffer[TABLE_HEAD_SIZE..TABLE_HEAD_SIZE + TABLE_CATALOG_ITEM_SIZE]);
+        buffer[8..12].copy_from_slice(&encode_fixed32_ret(catalog_crc));
+
+        let cache_manager = TableCacheManager::new(1);
+        assert!(ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut builder = ScTableBuilder::with_filter(0.01);
+        for i in 0..100 {
+            builder.add_kv(2, format!("key{:03}", i).as_bytes(), b"value");
+            builder.add_kv(1, format!("key{:03}", i).as_bytes(), b"older value");
+        }
+        let buffer = builder.build();
+        assert_eq!(buffer.len(), builder.size());
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).unwrap();
+        for i in 0..100 {
+            assert!(table.may_contain(format!("key{:03}", i).as_bytes()));
+        }
+        assert!((100..1100).filter(|i| table.may_contain(format!("key{:03}", i).as_bytes())).count() < 50);
+    }
+
+    #[test]
+    fn test_filter_corrupt() {
+        let mut builder = ScTableBuilder::with_filter(0.01);
+        builder.add_kv(1, b"key", b"value");
+        let mut buffer = builder.build();
+        let filter_end = buffer.len() - TABLE_MAGIC_SIZE - 1;
+        buffer[filter_end - 1] ^= 0xFF;
+
+        let cache_manager = TableCacheManager::new(1);
+        assert!(ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).is_err());
+    }
+
+    #[test]
+    fn test_incorrect_value_kind() {
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"key", b"value");
+        let mut buffer = builder.build();
+        buffer[TABLE_HEAD_SIZE + 8] = 2;
+        let catalog_crc = crc32::checksum_ieee(&buffer[TABLE_HEAD_SIZE..TABLE_HEAD_SIZE + TABLE_CATALOG_ITEM_SIZE]);
+        buffer[8..12].copy_from_slice(&encode_fixed32_ret(catalog_crc));
+
+        let cache_manager = TableCacheManager::new(1);
+        assert!(ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).is_err());
+    }
+
+    #[test]
+    fn test_compressed_table() {
+        let build = |compression| {
+            let mut builder = ScTableBuilder::with_filter(0.01).with_compression(compression);
+            for i in 0..200 {
+                builder.add_kv(1, format!("key{:03}", i).as_bytes(), format!("value{}", i % 10).repeat(8).as_bytes());
+            }
+            builder.add_deletion(1, b"key200");
+            builder.build()
+        };
+        let plain = build(Compression::None);
+        let compressed = build(Compression::Lz4);
+        assert!(plain.ends_with(TABLE_MAGIC));
+        assert!(compressed.ends_with(TABLE_MAGIC_COMPRESSED));
+        assert!(compressed.len() < plain.len() / 2);
+
+        let cache_manager = TableCacheManager::new(2);
+        let plain = ScTableCache::from_raw(&plain, cache_manager.acquire_quota()).unwrap();
+        let table = ScTableCache::from_raw(&compressed, cache_manager.acquire_quota()).unwrap();
+        assert_eq!(table.catalog_size(), plain.catalog_size());
+        for n in 0..plain.catalog_size() {
+            assert_eq!(table.nth_item(n), plain.nth_item(n));
+        }
+        assert_eq!(lookup(&table, 1, b"key123"), Some(Some(b"value3".repeat(8))));
+        assert_eq!(lookup(&table, 1, b"key200"), Some(None));
+        assert!(table.may_contain(b"key000"));
+    }
+
+    /// A compressed table with IEEE crcs in the layout of `TABLE_MAGIC_COMPRESSED_V5`, which
+    /// compresses its keys along with its values.
+    fn downgrade_to_v5(raw: &[u8]) -> Vec<u8> {
+        assert_eq!(decode_fixed32(&raw[24..28]), TABLE_CHECKSUM_IEEE);
+        let catalog_size = decode_fixed32(&raw[0..4]) as usize;
+        let data_size = decode_fixed32(&raw[4..8]) as usize;
+        let uncompressed_size = decode_fixed32(&raw[32..36]) as usize;
+        let keys_size = decode_fixed32(&raw[36..40]) as usize;
+        let data_off = TABLE_HEAD_SIZE_COMPRESSED + catalog_size;
+        let mut data = raw[data_off..data_off + keys_size].to_vec();
+        data.extend(lz4::decompress(&raw[data_off + keys_size..data_off + data_size], uncompressed_size - keys_size).unwrap());
+        let compressed = lz4::compress(&data);
+
+        let mut ret = raw[..TABLE_HEAD_SIZE_COMPRESSED_V5].to_vec();
+        ret[4..8].copy_from_slice(&encode_fixed32_ret(compressed.len() as u32));
+        ret[12..16].copy_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&compressed)));
+        ret.extend_from_slice(&raw[TABLE_HEAD_SIZE_COMPRESSED..data_off]);
+        ret.extend_from_slice(&compressed);
+        ret.extend_from_slice(&raw[data_off + data_size..raw.len() - TABLE_MAGIC_SIZE]);
+        ret.extend_from_slice(TABLE_MAGIC_COMPRESSED_V5);
+        ret
+    }
+
+    #[test]
+    fn test_keys_of_compressed_table() {
+        let mut builder = ScTableBuilder::with_filter(0.01).with_compression(Compression::Lz4);
+        for i in 0..100 {
+            builder.add_kv(1, format!("key{:03}", i).as_bytes(), format!("value{}", i % 10).repeat(8).as_bytes());
+        }
+        builder.add_deletion(1, b"key100");
+        let buffer = builder.build();
+        assert!(buffer.ends_with(TABLE_MAGIC_COMPRESSED));
+        let comparator = default_comparator();
+        let cache_manager = TableCacheManager::new(3);
+        let whole = ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).unwrap();
+
+        let decompressions = lz4::decompressions();
+        let keys = ScTableCache::from_raw_keys(&buffer, cache_manager.acquire_quota()).unwrap();
+        assert_eq!(lz4::decompressions(), decompressions);
+        assert_eq!(keys.catalog_size(), whole.catalog_size());
+        for n in 0..whole.catalog_size() {
+            assert!(keys.nth_key(n, &comparator) == whole.nth_key(n, &comparator));
+        }
+        let key = InternalKey::new(1, UserKey::new_borrow(b"key042", &comparator));
+        assert_eq!(keys.seek(&key), 42);
+        assert!(keys.data_size() < whole.data_size() / 4);
+
+        // the keys of the older layout are compressed along with the values
+        let v5 = downgrade_to_v5(&buffer);
+        let table = ScTableCache::from_raw(&v5, cache_manager.acquire_quota()).unwrap();
+        assert_eq!(lookup(&table, 1, b"key042"), Some(Some(b"value2".repeat(8))));
+        drop(table);
+        let decompressions = lz4::decompressions();
+        let keys = ScTableCache::from_raw_keys(&v5, cache_manager.acquire_quota()).unwrap();
+        assert_eq!(lz4::decompressions(), decompressions + 1);
+        for n in 0..whole.catalog_size() {
+            assert!(keys.nth_key(n, &comparator) == whole.nth_key(n, &comparator));
+        }
+    }
+
+    #[test]
+    fn test_compressed_table_corrupt() {
+        let mut builder = ScTableBuilder::new().with_compression(Compression::Lz4);
+        builder.add_kv(1, b"key", &[b'x'; 256]);
+        let buffer = builder.build();
+        assert!(buffer.ends_with(TABLE_MAGIC_COMPRESSED));
+        let cache_manager = TableCacheManager::new(1);
+
+        // caught by the crc of the compressed bytes
+        let mut corrupt = buffer.clone();
+        corrupt[TABLE_HEAD_SIZE_COMPRESSED + TABLE_CATALOG_ITEM_SIZE + 1] ^= 0xFF;
+        assert!(ScTableCache::from_raw(&corrupt, cache_manager.acquire_quota()).is_err());
+
+        // an uncompressed size not matching the data
+        let mut corrupt = buffer.clone();
+        corrupt[28..32].copy_from_slice(&encode_fixed32_ret(300));
+        assert!(ScTableCache::from_raw(&corrupt, cache_manager.acquire_quota()).is_err());
+
+        let mut corrupt = buffer.clone();
+        corrupt[24..28].copy_from_slice(&encode_fixed32_ret(9));
+        assert!(ScTableCache::from_raw(&corrupt, cache_manager.acquire_quota()).is_err());
+    }
+
+    #[test]
+    fn test_incompressible_table_stored_plain() {
+        let mut builder = ScTableBuilder::new().with_compression(Compression::Lz4);
+        builder.add_kv(1, b"k", b"v");
+        let buffer = builder.build();
+        assert!(buffer.ends_with(TABLE_MAGIC));
+        assert_eq!(buffer.len(), builder.size());
+    }
+
+    #[test]
+    fn test_load_v1_table() {
+        let v1 = legacy_table(&[(1, b"apple", Some(b"apple1")), (2, b"banana", None)], true);
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&v1, cache_manager.acquire_quota()).unwrap();
+        assert!(table.may_contain(b"cherry"));
+        assert_eq!(lookup(&table, u64::max_value(), b"apple"), Some(Some(b"apple1".to_vec())));
+        assert_eq!(lookup(&table, u64::max_value(), b"banana"), Some(None));
+    }
+
+    /// An uncompressed table with IEEE crcs in the layout of `TABLE_MAGIC_V4`, which lacks the
+    /// checksum in the header.
+    fn downgrade_to_v4(raw: &[u8]) -> Vec<u8> {
+        assert_eq!(decode_fixed32(&raw[24..28]), TABLE_CHECKSUM_IEEE);
+        let mut ret = raw[..TABLE_HEAD_SIZE_V4].to_vec();
+        ret.extend_from_slice(&raw[TABLE_HEAD_SIZE..raw.len() - TABLE_MAGIC_SIZE]);
+        ret.extend_from_slice(TABLE_MAGIC_V4);
+        ret
+    }
+
+    /// An uncompressed table with IEEE crcs in the layout of `TABLE_MAGIC_V3`, whose catalog items
+    /// lack the shared size.
+    fn downgrade_to_v3(raw: &[u8]) -> Vec<u8> {
+        let raw = downgrade_to_v4(raw);
+        let catalog_size = decode_fixed32(&raw[0..4]) as usize;
+        let mut ret = raw[..TABLE_HEAD_SIZE_V4].to_vec();
+        for item in raw[TABLE_HEAD_SIZE_V4..TABLE_HEAD_SIZE_V4 + catalog_size].chunks_exact(TABLE_CATALOG_ITEM_SIZE) {
+            assert_eq!(decode_fixed32(&item[TABLE_CATALOG_ITEM_SIZE_V3..]), 0);
+            ret.extend_from_slice(&item[..TABLE_CATALOG_ITEM_SIZE_V3]);
+        }
+        let v3_catalog_size = ret.len() - TABLE_HEAD_SIZE_V4;
+        ret[0..4].copy_from_slice(&encode_fixed32_ret(v3_catalog_size as u32));
+        let catalog_crc = crc32::checksum_ieee(&ret[TABLE_HEAD_SIZE_V4..]);
+        ret[8..12].copy_from_slice(&encode_fixed32_ret(catalog_crc));
+        ret.extend_from_slice(&raw[TABLE_HEAD_SIZE_V4 + catalog_size..raw.len() - TABLE_MAGIC_SIZE]);
+        ret.extend_from_slice(TABLE_MAGIC_V3);
+        ret
+    }
+
+    #[test]
+    fn test_load_v4_table() {
+        let mut builder = ScTableBuilder::with_filter(0.01);
+        builder.add_kv(2, b"apple", b"apple2");
+        builder.add_deletion(3, b"apricot");
+        builder.add_kv(1, b"apricot", b"apricot1");
+        let v4 = downgrade_to_v4(&builder.build());
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&v4, cache_manager.acquire_quota()).unwrap();
+        assert_eq!(lookup(&table, u64::max_value(), b"apple"), Some(Some(b"apple2".to_vec())));
+        assert_eq!(lookup(&table, u64::max_value(), b"apricot"), Some(None));
+        assert_eq!(lookup(&table, 2, b"apricot"), Some(Some(b"apricot1".to_vec())));
+    }
+
+    #[test]
+    fn test_castagnoli_checksum() {
+        let build = |checksum| {
+            let mut builder = ScTableBuilder::with_filter(0.01).with_checksum(checksum);
+            builder.add_kv(1, b"apple", b"apple1");
+            builder.add_kv(1, b"banana", b"banana1");
+            builder.build()
+        };
+        let (ieee, castagnoli) = (build(Checksum::Ieee), build(Checksum::Castagnoli));
+        assert_eq!(decode_fixed32(&castagnoli[24..28]), TABLE_CHECKSUM_CASTAGNOLI);
+        assert_ne!(ieee[8..24], castagnoli[8..24]);
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&castagnoli, cache_manager.acquire_quota()).unwrap();
+        assert_eq!(lookup(&table, u64::max_value(), b"banana"), Some(Some(b"banana1".to_vec())));
+        drop(table);
+
+        // the crcs are checked with the checksum the header declares
+        let mut mislabeled = castagnoli.clone();
+        mislabeled[24..28].copy_from_slice(&encode_fixed32_ret(TABLE_CHECKSUM_IEEE));
+        assert!(ScTableCache::from_raw(&mislabeled, cache_manager.acquire_quota()).is_err());
+        let mut unknown = castagnoli;
+        unknown[24..28].copy_from_slice(&encode_fixed32_ret(7));
+        match ScTableCache::from_raw(&unknown, cache_manager.acquire_quota()) {
+            Err(Error::ScTableCorrupt { reason }) => assert_eq!(reason.to_string(), "unknown checksum"),
+            _ => panic!("a table with an unknown checksum should not load")
+        }
+    }
+
+    #[test]
+    fn test_load_v3_table() {
+        let mut builder = ScTableBuilder::with_filter(0.01).with_restart_interval(1);
+        builder.add_kv(2, b"apple", b"apple2");
+        builder.add_deletion(3, b"apricot");
+        builder.add_kv(1, b"apricot", b"apricot1");
+        let v3 = downgrade_to_v3(&builder.build());
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&v3, cache_manager.acquire_quota()).unwrap();
+        assert!(table.may_contain(b"apple"));
+        assert_eq!(lookup(&table, u64::max_value(), b"apple"), Some(Some(b"apple2".to_vec())));
+        assert_eq!(lookup(&table, u64::max_value(), b"apricot"), Some(None));
+        assert_eq!(lookup(&table, 2, b"apricot"), Some(Some(b"apricot1".to_vec())));
+    }
+
+    #[test]
+    fn test_shared_prefix_corrupt() {
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"key1", b"value");
+        builder.add_kv(1, b"key2", b"value");
+        let mut buffer = builder.build();
+        let shared = TABLE_HEAD_SIZE + 2 * TABLE_CATALOG_ITEM_SIZE - 4;
+        assert_eq!(decode_fixed32(&buffer[shared..shared + 4]), 3);
+        // longer than the key before it
+        buffer[shared..shared + 4].copy_from_slice(&encode_fixed32_ret(5));
+        let catalog_crc = crc32::checksum_ieee(&buffer[TABLE_HEAD_SIZE..TABLE_HEAD_SIZE + 2 * TABLE_CATALOG_ITEM_SIZE]);
+        buffer[8..12].copy_from_slice(&encode_fixed32_ret(catalog_crc));
+
+        let cache_manager = TableCacheManager::new(1);
+        assert!(ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).is_err());
+    }
+
+    #[test]
+    fn test_seek_matches_linear_scan() {
+        let comparator = default_comparator();
+        let mut keys: Vec<String> = (0..3000u64).map(|i| format!("user/{:03}/item{:05}", i % 97, i * 31)).collect();
+        keys.sort();
+        for &restart_interval in &[1, 7, 16] {
+            // every fifth key also has an older tombstone
+            let mut builder = ScTableBuilder::new().with_restart_interval(restart_interval);
+            for (i, key) in keys.iter().enumerate() {
+                builder.add_kv(i as u64 + 2, key.as_bytes(), key.as_bytes());
+                if i % 5 == 0 {
+                    builder.add_deletion(i as u64 + 1, key.as_bytes());
+                }
+            }
+            let cache_manager = TableCacheManager::new(1);
+            let table = ScTableCache::from_raw(&builder.build(), cache_manager.acquire_quota()).unwrap();
+
+            let probes = keys.iter().step_by(3).cloned().chain(keys.iter().step_by(7).map(|key| format!("{}~", key)));
+            for (i, user_key) in probes.enumerate() {
+                for &seq in &[0, i as u64 + 1, i as u64 + 2, u64::max_value()] {
+                    let key = InternalKey::new(seq, UserKey::new_borrow(user_key.as_bytes(), &comparator));
+                    let expected = (0..table.catalog_size())
+                        .find(|&n| table.nth_key(n, &comparator) >= key)
+                        .unwrap_or(table.catalog_size());
+                    assert_eq!(table.seek(&key), expected);
+
+                    let found = (expected < table.catalog_size()).then(|| table.nth_item(expected))
+                        .filter(|(_, found_key, _)| found_key.as_ref() == user_key.as_bytes())
+                        .map(|(_, _, value)| if value.is_empty() { None } else { Some(value.to_vec()) });
+                    assert_eq!(lookup(&table, seq, user_key.as_bytes()), found);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_v2_table() {
+        let v2 = legacy_table(&[(3, b"apple", None),
+                                (1, b"apple", Some(b"apple1")),
+                                (2, b"banana", Some(b"")),
+                                (4, b"cherry", None)], false);
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&v2, cache_manager.acquire_quota()).unwrap();
+        assert_eq!(lookup(&table, u64::max_value(), b"apple"), Some(None));
+        assert_eq!(lookup(&table, 2, b"apple"), Some(Some(b"apple1".to_vec())));
+        assert_eq!(lookup(&table, u64::max_value(), b"banana"), Some(Some(Vec::new())));
+        assert_eq!(lookup(&table, u64::max_value(), b"cherry"), Some(None));
+        assert_eq!(table.nth_item(3), (4, Cow::from(b"cherry".as_ref()), b"".as_ref()));
+    }
+
+    #[test]
+    fn test_quota_waiters_complete() {
+        let cache_manager = Arc::new(TableCacheManager::new(2));
+        let holding = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8).map(|_| {
+            let cache_manager = cache_manager.clone();
+            let holding = holding.clone();
+            thread::spawn(move || {
+                let quota = cache_manager.acquire_quota();
+                assert!(holding.fetch_add(1, Ordering::SeqCst) < 2);
+                thread::sleep(Duration::from_millis(10));
+                holding.fetch_sub(1, Ordering::SeqCst);
+                drop(quota);
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(holding.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_get_or_load_reads_once() {
+        let dir = TestDir::new("cache_get_or_load_reads_once");
+        let io_manager = Arc::new(IOManager::new(dir.path(), 4));
+        let cache_manager = Arc::new(TableCacheManager::new(2));
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"apple", b"apple1");
+        let table_file = ScTableFile::new(1);
+        io_manager.acquire_quota().write_file(table_file.file_name(), &builder.build()).unwrap();
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            let cache_manager = cache_manager.clone();
+            let io_manager = io_manager.clone();
+            thread::spawn(move || cache_manager.get_or_load(table_file, &io_manager).unwrap())
+        }).collect();
+        let caches: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        // a second read would have replaced the cached table with another one
+        for cache in caches.iter() {
+            assert!(Arc::ptr_eq(cache, &caches[0]));
+        }
+        assert_eq!(lookup(&caches[0], u64::max_value(), b"apple"), Some(Some(b"apple1".to_vec())));
+
+        assert!(cache_manager.get_or_load(ScTableFile::new(2), &io_manager).is_err());
+        drop(caches);
+    }
+
+    #[test]
+    fn test_clear_reloads() {
+        let dir = TestDir::new("cache_clear_reloads");
+        let io_manager = IOManager::new(dir.path(), 4);
+        let cache_manager = TableCacheManager::new(4);
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"apple", b"apple1");
+        let buffer = builder.build();
+        for file_number in 0..4 {
+            io_manager.acquire_quota().write_file(ScTableFile::new(file_number).file_name(), &buffer).unwrap();
+            cache_manager.get_or_load(ScTableFile::new(file_number), &io_manager).unwrap();
+        }
+        assert_eq!(cache_manager.current_cache_count(), 4);
+
+        // a table in use keeps its quota until it is dropped
+        let held = cache_manager.get_cache(ScTableFile::new(0)).unwrap();
+        cache_manager.clear();
+        assert_eq!(cache_manager.current_cache_count(), 1);
+        assert!(cache_manager.get_cache(ScTableFile::new(0)).is_none());
+        drop(held);
+        assert_eq!(cache_manager.current_cache_count(), 0);
+
+        let misses = cache_manager.cache_misses();
+        for file_number in 0..4 {
+            let table = cache_manager.get_or_load(ScTableFile::new(file_number), &io_manager).unwrap();
+            assert_eq!(lookup(&table, u64::max_value(), b"apple"), Some(Some(b"apple1".to_vec())));
+        }
+        assert_eq!(cache_manager.cache_misses(), misses + 4);
+        assert_eq!(cache_manager.current_cache_count(), 4);
+        cache_manager.clear();
+        assert_eq!(cache_manager.current_cache_count(), 0);
+    }
+
+    #[test]
+    fn test_quota_follows_live_tables() {
+        let cache_manager = Arc::new(TableCacheManager::new(32));
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"apple", b"apple1");
+        let buffer = Arc::new(builder.build());
+
+        let threads: Vec<_> = (0..8u32).map(|t| {
+            let cache_manager = cache_manager.clone();
+            let buffer = buffer.clone();
+            thread::spawn(move || {
+                // tables kept alive after the LRU evicted or replaced them
+                let mut held = Vec::new();
+                for i in 0..500u64 {
+                    let table_file = ScTableFile::new(t as u64 % 2 * 16 + i % 16);
+                    let table = ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).unwrap();
+                    let table = cache_manager.add_cache(table_file, table);
+                    if i % 3 == 0 {
+                        held.push(table);
+                        if held.len() > 2 {
+                            held.remove(0);
+                        }
+                    }
+                    if i % 5 == 0 {
+                        cache_manager.evict(ScTableFile::new(t as u64 % 2 * 16 + (i + 7) % 16));
+                    }
+                    if i % 97 == 0 {
+                        cache_manager.clear();
+                    }
+                    let _ = cache_manager.get_cache(ScTableFile::new(t as u64 % 2 * 16 + (i + 3) % 16));
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let cached = cache_manager.lru.lock().unwrap().len();
+        assert_eq!(cache_manager.current_cache_count(), cached);
+        for file_number in 0..32 {
+            cache_manager.evict(ScTableFile::new(file_number));
+        }
+        assert_eq!(cache_manager.current_cache_count(), 0);
+    }
+
+    #[test]
+    fn test_key_cursor() {
+        let comparator = default_comparator();
+        let mut builder = ScTableBuilder::new().with_restart_interval(16);
+        for i in 0..100u32 {
+            builder.add_kv(i as u64, format!("key{:04}", i * 3).as_bytes(), b"value");
+        }
+        let table = ScTableCache::from_raw(&builder.build(), TableCacheManager::new(1).acquire_quota()).unwrap();
+
+        // forward one by one, then jumping around, the same entry twice and backwards
+        let mut cursor = KeyCursor::default();
+        let steps = (0..100).chain(vec![3, 40, 41, 41, 17, 99, 0, 1].into_iter());
+        for n in steps {
+            assert!(cursor.nth_key(&table, n, &comparator) == table.nth_key(n, &comparator), "entry {}", n);
+        }
+    }
+
+    #[test]
+    fn test_iter_in_catalog_order() {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..50u32)
+            .map(|i| (format!("key{:02}", i).into_bytes(), format!("value{}", i * 7).into_bytes()))
+            .collect();
+        let mut builder = ScTableBuilder::new();
+        for (seq, (key, value)) in entries.iter().enumerate() {
+            builder.add_kv(seq as u64, key, value);
+        }
+        let buffer = builder.build();
+
+        let cache_manager = TableCacheManager::new(1);
+        let table = ScTableCache::from_raw(&buffer, cache_manager.acquire_quota()).unwrap();
+        let comparator = default_comparator();
+        let iterated: Vec<_> = table.iter(&comparator).map(|(k, v)| (k.user_key.key().to_vec(), v.to_vec())).collect();
+        assert_eq!(iterated, entries);
+        assert!(table.iter(&comparator).enumerate().all(|(seq, (k, _))| k.seq == seq as u64));
     }
 }
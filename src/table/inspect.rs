@@ -0,0 +1,187 @@
+//! Looks into a single table file without opening its database, to find out why a table fails to
+//! load for example.
+
+use std::path::Path;
+
+use crate::Checksum;
+use crate::encode::decode_fixed32;
+use crate::error::Error;
+use crate::table::cache::{table_layout, ScTableCache, TableCacheManager, TableLayout};
+use crate::table::tablefmt::TABLE_MAGIC_SIZE;
+
+/// What `inspect_table` found in a table file. The header is reported as it is, even when it does
+/// not match the rest of the file.
+#[derive(Debug, Clone)]
+pub struct TableInfo {
+    pub file_size: usize,
+    /// The magic at the end of the file, which tells the format the table is in.
+    pub magic: Vec<u8>,
+    /// How the crcs below are computed.
+    pub checksum: Checksum,
+    pub catalog_size: usize,
+    pub data_size: usize,
+    pub catalog_crc: u32,
+    pub data_crc: u32,
+    /// `None` for tables of `TABLE_MAGIC_V1`, which have no filter region.
+    pub filter_size: Option<usize>,
+    pub filter_crc: Option<u32>,
+    /// `None` unless the data region is compressed.
+    pub uncompressed_data_size: Option<usize>,
+    /// Whether the region sizes in the header add up to the file size. If they do not, the regions
+    /// cannot be told apart and none of the crcs is valid.
+    pub size_valid: bool,
+    pub catalog_crc_valid: bool,
+    pub data_crc_valid: bool,
+    /// Also `true` for tables without a filter region.
+    pub filter_crc_valid: bool,
+    /// Catalog items the catalog size makes room for.
+    pub entries: usize,
+    /// `None` if the table fails to load.
+    pub smallest_key: Option<Vec<u8>>,
+    pub largest_key: Option<Vec<u8>>,
+    /// The error loading the table gives, `None` if it loads.
+    pub error: Option<Error>
+}
+
+/// Reads the table file at `path` and reports its header, whether its crcs match and the keys it
+/// holds. Fails only if the file cannot be read or is no table at all, that is too small for a
+/// header or ending with an unknown magic. Other corruption is reported in the `TableInfo`.
+pub fn inspect_table(path: impl AsRef<Path>) -> Result<TableInfo, Error> {
+    let path = path.as_ref();
+    let raw = std::fs::read(path).map_err(|e| Error::io_error(e, path.display().to_string()))?;
+    inspect_raw(&raw)
+}
+
+pub(crate) fn inspect_raw(raw: &[u8]) -> Result<TableInfo, Error> {
+    let TableLayout { head_size, item_size, has_filter, codec_off, checksum, .. } = table_layout(raw)?;
+    let catalog_size = decode_fixed32(&raw[0..4]) as usize;
+    let data_size = decode_fixed32(&raw[4..8]) as usize;
+    let catalog_crc = decode_fixed32(&raw[8..12]);
+    let data_crc = decode_fixed32(&raw[12..16]);
+    let (filter_size, filter_crc) = if has_filter {
+        (Some(decode_fixed32(&raw[16..20]) as usize), Some(decode_fixed32(&raw[20..24])))
+    } else {
+        (None, None)
+    };
+    let uncompressed_data_size = codec_off.map(|codec_off| decode_fixed32(&raw[codec_off + 4..codec_off + 8]) as usize);
+
+    let size_valid = head_size + catalog_size + data_size + filter_size.unwrap_or(0) + TABLE_MAGIC_SIZE == raw.len();
+    let (catalog_crc_valid, data_crc_valid, filter_crc_valid) = if size_valid {
+        let data_off = head_size + catalog_size;
+        let filter_off = data_off + data_size;
+        (checksum.checksum(&raw[head_size..data_off]) == catalog_crc,
+         checksum.checksum(&raw[data_off..filter_off]) == data_crc,
+         filter_crc.map_or(true, |filter_crc| {
+             checksum.checksum(&raw[filter_off..raw.len() - TABLE_MAGIC_SIZE]) == filter_crc
+         }))
+    } else {
+        (false, false, false)
+    };
+
+    let cache_manager = TableCacheManager::new(1);
+    let (smallest_key, largest_key, error) = match ScTableCache::from_raw(raw, cache_manager.acquire_quota()) {
+        Ok(table) => (Some(table.smallest_key().to_vec()), Some(table.largest_key().to_vec()), None),
+        Err(e) => (None, None, Some(e))
+    };
+
+    Ok(TableInfo {
+        file_size: raw.len(),
+        magic: raw[raw.len() - TABLE_MAGIC_SIZE..].to_vec(),
+        checksum,
+        catalog_size,
+        data_size,
+        catalog_crc,
+        data_crc,
+        filter_size,
+        filter_crc,
+        uncompressed_data_size,
+        size_valid,
+        catalog_crc_valid,
+        data_crc_valid,
+        filter_crc_valid,
+        entries: catalog_size / item_size,
+        smallest_key,
+        largest_key,
+        error
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Checksum, Compression};
+    use crate::error::Error;
+    use crate::table::builder::ScTableBuilder;
+    use crate::table::inspect::{inspect_table, inspect_raw};
+    use crate::table::tablefmt::{TABLE_HEAD_SIZE, TABLE_CATALOG_ITEM_SIZE, TABLE_MAGIC, TABLE_MAGIC_COMPRESSED};
+    use crate::testutil::TestDir;
+
+    fn build(compression: Compression, checksum: Checksum) -> Vec<u8> {
+        let mut builder = ScTableBuilder::with_filter(0.01).with_compression(compression).with_checksum(checksum);
+        for i in 0..100 {
+            builder.add_kv(i, format!("key{:03}", i).as_bytes(), b"some value, repeated and repeated");
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_inspect_table() {
+        let dir = TestDir::new("test_inspect_table");
+        let path = dir.path().join("00000001.sct");
+        let raw = build(Compression::None, Checksum::Ieee);
+        std::fs::write(&path, &raw).unwrap();
+
+        let info = inspect_table(&path).unwrap();
+        assert_eq!(info.file_size, raw.len());
+        assert_eq!(info.magic, TABLE_MAGIC.to_vec());
+        assert_eq!(info.checksum, Checksum::Ieee);
+        assert_eq!(info.catalog_size, 100 * TABLE_CATALOG_ITEM_SIZE);
+        assert!(info.filter_size.unwrap() > 0);
+        assert_eq!(info.uncompressed_data_size, None);
+        assert!(info.size_valid && info.catalog_crc_valid && info.data_crc_valid && info.filter_crc_valid);
+        assert_eq!(info.entries, 100);
+        assert_eq!(info.smallest_key, Some(b"key000".to_vec()));
+        assert_eq!(info.largest_key, Some(b"key099".to_vec()));
+        assert!(info.error.is_none());
+
+        match inspect_table(dir.path().join("00000002.sct")) {
+            Err(Error::IOError { file, .. }) => assert!(file.unwrap().ends_with("00000002.sct")),
+            _ => panic!("a missing file cannot be inspected")
+        }
+    }
+
+    #[test]
+    fn test_inspect_compressed() {
+        let raw = build(Compression::Lz4, Checksum::Castagnoli);
+        let info = inspect_raw(&raw).unwrap();
+        assert_eq!(info.magic, TABLE_MAGIC_COMPRESSED.to_vec());
+        assert_eq!(info.checksum, Checksum::Castagnoli);
+        assert!(info.catalog_crc_valid && info.filter_crc_valid);
+        assert!(info.uncompressed_data_size.unwrap() > info.data_size);
+        assert!(info.data_crc_valid);
+        assert_eq!(info.largest_key, Some(b"key099".to_vec()));
+    }
+
+    #[test]
+    fn test_inspect_corrupt() {
+        let raw = build(Compression::None, Checksum::Ieee);
+
+        let mut flipped = raw.clone();
+        let data_off = TABLE_HEAD_SIZE + 100 * TABLE_CATALOG_ITEM_SIZE;
+        flipped[data_off] ^= 1;
+        let info = inspect_raw(&flipped).unwrap();
+        assert!(info.size_valid && info.catalog_crc_valid && info.filter_crc_valid);
+        assert!(!info.data_crc_valid);
+        assert_eq!(info.entries, 100);
+        assert_eq!(info.smallest_key, None);
+        assert_eq!(info.error.unwrap().to_string(), "corrupt table: incorrect data crc");
+
+        let mut truncated = raw[..data_off].to_vec();
+        truncated.extend_from_slice(TABLE_MAGIC);
+        let info = inspect_raw(&truncated).unwrap();
+        assert_eq!(info.catalog_size, 100 * TABLE_CATALOG_ITEM_SIZE);
+        assert!(!info.size_valid && !info.catalog_crc_valid);
+        assert!(info.error.is_some());
+
+        assert!(inspect_raw(&raw[..raw.len() - 1]).is_err());
+    }
+}
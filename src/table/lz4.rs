@@ -0,0 +1,200 @@
+//! LZ4 block format, without the frame around it. A block is a run of sequences:
+//! ```raw
+//! +-SEQUENCE-----------------------------------+
+//! | 1byte token                                |
+//! | literal length beyond 15, if any           |
+//! | literals                                   |
+//! | 2byte little endian match offset           |
+//! | match length beyond 19, if any             |
+//! +--------------------------------------------+
+//! ```
+//! The high nibble of the token is the literal length, the low one the match length minus 4. A
+//! nibble of 15 is continued by bytes added to it, up to the first byte which is not 255. The last
+//! sequence stops after its literals. The uncompressed length is not part of the block, callers
+//! keep it themselves.
+
+#[cfg(test)]
+thread_local! {
+    /// Blocks the current thread decompressed.
+    static DECOMPRESSIONS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 0xFFFF;
+/// The last 5 bytes are always literals.
+const LAST_LITERALS: usize = 5;
+/// A match never starts in the last 12 bytes.
+const MF_LIMIT: usize = 12;
+const HASH_LOG: u32 = 12;
+
+pub(crate) fn compress(src: &[u8]) -> Vec<u8> {
+    let mut dest = Vec::with_capacity(src.len() / 2 + 16);
+    // positions plus one, zero marks an empty slot
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MF_LIMIT <= src.len() {
+        let sequence = read_u32(src, pos);
+        let slot = &mut table[hash(sequence)];
+        let candidate = *slot;
+        *slot = pos + 1;
+        if candidate != 0 && pos - (candidate - 1) <= MAX_OFFSET && read_u32(src, candidate - 1) == sequence {
+            let candidate = candidate - 1;
+            let mut match_len = MIN_MATCH;
+            while pos + match_len < src.len() - LAST_LITERALS && src[candidate + match_len] == src[pos + match_len] {
+                match_len += 1;
+            }
+            write_sequence(&mut dest, &src[anchor..pos], Some((pos - candidate, match_len)));
+            pos += match_len;
+            anchor = pos;
+        } else {
+            pos += 1;
+        }
+    }
+    write_sequence(&mut dest, &src[anchor..], None);
+    dest
+}
+
+/// Blocks `decompress` was called on by the current thread so far.
+#[cfg(test)]
+pub(crate) fn decompressions() -> usize {
+    DECOMPRESSIONS.with(|count| count.get())
+}
+
+/// `None` if `src` is no block of exactly `uncompressed_len` bytes.
+pub(crate) fn decompress(src: &[u8], uncompressed_len: usize) -> Option<Vec<u8>> {
+    #[cfg(test)]
+    DECOMPRESSIONS.with(|count| count.set(count.get() + 1));
+    let mut dest = Vec::with_capacity(uncompressed_len);
+    let mut pos = 0;
+    loop {
+        let token = *src.get(pos)?;
+        pos += 1;
+        let literal_len = read_length(src, &mut pos, (token >> 4) as usize)?;
+        let literals = src.get(pos..pos.checked_add(literal_len)?)?;
+        if dest.len() + literal_len > uncompressed_len {
+            return None
+        }
+        dest.extend_from_slice(literals);
+        pos += literal_len;
+        if pos == src.len() {
+            break;
+        }
+
+        let offset = src.get(pos..pos + 2).map(|bytes| bytes[0] as usize | (bytes[1] as usize) << 8)?;
+        pos += 2;
+        let match_len = read_length(src, &mut pos, (token & 0x0F) as usize)?.checked_add(MIN_MATCH)?;
+        if offset == 0 || offset > dest.len() || dest.len() + match_len > uncompressed_len {
+            return None
+        }
+        // byte by byte, a match may overlap the bytes it produces
+        let start = dest.len() - offset;
+        for i in 0..match_len {
+            let byte = dest[start + i];
+            dest.push(byte);
+        }
+    }
+    if dest.len() == uncompressed_len { Some(dest) } else { None }
+}
+
+fn write_sequence(dest: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_nibble = found.map_or(0, |(_, match_len)| (match_len - MIN_MATCH).min(15));
+    dest.push(((literals.len().min(15) as u8) << 4) | match_nibble as u8);
+    write_length(dest, literals.len());
+    dest.extend_from_slice(literals);
+    if let Some((offset, match_len)) = found {
+        dest.push(offset as u8);
+        dest.push((offset >> 8) as u8);
+        write_length(dest, match_len - MIN_MATCH);
+    }
+}
+
+/// The part of `len` not fitting into its nibble of the token.
+fn write_length(dest: &mut Vec<u8>, len: usize) {
+    if len < 15 {
+        return
+    }
+    let mut rest = len - 15;
+    while rest >= 255 {
+        dest.push(255);
+        rest -= 255;
+    }
+    dest.push(rest as u8);
+}
+
+fn read_length(src: &[u8], pos: &mut usize, nibble: usize) -> Option<usize> {
+    let mut len = nibble;
+    if nibble == 15 {
+        loop {
+            let byte = *src.get(*pos)?;
+            *pos += 1;
+            len = len.checked_add(byte as usize)?;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+fn read_u32(src: &[u8], pos: usize) -> u32 {
+    src[pos] as u32 | (src[pos + 1] as u32) << 8 | (src[pos + 2] as u32) << 16 | (src[pos + 3] as u32) << 24
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{thread_rng, Rng};
+
+    use crate::table::lz4::{compress, decompress};
+
+    fn round_trip(data: &[u8]) -> usize {
+        let compressed = compress(data);
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+        compressed.len()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(b"");
+        round_trip(b"a");
+        round_trip(b"hello, world");
+        round_trip(b"abcdabcdabcdabcd");
+        assert!(round_trip(&[b'a'; 1000]) < 20);
+        assert!(round_trip(&[0; 100000]) < 500);
+        let text: Vec<u8> = (0..1000).flat_map(|i| format!("key{:04}value{}", i, i % 7).into_bytes()).collect();
+        assert!(round_trip(&text) < text.len() / 2);
+        for len in 0..64 {
+            let random: Vec<u8> = (0..len * 37).map(|_| thread_rng().gen_range(0, 4)).collect();
+            round_trip(&random);
+        }
+    }
+
+    #[test]
+    fn test_decompress_handmade_block() {
+        // 4 literals, then 8 bytes copied from 4 back, then a last literal
+        let block = [0x44, b'a', b'b', b'c', b'd', 0x04, 0x00, 0x10, b'!'];
+        assert_eq!(decompress(&block, 13).unwrap(), b"abcdabcdabcd!".to_vec());
+        assert_eq!(decompress(&block, 12), None);
+        assert_eq!(decompress(&block, 14), None);
+    }
+
+    #[test]
+    fn test_decompress_corrupt() {
+        let data: Vec<u8> = (0..4096).map(|i| (i % 13) as u8).collect();
+        let compressed = compress(&data);
+        // offsets pointing before the start and truncated blocks are rejected, never panic
+        assert_eq!(decompress(&[0x04, 0x10, 0x00], 8), None);
+        for len in 0..compressed.len() {
+            assert_eq!(decompress(&compressed[..len], data.len()), None);
+        }
+        for i in 0..compressed.len() {
+            let mut corrupt = compressed.clone();
+            corrupt[i] ^= 0xFF;
+            let _ = decompress(&corrupt, data.len());
+        }
+    }
+}
@@ -1,21 +1,53 @@
 pub mod tablefmt;
 pub(crate) mod builder;
+pub(crate) mod bloom;
+pub(crate) mod lz4;
 pub(crate) mod cache;
+pub(crate) mod block_cache;
 pub(crate) mod sctable;
-pub(crate) mod scsplit;
+pub(crate) mod inspect;
 
 use std::cmp::Ordering;
+use std::sync::Arc;
 
 use crate::{Comparator, error};
+use crate::db::PinnedValue;
 use crate::io::IOManager;
-use crate::table::cache::TableCacheManager;
-use crate::partition::{InternalKey, UserKey};
+use crate::table::cache::{TableCacheManager, ScTableCache};
+use crate::table::sctable::ScTableFile;
+use crate::partition::{Entry, InternalKey, UserKey, LookupResult};
 
-pub(crate) trait Table<Comp: Comparator> {
+pub(crate) trait Table<Comp: Comparator>: Send + Sync {
     fn get<'a>(&self,
                key: &InternalKey<Comp>,
                cache_manager: &'a TableCacheManager,
-               io_manager: &'a IOManager) -> Result<Option<Vec<u8>>, error::Error>;
+               io_manager: &'a IOManager) -> Result<LookupResult, error::Error>;
+
+    /// Like `get`, but without copying the value out of the table.
+    fn contains<'a>(&self,
+                    key: &InternalKey<Comp>,
+                    cache_manager: &'a TableCacheManager,
+                    io_manager: &'a IOManager) -> Result<LookupResult<()>, error::Error>;
+
+    /// Like `get`, but also returns the sequence number of the version holding the value.
+    fn get_with_seq<'a>(&self,
+                        key: &InternalKey<Comp>,
+                        cache_manager: &'a TableCacheManager,
+                        io_manager: &'a IOManager) -> Result<LookupResult<(Vec<u8>, u64)>, error::Error>;
+
+    /// Like `get`, but the value stays in the cached table, which it keeps alive. Values of tables
+    /// cached without them are copied after all.
+    fn get_pinned<'a>(&self,
+                      key: &InternalKey<Comp>,
+                      cache_manager: &'a TableCacheManager,
+                      io_manager: &'a IOManager) -> Result<LookupResult<PinnedValue>, error::Error>;
+
+    /// The versions of the user key of `key` visible at its sequence number, newest first, up to
+    /// the first one which is not a merge operand. Empty if the table has none.
+    fn get_versions<'a>(&self,
+                        key: &InternalKey<Comp>,
+                        cache_manager: &'a TableCacheManager,
+                        io_manager: &'a IOManager) -> Result<Vec<Entry<Comp>>, error::Error>;
 
     fn cmp_key(&self, key: &UserKey<Comp>) -> Ordering {
         if key.cmp(self.lower_bound()) == Ordering::Less {
@@ -27,9 +59,31 @@ pub(crate) trait Table<Comp: Comparator> {
         }
     }
 
-    fn lower_bound(&self) -> &UserKey<Comp>;
+    /// Whether any key in `[lower, upper]` may be held by this table.
+    fn overlaps(&self, lower: &UserKey<Comp>, upper: &UserKey<Comp>) -> bool {
+        !(upper < self.lower_bound() || lower > self.upper_bound())
+    }
+
+    /// The whole table, values included, even if lookups only cache its keys.
+    fn load<'a>(&self,
+                cache_manager: &'a TableCacheManager,
+                io_manager: &'a IOManager) -> Result<Arc<ScTableCache>, error::Error>;
+
+    /// The keys of the table, its values may be left out, see `TableCacheManager::get_or_load_keys`.
+    fn load_keys<'a>(&self,
+                     cache_manager: &'a TableCacheManager,
+                     io_manager: &'a IOManager) -> Result<Arc<ScTableCache>, error::Error>;
+
+    fn table_file(&self) -> ScTableFile;
+
+    /// Bytes this table takes on disk.
+    fn file_size(&self) -> usize;
+
+    /// Lookups of a key within the bounds of this table which did not find it here, so reading
+    /// this table was for nothing.
+    fn wasted_reads(&self) -> usize;
 
-    fn upper_bound(&self) -> &UserKey<Comp>;
+    fn lower_bound(&self) -> &UserKey<'_, Comp>;
 
-    fn is_lazy(&self) -> bool;
+    fn upper_bound(&self) -> &UserKey<'_, Comp>;
 }
@@ -1,41 +1,124 @@
 use std::cmp::Ordering;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicUsize};
 
 use crate::error::Error;
 use crate::table::Table;
-use crate::table::cache::{TableCacheManager, ScTableCache};
+use crate::table::cache::{TableCacheManager, ScTableCache, KeyCursor};
 use crate::Comparator;
+use crate::db::PinnedValue;
 use crate::io::IOManager;
-use crate::partition::{InternalKey, UserKey};
+use crate::partition::{Entry, InternalKey, UserKey, LookupResult};
 
+/// Names a table by its file number. Numbers are handed out by `DbContext::new_table_file` and are
+/// unique within a database, across runs.
 #[derive(Ord, Eq, PartialOrd, PartialEq, Hash, Copy, Clone)]
 pub(crate) struct ScTableFile {
-    origin_partition: u32,
-    origin_level: u32,
-    origin_number: u64
+    file_number: u64
 }
 
 impl ScTableFile {
-    pub(crate) fn new(origin_partition: u32, origin_level: u32, origin_number: u64) -> Self {
-        Self {
-            origin_partition, origin_level, origin_number
-        }
+    pub(crate) fn new(file_number: u64) -> Self {
+        Self { file_number }
     }
 
     pub(crate) fn file_name(&self) -> String {
-        format!("{}_{}_{}.sst", self.origin_partition, self.origin_level, self.origin_number)
+        format!("{:08}.sct", self.file_number)
+    }
+
+    /// Where the table lives in the database directory `db_dir`.
+    #[cfg(test)]
+    pub(crate) fn path(&self, db_dir: &std::path::Path) -> std::path::PathBuf {
+        db_dir.join(self.file_name())
+    }
+
+    /// Parses a file name produced by `file_name` back.
+    pub(crate) fn parse_file_name(file_name: &str) -> Option<Self> {
+        let stem = file_name.strip_suffix(".sct")?;
+        if stem.is_empty() || !stem.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None
+        }
+        stem.parse().ok().map(Self::new)
+    }
+
+    pub(crate) fn file_number(&self) -> u64 {
+        self.file_number
     }
 }
 
 pub(crate) struct ScTable<Comp: Comparator> {
     table_file: ScTableFile,
+    file_size: usize,
 
-    key_lower_bound: UserKey<Comp>,
-    key_upper_bound: UserKey<Comp>
+    key_lower_bound: UserKey<'static, Comp>,
+    key_upper_bound: UserKey<'static, Comp>,
+
+    wasted_reads: AtomicUsize
 }
 
 impl<Comp: Comparator> ScTable<Comp> {
-    pub(crate) fn new(table_file: ScTableFile, key_lower_bound: UserKey<Comp>, key_upper_bound: UserKey<Comp>) -> Self {
-        Self { table_file, key_lower_bound, key_upper_bound }
+    pub(crate) fn new(table_file: ScTableFile,
+                      file_size: usize,
+                      key_lower_bound: UserKey<'static, Comp>,
+                      key_upper_bound: UserKey<'static, Comp>) -> Self {
+        Self { table_file, file_size, key_lower_bound, key_upper_bound, wasted_reads: AtomicUsize::new(0) }
+    }
+
+    /// Runs `lookup` on the cached table, unless the bounds or the filter already rule `key` out.
+    /// A key within the bounds but not found counts as a wasted read, and as a filter negative or
+    /// false positive with the cache manager if the table has a filter.
+    fn lookup<'a, T>(&self,
+                     key: &InternalKey<Comp>,
+                     cache_manager: &'a TableCacheManager,
+                     io_manager: &'a IOManager,
+                     lookup: impl FnOnce(&Arc<ScTableCache>) -> Result<LookupResult<T>, Error>) -> Result<LookupResult<T>, Error> {
+        if self.cmp_key(&key.user_key) != Ordering::Equal {
+            return Ok(None)
+        }
+        let cache = cache_manager.get_or_load(self.table_file, io_manager)?;
+        let found = if cache.may_contain(key.user_key.key()) {
+            let found = lookup(&cache)?;
+            if found.is_none() && cache.has_filter() {
+                cache_manager.record_filter_false_positive();
+            }
+            found
+        } else {
+            cache_manager.record_filter_negative();
+            None
+        };
+        if found.is_none() {
+            self.wasted_reads.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+        Ok(found)
+    }
+
+    /// The value at `range` of the data region, pinned in `cache` if it holds the values.
+    /// Otherwise it comes from the block cache, or is read from the file and checked against its
+    /// crc, then shared with the block cache if it fits there.
+    fn read_value(&self,
+                  cache: &Arc<ScTableCache>,
+                  range: Range<usize>,
+                  cache_manager: &TableCacheManager,
+                  io_manager: &IOManager) -> Result<PinnedValue, Error> {
+        let offset = match cache.values_offset() {
+            None => return Ok(PinnedValue::in_table(cache.clone(), range)),
+            Some(_) if range.is_empty() => return Ok(PinnedValue::copied(Vec::new())),
+            Some(values_offset) => values_offset + range.start as u64
+        };
+        let block_cache = cache_manager.block_cache();
+        if let Some(block) = block_cache.get(self.table_file, offset) {
+            return Ok(PinnedValue::shared(block))
+        }
+        let expected_crc = cache.value_crc(&range)
+            .ok_or_else(|| Error::sc_table_corrupt("no value at that range".into()))?;
+        let value = io_manager.read_verified(self.table_file.file_name(), offset, range.len(), expected_crc)?;
+        if !block_cache.admits(value.len()) {
+            return Ok(PinnedValue::copied(value))
+        }
+        let block: Arc<[u8]> = value.into();
+        block_cache.insert(self.table_file, offset, block.clone());
+        Ok(PinnedValue::shared(block))
     }
 }
 
@@ -43,35 +126,238 @@ impl<Comp: Comparator> Table<Comp> for ScTable<Comp> {
     fn get<'a>(&self,
                key: &InternalKey<Comp>,
                cache_manager: &'a TableCacheManager,
-               io_manager: &'a IOManager) -> Result<Option<Vec<u8>>, Error> {
-        if key.user_key.cmp(self.lower_bound()) == Ordering::Less {
-            return Ok(None)
-        } else if key.user_key.cmp(self.upper_bound()) == Ordering::Greater {
-            return Ok(None)
-        }
+               io_manager: &'a IOManager) -> Result<LookupResult, Error> {
+        let found = self.get_with_seq(key, cache_manager, io_manager)?;
+        Ok(found.map(|value| value.map(|(value, _)| value)))
+    }
 
-        if let Some(cache) = cache_manager.get_cache(self.table_file) {
-            Ok(cache.get::<Comp>(key))
-        } else {
-            let cache_quota = cache_manager.acquire_quota();
-            let cache =
-                ScTableCache::from_raw(
-                    &io_manager.acquire_quota()
-                                    .read_file(self.table_file.file_name())?, cache_quota)?;
-            let cache = cache_manager.add_cache(self.table_file, cache);
-            Ok(cache.get::<Comp>(key))
-        }
+    fn contains<'a>(&self,
+                    key: &InternalKey<Comp>,
+                    cache_manager: &'a TableCacheManager,
+                    io_manager: &'a IOManager) -> Result<LookupResult<()>, Error> {
+        self.lookup(key, cache_manager, io_manager, |cache| Ok(cache.contains(key)))
+    }
+
+    fn get_with_seq<'a>(&self,
+                        key: &InternalKey<Comp>,
+                        cache_manager: &'a TableCacheManager,
+                        io_manager: &'a IOManager) -> Result<LookupResult<(Vec<u8>, u64)>, Error> {
+        self.lookup(key, cache_manager, io_manager, |cache| match cache.get_range_with_seq(key) {
+            Some(Some((range, seq))) => {
+                Ok(Some(Some((self.read_value(cache, range, cache_manager, io_manager)?.into_vec(), seq))))
+            },
+            Some(None) => Ok(Some(None)),
+            None => Ok(None)
+        })
+    }
+
+    fn get_pinned<'a>(&self,
+                      key: &InternalKey<Comp>,
+                      cache_manager: &'a TableCacheManager,
+                      io_manager: &'a IOManager) -> Result<LookupResult<PinnedValue>, Error> {
+        self.lookup(key, cache_manager, io_manager, |cache| match cache.get_range(key) {
+            Some(Some(range)) => Ok(Some(Some(self.read_value(cache, range, cache_manager, io_manager)?))),
+            Some(None) => Ok(Some(None)),
+            None => Ok(None)
+        })
+    }
+
+    fn get_versions<'a>(&self,
+                        key: &InternalKey<Comp>,
+                        cache_manager: &'a TableCacheManager,
+                        io_manager: &'a IOManager) -> Result<Vec<Entry<Comp>>, Error> {
+        let found = self.lookup(key, cache_manager, io_manager, |cache| {
+            let comparator = key.user_key.comparator();
+            let mut versions = Vec::new();
+            let mut keys = KeyCursor::default();
+            for n in cache.seek(key)..cache.catalog_size() {
+                let version = keys.nth_key(cache, n, comparator);
+                if comparator.compare(version.user_key.key(), key.user_key.key()) != Ordering::Equal {
+                    break
+                }
+                let value = self.read_value(cache, cache.nth_value_range(n), cache_manager, io_manager)?.into_vec();
+                let is_merge = version.is_merge();
+                versions.push((version, value));
+                if !is_merge {
+                    break
+                }
+            }
+            Ok(Some(versions).filter(|versions| !versions.is_empty()).map(Some))
+        })?;
+        Ok(found.flatten().unwrap_or_default())
     }
 
-    fn lower_bound(&self) -> &UserKey<Comp> {
+    fn load<'a>(&self,
+                cache_manager: &'a TableCacheManager,
+                io_manager: &'a IOManager) -> Result<Arc<ScTableCache>, Error> {
+        cache_manager.get_or_load_whole(self.table_file, io_manager)
+    }
+
+    fn load_keys<'a>(&self,
+                     cache_manager: &'a TableCacheManager,
+                     io_manager: &'a IOManager) -> Result<Arc<ScTableCache>, Error> {
+        cache_manager.get_or_load_keys(self.table_file, io_manager)
+    }
+
+    fn table_file(&self) -> ScTableFile {
+        self.table_file
+    }
+
+    fn file_size(&self) -> usize {
+        self.file_size
+    }
+
+    fn wasted_reads(&self) -> usize {
+        self.wasted_reads.load(atomic::Ordering::Relaxed)
+    }
+
+    fn lower_bound(&self) -> &UserKey<'_, Comp> {
         &self.key_lower_bound
     }
 
-    fn upper_bound(&self) -> &UserKey<Comp> {
+    fn upper_bound(&self) -> &UserKey<'_, Comp> {
         &self.key_upper_bound
     }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::error::Error;
+    use crate::io::IOManager;
+    use crate::partition::{InternalKey, UserKey};
+    use crate::table::Table;
+    use crate::table::builder::ScTableBuilder;
+    use crate::table::cache::{ScTableCache, TableCacheManager};
+    use crate::table::sctable::{ScTable, ScTableFile};
+    use crate::testutil::{TestDir, default_comparator};
+
+    #[test]
+    fn test_table_file_name() {
+        let table_file = ScTableFile::new(42);
+        assert_eq!(table_file.file_name(), "00000042.sct");
+        assert_eq!(table_file.path(Path::new("db")), Path::new("db/00000042.sct"));
+        assert_eq!(ScTableFile::new(123456789).file_name(), "123456789.sct");
+        for file_number in [0, 42, 123456789, u64::max_value()].iter() {
+            let table_file = ScTableFile::new(*file_number);
+            assert!(ScTableFile::parse_file_name(&table_file.file_name()) == Some(table_file));
+        }
+        for file_name in ["00000042.sst", ".sct", "+0000042.sct", "0_0_1.sct", "MANIFEST", "0_0.log"].iter() {
+            assert!(ScTableFile::parse_file_name(file_name).is_none());
+        }
+    }
+
+    #[test]
+    fn test_get_loads_through_cache() {
+        let dir = TestDir::new("sctable_get_loads_through_cache");
+        let io_manager = IOManager::new(dir.path(), 4);
+        let cache_manager = TableCacheManager::new(4);
+        let comparator = default_comparator();
+
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(2, b"apple", b"red");
+        builder.add_deletion(3, b"banana");
+        builder.add_kv(1, b"cherry", b"dark");
+        let raw = builder.build();
+        let table_file = ScTableFile::new(7);
+        io_manager.acquire_quota().write_file(table_file.file_name(), &raw).unwrap();
+        let table = ScTable::new(table_file,
+                                 raw.len(),
+                                 UserKey::new_owned(b"apple".to_vec(), &comparator),
+                                 UserKey::new_owned(b"cherry".to_vec(), &comparator));
+
+        let key = |user_key: &'static [u8]| InternalKey::new(u64::max_value(), UserKey::new_borrow(user_key, &comparator));
+        // out of bounds, the table is not even read
+        assert_eq!(table.get(&key(b"zebra"), &cache_manager, &io_manager).unwrap(), None);
+        assert_eq!(cache_manager.cache_misses(), 0);
+
+        assert_eq!(table.get(&key(b"apple"), &cache_manager, &io_manager).unwrap(), Some(Some(b"red".to_vec())));
+        assert_eq!(cache_manager.cache_misses(), 1);
+        assert_eq!(table.get(&key(b"banana"), &cache_manager, &io_manager).unwrap(), Some(None));
+        assert_eq!(table.get(&key(b"blueberry"), &cache_manager, &io_manager).unwrap(), None);
+        assert_eq!(table.get(&key(b"cherry"), &cache_manager, &io_manager).unwrap(), Some(Some(b"dark".to_vec())));
+        assert_eq!(cache_manager.cache_misses(), 1);
+        assert_eq!(cache_manager.cache_hits(), 3);
+    }
+
+    #[test]
+    fn test_get_index_only() {
+        let dir = TestDir::new("sctable_get_index_only");
+        let io_manager = IOManager::new(dir.path(), 4);
+        let cache_manager = TableCacheManager::new(4).with_index_only(true);
+        let comparator = default_comparator();
 
-    fn is_lazy(&self) -> bool {
-        false
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(2, b"apple", b"red");
+        builder.add_kv(1, b"apricot", b"orange");
+        builder.add_deletion(3, b"banana");
+        builder.add_kv(4, b"cherry", &[9u8; 1000]);
+        builder.add_kv(2, b"cherry", b"dark");
+        builder.add_kv(5, b"empty", b"");
+        let raw = builder.build();
+        let table_file = ScTableFile::new(7);
+        io_manager.acquire_quota().write_file(table_file.file_name(), &raw).unwrap();
+        let table = ScTable::new(table_file,
+                                 raw.len(),
+                                 UserKey::new_owned(b"apple".to_vec(), &comparator),
+                                 UserKey::new_owned(b"empty".to_vec(), &comparator));
+
+        let key = |seq, user_key: &'static [u8]| InternalKey::new(seq, UserKey::new_borrow(user_key, &comparator));
+        assert_eq!(table.get(&key(9, b"apple"), &cache_manager, &io_manager).unwrap(), Some(Some(b"red".to_vec())));
+        assert_eq!(table.get(&key(9, b"apricot"), &cache_manager, &io_manager).unwrap(), Some(Some(b"orange".to_vec())));
+        assert_eq!(table.get(&key(9, b"banana"), &cache_manager, &io_manager).unwrap(), Some(None));
+        assert_eq!(table.get(&key(9, b"blueberry"), &cache_manager, &io_manager).unwrap(), None);
+        assert_eq!(table.get(&key(9, b"cherry"), &cache_manager, &io_manager).unwrap(), Some(Some(vec![9u8; 1000])));
+        assert_eq!(table.get_with_seq(&key(3, b"cherry"), &cache_manager, &io_manager).unwrap(),
+                   Some(Some((b"dark".to_vec(), 2))));
+        assert_eq!(table.get(&key(9, b"empty"), &cache_manager, &io_manager).unwrap(), Some(Some(Vec::new())));
+        let value = table.get_pinned(&key(9, b"apricot"), &cache_manager, &io_manager).unwrap().unwrap().unwrap();
+        assert!(!value.is_pinned());
+        assert_eq!(&*value, b"orange");
+        assert_eq!(cache_manager.cache_misses(), 1);
+
+        // only the keys are cached, loading reads the whole table without caching it
+        let cached = cache_manager.get_cache(table_file).unwrap();
+        assert!(cached.values_offset().is_some());
+        assert!(cached.data_size() < 1000);
+        let whole = table.load(&cache_manager, &io_manager).unwrap();
+        assert!(whole.values_offset().is_none());
+        let keys = |cache: &ScTableCache| {
+            (0..cache.catalog_size()).map(|n| cache.nth_key(n, &comparator).user_key.key().to_vec()).collect::<Vec<_>>()
+        };
+        assert_eq!(keys(&whole), keys(&cached));
+        assert!(cache_manager.get_cache(table_file).unwrap().values_offset().is_some());
+    }
+
+    #[test]
+    fn test_index_only_value_corrupted_later() {
+        let dir = TestDir::new("sctable_index_only_value_corrupted_later");
+        let io_manager = IOManager::new(dir.path(), 4);
+        let cache_manager = TableCacheManager::new(4).with_index_only(true);
+        let comparator = default_comparator();
+
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, b"apple", b"red");
+        builder.add_kv(1, b"cherry", b"dark");
+        let raw = builder.build();
+        let table_file = ScTableFile::new(7);
+        io_manager.acquire_quota().write_file(table_file.file_name(), &raw).unwrap();
+        let table = ScTable::new(table_file,
+                                 raw.len(),
+                                 UserKey::new_owned(b"apple".to_vec(), &comparator),
+                                 UserKey::new_owned(b"cherry".to_vec(), &comparator));
+        let key = |user_key: &'static [u8]| InternalKey::new(u64::max_value(), UserKey::new_borrow(user_key, &comparator));
+        assert_eq!(table.get(&key(b"apple"), &cache_manager, &io_manager).unwrap(), Some(Some(b"red".to_vec())));
+
+        // the keys are cached already, the value of cherry goes bad on disk only now
+        let mut flipped = raw.clone();
+        let value_at = raw.windows(4).position(|window| window == b"dark").unwrap();
+        flipped[value_at] ^= 0xff;
+        io_manager.acquire_quota().write_file(table_file.file_name(), &flipped).unwrap();
+        match table.get(&key(b"cherry"), &cache_manager, &io_manager) {
+            Err(Error::ScTableCorrupt { .. }) => (),
+            other => panic!("a flipped value should fail its crc, got {:?}", other)
+        }
     }
 }
@@ -5,24 +5,105 @@
 //! | 4byte data size                            |
 //! | 4byte catalog crc                          |
 //! | 4byte data crc                             |
-//! +-CATALOG---+---------------+----------------+
-//! | 8byte seq | 4byte key_off | 4byte key_size |
-//! | 8byte seq | 4byte key_off | 4byte key_size |
-//! | ...       | ...           | ...            |
-//! +-DATA------+---------------+----------------+
+//! | 4byte filter size                          |
+//! | 4byte filter crc                           |
+//! | 4byte checksum                             |
+//! +-CATALOG, one item per entry----------------+
+//! | 8byte seq                                  |
+//! | 1byte kind                                 |
+//! | 4byte key_off     | 4byte key_size         |
+//! | 4byte value_off   | 4byte value_size       |
+//! | 4byte shared size                          |
+//! | ...                                        |
+//! +-DATA---------------------------------------+
 //! | data_size binary data                      |
 //! |                                            |
+//! +-FILTER-------------------------------------+
+//! | filter_size bloom filter, may be empty     |
 //! +-TAIL---------------------------------------+
 //! | 8byte TABLE_MAGIC                          |
 //! +--------------------------------------------+
 //! ```
+//!
+//! The checksum tells how every crc of the table is computed: `0` for CRC-32 as in zlib, `1` for
+//! CRC-32C (Castagnoli).
+//!
+//! The data region holds the keys of all items, then their values.
+//!
+//! Tables ending with `TABLE_MAGIC_COMPRESSED` have their values compressed. Their header goes on
+//! after the checksum:
+//! ```raw
+//! +-HEADER, continued--------------------------+
+//! | 4byte codec                                |
+//! | 4byte uncompressed data size               |
+//! | 4byte keys size                            |
+//! +--------------------------------------------+
+//! ```
+//! The first `keys size` bytes of the data region are the keys as they are, the compressed values
+//! follow, so keys are read without decompressing anything. The data size and crc in front are
+//! those of the stored bytes, so corruption is found before decompressing. Codec `1` is LZ4, see
+//! `lz4`. Catalog offsets point into the uncompressed data. Tables built without compression use
+//! `TABLE_MAGIC`.
+//!
+//! The kind is `0` for a tombstone, `1` for a value and `3` for an operand of `Db::merge`. A
+//! tombstone has no value, its value size is 0.
+//!
+//! Keys are prefix compressed: the key of an item is the first `shared size` bytes of the key of
+//! the item before it, followed by the `key_size` bytes at `key_off`. Every `restart_interval`th
+//! item, starting with the first, is a restart point which shares nothing and stores its key in
+//! full. Lookups binary search the items sharing nothing, then scan forward from the one before the
+//! key expanding the keys on the way.
+//!
+//! Tables ending with `TABLE_MAGIC_COMPRESSED_V5` are from before keys were kept apart from the
+//! compressed values: their compressed header stops after the uncompressed data size and their
+//! whole data region is compressed, keys and values mixed in any order.
+//!
+//! Tables ending with `TABLE_MAGIC_V4` or `TABLE_MAGIC_COMPRESSED_V4` are from before the checksum
+//! could be chosen: their header has no checksum, the compressed header goes on right after the
+//! filter crc, and every crc is CRC-32 as in zlib. So is every table older than that.
+//!
+//! Tables ending with `TABLE_MAGIC_V3` or `TABLE_MAGIC_COMPRESSED_V3` are from before keys were
+//! prefix compressed: their header is that of `TABLE_MAGIC_V4`, their catalog items stop after the
+//! value size and every key is stored in full.
+//!
+//! Tables ending with `TABLE_MAGIC_V2` are from before the kind got its own byte: their catalog
+//! items have no kind and a tombstone sets the highest bit of its value offset instead. Tables
+//! ending with `TABLE_MAGIC_V1` are older still, from before filters were added: their header stops
+//! after the data crc and they have no filter region.
 
-pub const TABLE_HEAD_SIZE: usize = 16;
+pub const TABLE_HEAD_SIZE: usize = 28;
 pub const TABLE_MIN_SIZE: usize = TABLE_MAGIC_SIZE + TABLE_HEAD_SIZE;
-pub const TABLE_CATALOG_ITEM_SIZE: usize = 24;
+pub const TABLE_CATALOG_ITEM_SIZE: usize = 29;
 
 pub const TABLE_MAX_SIZE: usize = 0x7FFFFFFF;
 pub const TABLE_DELETION_BITMASK: u32 = 0x80000000;
 
-pub const TABLE_MAGIC: &'static [u8] = b"40490fd0";
+pub const TABLE_MAGIC: &'static [u8] = b"40490fd4";
 pub const TABLE_MAGIC_SIZE: usize = TABLE_MAGIC.len();
+
+pub const TABLE_HEAD_SIZE_COMPRESSED: usize = 40;
+pub const TABLE_MIN_SIZE_COMPRESSED: usize = TABLE_MAGIC_SIZE + TABLE_HEAD_SIZE_COMPRESSED;
+pub const TABLE_MAGIC_COMPRESSED: &'static [u8] = b"40490fc5";
+pub const TABLE_CODEC_LZ4: u32 = 1;
+
+pub const TABLE_CHECKSUM_IEEE: u32 = 0;
+pub const TABLE_CHECKSUM_CASTAGNOLI: u32 = 1;
+
+pub const TABLE_HEAD_SIZE_COMPRESSED_V5: usize = 36;
+pub const TABLE_MAGIC_COMPRESSED_V5: &'static [u8] = b"40490fc4";
+
+pub const TABLE_HEAD_SIZE_V4: usize = 24;
+pub const TABLE_HEAD_SIZE_COMPRESSED_V4: usize = 32;
+pub const TABLE_MAGIC_V4: &'static [u8] = b"40490fd3";
+pub const TABLE_MAGIC_COMPRESSED_V4: &'static [u8] = b"40490fc3";
+
+pub const TABLE_CATALOG_ITEM_SIZE_V3: usize = 25;
+pub const TABLE_MAGIC_V3: &'static [u8] = b"40490fd2";
+pub const TABLE_MAGIC_COMPRESSED_V3: &'static [u8] = b"40490fc2";
+
+pub const TABLE_CATALOG_ITEM_SIZE_V2: usize = 24;
+pub const TABLE_MAGIC_V2: &'static [u8] = b"40490fd1";
+
+pub const TABLE_HEAD_SIZE_V1: usize = 16;
+pub const TABLE_MIN_SIZE_V1: usize = TABLE_MAGIC_SIZE + TABLE_HEAD_SIZE_V1;
+pub const TABLE_MAGIC_V1: &'static [u8] = b"40490fd0";
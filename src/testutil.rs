@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicUsize};
+
+use crate::DefaultComparator;
+
+/// A scratch directory under the system temp directory, removed again on drop.
+pub(crate) struct TestDir(PathBuf);
+
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("pr65_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+pub(crate) fn default_comparator() -> Arc<DefaultComparator> {
+    Arc::new(DefaultComparator())
+}
+
+/// Counts the log appends and syncs of an `IOManager`, so tests can tell which writes reached the
+/// log and the disk.
+#[derive(Default)]
+pub(crate) struct IOCounts {
+    appends: AtomicUsize,
+    syncs: AtomicUsize
+}
+
+impl IOCounts {
+    pub(crate) fn record_append(&self) {
+        self.appends.fetch_add(1, atomic::Ordering::SeqCst);
+    }
+
+    /// Number of appends to logs issued so far.
+    pub(crate) fn appends(&self) -> usize {
+        self.appends.load(atomic::Ordering::SeqCst)
+    }
+
+    pub(crate) fn record_sync(&self) {
+        self.syncs.fetch_add(1, atomic::Ordering::SeqCst);
+    }
+
+    /// Number of syncs issued so far.
+    pub(crate) fn syncs(&self) -> usize {
+        self.syncs.load(atomic::Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crc::crc32;
+
+use crate::{Comparator, SyncPolicy};
+use crate::encode::{decode_fixed32, decode_fixed64, encode_fixed32_ret, encode_fixed64_ret};
+use crate::error::Error;
+use crate::io::IOManager;
+use crate::partition::{Entry, InternalKey};
+
+/// Every log file starts with
+///
+/// ```text
+/// | magic: u32 | version: u32 |
+/// ```
+///
+/// followed by its records. Every record holds the entries of one write to the partition, which
+/// are replayed all together or not at all:
+///
+/// ```text
+/// | crc: u32 | length: u32 | count: u32 | entry * count | batch: u64 | others: u32 | other * others |
+/// ```
+///
+/// and every entry is its key as `InternalKey::encode_into` lays it out, followed by its value:
+///
+/// ```text
+/// | seq: u64 | kind: u8 | key_length: u32 | key | value_length: u32 | value |
+/// ```
+///
+/// `length` counts everything after itself, `crc` covers `length` and the payload. A write going
+/// to several partitions logs a record in each of them, `batch` is its first sequence number and
+/// every other partition is an `| partition_id: u32 | log_number: u64 |`, the log segment its
+/// part went to. A write to one partition has a `batch` of 0 and no others.
+///
+/// Logs without the header are of version 1, written before there were batches: every record is
+/// one entry, laid out as above but without `value_length`, the value takes the rest of it.
+pub(crate) const LOG_RECORD_HEAD_SIZE: usize = 8;
+const LOG_PAYLOAD_HEAD_SIZE: usize = 4;
+const LOG_MAGIC: u32 = 0x6c6f6773;
+const LOG_VERSION: u32 = 2;
+const LOG_HEADER_SIZE: usize = 8;
+
+pub(crate) fn log_file_name(partition_id: u32, log_number: u64) -> String {
+    format!("{}_{}.log", partition_id, log_number)
+}
+
+/// Parses a file name produced by `log_file_name` back into `(partition_id, log_number)`.
+pub(crate) fn parse_log_file_name(file_name: &str) -> Option<(u32, u64)> {
+    let stem = file_name.strip_suffix(".log")?;
+    let mut parts = stem.splitn(2, '_');
+    let partition_id = parts.next()?.parse().ok()?;
+    let log_number = parts.next()?.parse().ok()?;
+    Some((partition_id, log_number))
+}
+
+/// Where the other parts of a write spanning several partitions were logged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct BatchParts {
+    /// The first sequence number of the write, 0 for a write to one partition.
+    pub(crate) batch: u64,
+    /// The id and log segment of every other partition written to.
+    pub(crate) others: Vec<(u32, u64)>
+}
+
+pub(crate) struct LogRecord<Comp: Comparator> {
+    pub(crate) entries: Vec<Entry<Comp>>,
+    pub(crate) parts: BatchParts,
+    /// Where the record is in its log.
+    range: Range<usize>
+}
+
+fn encode_header() -> Vec<u8> {
+    let mut buffer = encode_fixed32_ret(LOG_MAGIC).to_vec();
+    buffer.extend_from_slice(&encode_fixed32_ret(LOG_VERSION));
+    buffer
+}
+
+pub(crate) fn encode_record<Comp>(entries: &[Entry<Comp>], parts: &BatchParts) -> Vec<u8>
+    where Comp: Comparator {
+    let length = LOG_PAYLOAD_HEAD_SIZE + entries.iter()
+        .map(|(key, value)| key.encoded_size() + 4 + value.len())
+        .sum::<usize>() + 12 + parts.others.len() * 12;
+    let mut buffer = Vec::with_capacity(LOG_RECORD_HEAD_SIZE + length);
+    buffer.extend_from_slice(&[0u8; 4]);
+    buffer.extend_from_slice(&encode_fixed32_ret(length as u32));
+    buffer.extend_from_slice(&encode_fixed32_ret(entries.len() as u32));
+    for (key, value) in entries {
+        key.encode_into(&mut buffer);
+        buffer.extend_from_slice(&encode_fixed32_ret(value.len() as u32));
+        buffer.extend_from_slice(value);
+    }
+    buffer.extend_from_slice(&encode_fixed64_ret(parts.batch));
+    buffer.extend_from_slice(&encode_fixed32_ret(parts.others.len() as u32));
+    for &(partition_id, log_number) in parts.others.iter() {
+        buffer.extend_from_slice(&encode_fixed32_ret(partition_id));
+        buffer.extend_from_slice(&encode_fixed64_ret(log_number));
+    }
+    let crc = crc32::checksum_ieee(&buffer[4..]);
+    buffer[0..4].copy_from_slice(&encode_fixed32_ret(crc));
+    buffer
+}
+
+/// Decodes the records of a log in write order. A crash may leave the last record half written,
+/// so decoding stops at the first record that is cut short or fails its crc instead of failing.
+/// A log of a version this one does not know fails.
+pub(crate) fn decode_records<Comp>(raw: &[u8], comparator: &Arc<Comp>) -> Result<Vec<LogRecord<Comp>>, Error>
+    where Comp: Comparator {
+    let (version, mut offset) = if raw.len() >= LOG_HEADER_SIZE && decode_fixed32(&raw[0..4]) == LOG_MAGIC {
+        (decode_fixed32(&raw[4..8]), LOG_HEADER_SIZE)
+    } else if raw.len() < LOG_HEADER_SIZE && encode_header().starts_with(raw) {
+        // the header is written as the log is created, a crash may have cut it short
+        return Ok(Vec::new())
+    } else {
+        (1, 0)
+    };
+    if version != 1 && version != LOG_VERSION {
+        return Err(Error::decode_error(format!("unknown log version {}", version).into()))
+    }
+    let mut ret = Vec::new();
+    while let Some((entries, parts, record_size)) = decode_record(&raw[offset..], version, comparator) {
+        ret.push(LogRecord { entries, parts, range: offset..offset + record_size });
+        offset += record_size;
+    }
+    Ok(ret)
+}
+
+fn decode_record<Comp>(raw: &[u8], version: u32, comparator: &Arc<Comp>) -> Option<(Vec<Entry<Comp>>, BatchParts, usize)>
+    where Comp: Comparator {
+    if raw.len() < LOG_RECORD_HEAD_SIZE {
+        return None
+    }
+    let crc = decode_fixed32(&raw[0..4]);
+    let length = decode_fixed32(&raw[4..8]) as usize;
+    if length < LOG_PAYLOAD_HEAD_SIZE || raw.len() - LOG_RECORD_HEAD_SIZE < length {
+        return None
+    }
+    let record_size = LOG_RECORD_HEAD_SIZE + length;
+    if crc32::checksum_ieee(&raw[4..record_size]) != crc {
+        return None
+    }
+
+    let payload = &raw[LOG_RECORD_HEAD_SIZE..record_size];
+    if version == 1 {
+        let (key, key_end) = InternalKey::decode(payload, comparator).ok()?;
+        let value = payload[key_end..].to_vec();
+        return Some((vec![(key, value)], BatchParts::default(), record_size))
+    }
+    let count = decode_fixed32(&payload[0..4]) as usize;
+    let mut rest = &payload[LOG_PAYLOAD_HEAD_SIZE..];
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let (entry, entry_size) = decode_entry(rest, comparator)?;
+        entries.push(entry);
+        rest = &rest[entry_size..];
+    }
+    if rest.len() < 12 {
+        return None
+    }
+    let batch = decode_fixed64(&rest[0..8]);
+    let other_count = decode_fixed32(&rest[8..12]) as usize;
+    rest = &rest[12..];
+    if rest.len() != other_count * 12 {
+        return None
+    }
+    let others = rest.chunks(12)
+        .map(|other| (decode_fixed32(&other[0..4]), decode_fixed64(&other[4..12])))
+        .collect();
+    Some((entries, BatchParts { batch, others }, record_size))
+}
+
+fn decode_entry<Comp>(raw: &[u8], comparator: &Arc<Comp>) -> Option<(Entry<Comp>, usize)>
+    where Comp: Comparator {
+    let (key, key_end) = InternalKey::decode(raw, comparator).ok()?;
+    if raw.len() < key_end + 4 {
+        return None
+    }
+    let value_length = decode_fixed32(&raw[key_end..key_end + 4]) as usize;
+    let value_end = key_end + 4 + value_length;
+    if raw.len() < value_end {
+        return None
+    }
+    let value = raw[key_end + 4..value_end].to_vec();
+    Some(((key, value), value_end))
+}
+
+/// The batches a crash cut short, named by their first sequence numbers. Every part of a batch is
+/// logged before any of them is applied, and the log segment of every part exists before the first
+/// one is written, so a batch is unfinished if a segment one of its records names is there without
+/// it. A segment gone was flushed or replayed, which only happens to a finished batch. With
+/// `remove` set the records of unfinished batches are cut from their logs, so they never look
+/// finished once the other logs have been replayed and removed.
+pub(crate) fn unfinished_batches<Comp>(io_manager: &IOManager, comparator: &Arc<Comp>, remove: bool) -> Result<HashSet<u64>, Error>
+    where Comp: Comparator {
+    let mut logs = HashMap::new();
+    for file_name in io_manager.list_files()? {
+        if let Some(segment) = parse_log_file_name(&file_name) {
+            let raw = io_manager.acquire_quota().read_file(file_name)?;
+            let records = decode_records(&raw, comparator)?;
+            logs.insert(segment, (raw, records));
+        }
+    }
+    let mut unfinished = HashSet::new();
+    for (_, records) in logs.values() {
+        for record in records.iter().filter(|record| record.parts.batch != 0) {
+            let finished = record.parts.others.iter().all(|segment| match logs.get(segment) {
+                Some((_, records)) => records.iter().any(|other| other.parts.batch == record.parts.batch),
+                None => true
+            });
+            if !finished {
+                unfinished.insert(record.parts.batch);
+            }
+        }
+    }
+    if !remove {
+        return Ok(unfinished)
+    }
+    for (&(partition_id, log_number), (raw, records)) in logs.iter() {
+        let cut: Vec<_> = records.iter()
+            .filter(|record| unfinished.contains(&record.parts.batch))
+            .map(|record| record.range.clone())
+            .collect();
+        if cut.is_empty() {
+            continue;
+        }
+        let mut kept = Vec::with_capacity(raw.len());
+        let mut offset = 0;
+        for range in cut {
+            kept.extend_from_slice(&raw[offset..range.start]);
+            offset = range.end;
+        }
+        kept.extend_from_slice(&raw[offset..]);
+        io_manager.acquire_quota().write_atomic(log_file_name(partition_id, log_number), &kept)?;
+    }
+    Ok(unfinished)
+}
+
+/// Appends records to a log segment of one memtable. A memtable may be logged to several
+/// segments one after the other, each a log file of its own which is replayed after the ones
+/// before it.
+pub(crate) struct LogWriter {
+    file: File,
+    file_name: String,
+    unsynced_records: usize,
+    size: usize
+}
+
+impl LogWriter {
+    /// Creates the log file `file_name` and writes its header.
+    pub(crate) fn create(file_name: String, io_manager: &IOManager) -> Result<Self, Error> {
+        let mut file = io_manager.create_log_file(file_name.clone())?;
+        let header = encode_header();
+        io_manager.append(&mut file, &file_name, &header)?;
+        Ok(Self { file, file_name, unsynced_records: 0, size: header.len() })
+    }
+
+    /// Bytes of the log so far.
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn add_record<Comp>(&mut self, entries: &[Entry<Comp>], parts: &BatchParts, io_manager: &IOManager) -> Result<(), Error>
+        where Comp: Comparator {
+        let record = encode_record(entries, parts);
+        io_manager.append(&mut self.file, &self.file_name, &record)?;
+        self.unsynced_records += 1;
+        self.size += record.len();
+        Ok(())
+    }
+
+    /// Whether `policy` asks for the records added so far to be synced now.
+    pub(crate) fn should_sync(&self, policy: SyncPolicy) -> bool {
+        match policy {
+            SyncPolicy::Sync => self.unsynced_records > 0,
+            SyncPolicy::NoSync => false,
+            SyncPolicy::EveryN(n) => self.unsynced_records >= n.max(1)
+        }
+    }
+
+    pub(crate) fn sync(&mut self, io_manager: &IOManager) -> Result<(), Error> {
+        io_manager.sync(&self.file, &self.file_name)?;
+        self.unsynced_records = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crc::crc32;
+
+    use crate::DefaultComparator;
+    use crate::encode::encode_fixed32_ret;
+    use crate::io::IOManager;
+    use crate::partition::{Entry, InternalKey, UserKey};
+    use crate::wal::{BatchParts, LogWriter, encode_header, encode_record, decode_records, log_file_name,
+                     parse_log_file_name, unfinished_batches};
+    use crate::testutil::{default_comparator, TestDir};
+
+    fn records() -> Vec<Entry<DefaultComparator>> {
+        let comparator = default_comparator();
+        vec![
+            (InternalKey::new(1, UserKey::new_owned(b"hello".to_vec(), &comparator)), b"world".to_vec()),
+            (InternalKey::new_deletion(2, UserKey::new_owned(b"hello".to_vec(), &comparator)), Vec::new()),
+            (InternalKey::new(u64::max_value(), UserKey::new_owned(Vec::new(), &comparator)), b"empty key".to_vec()),
+        ]
+    }
+
+    fn summary(entries: &[Entry<DefaultComparator>]) -> Vec<(u64, bool, Vec<u8>, Vec<u8>)> {
+        entries.iter().map(|(k, v)| (k.seq, k.is_deletion(), k.user_key.key().to_vec(), v.clone())).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let entries = records();
+        let parts = BatchParts { batch: 2, others: vec![(3, 7), (4, 1)] };
+        let mut raw = encode_header();
+        raw.extend(encode_record(&entries[..1], &BatchParts::default()));
+        raw.extend(encode_record(&entries[1..], &parts));
+        raw.extend(encode_record::<DefaultComparator>(&[], &BatchParts::default()));
+        let decoded = decode_records(&raw, &default_comparator()).unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(summary(&decoded[0].entries), summary(&entries[..1]));
+        assert_eq!(decoded[0].parts, BatchParts::default());
+        assert_eq!(summary(&decoded[1].entries), summary(&entries[1..]));
+        assert_eq!(decoded[1].parts, parts);
+        assert!(decoded[2].entries.is_empty());
+    }
+
+    #[test]
+    fn test_torn_tail_skipped() {
+        let entries = records();
+        let mut raw = encode_header();
+        raw.extend(encode_record(&entries[..1], &BatchParts::default()));
+        let last_record = encode_record(&entries[1..], &BatchParts::default());
+        raw.extend_from_slice(&last_record);
+        // a record cut anywhere is dropped as a whole, never replayed in part
+        for cut in 1..last_record.len() {
+            let decoded = decode_records(&raw[..raw.len() - cut], &default_comparator()).unwrap();
+            assert_eq!(decoded.len(), 1);
+            assert_eq!(summary(&decoded[0].entries), summary(&entries[..1]));
+        }
+
+        let last = raw.len() - 1;
+        raw[last] ^= 0xFF;
+        let decoded = decode_records(&raw, &default_comparator()).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(summary(&decoded[0].entries), summary(&entries[..1]));
+
+        for len in 0..encode_header().len() {
+            assert!(decode_records(&encode_header()[..len], &default_comparator()).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        // a log of version 1 has no header and one entry per record
+        let mut raw = Vec::new();
+        for (key, value) in records() {
+            let mut record = vec![0u8; 8];
+            key.encode_into(&mut record);
+            record.extend_from_slice(&value);
+            let length = record.len() as u32 - 8;
+            record[4..8].copy_from_slice(&encode_fixed32_ret(length));
+            let crc = crc32::checksum_ieee(&record[4..]);
+            record[0..4].copy_from_slice(&encode_fixed32_ret(crc));
+            raw.extend(record);
+        }
+        let decoded = decode_records(&raw, &default_comparator()).unwrap();
+        assert_eq!(decoded.iter().map(|record| summary(&record.entries)).collect::<Vec<_>>(),
+                   records().chunks(1).map(summary).collect::<Vec<_>>());
+        assert!(decoded.iter().all(|record| record.parts == BatchParts::default()));
+
+        let mut unknown = encode_header();
+        unknown[4..8].copy_from_slice(&encode_fixed32_ret(3));
+        unknown.extend(encode_record(&records(), &BatchParts::default()));
+        assert!(decode_records(&unknown, &default_comparator()).is_err());
+    }
+
+    #[test]
+    fn test_unfinished_batches() {
+        let dir = TestDir::new("wal_unfinished_batches");
+        let io_manager = IOManager::new(dir.path(), 4);
+        let comparator = default_comparator();
+        let entries = records();
+        let write = |partition_id, log_number, writes: &[(u64, &[(u32, u64)])]| {
+            let mut log = LogWriter::create(log_file_name(partition_id, log_number), &io_manager).unwrap();
+            for &(batch, others) in writes {
+                log.add_record(&entries[..1], &BatchParts { batch, others: others.to_vec() }, &io_manager).unwrap();
+            }
+        };
+        // batch 10 went to both partitions, batch 20 to partition 0 alone before the crash, the
+        // first log of partition 1 holding batch 30 was flushed
+        write(0, 1, &[(10, &[(1, 2)]), (0, &[]), (30, &[(1, 1)]), (20, &[(1, 2)])]);
+        write(1, 2, &[(10, &[(0, 1)])]);
+        let read = |partition_id, log_number| {
+            let raw = io_manager.acquire_quota().read_file(log_file_name(partition_id, log_number)).unwrap();
+            decode_records(&raw, &comparator).unwrap().iter().map(|record| record.parts.batch).collect::<Vec<_>>()
+        };
+
+        assert_eq!(unfinished_batches(&io_manager, &comparator, false).unwrap(), [20].iter().cloned().collect());
+        assert_eq!(read(0, 1), vec![10, 0, 30, 20]);
+        assert_eq!(unfinished_batches(&io_manager, &comparator, true).unwrap(), [20].iter().cloned().collect());
+        assert_eq!(read(0, 1), vec![10, 0, 30]);
+        assert_eq!(read(1, 2), vec![10]);
+        assert_eq!(unfinished_batches(&io_manager, &comparator, true).unwrap(), HashSet::new());
+    }
+
+    #[test]
+    fn test_log_file_name() {
+        assert_eq!(parse_log_file_name(&log_file_name(3, 42)), Some((3, 42)));
+        assert_eq!(parse_log_file_name("3_0_42.sst"), None);
+        assert_eq!(parse_log_file_name("3_x.log"), None);
+    }
+}